@@ -0,0 +1,21 @@
+//! Relay forwarding the laminar traffic of the peers whose plugin was given a `RelayConfig`
+//! pointing to it.
+//!
+//! Usage: `cargo run --example relay_server -- [address]`, binding to `0.0.0.0:7777` by default.
+
+use std::{env, io, thread, time::Duration};
+
+use blaminar::prelude::RelayServer;
+
+fn main() -> io::Result<()> {
+    let address = env::args().nth(1).unwrap_or_else(|| "0.0.0.0:7777".to_string());
+    let mut relay = RelayServer::bind(address.as_str())?;
+    println!("Relaying on {}", relay.local_addr()?);
+
+    loop {
+        if let Err(e) = relay.poll() {
+            eprintln!("Relay error: {}", e);
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
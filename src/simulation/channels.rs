@@ -0,0 +1,334 @@
+//! Channels, dispatching the incoming messages to a bevy event type per kind of message.
+//!
+//! Every payload sent on a channel is prefixed with the channel id. `ChannelPlugin` registers an
+//! event type for an id, and emits one of those events for each message received on it, without
+//! the prefix. The `Message` events are still emitted as usual.
+//!
+//! The channels registered with `ChannelsConfig` are named delivery requirements instead, each
+//! given a stream id of its own so that the systems sending on them don't share a stream by
+//! accident. Nothing is added to their payloads.
+
+use std::net::SocketAddr;
+#[cfg(feature = "bevy")]
+use std::{any::{type_name, TypeId}, collections::HashMap, marker::PhantomData};
+
+#[cfg(feature = "bevy")]
+use bevy::app::App;
+#[cfg(feature = "bevy")]
+use bevy::prelude::{EventReader, EventWriter, Plugin, Res, Resource, SystemLabel, SystemSet};
+use bytes::Bytes;
+
+#[cfg(feature = "bevy")]
+use crate::simulation::events::NetworkSimulationEvent;
+use crate::simulation::{
+    requirements::{DeliveryRequirement, StreamId, UrgencyRequirement},
+    transport::TransportResource,
+};
+
+/// Stream ids given to the channels, laminar keeps 255 for the messages without one.
+const CHANNEL_STREAMS: usize = u8::MAX as usize;
+
+impl TransportResource {
+    /// Queues `payload` on `channel` with the specified guarantee, to be sent on next sim tick.
+    pub fn send_on_channel(
+        &mut self,
+        destination: SocketAddr,
+        channel: u8,
+        payload: &[u8],
+        delivery: DeliveryRequirement,
+    ) {
+        let mut prefixed = Vec::with_capacity(payload.len() + 1);
+        prefixed.push(channel);
+        prefixed.extend_from_slice(payload);
+        self.send_with_requirements(destination, &prefixed, delivery, UrgencyRequirement::OnTick);
+    }
+
+    /// Queues `payload` with the delivery requirement of `channel`, on its stream, to be sent on
+    /// next sim tick.
+    pub fn send_on(&mut self, channel: Channel, destination: SocketAddr, payload: &[u8]) {
+        self.send_with_requirements(destination, payload, channel.delivery, UrgencyRequirement::OnTick);
+    }
+}
+
+/// Handle of a channel registered with `ChannelsConfig::add`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Channel {
+    name:     &'static str,
+    delivery: DeliveryRequirement,
+}
+
+impl Channel {
+    /// Returns the name the channel was registered with.
+    #[must_use]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the delivery requirement of the messages sent on the channel, on its stream.
+    #[must_use]
+    pub fn delivery(&self) -> DeliveryRequirement {
+        self.delivery
+    }
+
+    /// Returns the stream of the channel, `None` for a requirement without streams.
+    #[must_use]
+    pub fn stream(&self) -> Option<StreamId> {
+        match self.delivery {
+            DeliveryRequirement::UnreliableSequenced(stream)
+            | DeliveryRequirement::ReliableSequenced(stream)
+            | DeliveryRequirement::ReliableOrdered(stream) => stream.map(StreamId),
+            _ => None,
+        }
+    }
+}
+
+/// Resource holding the named channels, usually registered while building the app. Each channel of
+/// a sequenced or ordered requirement is given a stream id of its own, counting from 0, which the
+/// game mustn't use itself.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "bevy", derive(Resource))]
+pub struct ChannelsConfig {
+    channels: Vec<Channel>,
+}
+
+impl ChannelsConfig {
+    /// Registers the channel `name`, sending with `delivery` on a stream of its own, and returns
+    /// its handle. The stream of `delivery`, if any, is replaced.
+    ///
+    /// # Panics
+    ///
+    /// If a channel is already registered as `name`, or if the 255 stream ids are all taken.
+    pub fn add(&mut self, name: &'static str, delivery: DeliveryRequirement) -> Channel {
+        assert!(self.get(name).is_none(), "channel {} is already registered", name);
+        let streams = self.channels.iter().filter(|channel| channel.stream().is_some()).count();
+        let stream = || {
+            assert!(streams < CHANNEL_STREAMS, "no stream id is left for channel {}", name);
+            Some(streams as u8)
+        };
+        let delivery = match delivery {
+            DeliveryRequirement::UnreliableSequenced(_) => DeliveryRequirement::UnreliableSequenced(stream()),
+            DeliveryRequirement::ReliableSequenced(_) => DeliveryRequirement::ReliableSequenced(stream()),
+            DeliveryRequirement::ReliableOrdered(_) => DeliveryRequirement::ReliableOrdered(stream()),
+            delivery => delivery,
+        };
+        let channel = Channel { name, delivery };
+        self.channels.push(channel);
+        channel
+    }
+
+    /// Returns the channel registered as `name`, if any.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<Channel> {
+        self.channels.iter().find(|channel| channel.name == name).copied()
+    }
+
+    /// Returns the channel a message delivered with `delivery` was sent on, e.g. the requirement of
+    /// a `Delivered` event. Only the channels with a stream can be told apart, and not once the
+    /// coalescing mixes the streams.
+    #[must_use]
+    pub fn channel_of(&self, delivery: DeliveryRequirement) -> Option<Channel> {
+        self.channels
+            .iter()
+            .find(|channel| channel.stream().is_some() && channel.delivery == delivery)
+            .copied()
+    }
+
+    /// Iterates over the channels, in the order they were registered.
+    pub fn iter(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.iter()
+    }
+}
+
+/// Event type a channel dispatches its messages to.
+pub trait ChannelEvent: Send + Sync + 'static {
+    /// Creates the event for a message received from `from`, its payload stripped of the channel
+    /// id.
+    fn from_message(from: SocketAddr, payload: Bytes) -> Self;
+}
+
+#[cfg(feature = "bevy")]
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct NetworkChannelsLabel;
+
+/// Resource holding the event type registered for each channel.
+#[cfg(feature = "bevy")]
+#[derive(Debug, Default, Resource)]
+pub struct NetworkChannels {
+    channels: HashMap<u8, (TypeId, &'static str)>,
+}
+
+#[cfg(feature = "bevy")]
+impl NetworkChannels {
+    /// Registers `E` as the event type of `channel`.
+    ///
+    /// # Panics
+    ///
+    /// If another event type is already registered for `channel`.
+    pub fn register<E: ChannelEvent>(&mut self, channel: u8) {
+        let (type_id, name) = *self.channels.entry(channel).or_insert((TypeId::of::<E>(), type_name::<E>()));
+        assert!(type_id == TypeId::of::<E>(), "channel {} is already registered for {}", channel, name);
+    }
+
+    /// Returns the channel `E` is registered for, if any.
+    #[must_use]
+    pub fn channel_of<E: ChannelEvent>(&self) -> Option<u8> {
+        self.channels
+            .iter()
+            .find(|(_, (type_id, _))| *type_id == TypeId::of::<E>())
+            .map(|(channel, _)| *channel)
+    }
+}
+
+/// Use this plugin to emit an `E` event for every message received on `channel`.
+#[cfg(feature = "bevy")]
+pub struct ChannelPlugin<E> {
+    channel: u8,
+    marker:  PhantomData<fn() -> E>,
+}
+
+#[cfg(feature = "bevy")]
+impl<E: ChannelEvent> ChannelPlugin<E> {
+    #[must_use]
+    pub fn new(channel: u8) -> Self {
+        Self { channel, marker: PhantomData }
+    }
+}
+
+#[cfg(feature = "bevy")]
+impl<E: ChannelEvent> Plugin for ChannelPlugin<E> {
+    fn build(&self, app: &mut App) {
+        app
+            .add_event::<E>()
+            .add_event::<NetworkSimulationEvent>()
+            .init_resource::<NetworkChannels>();
+        app.world.resource_mut::<NetworkChannels>().register::<E>(self.channel);
+        app.add_system_set(SystemSet::new()
+            .label(NetworkChannelsLabel)
+            .with_system(channel_dispatch_system::<E>)
+        );
+    }
+
+    fn name(&self) -> &str {
+        type_name::<Self>()
+    }
+}
+
+/// Creates a new system emitting an `E` event for every message received on the channel of `E`.
+#[cfg(feature = "bevy")]
+pub fn channel_dispatch_system<E: ChannelEvent>(mut network_events: EventReader<NetworkSimulationEvent>,
+                                                mut channel_events: EventWriter<E>,
+                                                    channels:       Res<NetworkChannels>) {
+    let Some(channel) = channels.channel_of::<E>() else {
+        return;
+    };
+    for event in network_events.iter() {
+        if let NetworkSimulationEvent::Message(addr, payload) = event {
+            if payload.first() == Some(&channel) {
+                channel_events.send(E::from_message(*addr, payload.slice(1..)));
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "bevy"))]
+mod tests {
+    use bevy::ecs::event::Events;
+
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Input(SocketAddr, Bytes);
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Chat(String);
+
+    impl ChannelEvent for Input {
+        fn from_message(from: SocketAddr, payload: Bytes) -> Self {
+            Input(from, payload)
+        }
+    }
+
+    impl ChannelEvent for Chat {
+        fn from_message(_: SocketAddr, payload: Bytes) -> Self {
+            Chat(String::from_utf8_lossy(&payload).into_owned())
+        }
+    }
+
+    #[test]
+    fn test_messages_are_dispatched_by_channel() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut transport = TransportResource::new();
+        transport.send_on_channel(addr, 1, b"jump", DeliveryRequirement::Unreliable);
+        transport.send_on_channel(addr, 2, b"hello", DeliveryRequirement::ReliableOrdered(None));
+        transport.send_on_channel(addr, 3, b"unregistered", DeliveryRequirement::Unreliable);
+        transport.send(addr, b"");
+
+        let mut app = App::new();
+        app.add_plugin(ChannelPlugin::<Input>::new(1))
+            .add_plugin(ChannelPlugin::<Chat>::new(2));
+        for message in transport.drain_messages_to_send(|_| true) {
+            app.world.send_event(NetworkSimulationEvent::Message(addr, message.payload));
+        }
+        app.update();
+
+        let inputs = app.world.resource::<Events<Input>>();
+        let inputs: Vec<_> = inputs.get_reader().iter(inputs).cloned().collect();
+        assert_eq!(inputs, [Input(addr, Bytes::from_static(b"jump"))]);
+        let chats = app.world.resource::<Events<Chat>>();
+        let chats: Vec<_> = chats.get_reader().iter(chats).cloned().collect();
+        assert_eq!(chats, [Chat("hello".to_string())]);
+    }
+
+    #[test]
+    #[should_panic(expected = "channel 1 is already registered")]
+    fn test_channels_are_registered_once() {
+        let mut channels = NetworkChannels::default();
+        channels.register::<Input>(1);
+        channels.register::<Input>(1);
+        channels.register::<Chat>(1);
+    }
+
+    #[test]
+    fn test_channels_get_streams_of_their_own() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut channels = ChannelsConfig::default();
+        let chat = channels.add("chat", DeliveryRequirement::ReliableOrdered(None));
+        let inputs = channels.add("inputs", DeliveryRequirement::Unreliable);
+        let positions = channels.add("positions", DeliveryRequirement::UnreliableSequenced(Some(0)));
+        assert_eq!(chat.stream(), Some(StreamId(0)));
+        assert_eq!(inputs.stream(), None);
+        assert_eq!(positions.delivery(), DeliveryRequirement::UnreliableSequenced(Some(1)));
+        assert_eq!(channels.get("chat"), Some(chat));
+        assert_eq!(channels.channel_of(DeliveryRequirement::ReliableOrdered(Some(0))), Some(chat));
+        assert_eq!(channels.channel_of(DeliveryRequirement::Unreliable), None);
+
+        let mut transport = TransportResource::new();
+        transport.send_on(chat, addr, b"gl hf");
+        let message = transport.drain_messages_to_send(|_| true).remove(0);
+        assert_eq!(message.delivery, DeliveryRequirement::ReliableOrdered(Some(0)));
+        assert_eq!(&message.payload[..], b"gl hf");
+    }
+
+    #[test]
+    #[should_panic(expected = "channel chat is already registered")]
+    fn test_channel_names_are_unique() {
+        let mut channels = ChannelsConfig::default();
+        channels.add("chat", DeliveryRequirement::ReliableOrdered(None));
+        channels.add("chat", DeliveryRequirement::Reliable);
+    }
+
+    #[test]
+    #[should_panic(expected = "no stream id is left for channel overflow")]
+    fn test_stream_ids_run_out() {
+        let names: Vec<&'static str> = (0..255)
+            .map(|i| &*Box::leak(format!("channel {}", i).into_boxed_str()))
+            .collect();
+        let mut channels = ChannelsConfig::default();
+        for name in names {
+            channels.add(name, DeliveryRequirement::ReliableSequenced(None));
+        }
+        // the channels without a stream don't take one
+        channels.add("unreliable", DeliveryRequirement::Unreliable);
+        channels.add("overflow", DeliveryRequirement::ReliableOrdered(None));
+    }
+}
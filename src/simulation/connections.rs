@@ -0,0 +1,135 @@
+//! Stable ids of the connected peers, so that the game can address a peer by its connection
+//! rather than by its address, which a relay, a host migration or a peer that moved changes.
+//!
+//! An id is allocated when the peer connects and retired when it disconnects. The ids aren't
+//! reused, so a stale id never reaches another peer which got the address since.
+
+use std::{collections::HashMap, fmt, net::SocketAddr};
+
+/// Id of a connection, see `ConnectionIds`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(pub u32);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connection {}", self.0)
+    }
+}
+
+/// The ids of the connected peers and their addresses, both ways. Kept up to date by
+/// `TransportResource::peer_connected` and `peer_disconnected`, see
+/// `TransportResource::connections`.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionIds {
+    by_addr: HashMap<SocketAddr, ConnectionId>,
+    by_id:   HashMap<ConnectionId, SocketAddr>,
+    next:    u32,
+}
+
+impl ConnectionIds {
+    /// Returns the id of the peer at `addr`, if it is connected.
+    #[must_use]
+    pub fn id_of(&self, addr: &SocketAddr) -> Option<ConnectionId> {
+        self.by_addr.get(addr).copied()
+    }
+
+    /// Returns the address of the peer of `id`, if the connection wasn't retired.
+    #[must_use]
+    pub fn addr_of(&self, id: ConnectionId) -> Option<SocketAddr> {
+        self.by_id.get(&id).copied()
+    }
+
+    /// Returns the ids of the connected peers, with their addresses.
+    pub fn iter(&self) -> impl Iterator<Item = (ConnectionId, SocketAddr)> + '_ {
+        self.by_id.iter().map(|(id, addr)| (*id, *addr))
+    }
+
+    /// Returns the number of connected peers.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    /// Returns true if no peer is connected.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.by_id.is_empty()
+    }
+
+    /// Returns the id of the peer at `addr`, allocating one if it has none yet, along with whether
+    /// it was.
+    pub(crate) fn allocate(&mut self, addr: SocketAddr) -> (ConnectionId, bool) {
+        if let Some(id) = self.id_of(&addr) {
+            return (id, false);
+        }
+        let id = ConnectionId(self.next);
+        self.next += 1;
+        self.by_addr.insert(addr, id);
+        self.by_id.insert(id, addr);
+        (id, true)
+    }
+
+    /// Retires the id of the peer at `addr`, returning it if it had one.
+    pub(crate) fn retire(&mut self, addr: &SocketAddr) -> Option<ConnectionId> {
+        let id = self.by_addr.remove(addr)?;
+        self.by_id.remove(&id);
+        Some(id)
+    }
+
+    /// Gives the id of the peer at `from` to `to` instead, e.g. when the peer moved. The id `to`
+    /// had already, e.g. the host elected by a migration, is retired and returned, so that it can't
+    /// reach the moved peer.
+    pub(crate) fn readdress(&mut self, from: &SocketAddr, to: SocketAddr) -> Option<ConnectionId> {
+        if from == &to {
+            return None;
+        }
+        let id = self.by_addr.remove(from)?;
+        let retired = self.retire(&to);
+        self.by_addr.insert(to, id);
+        self.by_id.insert(id, to);
+        retired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids_are_retired_and_never_reused() {
+        let a = "127.0.0.1:3000".parse().unwrap();
+        let b = "127.0.0.1:3001".parse().unwrap();
+        let mut ids = ConnectionIds::default();
+        let (first, allocated) = ids.allocate(a);
+        assert!(allocated);
+        assert_eq!(ids.allocate(a), (first, false));
+        assert_eq!(ids.addr_of(first), Some(a));
+
+        ids.readdress(&a, b);
+        assert_eq!((ids.id_of(&a), ids.id_of(&b), ids.addr_of(first)), (None, Some(first), Some(b)));
+
+        assert_eq!(ids.retire(&b), Some(first));
+        assert_eq!(ids.retire(&b), None);
+        assert!(ids.is_empty());
+        let (second, _) = ids.allocate(b);
+        assert_ne!(second, first);
+        assert_eq!(ids.addr_of(first), None);
+    }
+
+    #[test]
+    fn test_readdress_onto_a_connected_peer_retires_its_id() {
+        let lost = "127.0.0.1:3000".parse().unwrap();
+        let elected = "127.0.0.1:3001".parse().unwrap();
+        let mut ids = ConnectionIds::default();
+        let (moved, _) = ids.allocate(lost);
+        let (other, _) = ids.allocate(elected);
+
+        assert_eq!(ids.readdress(&lost, elected), Some(other));
+        assert_eq!(ids.id_of(&elected), Some(moved));
+        assert_eq!((ids.addr_of(moved), ids.addr_of(other)), (Some(elected), None));
+        assert_eq!(ids.len(), 1);
+        assert_eq!(ids.retire(&elected), Some(moved));
+        assert!(ids.is_empty());
+        assert_eq!(ids.readdress(&elected, elected), None);
+    }
+}
@@ -0,0 +1,219 @@
+//! Decoding of the received payloads into a message type of the game, so that its systems read
+//! `TypedNetworkEvent`s rather than each parsing the `Bytes` of the `Message` events again.
+//!
+//! The payloads are decoded with `DecodeMessage`, whatever their encoding. The connections and the
+//! errors are passed through along with the decoded messages, and a payload which can't be decoded
+//! is reported as a `DecodeError`. The `NetworkSimulationEvent`s are still emitted as usual.
+
+use std::{fmt, io, net::SocketAddr};
+#[cfg(feature = "bevy")]
+use std::{any::type_name, marker::PhantomData, sync::Mutex};
+
+#[cfg(feature = "bevy")]
+use bevy::app::App;
+#[cfg(feature = "bevy")]
+use bevy::prelude::{EventReader, EventWriter, IntoSystemDescriptor, Plugin, SystemLabel, SystemSet};
+
+use crate::simulation::{
+    events::{DisconnectReason, NetworkSimulationEvent},
+    message::Message,
+};
+#[cfg(feature = "bevy")]
+use crate::simulation::transport::laminar::{LaminarLabel, LaminarPlugin};
+
+/// Message type of the game, decoded from the received payloads.
+pub trait DecodeMessage: Sized + Send + Sync + 'static {
+    /// Error returned for a payload which isn't a message.
+    type Error: fmt::Debug + Send + Sync + 'static;
+
+    /// Decodes the payload of a message received from `from`.
+    fn decode(from: SocketAddr, payload: &[u8]) -> Result<Self, Self::Error>;
+}
+
+/// Events emitted by `TypedNetworkPlugin`, the `NetworkSimulationEvent`s of the same name with the
+/// messages decoded.
+#[derive(Debug)]
+pub enum TypedNetworkEvent<M: DecodeMessage> {
+    // A message was received from a remote client
+    Message(SocketAddr, M),
+    // A payload received from a remote client isn't a message
+    DecodeError(SocketAddr, M::Error),
+    // A new host has connected to us
+    Connect(SocketAddr),
+    // We sent a handshake to the host given to `TransportResource::connect`
+    Connecting(SocketAddr),
+    // A host has disconnected from us, see `DisconnectReason` for why
+    Disconnect(SocketAddr, DisconnectReason),
+    // An error occurred while receiving a message.
+    RecvError(io::Error),
+    // An error occurred while sending a message.
+    SendError(io::Error, Message),
+    // An error occurred while managing connections.
+    ConnectionError(io::Error, Option<SocketAddr>),
+}
+
+impl<M: DecodeMessage> TypedNetworkEvent<M> {
+    /// Returns the typed event of `event`, decoding its payload if it's a `Message`, or `None` for
+    /// the events which aren't passed through.
+    pub fn from_event(event: &NetworkSimulationEvent) -> Option<Self> {
+        // `io::Error` isn't `Clone`, the errors passed through keep their kind and message
+        let copy = |e: &io::Error| io::Error::new(e.kind(), e.to_string());
+        Some(match event {
+            NetworkSimulationEvent::Message(addr, payload) => match M::decode(*addr, payload) {
+                Ok(message) => Self::Message(*addr, message),
+                Err(e) => Self::DecodeError(*addr, e),
+            },
+            NetworkSimulationEvent::Connect(addr) => Self::Connect(*addr),
+            NetworkSimulationEvent::Connecting(addr) => Self::Connecting(*addr),
+            NetworkSimulationEvent::Disconnect(addr, reason) => Self::Disconnect(*addr, *reason),
+            NetworkSimulationEvent::RecvError(e) => Self::RecvError(copy(e)),
+            NetworkSimulationEvent::SendError(e, message) => Self::SendError(copy(e), message.clone()),
+            NetworkSimulationEvent::ConnectionError(e, addr) => Self::ConnectionError(copy(e), *addr),
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(feature = "bevy")]
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct TypedNetworkLabel;
+
+/// Use this plugin in place of the `LaminarPlugin` it wraps to also emit a `TypedNetworkEvent<M>`
+/// for every message received, decoded as an `M`.
+#[cfg(feature = "bevy")]
+pub struct TypedNetworkPlugin<M> {
+    laminar: Mutex<Option<LaminarPlugin>>,
+    marker:  PhantomData<fn() -> M>,
+}
+
+#[cfg(feature = "bevy")]
+impl<M: DecodeMessage> TypedNetworkPlugin<M> {
+    #[must_use]
+    pub fn new(laminar: LaminarPlugin) -> Self {
+        Self { laminar: Mutex::new(Some(laminar)), marker: PhantomData }
+    }
+}
+
+#[cfg(feature = "bevy")]
+impl<M: DecodeMessage> Plugin for TypedNetworkPlugin<M> {
+    fn build(&self, app: &mut App) {
+        if let Some(laminar) = self.laminar.lock().unwrap_or_else(std::sync::PoisonError::into_inner).take() {
+            app.add_plugin(laminar);
+        }
+        app
+            .add_event::<TypedNetworkEvent<M>>()
+            .add_event::<NetworkSimulationEvent>()
+            .add_system_set(SystemSet::new()
+                .label(TypedNetworkLabel)
+                .with_system(typed_network_event_system::<M>.after(LaminarLabel))
+            );
+    }
+
+    fn name(&self) -> &str {
+        type_name::<Self>()
+    }
+}
+
+/// Creates a new system emitting the `TypedNetworkEvent` of every `NetworkSimulationEvent` passed
+/// through, see `TypedNetworkEvent::from_event`.
+#[cfg(feature = "bevy")]
+pub fn typed_network_event_system<M: DecodeMessage>(mut network_events: EventReader<NetworkSimulationEvent>,
+                                                    mut typed_events:   EventWriter<TypedNetworkEvent<M>>) {
+    typed_events.send_batch(network_events.iter().filter_map(TypedNetworkEvent::from_event));
+}
+
+#[cfg(all(test, feature = "bevy"))]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use bevy::ecs::event::Events;
+
+    use super::*;
+    use crate::simulation::transport::{
+        laminar::{LaminarConfig, LaminarSocketResource},
+        TransportResource,
+    };
+
+    #[derive(Debug, PartialEq)]
+    enum GameMessage {
+        StartMatch { map: u8 },
+        Chat(String),
+    }
+
+    impl GameMessage {
+        fn encode(&self) -> Vec<u8> {
+            match self {
+                GameMessage::StartMatch { map } => vec![0, *map],
+                GameMessage::Chat(text) => [&[1], text.as_bytes()].concat(),
+            }
+        }
+    }
+
+    impl DecodeMessage for GameMessage {
+        type Error = String;
+
+        fn decode(_: SocketAddr, payload: &[u8]) -> Result<Self, Self::Error> {
+            match payload {
+                [0, map] => Ok(GameMessage::StartMatch { map: *map }),
+                [1, text @ ..] => String::from_utf8(text.to_vec()).map(GameMessage::Chat).map_err(|e| e.to_string()),
+                _ => Err(format!("unknown message {:?}", payload)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_messages_are_decoded_over_loopback() {
+        let mut server = create_test_app();
+        let mut client = create_test_app();
+        let server_addr = server.world.resource::<LaminarSocketResource>().local_addr().unwrap();
+        let client_addr = client.world.resource::<LaminarSocketResource>().local_addr().unwrap();
+        let mut transport = client.world.resource_mut::<TransportResource>();
+        for message in [GameMessage::StartMatch { map: 3 }, GameMessage::Chat("gl hf".to_string())] {
+            transport.send_reliable_ordered(server_addr, &message.encode(), None);
+        }
+        transport.send_reliable_ordered(server_addr, b"\x07garbage", None);
+
+        let mut reader = server.world.resource::<Events<TypedNetworkEvent<GameMessage>>>().get_reader();
+        let mut received = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while received.len() < 3 && Instant::now() < deadline {
+            client.update();
+            server.update();
+            let events = server.world.resource::<Events<TypedNetworkEvent<GameMessage>>>();
+            received.extend(reader.iter(events).map(|event| match event {
+                TypedNetworkEvent::Message(addr, message) => format!("{} {:?}", addr, message),
+                TypedNetworkEvent::DecodeError(addr, e) => format!("{} {}", addr, e),
+                event => format!("{:?}", event),
+            }));
+        }
+
+        assert_eq!(received, [
+            format!("{} StartMatch {{ map: 3 }}", client_addr),
+            format!("{} Chat(\"gl hf\")", client_addr),
+            format!("{} unknown message [7, 103, 97, 114, 98, 97, 103, 101]", client_addr),
+        ]);
+    }
+
+    #[test]
+    fn test_connections_and_errors_are_passed_through() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let typed = |event| TypedNetworkEvent::<GameMessage>::from_event(&event);
+        assert!(
+            matches!(typed(NetworkSimulationEvent::Connect(addr)), Some(TypedNetworkEvent::Connect(a)) if a == addr)
+        );
+        assert!(matches!(
+            typed(NetworkSimulationEvent::RecvError(io::Error::new(io::ErrorKind::InvalidData, "oops"))),
+            Some(TypedNetworkEvent::RecvError(e)) if e.kind() == io::ErrorKind::InvalidData && e.to_string() == "oops"
+        ));
+        assert!(typed(NetworkSimulationEvent::Heartbeat(addr)).is_none());
+    }
+
+    fn create_test_app() -> App {
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(TypedNetworkPlugin::<GameMessage>::new(
+                LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default()),
+            ));
+        app
+    }
+}
@@ -0,0 +1,154 @@
+//! Network throughput diagnostics, shown e.g. by bevy's `LogDiagnosticsPlugin`. Only available
+//! with the `diagnostics` feature.
+
+use bevy::app::App;
+use bevy::diagnostic::{Diagnostic, DiagnosticId, Diagnostics};
+use bevy::prelude::{Local, Plugin, Res, ResMut, Resource};
+use bevy::time::Time;
+
+use crate::simulation::transport::TransportResource;
+
+/// Totals counted by the laminar systems since startup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Resource)]
+pub struct NetworkStats {
+    pub bytes_sent:       u64,
+    pub bytes_received:   u64,
+    pub packets_sent:     u64,
+    pub packets_received: u64,
+}
+
+impl NetworkStats {
+    /// Counts a payload handed to the socket.
+    pub(crate) fn record_sent(&mut self, len: usize) {
+        self.bytes_sent += len as u64;
+        self.packets_sent += 1;
+    }
+
+    /// Counts a payload received from the socket.
+    pub(crate) fn record_received(&mut self, len: usize) {
+        self.bytes_received += len as u64;
+        self.packets_received += 1;
+    }
+}
+
+/// Use this plugin to register the per second network throughput diagnostics.
+pub struct NetworkDiagnosticsPlugin;
+
+impl NetworkDiagnosticsPlugin {
+    pub const BYTES_SENT: DiagnosticId = DiagnosticId::from_u128(0x62b6_6a55_0f9e_4d51_9d0c_5c3a_2f4e_7a01);
+    pub const BYTES_RECEIVED: DiagnosticId = DiagnosticId::from_u128(0x62b6_6a55_0f9e_4d51_9d0c_5c3a_2f4e_7a02);
+    pub const PACKETS_SENT: DiagnosticId = DiagnosticId::from_u128(0x62b6_6a55_0f9e_4d51_9d0c_5c3a_2f4e_7a03);
+    pub const PACKETS_RECEIVED: DiagnosticId = DiagnosticId::from_u128(0x62b6_6a55_0f9e_4d51_9d0c_5c3a_2f4e_7a04);
+    pub const PENDING_MESSAGES: DiagnosticId = DiagnosticId::from_u128(0x62b6_6a55_0f9e_4d51_9d0c_5c3a_2f4e_7a05);
+    pub const OLDEST_PENDING_AGE: DiagnosticId = DiagnosticId::from_u128(0x62b6_6a55_0f9e_4d51_9d0c_5c3a_2f4e_7a06);
+    pub const EXPIRED_MESSAGES: DiagnosticId = DiagnosticId::from_u128(0x62b6_6a55_0f9e_4d51_9d0c_5c3a_2f4e_7a07);
+
+    pub fn setup_system(mut diagnostics: ResMut<Diagnostics>) {
+        diagnostics.add(Diagnostic::new(Self::BYTES_SENT, "bytes_sent", 20).with_suffix("B/s"));
+        diagnostics.add(Diagnostic::new(Self::BYTES_RECEIVED, "bytes_received", 20).with_suffix("B/s"));
+        diagnostics.add(Diagnostic::new(Self::PACKETS_SENT, "packets_sent", 20).with_suffix("/s"));
+        diagnostics.add(Diagnostic::new(Self::PACKETS_RECEIVED, "packets_received", 20).with_suffix("/s"));
+        diagnostics.add(Diagnostic::new(Self::PENDING_MESSAGES, "pending_messages", 20));
+        diagnostics.add(Diagnostic::new(Self::OLDEST_PENDING_AGE, "oldest_pending_age", 20).with_suffix("ms"));
+        diagnostics.add(Diagnostic::new(Self::EXPIRED_MESSAGES, "expired_messages", 20));
+    }
+
+    /// Measures the rates from what was counted since the previous frame.
+    pub fn diagnostic_system(mut diagnostics: ResMut<Diagnostics>,
+                                 time:        Res<Time>,
+                                 stats:       Res<NetworkStats>,
+                             mut previous:    Local<NetworkStats>) {
+        let delta_seconds = time.raw_delta_seconds_f64();
+        if delta_seconds == 0.0 {
+            return;
+        }
+
+        let rate = |current: u64, previous: u64| (current - previous) as f64 / delta_seconds;
+        diagnostics.add_measurement(Self::BYTES_SENT, || rate(stats.bytes_sent, previous.bytes_sent));
+        diagnostics.add_measurement(Self::BYTES_RECEIVED, || rate(stats.bytes_received, previous.bytes_received));
+        diagnostics.add_measurement(Self::PACKETS_SENT, || rate(stats.packets_sent, previous.packets_sent));
+        diagnostics.add_measurement(Self::PACKETS_RECEIVED, || rate(stats.packets_received, previous.packets_received));
+        *previous = *stats;
+    }
+
+    /// Measures the backlog of the `TransportResource`, the age being 0 while it's empty, and the
+    /// total of its expired messages.
+    pub fn queue_diagnostic_system(mut diagnostics: ResMut<Diagnostics>,
+                                       transport:   Res<TransportResource>) {
+        diagnostics.add_measurement(Self::PENDING_MESSAGES, || transport.pending_len() as f64);
+        diagnostics.add_measurement(Self::OLDEST_PENDING_AGE, || {
+            transport.oldest_pending_age().map_or(0.0, |age| age.as_secs_f64() * 1000.0)
+        });
+        diagnostics.add_measurement(Self::EXPIRED_MESSAGES, || transport.expired_messages() as f64);
+    }
+}
+
+impl Plugin for NetworkDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<Diagnostics>()
+            .init_resource::<NetworkStats>()
+            .init_resource::<TransportResource>()
+            .add_startup_system(Self::setup_system)
+            .add_system(Self::diagnostic_system)
+            .add_system(Self::queue_diagnostic_system);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::simulation::{
+        requirements::{DeliveryRequirement, UrgencyRequirement},
+        transport::{laminar::{LaminarConfig, LaminarPlugin, LaminarSocketResource}, TransportResource},
+    };
+
+    #[test]
+    fn test_counters_advance() {
+        let mut apps: Vec<_> = (0..2)
+            .map(|_| {
+                let mut app = App::new();
+                app.init_resource::<Time>()
+                    .add_plugin(NetworkDiagnosticsPlugin)
+                    .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default()));
+                app
+            })
+            .collect();
+        let receiver_addr = apps[1].world.resource::<LaminarSocketResource>().get().unwrap().local_addr().unwrap();
+
+        let mut transport = apps[0].world.resource_mut::<TransportResource>();
+        for _ in 0..3 {
+            transport.send_with_requirements(
+                receiver_addr,
+                b"test",
+                DeliveryRequirement::Unreliable,
+                UrgencyRequirement::Immediate,
+            );
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while apps[1].world.resource::<NetworkStats>().packets_received < 3 && Instant::now() < deadline {
+            for app in &mut apps {
+                app.update();
+            }
+        }
+
+        assert_eq!(*apps[0].world.resource::<NetworkStats>(), NetworkStats {
+            bytes_sent: 12,
+            packets_sent: 3,
+            ..NetworkStats::default()
+        });
+        assert_eq!(*apps[1].world.resource::<NetworkStats>(), NetworkStats {
+            bytes_received: 12,
+            packets_received: 3,
+            ..NetworkStats::default()
+        });
+        let diagnostics = apps[0].world.resource::<Diagnostics>();
+        assert!(diagnostics.get(NetworkDiagnosticsPlugin::BYTES_SENT).is_some());
+        // measured before or after the send system, depending on the order the systems ran in
+        let pending = diagnostics.get(NetworkDiagnosticsPlugin::PENDING_MESSAGES).and_then(Diagnostic::value);
+        assert!(pending.is_some_and(|pending| pending <= 3.0), "{:?}", pending);
+    }
+}
@@ -0,0 +1,403 @@
+//! LAN discovery: servers periodically broadcast a small beacon, clients listen for them and keep
+//! a list of the servers found.
+//!
+//! Discovery runs on its own UDP socket so it doesn't interfere with the game transport. The
+//! address of a discovered server is the one of its discovery socket, put the address or port to
+//! join the game on in the beacon payload.
+//!
+//! A beacon is `BLAN`, a kind byte, the big-endian game id and the payload. Whenever a client
+//! receives a beacon it probes the server back with a token, which the server echoes so that the
+//! client can estimate the RTT.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use bevy::log::{info, error};
+use bevy::prelude::{EventWriter, Plugin, Res, ResMut, Resource, SystemLabel, SystemSet};
+use bevy::app::App;
+
+/// Port beacons are broadcast to unless told otherwise.
+pub const DEFAULT_DISCOVERY_PORT: u16 = 47_777;
+/// How long a server stays listed after its last beacon unless told otherwise.
+const DEFAULT_EXPIRY: Duration = Duration::from_secs(5);
+const MAGIC: &[u8; 4] = b"BLAN";
+const HEADER_SIZE: usize = 9;
+const KIND_BEACON: u8 = 0;
+const KIND_PROBE: u8 = 1;
+const KIND_PONG: u8 = 2;
+const MAX_BEACON_SIZE: usize = 1_200;
+
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct LanDiscoveryLabel;
+
+/// What the discovery plugin does, servers announce themselves and clients browse.
+#[derive(Clone, Debug)]
+pub enum DiscoveryMode {
+    /// Broadcasts a beacon carrying `payload` every `interval`.
+    Announce { game_id: u32, payload: Bytes, interval: Duration },
+    /// Lists the servers announcing the same game id.
+    Browse { game_id: u32 },
+}
+
+impl DiscoveryMode {
+    fn game_id(&self) -> u32 {
+        match self {
+            DiscoveryMode::Announce { game_id, .. } | DiscoveryMode::Browse { game_id } => *game_id,
+        }
+    }
+}
+
+/// Events emitted by the discovery plugin.
+#[derive(Debug)]
+pub enum DiscoveryEvent {
+    // A server announced itself for the first time, or again after it was lost
+    Found(SocketAddr),
+    // No beacon came from a server for longer than the expiry
+    Lost(SocketAddr),
+    // An error occurred on the discovery socket
+    Error(io::Error),
+}
+
+/// A server found by browsing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveredServer {
+    /// Payload of the latest beacon.
+    pub payload:   Bytes,
+    /// When the latest beacon was received.
+    pub last_seen: Instant,
+    /// Latest round-trip time measured by probing the server, `None` until a probe came back.
+    pub rtt:       Option<Duration>,
+}
+
+/// Resource holding the servers found by browsing which didn't expire yet.
+#[derive(Debug, Default, Resource)]
+pub struct DiscoveredServers {
+    servers: HashMap<SocketAddr, DiscoveredServer>,
+}
+
+impl DiscoveredServers {
+    /// Returns the server announcing itself from `addr`, if it is listed.
+    #[must_use]
+    pub fn get(&self, addr: &SocketAddr) -> Option<&DiscoveredServer> {
+        self.servers.get(addr)
+    }
+
+    /// Iterates over the listed servers, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&SocketAddr, &DiscoveredServer)> {
+        self.servers.iter()
+    }
+
+    /// Returns the number of listed servers.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    /// Returns true if no server is listed.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+}
+
+/// Use this plugin to announce a server on the LAN, or to look for the servers announced there.
+pub struct LanDiscoveryPlugin {
+    mode:   DiscoveryMode,
+    port:   u16,
+    target: Option<SocketAddr>,
+    expiry: Duration,
+}
+
+impl LanDiscoveryPlugin {
+    /// Creates a plugin using the default discovery port.
+    pub fn new(mode: DiscoveryMode) -> Self {
+        LanDiscoveryPlugin { mode, port: DEFAULT_DISCOVERY_PORT, target: None, expiry: DEFAULT_EXPIRY }
+    }
+
+    /// Sets the port browsers listen on and beacons are broadcast to.
+    #[must_use]
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Sends the beacons to `target` rather than to the limited broadcast address, e.g. a subnet
+    /// broadcast address. A browser binds to the address given here instead of every interface.
+    #[must_use]
+    pub fn with_target(mut self, target: SocketAddr) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Sets how long a server stays listed after its last beacon.
+    #[must_use]
+    pub fn with_expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    fn bind(&self) -> io::Result<UdpSocket> {
+        let socket = match self.mode {
+            DiscoveryMode::Announce { .. } => {
+                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+                socket.set_broadcast(true)?;
+                socket
+            }
+            DiscoveryMode::Browse { .. } => match self.target {
+                Some(target) => UdpSocket::bind(target)?,
+                None => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, self.port))?,
+            },
+        };
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }
+}
+
+impl Plugin for LanDiscoveryPlugin {
+    /// Failing to bind the socket doesn't panic, it is reported as a `DiscoveryEvent::Error` on the
+    /// first frame and the resource is left without a socket.
+    fn build(&self, app: &mut App) {
+        let (socket, error) = match self.bind() {
+            Ok(socket) => (Some(socket), None),
+            Err(e) => (None, Some(e)),
+        };
+        let target = self.target.unwrap_or_else(|| SocketAddr::from((Ipv4Addr::BROADCAST, self.port)));
+
+        app
+            .add_startup_system(log_startup)
+            .add_event::<DiscoveryEvent>()
+            .init_resource::<DiscoveredServers>()
+            .insert_resource(LanDiscoveryResource {
+                socket,
+                mode: self.mode.clone(),
+                target,
+                expiry: self.expiry,
+                next_beacon: Instant::now(),
+                started: Instant::now(),
+            })
+            .add_system_set(SystemSet::new()
+                .label(LanDiscoveryLabel)
+                .with_system(lan_discovery_system)
+            );
+
+        if let Some(e) = error {
+            app.world.send_event(DiscoveryEvent::Error(e));
+        }
+    }
+
+    fn name(&self) -> &str {
+        "lan_discovery"
+    }
+}
+
+fn log_startup(discovery: Res<LanDiscoveryResource>) {
+    match discovery.socket.as_ref().map(UdpSocket::local_addr) {
+        Some(Ok(addr)) => info!("LAN discovery running on {}", addr),
+        _ => error!("LAN discovery socket is not bound"),
+    }
+}
+
+/// Resource that owns the discovery socket.
+#[derive(Resource)]
+pub struct LanDiscoveryResource {
+    socket:      Option<UdpSocket>,
+    mode:        DiscoveryMode,
+    target:      SocketAddr,
+    expiry:      Duration,
+    next_beacon: Instant,
+    // origin of the probe tokens
+    started:     Instant,
+}
+
+impl LanDiscoveryResource {
+    /// Returns a reference to the socket if there is one configured.
+    #[must_use]
+    pub fn get(&self) -> Option<&UdpSocket> {
+        self.socket.as_ref()
+    }
+
+    /// Returns the mode the plugin was set up with.
+    #[must_use]
+    pub fn mode(&self) -> &DiscoveryMode {
+        &self.mode
+    }
+
+    /// Replaces the payload of the beacons, e.g. when the player count changes. Does nothing when
+    /// browsing.
+    pub fn set_payload(&mut self, new_payload: Bytes) {
+        if let DiscoveryMode::Announce { payload, .. } = &mut self.mode {
+            *payload = new_payload;
+        }
+    }
+}
+
+/// Creates a new discovery system, sending the beacons or listing the servers depending on the
+/// mode.
+pub fn lan_discovery_system(mut discovery:     ResMut<LanDiscoveryResource>,
+                            mut servers:       ResMut<DiscoveredServers>,
+                            mut event_channel: EventWriter<DiscoveryEvent>) {
+    let now = Instant::now();
+    let discovery = &mut *discovery;
+    let socket = match &discovery.socket {
+        Some(socket) => socket,
+        None => return,
+    };
+    let game_id = discovery.mode.game_id();
+
+    if let DiscoveryMode::Announce { payload, interval, .. } = &discovery.mode {
+        if now >= discovery.next_beacon {
+            discovery.next_beacon = now + *interval;
+            let beacon = datagram(KIND_BEACON, game_id, payload);
+            if let Err(e) = socket.send_to(&beacon, discovery.target) {
+                event_channel.send(DiscoveryEvent::Error(e));
+            }
+        }
+    }
+
+    let mut buffer = [0; MAX_BEACON_SIZE];
+    loop {
+        let (len, addr) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => {
+                event_channel.send(DiscoveryEvent::Error(e));
+                break;
+            }
+        };
+        let (kind, body) = match parse(&buffer[..len], game_id) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+
+        let reply = match (&discovery.mode, kind) {
+            (DiscoveryMode::Announce { .. }, KIND_PROBE) => Some(datagram(KIND_PONG, game_id, body)),
+            (DiscoveryMode::Browse { .. }, KIND_BEACON) => {
+                let payload = Bytes::copy_from_slice(body);
+                match servers.servers.get_mut(&addr) {
+                    Some(server) => {
+                        server.payload = payload;
+                        server.last_seen = now;
+                    }
+                    None => {
+                        servers.servers.insert(addr, DiscoveredServer { payload, last_seen: now, rtt: None });
+                        event_channel.send(DiscoveryEvent::Found(addr));
+                    }
+                }
+                let token = now.duration_since(discovery.started).as_nanos() as u64;
+                Some(datagram(KIND_PROBE, game_id, &token.to_be_bytes()))
+            }
+            (DiscoveryMode::Browse { .. }, KIND_PONG) => {
+                if let (Some(server), Ok(token)) = (servers.servers.get_mut(&addr), <[u8; 8]>::try_from(body)) {
+                    let sent = discovery.started + Duration::from_nanos(u64::from_be_bytes(token));
+                    server.rtt = Some(now.saturating_duration_since(sent));
+                }
+                None
+            }
+            _ => None,
+        };
+        if let Some(reply) = reply {
+            if let Err(e) = socket.send_to(&reply, addr) {
+                event_channel.send(DiscoveryEvent::Error(e));
+            }
+        }
+    }
+
+    let expiry = discovery.expiry;
+    servers.servers.retain(|addr, server| {
+        let alive = now.saturating_duration_since(server.last_seen) <= expiry;
+        if !alive {
+            event_channel.send(DiscoveryEvent::Lost(*addr));
+        }
+        alive
+    });
+}
+
+fn datagram(kind: u8, game_id: u32, body: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(HEADER_SIZE + body.len());
+    datagram.extend_from_slice(MAGIC);
+    datagram.push(kind);
+    datagram.extend_from_slice(&game_id.to_be_bytes());
+    datagram.extend_from_slice(body);
+    datagram
+}
+
+/// Returns the kind and body of a discovery datagram of the given game.
+fn parse(datagram: &[u8], game_id: u32) -> Option<(u8, &[u8])> {
+    let header = datagram.get(..HEADER_SIZE)?;
+    if &header[..4] != MAGIC || header[5..] != game_id.to_be_bytes() {
+        return None;
+    }
+    Some((header[4], &datagram[HEADER_SIZE..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::event::Events;
+
+    use super::*;
+
+    #[test]
+    fn test_server_is_found_then_lost() {
+        let mut browser = App::new();
+        browser.add_plugin(LanDiscoveryPlugin::new(DiscoveryMode::Browse { game_id: 7 })
+            .with_target("127.0.0.1:0".parse().unwrap())
+            .with_expiry(Duration::from_millis(200)));
+        let target = browser.world.resource::<LanDiscoveryResource>().get().unwrap().local_addr().unwrap();
+        let mut server = announcer(7, target);
+        let mut other_game = announcer(8, target);
+
+        // bound to every interface, the beacons come from loopback
+        let port = server.world.resource::<LanDiscoveryResource>().get().unwrap().local_addr().unwrap().port();
+        let server_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while Instant::now() < deadline {
+            server.update();
+            other_game.update();
+            browser.update();
+            let servers = browser.world.resource::<DiscoveredServers>();
+            if servers.get(&server_addr).is_some_and(|server| server.rtt.is_some()) {
+                break;
+            }
+        }
+        let servers = browser.world.resource::<DiscoveredServers>();
+        assert_eq!(servers.len(), 1);
+        let found = servers.get(&server_addr).unwrap();
+        assert_eq!(found.payload, Bytes::from_static(b"join on 3000"));
+        assert!(found.rtt.is_some());
+
+        drop(server);
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !browser.world.resource::<DiscoveredServers>().is_empty() && Instant::now() < deadline {
+            browser.update();
+        }
+        assert!(browser.world.resource::<DiscoveredServers>().is_empty());
+
+        let events = browser.world.resource::<Events<DiscoveryEvent>>();
+        let mut reader = events.get_reader();
+        // events only last two updates, the `Found` one is gone by now
+        assert!(reader.iter(events).any(|event| matches!(event, DiscoveryEvent::Lost(addr) if *addr == server_addr)));
+    }
+
+    #[test]
+    fn test_other_datagrams_are_ignored() {
+        assert_eq!(parse(&datagram(KIND_BEACON, 7, b"payload"), 7), Some((KIND_BEACON, &b"payload"[..])));
+        assert_eq!(parse(&datagram(KIND_BEACON, 7, b"payload"), 8), None);
+        assert_eq!(parse(b"BLAN", 7), None);
+        assert_eq!(parse(b"not a beacon", 7), None);
+    }
+
+    fn announcer(game_id: u32, target: SocketAddr) -> App {
+        let mut app = App::new();
+        app.add_plugin(LanDiscoveryPlugin::new(DiscoveryMode::Announce {
+            game_id,
+            payload: Bytes::from_static(b"join on 3000"),
+            interval: Duration::from_millis(10),
+        }).with_target(target));
+        app
+    }
+}
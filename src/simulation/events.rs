@@ -1,8 +1,12 @@
-use std::{io, net::SocketAddr};
+use std::{io, net::SocketAddr, time::Duration};
 
 use bytes::Bytes;
+use laminar::ErrorKind;
 
-use crate::simulation::Message;
+use crate::simulation::{
+    connections::ConnectionId, message::MessageId, requirements::DeliveryRequirement, transport::routing::TransportId,
+    Message,
+};
 
 /// Events which can be received from the network.
 #[derive(Debug)]
@@ -11,12 +15,109 @@ pub enum NetworkSimulationEvent {
     Message(SocketAddr, Bytes),
     // A new host has connected to us
     Connect(SocketAddr),
-    // A host has disconnected from us
-    Disconnect(SocketAddr),
+    // We sent a handshake to the host given to `TransportResource::connect`, its `Connect` follows
+    // once it answered
+    Connecting(SocketAddr),
+    // A host has disconnected from us, see `DisconnectReason` for why
+    Disconnect(SocketAddr, DisconnectReason),
     // An error occurred while receiving a message.
     RecvError(io::Error),
     // An error occurred while sending a message.
     SendError(io::Error, Message),
+    // laminar refused to send a message for another reason than an IO error. Only reported by the
+    // laminar transport.
+    ProtocolError(ErrorKind, Message),
     // An error occurred while managing connections.
     ConnectionError(io::Error, Option<SocketAddr>),
+    // The smoothed round-trip time to a host changed. Only reported by the laminar transport,
+    // whenever a reliable packet from the host acknowledges one of ours, so there is none before
+    // reliable traffic went both ways.
+    Latency(SocketAddr, Duration),
+    // A socket was bound to the address, with the port the OS chose if any. Only reported by the
+    // laminar transport, on the first frame.
+    Listening(SocketAddr),
+    // A heartbeat, which laminar sends to keep idle connections alive, came from a host. Only
+    // reported by the laminar transport.
+    Heartbeat(SocketAddr),
+    // A host acknowledged the reliable message queued with `TransportResource::send_with_tag` with
+    // this tag. Only reported by the laminar transport, for payloads sent in a single datagram.
+    Acked(SocketAddr, u64),
+    // The `Message` emitted right before it was delivered with this requirement, e.g. on stream 3.
+    // Only reported by the laminar transport once enabled with `LaminarPlugin::report_deliveries`.
+    // laminar doesn't tell `Reliable` from `ReliableUnordered`, nor `Default` from
+    // `ReliableOrdered(None)`, the latter ones are reported.
+    Delivered(SocketAddr, Bytes, DeliveryRequirement),
+    // There is no socket to send or receive with, e.g. the bind failed or the socket was dropped,
+    // so the messages stay queued. Only reported by the laminar transport, once until a socket is
+    // set again.
+    SocketUnavailable,
+    // The host acknowledged the message queued with `TransportResource::send_with_ack`. Only
+    // reported by the laminar transport.
+    MessageAcked(MessageId),
+    // The message queued with `TransportResource::send_with_ack` wasn't acknowledged within the
+    // ack timeout, or can't be: it was unreliable, or its host disconnected. Only reported by the
+    // laminar transport.
+    MessageAckTimeout(MessageId),
+    // Nothing came from the connected host for `ConnectedPeers::unstable_after`, e.g. to show the
+    // connection as unstable before the transport times it out. Reported once by
+    // `connected_peers_system`, until something comes from the host again.
+    ConnectionUnstable(SocketAddr),
+    // Something came from the host reported as `ConnectionUnstable` again.
+    ConnectionStable(SocketAddr),
+    // The `Connect` of a new host, with the id allocated to its connection. Reported by
+    // `connected_peers_system`, see `TransportResource::connections`.
+    PeerConnected(ConnectionId, SocketAddr),
+    // The `Disconnect` of a connected host, with the id of its connection, now retired. Reported by
+    // `connected_peers_system`.
+    PeerDisconnected(ConnectionId, SocketAddr, DisconnectReason),
+    // A payload was queued with `TransportResource::send_to` for a connection which was retired,
+    // and was dropped.
+    RetiredConnection(ConnectionId, Bytes),
+}
+
+/// Why a host disconnected. Before it was added, both were reported as `Disconnect(addr)`, match
+/// `Disconnect(addr, _)` to keep handling them alike.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DisconnectReason {
+    // The host hasn't been heard from within the idle timeout
+    Timeout,
+    // The connection was closed, by the host or by the transport after an error
+    Closed,
+}
+
+/// Copy of a `Message`, `Connect` or `Disconnect` event tagged with the transport it came from, to
+/// tell them apart when several transports are used at once. It is emitted along with the plain
+/// event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaggedNetworkEvent {
+    Message(TransportId, SocketAddr, Bytes),
+    Connect(TransportId, SocketAddr),
+    Disconnect(TransportId, SocketAddr, DisconnectReason),
+}
+
+impl TaggedNetworkEvent {
+    /// Returns the tagged copy of the event, if it is one which gets tagged.
+    #[must_use]
+    pub fn tag(transport: TransportId, event: &NetworkSimulationEvent) -> Option<Self> {
+        match event {
+            NetworkSimulationEvent::Message(addr, payload) => {
+                Some(TaggedNetworkEvent::Message(transport, *addr, payload.clone()))
+            }
+            NetworkSimulationEvent::Connect(addr) => Some(TaggedNetworkEvent::Connect(transport, *addr)),
+            NetworkSimulationEvent::Disconnect(addr, reason) => {
+                Some(TaggedNetworkEvent::Disconnect(transport, *addr, *reason))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the transport the event came from.
+    #[must_use]
+    pub fn transport(&self) -> TransportId {
+        match self {
+            TaggedNetworkEvent::Message(transport, ..)
+            | TaggedNetworkEvent::Connect(transport, _)
+            | TaggedNetworkEvent::Disconnect(transport, ..) => *transport,
+        }
+    }
 }
\ No newline at end of file
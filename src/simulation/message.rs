@@ -1,12 +1,20 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Instant};
 
 use bytes::Bytes;
 
-use super::requirements::{DeliveryRequirement, UrgencyRequirement};
+use super::{
+    requirements::{DeliveryRequirement, UrgencyRequirement},
+    transport::routing::TransportId,
+};
+
+/// Id of a message queued with `TransportResource::send_with_ack`, reported in its `MessageAcked`
+/// or `MessageAckTimeout` event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageId(pub u64);
 
 /// Structure used to hold message payloads before they are consumed and sent by an underlying
 /// `NetworkSystem`.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Message {
     /// The destination to send the message.
     pub destination: SocketAddr,
@@ -16,21 +24,63 @@ pub struct Message {
     pub delivery: DeliveryRequirement,
     /// The requirement around when this message should be sent.
     pub urgency: UrgencyRequirement,
+    /// Messages with a higher priority are handed to the transport first.
+    pub priority: u8,
+    /// The transport which must send this message, any of them may if `None`.
+    pub transport: Option<TransportId>,
+    /// Tag given with `TransportResource::send_with_tag`, e.g. to tell which operation a message
+    /// handed back in a `SendError` belongs to.
+    pub tag: Option<u64>,
+    /// Id given by `TransportResource::send_with_ack`, whose acknowledgment is reported.
+    pub ack: Option<MessageId>,
+    /// True for the copies queued by `TransportResource::broadcast_to_peers`, which are dropped if
+    /// their peer disconnects before they are sent.
+    pub to_peers: bool,
+    /// When the message was queued.
+    pub queued_at: Instant,
+    /// When the message is dropped if it is still queued, see `TransportResource::send_with_ttl`.
+    pub expires_at: Option<Instant>,
+    /// Number of times sending the message failed and it was queued again, see
+    /// `TransportResource::set_retry_policy`.
+    pub attempts: u32,
+    /// When the message queued again is sent, it stays in the queue until then.
+    pub retry_at: Option<Instant>,
+    // place of the message in the queue, which it takes again when retried, 0 until it is queued
+    pub(crate) number: u64,
+    /// True for the messages queued by `TransportResource::send_compressed`, compressed whatever
+    /// the threshold of the laminar socket.
+    #[cfg(feature = "compression")]
+    pub compress: bool,
 }
 
 impl Message {
-    /// Creates and returns a new Message.
+    /// Priority of the messages which weren't given one, right in the middle of the range.
+    pub const DEFAULT_PRIORITY: u8 = 128;
+
+    /// Creates and returns a new Message with the default priority.
     pub(crate) fn new(
         destination: SocketAddr,
-        payload: &[u8],
+        payload: Bytes,
         delivery: DeliveryRequirement,
         urgency: UrgencyRequirement,
     ) -> Self {
         Self {
             destination,
-            payload: Bytes::copy_from_slice(payload),
+            payload,
             delivery,
             urgency,
+            priority: Self::DEFAULT_PRIORITY,
+            transport: None,
+            tag: None,
+            ack: None,
+            to_peers: false,
+            queued_at: Instant::now(),
+            expires_at: None,
+            attempts: 0,
+            retry_at: None,
+            number: 0,
+            #[cfg(feature = "compression")]
+            compress: false,
         }
     }
 }
\ No newline at end of file
@@ -2,17 +2,86 @@
 //! more utilities to make their way into this module. e.g. "Component synchronization",
 //! "Matchmaking", etc.
 
+mod channels;
+mod connections;
+mod decode;
+#[cfg(feature = "diagnostics")]
+mod diagnostics;
+#[cfg(feature = "bevy")]
+mod discovery;
 mod events;
 mod message;
+#[cfg(feature = "bevy")]
+mod peers;
 mod requirements;
+#[cfg(feature = "bevy")]
+mod resolve;
 mod timing;
 mod transport;
+#[cfg(feature = "serde")]
+mod typed;
 
-pub use events::NetworkSimulationEvent;
-pub use message::Message;
-pub use requirements::{DeliveryRequirement, UrgencyRequirement};
+pub use channels::{Channel, ChannelEvent, ChannelsConfig};
+#[cfg(feature = "bevy")]
+pub use channels::{channel_dispatch_system, ChannelPlugin, NetworkChannels, NetworkChannelsLabel};
+pub use connections::{ConnectionId, ConnectionIds};
+pub use decode::{DecodeMessage, TypedNetworkEvent};
+#[cfg(feature = "bevy")]
+pub use decode::{typed_network_event_system, TypedNetworkLabel, TypedNetworkPlugin};
+#[cfg(feature = "diagnostics")]
+pub use diagnostics::{NetworkDiagnosticsPlugin, NetworkStats};
+#[cfg(feature = "bevy")]
+pub use discovery::{
+    DiscoveredServer, DiscoveredServers, DiscoveryEvent, DiscoveryMode, LanDiscoveryLabel,
+    LanDiscoveryPlugin, LanDiscoveryResource, DEFAULT_DISCOVERY_PORT,
+};
+pub use events::{DisconnectReason, NetworkSimulationEvent, TaggedNetworkEvent};
+pub use message::{Message, MessageId};
+#[cfg(feature = "bevy")]
+pub use peers::{ConnectedPeers, PeerState};
+pub use requirements::{DeliveryRequirement, StreamId, UrgencyRequirement};
+#[cfg(feature = "bevy")]
+pub use resolve::{host_resolution_system, HostResolutionLabel, HostResolutionPlugin, ResolvedHosts};
 pub use timing::NetworkSimulationTime;
 pub use transport::{
-    laminar::{LaminarPlugin, LaminarLabel, LaminarConfig, LaminarSocket},
-    TransportResource
+    generic::{Transport, TransportEvent, TransportSocketResource},
+    laminar::{
+        flush_laminar, pump_laminar, poll_laminar, receive_laminar, CoalescingOptions, ConnectionMetrics,
+        FragmentationOptions, LaminarConfig, LaminarSocket, LaminarSocketResource, MiddlewareError,
+        PacketMiddleware, PeerMetrics, RelayConfig, RelayServer, SocketError, SocketOptions,
+    },
+    routing::TransportId,
+    Disposition, NetworkRole, QueuePolicy, QueueStats, RetryPolicy, StreamSender, TransportResource
+};
+#[cfg(feature = "conditioner")]
+pub use transport::conditioner::NetworkConditions;
+#[cfg(all(feature = "conditioner", feature = "bevy"))]
+pub use transport::conditioner::NetworkConditionerPlugin;
+#[cfg(feature = "steam")]
+pub use transport::steam::{
+    SteamConnection, SteamConnectionState, SteamError, SteamSockets, SteamStatusChange, SteamTransport,
+};
+#[cfg(all(feature = "steam", feature = "bevy"))]
+pub use transport::steam::SteamTransportPlugin;
+#[cfg(feature = "bevy")]
+pub use transport::{
+    fallback::{FallbackConnect, FallbackConnectLabel, FallbackConnectPlugin, FallbackEvent},
+    generic::{TransportLabel, TransportPlugin},
+    laminar::{
+        HostMigration, HostMigrationLabel, HostMigrationPlugin, LaminarEndpoints, LaminarEndpointsLabel,
+        LaminarPlugin, LaminarPluginBuilder, LaminarLabel, MigrationEvent, NatEvent, NatTraversal,
+        NatTraversalLabel, NatTraversalPlugin, PublicAddress, QueuedMessages, Reconnect, ReconnectConfig,
+        ReconnectEvent, ReconnectLabel, ReconnectPlugin,
+    },
+    memory::{
+        LinkConditions, MemoryTransportPlugin, MemoryLabel, MemoryNetwork, MemorySocket,
+        MemorySocketResource,
+    },
+    routing::{NetworkEventWriter, unroutable_messages_system},
+    tcp::{TcpPlugin, TcpLabel, TcpStreamsResource},
+    udp::{MulticastInterface, MulticastMessage, UdpPlugin, UdpLabel, UdpSocketResource},
 };
+#[cfg(all(unix, feature = "bevy"))]
+pub use transport::unix::{UnixSocketPlugin, UnixLabel, UnixSocketResource};
+#[cfg(feature = "serde")]
+pub use typed::{CodecError, decode_message};
@@ -0,0 +1,274 @@
+//! Registry of the peers currently connected, kept up to date from the `Connect` and `Disconnect`
+//! events of the transports.
+
+use std::{collections::{hash_map::Entry, HashMap}, net::SocketAddr, time::{Duration, Instant}};
+
+use bevy::prelude::{EventReader, EventWriter, ParamSet, ResMut, Resource};
+
+use crate::simulation::{events::NetworkSimulationEvent, transport::TransportResource};
+
+/// What is known about a connected peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerState {
+    /// When the `Connect` event of the peer was processed.
+    pub connected_since: Instant,
+    /// When the latest `Message` or `Heartbeat` event of the peer was processed, or its `Connect`
+    /// event if none came since.
+    pub last_seen:       Instant,
+    /// True once the peer was silent for `ConnectedPeers::unstable_after`, until something comes
+    /// from it again.
+    pub unstable:        bool,
+}
+
+/// Resource holding the peers which connected and didn't disconnect since.
+#[derive(Debug, Default, Resource)]
+pub struct ConnectedPeers {
+    peers:          HashMap<SocketAddr, PeerState>,
+    unstable_after: Option<Duration>,
+}
+
+impl ConnectedPeers {
+    /// Returns true if `addr` is currently connected.
+    #[must_use]
+    pub fn is_connected(&self, addr: &SocketAddr) -> bool {
+        self.peers.contains_key(addr)
+    }
+
+    /// Returns the state of the peer at `addr`, if it is connected.
+    #[must_use]
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PeerState> {
+        self.peers.get(addr)
+    }
+
+    /// Returns how long ago something last came from `addr`, if it is connected. Useful to warn
+    /// about a peer going silent before the transport disconnects it.
+    #[must_use]
+    pub fn time_since_last_packet(&self, addr: &SocketAddr) -> Option<Duration> {
+        self.peers.get(addr).map(|peer| peer.last_seen.elapsed())
+    }
+
+    /// Sets how long a peer must be silent for a `ConnectionUnstable` event to be emitted, which
+    /// should be shorter than the idle timeout of the transport. `None`, the default, never reports
+    /// one.
+    pub fn set_unstable_after(&mut self, threshold: Option<Duration>) {
+        self.unstable_after = threshold;
+    }
+
+    /// Returns how long a peer must be silent to be reported as unstable, see `set_unstable_after`.
+    #[must_use]
+    pub fn unstable_after(&self) -> Option<Duration> {
+        self.unstable_after
+    }
+
+    /// Returns true if `addr` is connected but was silent for `unstable_after`.
+    #[must_use]
+    pub fn is_unstable(&self, addr: &SocketAddr) -> bool {
+        self.peers.get(addr).is_some_and(|peer| peer.unstable)
+    }
+
+    /// Marks the peers silent for `unstable_after` as of `now` as unstable, and returns their
+    /// `ConnectionUnstable` events.
+    fn detect_unstable(&mut self, now: Instant) -> Vec<NetworkSimulationEvent> {
+        let Some(threshold) = self.unstable_after else {
+            return Vec::new();
+        };
+        self.peers
+            .iter_mut()
+            .filter(|(_, peer)| !peer.unstable && now.saturating_duration_since(peer.last_seen) >= threshold)
+            .map(|(addr, peer)| {
+                peer.unstable = true;
+                NetworkSimulationEvent::ConnectionUnstable(*addr)
+            })
+            .collect()
+    }
+
+    /// Iterates over the connected peers, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&SocketAddr, &PeerState)> {
+        self.peers.iter()
+    }
+
+    /// Returns the number of connected peers.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Returns true if no peer is connected.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+/// Creates a new system keeping `ConnectedPeers` up to date, along with the peers of
+/// `TransportResource::broadcast_to_peers`. A repeated `Connect` keeps the original connection
+/// time, a `Disconnect` of an unknown peer is ignored, and so are the messages and heartbeats of
+/// peers which aren't connected. It also emits the `ConnectionUnstable` and `ConnectionStable`
+/// events, see `ConnectedPeers::set_unstable_after`, and the `PeerConnected` and
+/// `PeerDisconnected` events carrying the id of the connection.
+pub fn connected_peers_system(mut peers:     ResMut<ConnectedPeers>,
+                              mut transport: ResMut<TransportResource>,
+                              mut events:    ParamSet<(EventReader<NetworkSimulationEvent>,
+                                                       EventWriter<NetworkSimulationEvent>)>) {
+    let mut emitted = Vec::new();
+    for event in events.p0().iter() {
+        match event {
+            NetworkSimulationEvent::Connect(addr) => {
+                let id = transport.peer_connected(*addr);
+                if let Entry::Vacant(entry) = peers.peers.entry(*addr) {
+                    let now = Instant::now();
+                    entry.insert(PeerState { connected_since: now, last_seen: now, unstable: false });
+                    emitted.push(NetworkSimulationEvent::PeerConnected(id, *addr));
+                }
+            }
+            NetworkSimulationEvent::Message(addr, _) | NetworkSimulationEvent::Heartbeat(addr) => {
+                if let Some(peer) = peers.peers.get_mut(addr) {
+                    peer.last_seen = Instant::now();
+                    if std::mem::replace(&mut peer.unstable, false) {
+                        emitted.push(NetworkSimulationEvent::ConnectionStable(*addr));
+                    }
+                }
+            }
+            NetworkSimulationEvent::Disconnect(addr, reason) => {
+                peers.peers.remove(addr);
+                if let Some(id) = transport.peer_disconnected(addr) {
+                    emitted.push(NetworkSimulationEvent::PeerDisconnected(id, *addr, *reason));
+                }
+            }
+            _ => {}
+        }
+    }
+    emitted.extend(peers.detect_unstable(Instant::now()));
+    events.p1().send_batch(emitted);
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{app::App, ecs::event::Events};
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::simulation::events::DisconnectReason;
+
+    #[test]
+    fn test_registry_follows_connections() {
+        let mut app = App::new();
+        app.add_event::<NetworkSimulationEvent>()
+            .init_resource::<ConnectedPeers>()
+            .init_resource::<TransportResource>()
+            .add_system(connected_peers_system);
+        let a = "127.0.0.1:3000".parse().unwrap();
+        let b = "127.0.0.1:3001".parse().unwrap();
+        let unknown = "127.0.0.1:3002".parse().unwrap();
+
+        app.world.send_event(NetworkSimulationEvent::Connect(a));
+        app.world.send_event(NetworkSimulationEvent::Connect(b));
+        app.update();
+        let since = app.world.resource::<ConnectedPeers>().get(&a).unwrap().connected_since;
+        assert_eq!(app.world.resource::<ConnectedPeers>().len(), 2);
+
+        app.world.send_event(NetworkSimulationEvent::Connect(a));
+        app.world.send_event(NetworkSimulationEvent::Disconnect(b, DisconnectReason::Closed));
+        app.world.send_event(NetworkSimulationEvent::Disconnect(unknown, DisconnectReason::Timeout));
+        app.update();
+
+        let peers = app.world.resource::<ConnectedPeers>();
+        assert_eq!(peers.iter().map(|(addr, _)| *addr).collect::<Vec<_>>(), vec![a]);
+        assert_eq!(peers.get(&a).unwrap().connected_since, since);
+        assert!(!peers.is_connected(&b));
+    }
+
+    #[test]
+    fn test_connections_are_reported_with_their_id() {
+        let mut app = App::new();
+        app.add_event::<NetworkSimulationEvent>()
+            .init_resource::<ConnectedPeers>()
+            .init_resource::<TransportResource>()
+            .add_system(connected_peers_system);
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut reader = app.world.resource::<Events<NetworkSimulationEvent>>().get_reader();
+
+        app.world.send_event(NetworkSimulationEvent::Connect(addr));
+        app.world.send_event(NetworkSimulationEvent::Connect(addr));
+        app.update();
+        let id = app.world.resource::<TransportResource>().connections().id_of(&addr).unwrap();
+        app.world.send_event(NetworkSimulationEvent::Disconnect(addr, DisconnectReason::Closed));
+        app.update();
+
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        let reported: Vec<_> = reader
+            .iter(events)
+            .filter(|event| {
+                matches!(
+                    event,
+                    NetworkSimulationEvent::PeerConnected(..) | NetworkSimulationEvent::PeerDisconnected(..)
+                )
+            })
+            .map(|event| format!("{:?}", event))
+            .collect();
+        assert_eq!(reported, [
+            format!("PeerConnected({:?}, {})", id, addr),
+            format!("PeerDisconnected({:?}, {}, Closed)", id, addr),
+        ]);
+        assert!(app.world.resource::<TransportResource>().connections().is_empty());
+    }
+
+    #[test]
+    fn test_last_seen_follows_packets() {
+        let mut app = App::new();
+        app.add_event::<NetworkSimulationEvent>()
+            .init_resource::<ConnectedPeers>()
+            .init_resource::<TransportResource>()
+            .add_system(connected_peers_system);
+        let addr = "127.0.0.1:3000".parse().unwrap();
+
+        app.world.send_event(NetworkSimulationEvent::Connect(addr));
+        app.update();
+        let connected = *app.world.resource::<ConnectedPeers>().get(&addr).unwrap();
+        assert_eq!(connected.last_seen, connected.connected_since);
+
+        for event in [NetworkSimulationEvent::Heartbeat(addr), NetworkSimulationEvent::Message(addr, Bytes::new())] {
+            std::thread::sleep(Duration::from_millis(5));
+            let previous = app.world.resource::<ConnectedPeers>().get(&addr).unwrap().last_seen;
+            app.world.send_event(event);
+            app.update();
+            assert!(app.world.resource::<ConnectedPeers>().get(&addr).unwrap().last_seen > previous);
+        }
+        let since = app.world.resource::<ConnectedPeers>().time_since_last_packet(&addr).unwrap();
+        assert!(since < connected.connected_since.elapsed());
+    }
+
+    #[test]
+    fn test_silent_peer_is_reported_unstable_once() {
+        let mut app = App::new();
+        app.add_event::<NetworkSimulationEvent>()
+            .init_resource::<ConnectedPeers>()
+            .init_resource::<TransportResource>()
+            .add_system(connected_peers_system);
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        app.world.resource_mut::<ConnectedPeers>().set_unstable_after(Some(Duration::from_secs(1)));
+        app.world.send_event(NetworkSimulationEvent::Connect(addr));
+        app.update();
+
+        let mut peers = app.world.resource_mut::<ConnectedPeers>();
+        let last_seen = peers.get(&addr).unwrap().last_seen;
+        assert!(peers.detect_unstable(last_seen + Duration::from_millis(999)).is_empty());
+        let later = last_seen + Duration::from_secs(1);
+        assert!(
+            matches!(peers.detect_unstable(later)[..], [NetworkSimulationEvent::ConnectionUnstable(a)] if a == addr)
+        );
+        assert!(peers.detect_unstable(later + Duration::from_secs(1)).is_empty());
+        assert!(peers.is_unstable(&addr));
+
+        let mut reader = app.world.resource::<Events<NetworkSimulationEvent>>().get_reader();
+        app.world.send_event(NetworkSimulationEvent::Heartbeat(addr));
+        app.update();
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        let stable = reader
+            .iter(events)
+            .filter(|event| matches!(event, NetworkSimulationEvent::ConnectionStable(a) if *a == addr))
+            .count();
+        assert_eq!(stable, 1);
+        assert!(!app.world.resource::<ConnectedPeers>().is_unstable(&addr));
+    }
+}
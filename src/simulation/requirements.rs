@@ -11,9 +11,13 @@ pub enum DeliveryRequirement {
     /// messages.
     /// Client receives 5
     UnreliableSequenced(Option<u8>),
-    /// Messages must all be delivered.
+    /// Messages must all be delivered, in no particular order. This is currently the same as
+    /// `ReliableUnordered`, which states the lack of ordering explicitly.
     /// Client receives 5, 1, 4, 2, 3, 6
     Reliable,
+    /// Messages must all be delivered, in no particular order and without any stream.
+    /// Client receives 5, 1, 4, 2, 3, 6
+    ReliableUnordered,
     /// Messages must all be delivered but only the newest messages are returned to the client.
     /// Client receives 5, 6
     ReliableSequenced(Option<u8>),
@@ -37,4 +41,29 @@ pub enum UrgencyRequirement {
     OnTick,
     /// Message will be sent as soon as possible.
     Immediate,
-}
\ No newline at end of file
+}
+
+/// Identifies a stream of the sequenced and ordered delivery requirements, the messages of
+/// different streams are sequenced or ordered independently of each other.
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub struct StreamId(pub u8);
+
+impl StreamId {
+    /// Returns `UnreliableSequenced` on this stream.
+    #[must_use]
+    pub fn unreliable_sequenced(self) -> DeliveryRequirement {
+        DeliveryRequirement::UnreliableSequenced(Some(self.0))
+    }
+
+    /// Returns `ReliableSequenced` on this stream.
+    #[must_use]
+    pub fn reliable_sequenced(self) -> DeliveryRequirement {
+        DeliveryRequirement::ReliableSequenced(Some(self.0))
+    }
+
+    /// Returns `ReliableOrdered` on this stream.
+    #[must_use]
+    pub fn reliable_ordered(self) -> DeliveryRequirement {
+        DeliveryRequirement::ReliableOrdered(Some(self.0))
+    }
+}
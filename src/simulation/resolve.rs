@@ -0,0 +1,260 @@
+//! Hostname resolution for message destinations, e.g. a server address typed by a player.
+//!
+//! The lookups block, so each one runs on a thread of its own. Messages sent to a host meanwhile
+//! are kept aside, and queued in the `TransportResource` once it resolved. Results are cached for a
+//! while, then resolved again the next time a message is sent to the host.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::app::App;
+use bevy::prelude::{EventWriter, Plugin, ResMut, Resource, SystemLabel, SystemSet};
+
+use crate::simulation::{
+    events::NetworkSimulationEvent,
+    requirements::{DeliveryRequirement, UrgencyRequirement},
+    transport::TransportResource,
+};
+
+type Lookups = Arc<Mutex<Vec<(String, io::Result<Vec<SocketAddr>>)>>>;
+
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct HostResolutionLabel;
+
+/// Use this plugin to send messages to hostnames, see `ResolvedHosts`.
+pub struct HostResolutionPlugin;
+
+impl Plugin for HostResolutionPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_event::<NetworkSimulationEvent>()
+            .init_resource::<ResolvedHosts>()
+            .init_resource::<TransportResource>()
+            .add_system_set(SystemSet::new()
+                .label(HostResolutionLabel)
+                .with_system(host_resolution_system)
+            );
+    }
+}
+
+#[derive(Debug)]
+struct Resolution {
+    addrs:   Vec<SocketAddr>,
+    expires: Instant,
+}
+
+/// Resource resolving hostnames off the main thread, and holding the messages sent to them until
+/// they are. The hosts are anything `ToSocketAddrs` accepts as a string, a hostname or an IP
+/// along with a port, e.g. `play.example.com:3000`.
+#[derive(Debug, Resource)]
+pub struct ResolvedHosts {
+    cache:     HashMap<String, Resolution>,
+    queued:    HashMap<String, Vec<(Vec<u8>, DeliveryRequirement)>>,
+    resolving: HashSet<String>,
+    lookups:   Lookups,
+    ttl:       Duration,
+}
+
+impl Default for ResolvedHosts {
+    fn default() -> Self {
+        Self {
+            cache:     HashMap::new(),
+            queued:    HashMap::new(),
+            resolving: HashSet::new(),
+            lookups:   Lookups::default(),
+            ttl:       Self::DEFAULT_TTL,
+        }
+    }
+}
+
+impl ResolvedHosts {
+    /// How long a resolved address is used before the host is resolved again.
+    pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+    /// Queues `payload` for `host` with the specified guarantee. It goes to the `TransportResource`
+    /// once the host is resolved, at the latest, to the first of its addresses. It is dropped if
+    /// the resolution fails, which is reported as a `ConnectionError` naming the host.
+    pub fn send(&mut self, host: &str, payload: &[u8], delivery: DeliveryRequirement) {
+        self.queued.entry(host.to_owned()).or_default().push((payload.to_vec(), delivery));
+        self.resolve(host);
+    }
+
+    /// Same as `send`, with the port given apart from the host, e.g. a hostname or an IPv6 address
+    /// typed by a player. Nothing blocks on the lookup, the message waits for it like with `send`.
+    pub fn send_to_host(&mut self, host: &str, port: u16, payload: &[u8], delivery: DeliveryRequirement) {
+        self.send(&host_with_port(host, port), payload, delivery);
+    }
+
+    /// Starts resolving `host`, unless it's already being resolved or its cached addresses are
+    /// still fresh.
+    pub fn resolve(&mut self, host: &str) {
+        if self.get(host).is_some() || !self.resolving.insert(host.to_owned()) {
+            return;
+        }
+        let lookups = Arc::clone(&self.lookups);
+        let host = host.to_owned();
+        thread::spawn(move || {
+            let addrs = host.to_socket_addrs().map(Iterator::collect);
+            lookups.lock().unwrap().push((host, addrs));
+        });
+    }
+
+    /// Returns the address `host` resolved to, if it did and the result didn't expire.
+    #[must_use]
+    pub fn get(&self, host: &str) -> Option<SocketAddr> {
+        self.cache
+            .get(host)
+            .filter(|resolution| resolution.expires > Instant::now())
+            .and_then(|resolution| resolution.addrs.first().copied())
+    }
+
+    /// Returns true while `host` is being resolved.
+    #[must_use]
+    pub fn is_resolving(&self, host: &str) -> bool {
+        self.resolving.contains(host)
+    }
+
+    /// Returns how long the resolved addresses are cached.
+    #[must_use]
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Sets how long the resolved addresses are cached, `DEFAULT_TTL` by default. The addresses
+    /// already cached keep the time they were given.
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+}
+
+/// Returns `host` along with `port` the way `ToSocketAddrs` parses it, the IPv6 addresses between
+/// brackets.
+fn host_with_port(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Creates a new system caching the finished lookups, and queueing the messages of the resolved
+/// hosts in the `TransportResource`.
+pub fn host_resolution_system(mut hosts:          ResMut<ResolvedHosts>,
+                              mut transport:      ResMut<TransportResource>,
+                              mut network_events: EventWriter<NetworkSimulationEvent>) {
+    let now = Instant::now();
+    let ResolvedHosts { cache, queued, resolving, lookups, ttl } = &mut *hosts;
+
+    for (host, addrs) in std::mem::take(&mut *lookups.lock().unwrap()) {
+        resolving.remove(&host);
+        let addrs = addrs.and_then(|addrs: Vec<_>| if addrs.is_empty() {
+            Err(io::Error::new(io::ErrorKind::NotFound, "no address found"))
+        } else {
+            Ok(addrs)
+        });
+        match addrs {
+            Ok(addrs) => {
+                cache.insert(host, Resolution { addrs, expires: now + *ttl });
+            }
+            Err(e) => {
+                queued.remove(&host);
+                let e = io::Error::new(e.kind(), format!("failed to resolve {}: {}", host, e));
+                network_events.send(NetworkSimulationEvent::ConnectionError(e, None));
+            }
+        }
+    }
+
+    queued.retain(|host, messages| {
+        let Some(addr) = cache.get(host).and_then(|resolution| resolution.addrs.first()) else {
+            return true;
+        };
+        for (payload, delivery) in messages.drain(..) {
+            transport.send_with_requirements(*addr, &payload, delivery, UrgencyRequirement::OnTick);
+        }
+        false
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::event::Events;
+
+    use super::*;
+
+    #[test]
+    fn test_messages_wait_for_the_host_to_resolve() {
+        let mut app = App::new();
+        app.add_plugin(HostResolutionPlugin);
+        let mut hosts = app.world.resource_mut::<ResolvedHosts>();
+        hosts.send("localhost:3000", b"hello", DeliveryRequirement::Reliable);
+        hosts.send("localhost:3000", b"again", DeliveryRequirement::Reliable);
+        assert!(hosts.is_resolving("localhost:3000"));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while app.world.resource::<ResolvedHosts>().is_resolving("localhost:3000") && Instant::now() < deadline {
+            app.update();
+            thread::sleep(Duration::from_millis(1));
+        }
+        app.update();
+
+        let addr = app.world.resource::<ResolvedHosts>().get("localhost:3000").expect("localhost didn't resolve");
+        assert!(addr.ip().is_loopback());
+        assert_eq!(addr.port(), 3000);
+        let messages: Vec<_> = app.world.resource::<TransportResource>().get_messages().collect();
+        let sent: Vec<_> = messages.iter().map(|message| (message.destination, &message.payload[..])).collect();
+        assert_eq!(sent, [(addr, &b"hello"[..]), (addr, &b"again"[..])]);
+    }
+
+    #[test]
+    fn test_send_to_host_queues_for_the_resolved_address() {
+        let mut app = App::new();
+        app.add_plugin(HostResolutionPlugin);
+        app.world.resource_mut::<ResolvedHosts>().send_to_host(
+            "localhost",
+            3000,
+            b"hello",
+            DeliveryRequirement::Reliable,
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !app.world.resource::<TransportResource>().has_messages() && Instant::now() < deadline {
+            app.update();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let messages: Vec<_> = app.world.resource::<TransportResource>().get_messages().collect();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].destination.ip().is_loopback());
+        assert_eq!(messages[0].destination.port(), 3000);
+        assert_eq!(host_with_port("::1", 3000), "[::1]:3000");
+    }
+
+    #[test]
+    fn test_resolution_failure_names_the_host() {
+        let mut app = App::new();
+        app.add_plugin(HostResolutionPlugin);
+        // no port
+        app.world.resource_mut::<ResolvedHosts>().send("localhost", b"hello", DeliveryRequirement::Reliable);
+
+        let mut reader = app.world.resource::<Events<NetworkSimulationEvent>>().get_reader();
+        let mut errors = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while errors.is_empty() && Instant::now() < deadline {
+            app.update();
+            let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+            errors.extend(reader.iter(events).filter_map(|event| match event {
+                NetworkSimulationEvent::ConnectionError(e, None) => Some(e.to_string()),
+                _ => None,
+            }));
+        }
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("failed to resolve localhost: "), "{}", errors[0]);
+        assert!(!app.world.resource::<TransportResource>().has_messages());
+    }
+}
@@ -1,12 +1,14 @@
 //! Systems and resources to have a consistent, separate simulation frame rate from the ECS
 //! frame rate.
 
-use std::{ops::RangeInclusive, time::Duration};
+use std::time::Duration;
+#[cfg(feature = "bevy")]
 use bevy::prelude::{Resource, ResMut, Res, Time};
 
 /// Default number of network simulation frames per second.
 const DEFAULT_SIM_FRAME_RATE: u32 = 30;
 
+#[cfg(feature = "bevy")]
 /// This system is used exclusively to update the state of the `NetworkSimulationTime` resource.
 pub fn network_simulation_time_system(mut sim_time:  ResMut<NetworkSimulationTime>,
                                           game_time: Res<Time>) {
@@ -18,7 +20,8 @@ pub fn network_simulation_time_system(mut sim_time:  ResMut<NetworkSimulationTim
 }
 
 /// Resource to track the state of the network simulation separately from the ECS frame timings
-#[derive(Clone, Copy, Debug, PartialEq, Resource)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "bevy", derive(Resource))]
 pub struct NetworkSimulationTime {
     /// The current simulation frame
     frame_number: u32,
@@ -28,34 +31,44 @@ pub struct NetworkSimulationTime {
     per_frame_duration: Duration,
     /// Determines how often we send messages. i.e. "Every N frames" where N is message_send_rate
     message_send_rate: u8,
+    /// Duration between two sends when a send frequency is set, overriding `message_send_rate`
+    send_interval: Option<Duration>,
+    /// Accumulated duration since the last send opportunity
+    send_elapsed_duration: Duration,
+    /// Whether the current game frame is a send opportunity, when a send frequency is set
+    send_due: bool,
     /// Number of frames behind the simulation is. This will usually be 0 or 1 if the ECS system
     /// is keeping up
     frame_lag: u32,
 }
 
 impl NetworkSimulationTime {
-    /// Returns the simulation frame numbers needed to be run this game frame.
-    #[must_use]
-    pub fn sim_frames_to_run(&self) -> RangeInclusive<u32> {
-        (self.frame_number + 1 - self.frame_lag)..=self.frame_number
+    /// Returns the simulation frame numbers needed to be run this game frame, oldest first. They
+    /// wrap around to 0 after `u32::MAX`, like the frame number.
+    pub fn sim_frames_to_run(&self) -> impl Iterator<Item = u32> + Clone {
+        let first = self.frame_number.wrapping_sub(self.frame_lag).wrapping_add(1);
+        (0..self.frame_lag).map(move |offset| first.wrapping_add(offset))
     }
 
-    /// Determines whether or not to send a message in the current frame based on the
-    /// `message_send_rate`
+    /// Determines whether or not to send a message in the current frame based on the send
+    /// frequency if one is set, or else on the `message_send_rate`
     #[must_use]
     pub fn should_send_message_now(&self) -> bool {
-        self.should_send_message(self.frame_number)
+        match self.send_interval {
+            Some(_) => self.send_due,
+            None => self.should_send_message(self.frame_number),
+        }
     }
 
     /// Determines whether or not to send a message based on the `message_send_rate`
     #[must_use]
     pub fn should_send_message(&self, frame: u32) -> bool {
-        frame % u32::from(self.message_send_rate) == 0
+        frame.is_multiple_of(u32::from(self.message_send_rate))
     }
 
-    /// Bumps the frame number
+    /// Bumps the frame number, wrapping around to 0 after `u32::MAX`
     pub fn increment_frame_number(&mut self) {
-        self.frame_number += 1;
+        self.frame_number = self.frame_number.wrapping_add(1);
         self.elapsed_duration -= self.per_frame_duration;
         self.frame_lag += 1;
     }
@@ -65,12 +78,26 @@ impl NetworkSimulationTime {
         self.frame_lag = 0;
     }
 
-    /// Increases the `elapsed_duration` by the given duration
+    /// Increases the `elapsed_duration` by the given duration. When a send frequency is set, this
+    /// also determines whether the current game frame is a send opportunity. Time left over after
+    /// the last opportunity carries over so sends don't drift, but opportunities missed by a long
+    /// frame are merged into one.
     pub fn update_elapsed(&mut self, duration: Duration) {
         self.elapsed_duration += duration;
+        if let Some(send_interval) = self.send_interval {
+            self.send_elapsed_duration += duration;
+            self.send_due = self.send_elapsed_duration >= send_interval;
+            if self.send_due {
+                self.send_elapsed_duration = Duration::from_nanos(
+                    (self.send_elapsed_duration.as_nanos() % send_interval.as_nanos()) as u64,
+                );
+            }
+        }
     }
 
-    /// Returns the current simulation frame number
+    /// Returns the current simulation frame number. It is incremented once per simulation frame by
+    /// `network_simulation_time_system` and wraps around to 0 after `u32::MAX`, which takes more
+    /// than four years at 30 frames per second.
     #[must_use]
     pub fn frame_number(&self) -> u32 {
         self.frame_number
@@ -95,6 +122,13 @@ impl NetworkSimulationTime {
         self.per_frame_duration
     }
 
+    /// Returns the duration between each simulation frame in seconds, i.e. the time step to use
+    /// when simulating a single frame.
+    #[must_use]
+    pub fn per_frame_seconds(&self) -> f32 {
+        self.per_frame_duration.as_secs_f32()
+    }
+
     /// Returns the rate at which messages should be sent over the network.
     /// i.e. 'every N frames' where N is `message_send_rate`.
     #[must_use]
@@ -118,6 +152,28 @@ impl NetworkSimulationTime {
     pub fn set_message_send_rate(&mut self, new_rate: u8) {
         self.message_send_rate = new_rate;
     }
+
+    /// Returns the number of times per second messages are sent, if set.
+    #[must_use]
+    pub fn send_frequency(&self) -> Option<u16> {
+        self.send_interval
+            .map(|interval| (Duration::from_secs(1).as_nanos() / interval.as_nanos()) as u16)
+    }
+
+    /// Sets the number of times per second messages are sent, independently of the simulation
+    /// frame rate. This overrides the `message_send_rate`, setting it to 0 goes back to it.
+    pub fn set_send_frequency(&mut self, hz: u16) {
+        self.send_interval = (hz != 0).then(|| Duration::from_secs(1) / u32::from(hz));
+        self.send_elapsed_duration = Duration::from_secs(0);
+        self.send_due = false;
+    }
+
+    /// Returns the time with the given send frequency. See `set_send_frequency`.
+    #[must_use]
+    pub fn with_rate(mut self, hz: u16) -> Self {
+        self.set_send_frequency(hz);
+        self
+    }
 }
 
 impl Default for NetworkSimulationTime {
@@ -129,6 +185,9 @@ impl Default for NetworkSimulationTime {
             per_frame_duration: Duration::from_secs(1) / DEFAULT_SIM_FRAME_RATE,
             // Default to sending a message with every simulation frame
             message_send_rate: 1,
+            send_interval: None,
+            send_elapsed_duration: Duration::from_secs(0),
+            send_due: false,
             // Default the lag to run so systems have a chance to run on the frame 0
             frame_lag: 1,
         }
@@ -139,6 +198,9 @@ impl Default for NetworkSimulationTime {
 mod tests {
     use std::time::Duration;
 
+    #[cfg(feature = "bevy")]
+    use bevy::app::App;
+
     use super::*;
 
     #[test]
@@ -152,6 +214,22 @@ mod tests {
         assert_eq!(time.elapsed_duration(), Duration::from_millis(0));
     }
 
+    #[test]
+    fn test_frames_to_run_wrap_around() {
+        let mut time = NetworkSimulationTime::default();
+        time.set_frame_number(u32::MAX - 1);
+        time.reset_frame_lag();
+        time.update_elapsed(time.per_frame_duration() * 3);
+        for _ in 0..3 {
+            time.increment_frame_number();
+        }
+        assert_eq!(time.frame_number(), 1);
+        assert_eq!(time.sim_frames_to_run().collect::<Vec<_>>(), [u32::MAX, 0, 1]);
+
+        time.reset_frame_lag();
+        assert_eq!(time.sim_frames_to_run().count(), 0);
+    }
+
     #[test]
     fn test_message_send_rate_should_send_every_2_frames() {
         let mut time = NetworkSimulationTime::default();
@@ -167,6 +245,69 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "bevy")]
+    #[test]
+    fn test_system_increments_frame_number_per_simulation_frame() {
+        let mut app = App::new();
+        app.init_resource::<NetworkSimulationTime>()
+            .insert_resource(Time::default())
+            .add_system(network_simulation_time_system);
+        app.world.resource_mut::<NetworkSimulationTime>().set_sim_frame_rate(10);
+        app.world.resource_mut::<Time>().update();
+
+        // the 50ms left over from each update carry over to the next one
+        for (expected_frame, expected_lag) in [(3, 3), (6, 3), (10, 4)] {
+            let mut time = app.world.resource_mut::<Time>();
+            let last_update = time.last_update().unwrap();
+            time.update_with_instant(last_update + Duration::from_millis(350));
+            app.update();
+
+            let sim_time = app.world.resource::<NetworkSimulationTime>();
+            assert_eq!(sim_time.frame_number(), expected_frame);
+            assert_eq!(sim_time.frame_lag(), expected_lag);
+            assert_eq!(
+                sim_time.sim_frames_to_run().collect::<Vec<_>>(),
+                (expected_frame + 1 - expected_lag..=expected_frame).collect::<Vec<_>>()
+            );
+        }
+        let sim_time = app.world.resource::<NetworkSimulationTime>();
+        assert!((sim_time.per_frame_seconds() - 0.1).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn test_frame_number_wraps() {
+        let mut time = NetworkSimulationTime::default();
+        time.set_frame_number(u32::MAX);
+        time.update_elapsed(time.per_frame_duration());
+        time.increment_frame_number();
+        assert_eq!(time.frame_number(), 0);
+    }
+
+    #[test]
+    fn test_send_frequency_gives_ten_opportunities_per_second_at_10_hz() {
+        let mut time = NetworkSimulationTime::default().with_rate(10);
+        assert_eq!(time.send_frequency(), Some(10));
+
+        let mut opportunities = 0;
+        // 1 second of uneven game frames
+        for millis in [7, 13].iter().cycle().take(100) {
+            time.update_elapsed(Duration::from_millis(*millis));
+            if time.should_send_message_now() {
+                opportunities += 1;
+            }
+        }
+
+        assert_eq!(opportunities, 10);
+    }
+
+    #[test]
+    fn test_send_frequency_of_0_goes_back_to_message_send_rate() {
+        let mut time = NetworkSimulationTime::default().with_rate(10);
+        time.set_send_frequency(0);
+        assert_eq!(time.send_frequency(), None);
+        assert!(time.should_send_message_now());
+    }
+
     #[test]
     fn test_elapsed_duration_gets_updated() {
         let mut time = NetworkSimulationTime::default();
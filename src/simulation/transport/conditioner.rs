@@ -0,0 +1,231 @@
+//! Artificially degraded network, to debug netcode without a bad connection at hand. Only built
+//! with the `conditioner` feature, so that it can't ship by accident.
+//!
+//! The conditions apply to the messages drained from the `TransportResource`, right before they
+//! are handed to the socket: each one is held for the latency plus a normally distributed jitter,
+//! which reorders them, and the unreliable ones may be dropped or duplicated. Reliable messages are
+//! only ever delayed, as the transport would resend or deduplicate them. The draws come from a
+//! seeded generator, so a test sees the same conditions on every run.
+
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "bevy")]
+use bevy::app::App;
+#[cfg(feature = "bevy")]
+use bevy::prelude::Plugin;
+
+use crate::simulation::{message::Message, requirements::DeliveryRequirement, transport::TransportResource};
+
+/// Seed of the generator when none is given.
+#[cfg(feature = "bevy")]
+const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+/// Conditions applied to the messages sent, see `TransportResource::set_network_conditions`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NetworkConditions {
+    /// Time every message is held before it is sent.
+    pub latency:        Duration,
+    /// Standard deviation of the time added to, or taken from, the latency of each message.
+    pub jitter:         Duration,
+    /// Probability, between 0 and 1, for an unreliable message to be dropped.
+    pub drop_rate:      f32,
+    /// Probability, between 0 and 1, for an unreliable message to be sent twice.
+    pub duplicate_rate: f32,
+}
+
+/// Holds the drained messages until the conditions let them through.
+pub(crate) struct NetworkConditioner {
+    conditions: NetworkConditions,
+    rng:        u64,
+    held:       Vec<(Instant, Message)>,
+}
+
+impl NetworkConditioner {
+    pub(crate) fn new(conditions: NetworkConditions, seed: u64) -> Self {
+        // xorshift gets stuck on zero
+        Self { conditions, rng: seed.max(1), held: Vec::new() }
+    }
+
+    pub(crate) fn conditions(&self) -> NetworkConditions {
+        self.conditions
+    }
+
+    /// Holds the messages drained at `now`, dropping or duplicating the unreliable ones.
+    pub(crate) fn hold(&mut self, messages: Vec<Message>, now: Instant) {
+        for message in messages {
+            let unreliable = matches!(
+                message.delivery,
+                DeliveryRequirement::Unreliable | DeliveryRequirement::UnreliableSequenced(_)
+            );
+            if unreliable && self.draw_below(self.conditions.drop_rate) {
+                continue;
+            }
+            if unreliable && self.draw_below(self.conditions.duplicate_rate) {
+                let release = now + self.draw_delay();
+                self.held.push((release, message.clone()));
+            }
+            let release = now + self.draw_delay();
+            self.held.push((release, message));
+        }
+        self.held.sort_by_key(|(release, _)| *release);
+    }
+
+    /// Returns the messages due at `now` for which `routed_here` is true, in the order of their
+    /// release.
+    pub(crate) fn release(&mut self, now: Instant, mut routed_here: impl FnMut(&Message) -> bool) -> Vec<Message> {
+        let mut released = Vec::new();
+        let mut i = 0;
+        while i != self.held.len() && self.held[i].0 <= now {
+            if routed_here(&self.held[i].1) {
+                released.push(self.held.remove(i).1);
+            } else {
+                i += 1;
+            }
+        }
+        released
+    }
+
+    /// Returns the number of messages held.
+    pub(crate) fn len(&self) -> usize {
+        self.held.len()
+    }
+
+    fn draw_delay(&mut self) -> Duration {
+        let NetworkConditions { latency, jitter, .. } = self.conditions;
+        if jitter.is_zero() {
+            return latency;
+        }
+        // Box-Muller transform, the first uniform must not be zero
+        let u1 = 1.0 - self.next_uniform();
+        let u2 = self.next_uniform();
+        let normal = (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos();
+        Duration::from_secs_f64((latency.as_secs_f64() + jitter.as_secs_f64() * normal).max(0.0))
+    }
+
+    fn draw_below(&mut self, probability: f32) -> bool {
+        probability > 0.0 && self.next_uniform() < f64::from(probability)
+    }
+
+    /// Draws a number in `[0, 1)` with a xorshift generator.
+    fn next_uniform(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl TransportResource {
+    /// Applies `conditions` to the messages drained from now on, the draws coming from a generator
+    /// seeded with `seed`. The messages held by previous conditions are dropped.
+    pub fn set_network_conditions(&mut self, conditions: NetworkConditions, seed: u64) {
+        self.conditioner = Some(NetworkConditioner::new(conditions, seed));
+    }
+
+    /// Stops conditioning the messages, dropping the ones held.
+    pub fn clear_network_conditions(&mut self) {
+        self.conditioner = None;
+    }
+
+    /// Returns the conditions applied to the messages drained, if any.
+    #[must_use]
+    pub fn network_conditions(&self) -> Option<NetworkConditions> {
+        self.conditioner.as_ref().map(NetworkConditioner::conditions)
+    }
+
+    /// Returns the number of messages drained but held back by the conditions.
+    #[must_use]
+    pub fn conditioned_messages(&self) -> usize {
+        self.conditioner.as_ref().map_or(0, NetworkConditioner::len)
+    }
+}
+
+/// Use this plugin to apply `NetworkConditions` to every message sent.
+#[cfg(feature = "bevy")]
+pub struct NetworkConditionerPlugin {
+    conditions: NetworkConditions,
+    seed:       u64,
+}
+
+#[cfg(feature = "bevy")]
+impl NetworkConditionerPlugin {
+    #[must_use]
+    pub fn new(conditions: NetworkConditions) -> Self {
+        Self { conditions, seed: DEFAULT_SEED }
+    }
+
+    /// Seeds the generator the drops, duplicates and jitter are drawn from.
+    #[must_use]
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+#[cfg(feature = "bevy")]
+impl Plugin for NetworkConditionerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<TransportResource>();
+        app.world.resource_mut::<TransportResource>().set_network_conditions(self.conditions, self.seed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::simulation::requirements::UrgencyRequirement;
+
+    #[test]
+    fn test_unreliable_messages_are_dropped_at_the_drop_rate() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut transport = TransportResource::new();
+        transport.set_network_conditions(NetworkConditions { drop_rate: 0.1, ..NetworkConditions::default() }, 7);
+        for _ in 0..1000 {
+            transport.send_with_requirements(
+                addr,
+                b"test",
+                DeliveryRequirement::Unreliable,
+                UrgencyRequirement::Immediate,
+            );
+        }
+        transport.send_with_requirements(
+            addr,
+            b"reliable",
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::Immediate,
+        );
+
+        let sent = transport.drain_messages_to_send(|_| true);
+        let unreliable = sent.iter().filter(|message| message.delivery == DeliveryRequirement::Unreliable).count();
+        assert!((870..=930).contains(&unreliable), "{} sent", unreliable);
+        assert_eq!(sent.len(), unreliable + 1);
+    }
+
+    #[test]
+    fn test_messages_are_held_for_the_latency() {
+        let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        let conditions = NetworkConditions {
+            latency:        Duration::from_millis(100),
+            jitter:         Duration::from_millis(20),
+            duplicate_rate: 0.5,
+            ..NetworkConditions::default()
+        };
+        let mut conditioner = NetworkConditioner::new(conditions, 7);
+        let now = Instant::now();
+        let messages = (0..100u8)
+            .map(|i| Message::new(addr, vec![i].into(), DeliveryRequirement::Unreliable, UrgencyRequirement::Immediate))
+            .collect();
+        conditioner.hold(messages, now);
+        assert!(conditioner.release(now + Duration::from_millis(20), |_| true).is_empty());
+
+        let released = conditioner.release(now + Duration::from_secs(1), |_| true);
+        assert!((120..=180).contains(&released.len()), "{} released", released.len());
+        let order: Vec<_> = released.iter().map(|message| message.payload[0]).collect();
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        assert_ne!(order, sorted, "jitter should reorder the messages");
+        assert_eq!(conditioner.len(), 0);
+    }
+}
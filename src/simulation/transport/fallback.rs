@@ -0,0 +1,274 @@
+//! Fallback to TCP for the networks which block UDP, where the laminar connection would otherwise
+//! never come up.
+//!
+//! The messages to the server first go over laminar, routed there with `TransportResource::set_route`.
+//! If laminar doesn't connect to the server within the timeout, its sockets are dropped and the
+//! messages to the server go over TCP instead, to the same host on the fallback port, so the game
+//! keeps sending them to the server address either way. The incoming events come from the address
+//! of the transport which won though, the fallback one in the case of TCP.
+//!
+//! Laminar only connects once the server answered, the game must keep sending to the server, e.g.
+//! its join request, until it does. Both the `LaminarPlugin` and the `TcpPlugin` must be added.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bevy::app::App;
+use bevy::prelude::{EventReader, EventWriter, ParamSet, Plugin, ResMut, Resource, SystemLabel, SystemSet};
+
+use crate::simulation::{
+    events::{NetworkSimulationEvent, TaggedNetworkEvent},
+    transport::{
+        laminar::LaminarSocketResource,
+        routing::{NetworkEventWriter, TransportId},
+        tcp::TcpStreamsResource,
+        TransportResource,
+    },
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct FallbackConnectLabel;
+
+/// Events reporting the progress of the connection to the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FallbackEvent {
+    // A connection over the given transport is being attempted
+    Attempting(TransportId),
+    // The first transport timed out, the given one connected instead
+    FellBackTo(TransportId),
+    // No transport connected
+    Failed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stage {
+    Idle,
+    Attempting(Instant),
+    Connected(TransportId),
+    Failed,
+}
+
+/// Resource tracking which transport connects to the server.
+#[derive(Debug, Resource)]
+pub struct FallbackConnect {
+    server:   SocketAddr,
+    fallback: SocketAddr,
+    timeout:  Duration,
+    stage:    Stage,
+}
+
+impl FallbackConnect {
+    /// Returns the address the game sends its messages to the server to.
+    #[must_use]
+    pub fn server_addr(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// Returns the address the server listens on for TCP connections.
+    #[must_use]
+    pub fn fallback_addr(&self) -> SocketAddr {
+        self.fallback
+    }
+
+    /// Returns the transport which connected to the server, if one did.
+    #[must_use]
+    pub fn connected_via(&self) -> Option<TransportId> {
+        match self.stage {
+            Stage::Connected(transport) => Some(transport),
+            _ => None,
+        }
+    }
+
+    /// Returns true once every transport failed to connect.
+    #[must_use]
+    pub fn has_failed(&self) -> bool {
+        self.stage == Stage::Failed
+    }
+}
+
+/// Use this plugin next to the `LaminarPlugin` and the `TcpPlugin` to connect to the server over
+/// TCP when it can't be reached over UDP. See `FallbackConnect`.
+pub struct FallbackConnectPlugin {
+    server:        SocketAddr,
+    fallback_port: u16,
+    timeout:       Duration,
+}
+
+impl FallbackConnectPlugin {
+    /// Creates a plugin connecting to `server`, falling back to TCP on `fallback_port` of the same
+    /// host.
+    #[must_use]
+    pub fn new(server: SocketAddr, fallback_port: u16) -> Self {
+        Self { server, fallback_port, timeout: DEFAULT_TIMEOUT }
+    }
+
+    /// Sets how long laminar has to connect before falling back, and how long the TCP connection
+    /// may then take, 3 seconds by default. Connecting over TCP blocks the frame for that long at
+    /// most.
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl Plugin for FallbackConnectPlugin {
+    fn build(&self, app: &mut App) {
+        let mut fallback = self.server;
+        fallback.set_port(self.fallback_port);
+
+        app
+            .add_event::<FallbackEvent>()
+            .add_event::<NetworkSimulationEvent>()
+            .add_event::<TaggedNetworkEvent>()
+            .init_resource::<TransportResource>()
+            .insert_resource(FallbackConnect {
+                server:  self.server,
+                fallback,
+                timeout: self.timeout,
+                stage:   Stage::Idle,
+            })
+            .add_system_set(SystemSet::new()
+                .label(FallbackConnectLabel)
+                .with_system(fallback_connect_system)
+            );
+        // routed right away, or the transport which drains the queue first would send the messages
+        // of the first frame
+        app.world.resource_mut::<TransportResource>().set_route(self.server, TransportId::LAMINAR, self.server);
+    }
+}
+
+/// Creates a new fallback system, routing the messages to the server to laminar, then to TCP if
+/// laminar didn't connect in time.
+pub fn fallback_connect_system(mut fallback:        ResMut<FallbackConnect>,
+                               mut transport:       ResMut<TransportResource>,
+                               mut socket:          ResMut<LaminarSocketResource>,
+                               mut streams:         ResMut<TcpStreamsResource>,
+                               mut network_events:  ParamSet<(EventReader<TaggedNetworkEvent>, NetworkEventWriter)>,
+                               mut fallback_events: EventWriter<FallbackEvent>) {
+    let now = Instant::now();
+    let server = fallback.server;
+    let since = match fallback.stage {
+        Stage::Idle => {
+            fallback.stage = Stage::Attempting(now);
+            fallback_events.send(FallbackEvent::Attempting(TransportId::LAMINAR));
+            now
+        }
+        Stage::Attempting(since) => since,
+        Stage::Connected(_) | Stage::Failed => return,
+    };
+
+    let connected = network_events
+        .p0()
+        .iter()
+        .any(|event| *event == TaggedNetworkEvent::Connect(TransportId::LAMINAR, server));
+    if connected {
+        fallback.stage = Stage::Connected(TransportId::LAMINAR);
+        return;
+    }
+    if now.duration_since(since) < fallback.timeout {
+        return;
+    }
+
+    // nothing is left of the laminar attempt, its queued messages go over TCP
+    socket.drop_socket();
+    transport.set_route(server, TransportId::TCP, fallback.fallback);
+    fallback_events.send(FallbackEvent::Attempting(TransportId::TCP));
+    match streams.connect(fallback.fallback, fallback.timeout) {
+        Ok(connected) => {
+            if connected {
+                network_events.p1().send(TransportId::TCP, NetworkSimulationEvent::Connect(fallback.fallback));
+            }
+            fallback.stage = Stage::Connected(TransportId::TCP);
+            fallback_events.send(FallbackEvent::FellBackTo(TransportId::TCP));
+        }
+        Err(e) => {
+            let error = NetworkSimulationEvent::ConnectionError(e, Some(fallback.fallback));
+            network_events.p1().send(TransportId::TCP, error);
+            fallback.stage = Stage::Failed;
+            fallback_events.send(FallbackEvent::Failed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use bevy::ecs::event::Events;
+
+    use super::*;
+    use crate::simulation::transport::{
+        laminar::{LaminarConfig, LaminarPlugin},
+        tcp::TcpPlugin,
+    };
+
+    #[test]
+    fn test_falls_back_to_tcp_when_laminar_times_out() {
+        let mut server = App::new();
+        server.init_resource::<bevy::time::Time>()
+            .add_plugin(TcpPlugin::new("127.0.0.1:0".parse().unwrap()));
+        let server_addr = server.world.resource::<TcpStreamsResource>().listener().unwrap().local_addr().unwrap();
+        // nothing answers over UDP on the port of the TCP listener
+        let mut client = create_test_app(server_addr, server_addr.port());
+
+        let mut reader = client.world.resource::<Events<FallbackEvent>>().get_reader();
+        let mut progress = Vec::new();
+        let mut payloads = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while payloads.is_empty() && Instant::now() < deadline {
+            client.world.resource_mut::<TransportResource>().send(server_addr, b"join");
+            client.update();
+            server.update();
+            progress.extend(reader.iter(client.world.resource::<Events<FallbackEvent>>()).cloned());
+            let events = server.world.resource::<Events<NetworkSimulationEvent>>();
+            payloads.extend(events.get_reader().iter(events).filter_map(|event| match event {
+                NetworkSimulationEvent::Message(_, payload) => Some(payload.clone()),
+                _ => None,
+            }));
+            server.world.resource_mut::<Events<NetworkSimulationEvent>>().clear();
+        }
+
+        assert_eq!(payloads.first().map(|payload| &payload[..]), Some(&b"join"[..]));
+        assert_eq!(progress, [
+            FallbackEvent::Attempting(TransportId::LAMINAR),
+            FallbackEvent::Attempting(TransportId::TCP),
+            FallbackEvent::FellBackTo(TransportId::TCP),
+        ]);
+        assert_eq!(client.world.resource::<FallbackConnect>().connected_via(), Some(TransportId::TCP));
+        assert!(client.world.resource::<LaminarSocketResource>().sockets().is_empty());
+    }
+
+    #[test]
+    fn test_fails_when_tcp_is_refused_too() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        drop(listener);
+        let mut client = create_test_app(server_addr, server_addr.port());
+
+        let mut reader = client.world.resource::<Events<FallbackEvent>>().get_reader();
+        let mut progress = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !client.world.resource::<FallbackConnect>().has_failed() && Instant::now() < deadline {
+            client.update();
+            progress.extend(reader.iter(client.world.resource::<Events<FallbackEvent>>()).cloned());
+        }
+
+        assert_eq!(progress.last(), Some(&FallbackEvent::Failed));
+        assert!(client.world.resource::<TcpStreamsResource>().connected_addrs().next().is_none());
+        assert!(client.world.resource::<LaminarSocketResource>().sockets().is_empty());
+    }
+
+    fn create_test_app(server: SocketAddr, fallback_port: u16) -> App {
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default()))
+            .add_plugin(TcpPlugin::new("127.0.0.1:0".parse().unwrap()))
+            .add_plugin(FallbackConnectPlugin::new(server, fallback_port).with_timeout(Duration::from_millis(100)));
+        app
+    }
+}
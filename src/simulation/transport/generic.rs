@@ -0,0 +1,365 @@
+//! Network systems implementation working with any socket implementing the `Transport` trait, so
+//! that other socket types, e.g. the one of a platform SDK, can be plugged in without writing the
+//! systems again.
+
+use std::{io, net::SocketAddr, time::Duration};
+#[cfg(feature = "bevy")]
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+use crate::simulation::{
+    events::{DisconnectReason, NetworkSimulationEvent},
+    message::MessageId,
+    requirements::DeliveryRequirement,
+    transport::routing::TransportId,
+};
+#[cfg(feature = "bevy")]
+use crate::simulation::{
+    events::TaggedNetworkEvent,
+    peers::{ConnectedPeers, connected_peers_system},
+    timing::{NetworkSimulationTime, network_simulation_time_system},
+    transport::{
+        routing::{NetworkEventWriter, unroutable_messages_system},
+        TransportResource,
+    },
+};
+#[cfg(feature = "bevy")]
+use bevy::prelude::{Plugin, Resource, Res, ResMut, EventWriter, SystemSet, SystemLabel};
+#[cfg(feature = "bevy")]
+use bevy::app::App;
+
+#[cfg(feature = "bevy")]
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct TransportLabel;
+
+/// Events a `Transport` reports when polled, they are forwarded as the `NetworkSimulationEvent` of
+/// the same name.
+#[derive(Debug)]
+pub enum TransportEvent {
+    // A message was received from a remote client
+    Message(SocketAddr, Bytes),
+    // A new host has connected to us
+    Connect(SocketAddr),
+    // A host has disconnected from us
+    Disconnect(SocketAddr, DisconnectReason),
+    // An error occurred while receiving a message.
+    RecvError(io::Error),
+    // An error occurred while managing connections.
+    ConnectionError(io::Error, Option<SocketAddr>),
+    // The round-trip time to a host changed.
+    Latency(SocketAddr, Duration),
+    // A keepalive packet came from a host.
+    Heartbeat(SocketAddr),
+    // A host acknowledged the tagged message.
+    Acked(SocketAddr, u64),
+    // A host acknowledged the message sent with an ack.
+    MessageAcked(MessageId),
+    // The message sent with an ack won't be acknowledged.
+    MessageAckTimeout(MessageId),
+}
+
+impl From<TransportEvent> for NetworkSimulationEvent {
+    fn from(event: TransportEvent) -> Self {
+        match event {
+            TransportEvent::Message(addr, payload) => NetworkSimulationEvent::Message(addr, payload),
+            TransportEvent::Connect(addr) => NetworkSimulationEvent::Connect(addr),
+            TransportEvent::Disconnect(addr, reason) => NetworkSimulationEvent::Disconnect(addr, reason),
+            TransportEvent::RecvError(e) => NetworkSimulationEvent::RecvError(e),
+            TransportEvent::ConnectionError(e, addr) => NetworkSimulationEvent::ConnectionError(e, addr),
+            TransportEvent::Latency(addr, rtt) => NetworkSimulationEvent::Latency(addr, rtt),
+            TransportEvent::Heartbeat(addr) => NetworkSimulationEvent::Heartbeat(addr),
+            TransportEvent::Acked(addr, tag) => NetworkSimulationEvent::Acked(addr, tag),
+            TransportEvent::MessageAcked(id) => NetworkSimulationEvent::MessageAcked(id),
+            TransportEvent::MessageAckTimeout(id) => NetworkSimulationEvent::MessageAckTimeout(id),
+        }
+    }
+}
+
+/// A socket the generic network systems can drive. Like every socket used by the systems, it must
+/// never block.
+pub trait Transport: Send + Sync + 'static {
+    /// Sends, or queues for the next `poll`, the payload to `destination` honoring the delivery
+    /// requirement. An error is emitted as a `SendError` along with the message, unless it is
+    /// retried, see `TransportResource::set_retry_policy`.
+    fn send(&mut self, destination: SocketAddr, payload: Bytes, delivery: DeliveryRequirement) -> io::Result<()>;
+
+    /// Processes the pending IO and returns what happened since the previous poll.
+    fn poll(&mut self) -> Vec<TransportEvent>;
+}
+
+/// Use this plugin to drive your own `Transport` implementation.
+#[cfg(feature = "bevy")]
+pub struct TransportPlugin<T: Transport> {
+    // `build` only borrows the plugin, the socket is moved out of it into the resource
+    socket: Mutex<Option<T>>,
+    id:     TransportId,
+}
+
+#[cfg(feature = "bevy")]
+impl<T: Transport> TransportPlugin<T> {
+    /// Creates a plugin driving `socket`, identified by the name of its type.
+    pub fn new(socket: T) -> Self {
+        TransportPlugin { socket: Mutex::new(Some(socket)), id: default_id::<T>() }
+    }
+
+    /// Sets the id messages are routed to this transport with.
+    #[must_use]
+    pub fn with_id(mut self, id: TransportId) -> Self {
+        self.id = id;
+        self
+    }
+}
+
+fn default_id<T: Transport>() -> TransportId {
+    TransportId(std::any::type_name::<T>())
+}
+
+#[cfg(feature = "bevy")]
+impl<T: Transport> Plugin for TransportPlugin<T> {
+    fn build(&self, app: &mut App) {
+        let socket = self.socket.lock().unwrap_or_else(std::sync::PoisonError::into_inner).take();
+
+        app
+            .add_event::<NetworkSimulationEvent>()
+            .add_event::<TaggedNetworkEvent>()
+            .init_resource::<NetworkSimulationTime>()
+            .init_resource::<TransportResource>()
+            .init_resource::<ConnectedPeers>()
+            .insert_resource(TransportSocketResource { socket, id: self.id })
+            .add_system_set(SystemSet::new()
+                .label(TransportLabel)
+                .with_system(network_simulation_time_system)
+                .with_system(unroutable_messages_system)
+                .with_system(connected_peers_system)
+                .with_system(transport_send_system::<T>)
+                .with_system(transport_poll_system::<T>)
+            );
+        app.world.resource_mut::<TransportResource>().register_transport(self.id);
+    }
+
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// Creates a new generic network send system.
+#[cfg(feature = "bevy")]
+pub fn transport_send_system<T: Transport>(mut transport: ResMut<TransportResource>,
+                                           mut socket:        ResMut<TransportSocketResource<T>>,
+                                           mut event_channel: EventWriter<NetworkSimulationEvent>,
+                                               sim_time:      Res<NetworkSimulationTime>) {
+    let id = socket.id();
+    if let Some(socket) = socket.get_mut() {
+        let messages = transport
+            .drain_messages_to_send_via(id, |_| sim_time.should_send_message_now());
+
+        for message in messages {
+            if let Err(e) = socket.send(message.destination, message.payload.clone(), message.delivery) {
+                if let Some((e, message)) = transport.retry_failed(e, message) {
+                    event_channel.send(NetworkSimulationEvent::SendError(e, message));
+                }
+            }
+        }
+    }
+}
+
+/// Creates a new generic network poll system, emitting the events reported by the socket.
+#[cfg(feature = "bevy")]
+pub fn transport_poll_system<T: Transport>(mut socket:        ResMut<TransportSocketResource<T>>,
+                                           mut event_channel: NetworkEventWriter) {
+    let id = socket.id();
+    if let Some(socket) = socket.get_mut() {
+        event_channel.send_batch(id, socket.poll().into_iter().map(NetworkSimulationEvent::from));
+    }
+}
+
+/// Resource that owns the socket driven by the generic systems.
+#[cfg_attr(feature = "bevy", derive(Resource))]
+pub struct TransportSocketResource<T: Transport> {
+    socket: Option<T>,
+    id:     TransportId,
+}
+
+impl<T: Transport> Default for TransportSocketResource<T> {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl<T: Transport> TransportSocketResource<T> {
+    /// Creates a new instance of the `TransportSocketResource`, identified by the name of the
+    /// socket type.
+    #[must_use]
+    pub fn new(socket: Option<T>) -> Self {
+        Self { socket, id: default_id::<T>() }
+    }
+
+    /// Returns the id of the transport.
+    #[must_use]
+    pub fn id(&self) -> TransportId {
+        self.id
+    }
+
+    /// Returns a reference to the socket if there is one configured.
+    #[must_use]
+    pub fn get(&self) -> Option<&T> {
+        self.socket.as_ref()
+    }
+
+    /// Returns a mutable reference to the socket if there is one configured.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        self.socket.as_mut()
+    }
+
+    /// Sets the socket, dropping the previous one if any.
+    pub fn set_socket(&mut self, socket: T) {
+        self.socket = Some(socket);
+    }
+
+    /// Drops the socket, if there is one configured.
+    pub fn drop_socket(&mut self) {
+        self.socket = None;
+    }
+}
+
+#[cfg(all(test, feature = "bevy"))]
+mod tests {
+    use std::time::Instant;
+
+    use bevy::ecs::event::Events;
+
+    use super::*;
+    use crate::simulation::{
+        requirements::UrgencyRequirement,
+        transport::{laminar::LaminarSocket, RetryPolicy},
+    };
+
+    #[test]
+    fn test_custom_transport() {
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(TransportPlugin::new(EchoTransport::default()));
+        let addr = "127.0.0.1:3000".parse().unwrap();
+
+        let mut transport = app.world.resource_mut::<TransportResource>();
+        transport.send_with_requirements(addr, b"echo", DeliveryRequirement::Reliable, UrgencyRequirement::Immediate);
+        transport.send_with_requirements(addr, b"lost", DeliveryRequirement::Unreliable, UrgencyRequirement::Immediate);
+        app.update();
+        app.update();
+
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        let events: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .map(|event| match event {
+                NetworkSimulationEvent::Message(addr, payload) => (*addr, payload.clone(), None),
+                NetworkSimulationEvent::SendError(e, message) => {
+                    (message.destination, message.payload.clone(), Some(e.kind()))
+                }
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect();
+        assert_eq!(events, vec![
+            (addr, Bytes::from_static(b"lost"), Some(io::ErrorKind::Unsupported)),
+            (addr, Bytes::from_static(b"echo"), None),
+        ]);
+    }
+
+    #[test]
+    fn test_laminar_socket_as_transport() {
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(TransportPlugin::new(LaminarSocket::bind_any().unwrap()));
+        let receiver_addr = app.world.resource::<TransportSocketResource<LaminarSocket>>()
+            .get().unwrap().local_addr().unwrap();
+        let mut peer = LaminarSocket::bind_any().unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+
+        Transport::send(
+            &mut peer,
+            receiver_addr,
+            Bytes::from_static(b"test"),
+            DeliveryRequirement::Unreliable,
+        )
+        .unwrap();
+        peer.manual_poll(Instant::now());
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut received = None;
+        while received.is_none() && Instant::now() < deadline {
+            app.update();
+            let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+            received = events.get_reader().iter(events).find_map(|event| match event {
+                NetworkSimulationEvent::Message(addr, payload) => Some((*addr, payload.clone())),
+                _ => None,
+            });
+        }
+        assert_eq!(received, Some((peer_addr, Bytes::from_static(b"test"))));
+    }
+
+    #[test]
+    fn test_transient_errors_are_retried() {
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(TransportPlugin::new(FlakyTransport { failures: 2, sent: Vec::new() }));
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut transport = app.world.resource_mut::<TransportResource>();
+        transport.set_retry_policy(Some(RetryPolicy { backoff: Duration::ZERO, ..RetryPolicy::default() }));
+        transport.send_with_requirements(addr, b"state", DeliveryRequirement::Reliable, UrgencyRequirement::Immediate);
+        for _ in 0..3 {
+            app.update();
+        }
+
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        assert!(!events.get_reader().iter(events).any(|event| matches!(event, NetworkSimulationEvent::SendError(..))));
+        let socket = app.world.resource::<TransportSocketResource<FlakyTransport>>().get().unwrap();
+        assert_eq!(socket.sent, [Bytes::from_static(b"state")]);
+    }
+
+    /// Transport failing the first sends with `WouldBlock`.
+    struct FlakyTransport {
+        failures: usize,
+        sent:     Vec<Bytes>,
+    }
+
+    impl Transport for FlakyTransport {
+        fn send(&mut self, _destination: SocketAddr, payload: Bytes, _delivery: DeliveryRequirement) -> io::Result<()> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.sent.push(payload);
+            Ok(())
+        }
+
+        fn poll(&mut self) -> Vec<TransportEvent> {
+            Vec::new()
+        }
+    }
+
+    /// Transport sending reliable messages back to their sender and rejecting the other ones.
+    #[derive(Default)]
+    struct EchoTransport {
+        echoed: Vec<(SocketAddr, Bytes)>,
+    }
+
+    impl Transport for EchoTransport {
+        fn send(&mut self, destination: SocketAddr, payload: Bytes, delivery: DeliveryRequirement) -> io::Result<()> {
+            match delivery {
+                DeliveryRequirement::Reliable => {
+                    self.echoed.push((destination, payload));
+                    Ok(())
+                }
+                _ => Err(io::ErrorKind::Unsupported.into()),
+            }
+        }
+
+        fn poll(&mut self) -> Vec<TransportEvent> {
+            self.echoed
+                .drain(..)
+                .map(|(addr, payload)| TransportEvent::Message(addr, payload))
+                .collect()
+        }
+    }
+}
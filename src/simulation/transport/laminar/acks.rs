@@ -0,0 +1,210 @@
+//! Delivery acknowledgments of the tagged reliable messages, see `TransportResource::send_with_tag`,
+//! and of the ones sent with `TransportResource::send_with_ack`.
+//!
+//! Laminar doesn't tell which packets were acked, so like the RTT estimation this looks at the raw
+//! datagrams: an outgoing reliable datagram ending with the payload of a tagged message is taken
+//! to carry it, and the message is acked once one of the sequence numbers it was sent under shows
+//! up in the acknowledgment header of an incoming datagram. Resends go out under new sequence
+//! numbers, they are matched the same way.
+//!
+//! Only payloads laminar sends in a single datagram are tracked. A payload large enough to be
+//! fragmented is never acked, nor is a message to a peer which disconnected before acking it, nor
+//! an empty payload, which every datagram would end with. The messages sent with an ack time out
+//! instead, and so do the ones still unacked at their deadline. The tagged payloads are forgotten
+//! at their deadline, so that the ones never acked don't pile up.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::Instant,
+};
+
+use crate::simulation::message::MessageId;
+use super::latency::AckHeader;
+
+/// What the acknowledgment of a payload is reported as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Waiter {
+    // The tag of the message, forgotten at the given deadline
+    Tag(u64, Instant),
+    // The id of the message, which times out at the given deadline
+    Message(MessageId, Instant),
+}
+
+#[derive(Debug)]
+struct TaggedPayload {
+    waiter:    Waiter,
+    payload:   Vec<u8>,
+    sequences: Vec<u16>,
+}
+
+/// Keeps the tagged payloads waiting for an acknowledgment, per peer.
+#[derive(Debug, Default)]
+pub(crate) struct AckTracker {
+    peers:          HashMap<SocketAddr, Vec<TaggedPayload>>,
+    acked:          Vec<(SocketAddr, u64)>,
+    acked_messages: Vec<MessageId>,
+    timed_out:      Vec<MessageId>,
+}
+
+impl AckTracker {
+    /// Waits for the acknowledgment of `payload`, about to be sent to `addr` reliably, until
+    /// `deadline`.
+    pub(crate) fn expect(&mut self, addr: SocketAddr, tag: u64, payload: &[u8], deadline: Instant) {
+        self.wait(addr, Waiter::Tag(tag, deadline), payload);
+    }
+
+    /// Waits for the acknowledgment of `payload`, about to be sent to `addr` reliably, until
+    /// `deadline`.
+    pub(crate) fn expect_message(&mut self, addr: SocketAddr, id: MessageId, payload: &[u8], deadline: Instant) {
+        self.wait(addr, Waiter::Message(id, deadline), payload);
+    }
+
+    /// Times out the message right away, as it won't be acknowledged.
+    pub(crate) fn time_out(&mut self, id: MessageId) {
+        self.timed_out.push(id);
+    }
+
+    fn wait(&mut self, addr: SocketAddr, waiter: Waiter, payload: &[u8]) {
+        if payload.is_empty() {
+            if let Waiter::Message(id, _) = waiter {
+                self.timed_out.push(id);
+            }
+            return;
+        }
+        self.peers.entry(addr).or_default().push(TaggedPayload {
+            waiter,
+            payload:   payload.to_vec(),
+            sequences: Vec::new(),
+        });
+    }
+
+    /// Records the sequence number of a datagram sent to `addr`, if it carries a tagged payload.
+    pub(crate) fn on_send(&mut self, addr: SocketAddr, datagram: &[u8]) {
+        let (Some(header), Some(pending)) = (AckHeader::read(datagram), self.peers.get_mut(&addr)) else {
+            return;
+        };
+        if let Some(tagged) = pending.iter_mut().find(|tagged| datagram.ends_with(&tagged.payload)) {
+            tagged.sequences.push(header.sequence);
+        }
+    }
+
+    /// Processes a datagram received from `addr`, acking the tagged payloads it acknowledges.
+    pub(crate) fn on_recv(&mut self, addr: SocketAddr, datagram: &[u8]) {
+        let (Some(header), Some(pending)) = (AckHeader::read(datagram), self.peers.get_mut(&addr)) else {
+            return;
+        };
+        let sequences: Vec<_> = header.acked().collect();
+        let (acked, acked_messages) = (&mut self.acked, &mut self.acked_messages);
+        pending.retain(|tagged| {
+            let is_acked = tagged.sequences.iter().any(|sequence| sequences.contains(sequence));
+            if is_acked {
+                match tagged.waiter {
+                    Waiter::Tag(tag, _) => acked.push((addr, tag)),
+                    Waiter::Message(id, _) => acked_messages.push(id),
+                }
+            }
+            !is_acked
+        });
+    }
+
+    /// Returns and clears the tags acked since the previous call, with the peer which acked them.
+    pub(crate) fn drain_acked(&mut self) -> Vec<(SocketAddr, u64)> {
+        std::mem::take(&mut self.acked)
+    }
+
+    /// Returns and clears the ids of the messages acked since the previous call, then the ones
+    /// which timed out, as of `now`. The tagged payloads past their deadline are forgotten.
+    pub(crate) fn drain_message_acks(&mut self, now: Instant) -> (Vec<MessageId>, Vec<MessageId>) {
+        let timed_out = &mut self.timed_out;
+        self.peers.retain(|_, pending| {
+            pending.retain(|tagged| match tagged.waiter {
+                Waiter::Message(id, deadline) if deadline <= now => {
+                    timed_out.push(id);
+                    false
+                }
+                Waiter::Tag(_, deadline) => deadline > now,
+                _ => true,
+            });
+            !pending.is_empty()
+        });
+        (std::mem::take(&mut self.acked_messages), std::mem::take(&mut self.timed_out))
+    }
+
+    /// Forgets the payloads waiting for `addr`, the messages sent with an ack time out.
+    pub(crate) fn remove(&mut self, addr: &SocketAddr) {
+        for tagged in self.peers.remove(addr).into_iter().flatten() {
+            if let Waiter::Message(id, _) = tagged.waiter {
+                self.timed_out.push(id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tagged_payloads_are_acked_by_sequence() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut tracker = AckTracker::default();
+        let deadline = Instant::now() + std::time::Duration::from_secs(1);
+        tracker.expect(addr, 1, b"first", deadline);
+        tracker.expect(addr, 2, b"second", deadline);
+
+        tracker.on_send(addr, &reliable(0, 0, 0, b"first"));
+        tracker.on_send(addr, &reliable(1, 0, 0, b"untagged"));
+        // resent under a new sequence number after getting lost
+        tracker.on_send(addr, &reliable(2, 0, 0, b"second"));
+        tracker.on_send(addr, &reliable(3, 0, 0, b"second"));
+
+        tracker.on_recv(addr, &reliable(0, 1, 0b1, b""));
+        assert_eq!(tracker.drain_acked(), vec![(addr, 1)]);
+        tracker.on_recv(addr, &reliable(1, 3, 0b11, b""));
+        assert_eq!(tracker.drain_acked(), vec![(addr, 2)]);
+        tracker.on_recv(addr, &reliable(2, 3, 0b111, b""));
+        assert!(tracker.drain_acked().is_empty());
+    }
+
+    #[test]
+    fn test_message_acks_time_out() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let other = "127.0.0.1:3001".parse().unwrap();
+        let now = Instant::now();
+        let deadline = now + std::time::Duration::from_secs(1);
+        let mut tracker = AckTracker::default();
+        tracker.expect_message(addr, MessageId(0), b"match start", deadline);
+        tracker.expect_message(addr, MessageId(1), b"late", deadline);
+        tracker.expect_message(other, MessageId(2), b"lost peer", deadline);
+        tracker.expect(addr, 3, b"tagged", deadline);
+        tracker.expect(addr, 5, b"", now);
+        tracker.expect_message(addr, MessageId(6), b"", deadline);
+        tracker.time_out(MessageId(4));
+        tracker.on_send(addr, &reliable(0, 0, 0, b"match start"));
+        tracker.on_send(addr, &reliable(1, 0, 0, b"tagged"));
+        tracker.on_recv(addr, &reliable(0, 0, 0b1, b""));
+        tracker.remove(&other);
+
+        // the empty payloads aren't tracked, a heartbeat would ack them
+        assert_eq!(tracker.peers[&addr].len(), 2);
+        assert_eq!(
+            tracker.drain_message_acks(now),
+            (vec![MessageId(0)], vec![MessageId(6), MessageId(4), MessageId(2)])
+        );
+        assert_eq!(tracker.peers[&addr].len(), 2);
+        assert_eq!(tracker.drain_message_acks(deadline), (vec![], vec![MessageId(1)]));
+        // the tag never acked is forgotten along with the message
+        assert!(tracker.peers.is_empty());
+        assert!(tracker.drain_acked().is_empty());
+    }
+
+    fn reliable(sequence: u16, ack_seq: u16, ack_field: u32, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0, 0, 0, 1, 0];
+        datagram.extend_from_slice(&sequence.to_be_bytes());
+        datagram.extend_from_slice(&ack_seq.to_be_bytes());
+        datagram.extend_from_slice(&ack_field.to_be_bytes());
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+}
@@ -0,0 +1,81 @@
+//! Broadcast datagrams, sent next to laminar's connection management which has no meaning for a
+//! destination standing for a whole subnet.
+//!
+//! A broadcast datagram is written straight to the socket with the header of a plain unreliable
+//! laminar packet, so that the laminar sockets receiving it see a regular unreliable message.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// Version string laminar 0.5 puts the checksum of in every header.
+const PROTOCOL_VERSION: &str = "laminar-0.1.0";
+const PROTOCOL_CRC16: u16 = crc16_x25(PROTOCOL_VERSION.as_bytes());
+const PACKET_TYPE_PACKET: u8 = 0;
+const DELIVERY_UNRELIABLE: u8 = 0;
+const ORDERING_NONE: u8 = 0;
+
+/// CRC-16/X-25, the checksum laminar identifies its protocol version with.
+const fn crc16_x25(bytes: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    let mut i = 0;
+    while i < bytes.len() {
+        crc ^= bytes[i] as u16;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0x8408 } else { crc >> 1 };
+            bit += 1;
+        }
+        i += 1;
+    }
+    !crc
+}
+
+/// Returns the datagram carrying `payload` as an unreliable laminar packet.
+pub(crate) fn unreliable_datagram(payload: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(5 + payload.len());
+    datagram.extend_from_slice(&PROTOCOL_CRC16.to_be_bytes());
+    datagram.extend_from_slice(&[PACKET_TYPE_PACKET, DELIVERY_UNRELIABLE, ORDERING_NONE]);
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+/// Returns true if `addr` is the limited broadcast address or one of the given subnet broadcast
+/// addresses.
+pub(crate) fn is_broadcast(addr: &SocketAddr, subnet_broadcasts: &[Ipv4Addr]) -> bool {
+    match addr.ip() {
+        IpAddr::V4(ip) => ip.is_broadcast() || subnet_broadcasts.contains(&ip),
+        IpAddr::V6(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, time::{Duration, Instant}};
+
+    use laminar::Packet;
+
+    use super::*;
+    use crate::simulation::transport::laminar::LaminarSocket;
+
+    #[test]
+    fn test_header_matches_laminar() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut sender = LaminarSocket::bind_any().unwrap();
+
+        sender.send(Packet::unreliable(receiver.local_addr().unwrap(), b"test".to_vec())).unwrap();
+        sender.manual_poll(Instant::now());
+
+        let mut buffer = [0; 64];
+        let (len, _) = receiver.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], unreliable_datagram(b"test").as_slice());
+    }
+
+    #[test]
+    fn test_broadcast_addresses() {
+        let subnet = [Ipv4Addr::new(192, 168, 1, 255)];
+        assert!(is_broadcast(&"255.255.255.255:3000".parse().unwrap(), &subnet));
+        assert!(is_broadcast(&"192.168.1.255:3000".parse().unwrap(), &subnet));
+        assert!(!is_broadcast(&"192.168.2.255:3000".parse().unwrap(), &subnet));
+        assert!(!is_broadcast(&"[ff02::1]:3000".parse().unwrap(), &subnet));
+    }
+}
@@ -0,0 +1,204 @@
+//! Coalescing of the small messages sent in the same frame into fewer laminar packets.
+//!
+//! Every payload is framed with its length, as a LEB128 varint, and the messages of the same
+//! destination and delivery requirement are packed into one payload, as long as it fits in a
+//! single packet or the size set with `CoalescingOptions`. Only compatible deliveries are packed
+//! together, the ones of another requirement go into packets of their own, and so do the ones of
+//! another stream unless `CoalescingOptions::mix_streams` is set. Tagged messages and the ones sent
+//! with an ack are never packed, so that their ack is their own, and the ones sent with
+//! `send_compressed` are only packed with each other. Nothing is negotiated: both peers must enable
+//! coalescing, a peer which doesn't would take the framed payloads for its messages.
+
+use std::{io, net::SocketAddr};
+
+use bytes::{Bytes, BytesMut};
+
+use crate::simulation::{message::Message, requirements::DeliveryRequirement, transport::generic::TransportEvent};
+
+/// Options of the coalescing, see `LaminarSocketResource::set_coalescing_options`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CoalescingOptions {
+    /// Largest payload the messages are packed into, the single packet size of the socket by
+    /// default. A larger one is fragmented by laminar, which only applies to the reliable
+    /// deliveries: the unreliable messages are still packed into single packets.
+    pub max_size:    Option<usize>,
+    /// Whether the messages of the same requirement but of different streams are packed together,
+    /// sequenced or ordered on the stream of the first one. Off by default.
+    pub mix_streams: bool,
+}
+
+/// Returns the delivery requirement of the packet the message is packed into.
+fn packed_delivery(delivery: DeliveryRequirement, mix_streams: bool) -> DeliveryRequirement {
+    match delivery {
+        DeliveryRequirement::UnreliableSequenced(_) if mix_streams => DeliveryRequirement::UnreliableSequenced(None),
+        DeliveryRequirement::ReliableSequenced(_) if mix_streams => DeliveryRequirement::ReliableSequenced(None),
+        DeliveryRequirement::ReliableOrdered(_) if mix_streams => DeliveryRequirement::ReliableOrdered(None),
+        delivery => delivery,
+    }
+}
+
+/// Returns true if the payload the message is packed into must be compressed.
+#[cfg(feature = "compression")]
+fn is_compressed(message: &Message) -> bool {
+    message.compress
+}
+
+#[cfg(not(feature = "compression"))]
+fn is_compressed(_message: &Message) -> bool {
+    false
+}
+
+/// Returns the size of a payload of `len` bytes once framed.
+fn framed_len(len: usize) -> usize {
+    let mut prefix = 1;
+    let mut rest = len >> 7;
+    while rest != 0 {
+        prefix += 1;
+        rest >>= 7;
+    }
+    prefix + len
+}
+
+/// Frames the payloads one after the other.
+pub(crate) fn frame<'a>(payloads: impl IntoIterator<Item = &'a [u8]>) -> Bytes {
+    let mut framed = BytesMut::new();
+    for payload in payloads {
+        let mut len = payload.len();
+        loop {
+            let byte = (len & 0x7f) as u8;
+            len >>= 7;
+            if len == 0 {
+                framed.extend_from_slice(&[byte]);
+                break;
+            }
+            framed.extend_from_slice(&[byte | 0x80]);
+        }
+        framed.extend_from_slice(payload);
+    }
+    framed.freeze()
+}
+
+/// Splits a payload written by `frame` into the payloads it holds.
+pub(crate) fn deframe(mut payload: Bytes) -> io::Result<Vec<Bytes>> {
+    let malformed = |reason| io::Error::new(io::ErrorKind::InvalidData, reason);
+    let mut payloads = Vec::new();
+    while !payload.is_empty() {
+        let mut len = 0usize;
+        let mut prefix = 0;
+        loop {
+            let byte = *payload.get(prefix).ok_or_else(|| malformed("truncated length prefix"))?;
+            if prefix == 4 && byte > 0x0f {
+                return Err(malformed("length prefix is too large"));
+            }
+            len |= usize::from(byte & 0x7f) << (7 * prefix);
+            prefix += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        if payload.len() - prefix < len {
+            return Err(malformed("frame is longer than the payload"));
+        }
+        let rest = payload.split_off(prefix + len);
+        payloads.push(payload.slice(prefix..));
+        payload = rest;
+    }
+    Ok(payloads)
+}
+
+/// Splits the payload of a `Message` event into one event per framed payload. A malformed payload
+/// is dropped and reported as a `RecvError` instead.
+pub(crate) fn deframe_event(event: TransportEvent) -> Vec<TransportEvent> {
+    match event {
+        TransportEvent::Message(addr, payload) => match deframe(payload) {
+            Ok(payloads) => payloads.into_iter().map(|payload| TransportEvent::Message(addr, payload)).collect(),
+            Err(e) => vec![TransportEvent::RecvError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed payload from {}: {}", addr, e),
+            ))],
+        },
+        event => vec![event],
+    }
+}
+
+/// Groups the messages, by their index, into the batches sent as one payload each, in the order of
+/// their first message. A batch takes the messages of the same destination and delivery
+/// requirement as long as their framed payloads fit in the `limit` of its first message.
+pub(crate) fn batches(
+    messages: &[Message],
+    mix_streams: bool,
+    limit: impl Fn(&Message) -> usize,
+) -> Vec<Vec<usize>> {
+    let mut batches: Vec<(Vec<usize>, usize, usize)> = Vec::new();
+    let mut open: Vec<((SocketAddr, DeliveryRequirement, bool), usize)> = Vec::new();
+    for (index, message) in messages.iter().enumerate() {
+        let size = framed_len(message.payload.len());
+        let key = (message.destination, packed_delivery(message.delivery, mix_streams), is_compressed(message));
+        if message.tag.is_none() && message.ack.is_none() {
+            if let Some((_, batch)) = open.iter().find(|(open, _)| *open == key) {
+                let (indices, used, limit) = &mut batches[*batch];
+                if *used + size <= *limit {
+                    indices.push(index);
+                    *used += size;
+                    continue;
+                }
+            }
+            open.retain(|(open, _)| *open != key);
+            open.push((key, batches.len()));
+        }
+        batches.push((vec![index], size, limit(message)));
+    }
+    batches.into_iter().map(|(indices, ..)| indices).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::requirements::UrgencyRequirement;
+
+    #[test]
+    fn test_frame_round_trip() {
+        let long = vec![7; 300];
+        let payloads = [&b""[..], b"input", &long];
+        let framed = frame(payloads);
+        assert_eq!(framed.len(), payloads.iter().map(|payload| framed_len(payload.len())).sum::<usize>());
+        assert_eq!(deframe(framed.clone()).unwrap(), payloads);
+        assert!(deframe(framed.slice(..framed.len() - 1)).is_err());
+        assert!(deframe(Bytes::from_static(&[0x80])).is_err());
+    }
+
+    #[test]
+    fn test_batches_keep_the_deliveries_apart() {
+        let a = "127.0.0.1:3000".parse().unwrap();
+        let b = "127.0.0.1:3001".parse().unwrap();
+        let message = |destination, delivery, len| {
+            Message::new(destination, vec![0; len].into(), delivery, UrgencyRequirement::OnTick)
+        };
+        let mut tagged = message(a, DeliveryRequirement::Reliable, 1);
+        tagged.tag = Some(1);
+        let messages = [
+            message(a, DeliveryRequirement::Reliable, 1),
+            message(b, DeliveryRequirement::Reliable, 1),
+            message(a, DeliveryRequirement::Unreliable, 1),
+            message(a, DeliveryRequirement::Reliable, 1),
+            tagged,
+            message(a, DeliveryRequirement::Reliable, 8),
+            message(a, DeliveryRequirement::Reliable, 1),
+        ];
+        assert_eq!(batches(&messages, false, |_| 10), [vec![0, 3], vec![1], vec![2], vec![4], vec![5], vec![6]]);
+    }
+
+    #[test]
+    fn test_streams_are_only_mixed_when_allowed() {
+        let a = "127.0.0.1:3000".parse().unwrap();
+        let messages: Vec<_> = [Some(1), Some(2), Some(1), None]
+            .into_iter()
+            .map(|stream| {
+                let delivery = DeliveryRequirement::ReliableOrdered(stream);
+                Message::new(a, vec![0].into(), delivery, UrgencyRequirement::OnTick)
+            })
+            .collect();
+        assert_eq!(batches(&messages, false, |_| 100), [vec![0, 2], vec![1], vec![3]]);
+        assert_eq!(batches(&messages, true, |_| 100), [vec![0, 1, 2, 3]]);
+    }
+}
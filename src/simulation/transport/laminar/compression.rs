@@ -0,0 +1,103 @@
+//! Compression of the laminar payloads, only available with the `compression` feature.
+//!
+//! Every payload is prefixed with a byte telling whether it is compressed. Payloads below the
+//! threshold, and the ones deflate doesn't make any smaller, are sent raw. Both peers must have
+//! compression enabled.
+
+use std::io::{self, Read, Write};
+
+use bytes::Bytes;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+use crate::simulation::transport::generic::TransportEvent;
+
+const RAW: u8 = 0;
+const COMPRESSED: u8 = 1;
+/// Largest payload a compressed one may inflate to, so that a tiny packet can't exhaust memory.
+const MAX_DECOMPRESSED_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Prefixes the payload with its header, compressing it if it is at least `threshold` bytes.
+pub(crate) fn encode(payload: &[u8], threshold: usize) -> Bytes {
+    if payload.len() >= threshold {
+        let mut encoder = DeflateEncoder::new(vec![COMPRESSED], Compression::fast());
+        // writing to a `Vec` can't fail
+        if encoder.write_all(payload).is_ok() {
+            if let Ok(compressed) = encoder.finish() {
+                if compressed.len() <= payload.len() {
+                    return Bytes::from(compressed);
+                }
+            }
+        }
+    }
+    let mut raw = Vec::with_capacity(payload.len() + 1);
+    raw.push(RAW);
+    raw.extend_from_slice(payload);
+    Bytes::from(raw)
+}
+
+/// Strips the header of the payload, decompressing it if needed.
+pub(crate) fn decode(payload: Bytes) -> io::Result<Bytes> {
+    match payload.first() {
+        Some(&RAW) => Ok(payload.slice(1..)),
+        Some(&COMPRESSED) => {
+            let mut decoder = DeflateDecoder::new(&payload[1..]).take(MAX_DECOMPRESSED_SIZE + 1);
+            let mut decompressed = Vec::new();
+            decoder.read_to_end(&mut decompressed)?;
+            if decompressed.len() as u64 > MAX_DECOMPRESSED_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "decompressed payload is too large"));
+            }
+            Ok(Bytes::from(decompressed))
+        }
+        Some(header) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown compression header {}", header),
+        )),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "payload has no compression header")),
+    }
+}
+
+/// Decodes the payload of a `Message` event. A malformed payload is dropped and reported as a
+/// `RecvError` instead.
+pub(crate) fn decode_event(event: TransportEvent) -> TransportEvent {
+    match event {
+        TransportEvent::Message(addr, payload) => match decode(payload) {
+            Ok(payload) => TransportEvent::Message(addr, payload),
+            Err(e) => TransportEvent::RecvError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("malformed payload from {}: {}", addr, e),
+            )),
+        },
+        event => event,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let snapshot: Vec<u8> = (0..4096).map(|i| (i / 64) as u8).collect();
+        let compressed = encode(&snapshot, 128);
+        assert_eq!(compressed[0], COMPRESSED);
+        assert!(compressed.len() < snapshot.len() / 4);
+        assert_eq!(decode(compressed).unwrap(), snapshot);
+
+        let small = encode(b"small", 128);
+        assert_eq!(&small[..], b"\0small");
+        assert_eq!(decode(small).unwrap(), &b"small"[..]);
+    }
+
+    #[test]
+    fn test_malformed_payloads_are_errors() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        for payload in [&b""[..], b"\x01not deflate", b"\x07unknown"] {
+            let event = decode_event(TransportEvent::Message(addr, Bytes::copy_from_slice(payload)));
+            assert!(
+                matches!(&event, TransportEvent::RecvError(e) if e.kind() == io::ErrorKind::InvalidData),
+                "{:?}",
+                event,
+            );
+        }
+    }
+}
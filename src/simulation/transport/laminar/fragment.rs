@@ -0,0 +1,244 @@
+//! Fragmentation of the unreliable payloads too large for a single packet, as laminar only
+//! fragments the reliable ones.
+//!
+//! Every unreliable payload is prefixed with a byte telling whether it is whole or a fragment. A
+//! fragment then carries the id of its payload, its index and the number of fragments, and the
+//! payload is reassembled once all of them were received, in whichever order they came. Duplicate
+//! fragments are ignored. A payload still missing fragments once the timeout elapsed is discarded,
+//! and so is the oldest one of a peer reassembling too many at once. The fragments keep the
+//! delivery requirement of their message, so a sequenced payload is lost when its fragments arrive
+//! out of order. Nothing is negotiated: both peers must enable fragmentation.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+
+use crate::simulation::transport::generic::TransportEvent;
+
+const WHOLE: u8 = 0;
+const FRAGMENT: u8 = 1;
+/// Size of the header of a fragment: the byte telling it is one, the id of its payload, its index
+/// and the number of fragments.
+const FRAGMENT_HEADER_SIZE: usize = 5;
+const MAX_FRAGMENTS: usize = u8::MAX as usize;
+
+/// Options of the fragmentation, see `LaminarSocketResource::set_fragmentation`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FragmentationOptions {
+    /// Time after its first fragment was received that an incomplete payload is discarded.
+    pub timeout:               Duration,
+    /// Number of payloads of a single peer reassembled at once, the oldest one is discarded to make
+    /// room for another. Bounds the memory a peer can hold, along with the 255 fragments at most
+    /// a payload is sent in.
+    pub max_payloads_per_peer: usize,
+}
+
+impl Default for FragmentationOptions {
+    fn default() -> Self {
+        Self {
+            timeout:               Duration::from_secs(1),
+            max_payloads_per_peer: 8,
+        }
+    }
+}
+
+/// A payload being reassembled.
+#[derive(Debug)]
+struct Reassembly {
+    id:        u16,
+    started:   Instant,
+    fragments: Vec<Option<Bytes>>,
+    missing:   usize,
+}
+
+/// Fragments the sent payloads and reassembles the received ones.
+#[derive(Debug)]
+pub(crate) struct Fragmenter {
+    options:      FragmentationOptions,
+    next_id:      u16,
+    reassemblies: HashMap<SocketAddr, Vec<Reassembly>>,
+}
+
+impl Fragmenter {
+    pub(crate) fn new(options: FragmentationOptions) -> Self {
+        Self { options, next_id: 0, reassemblies: HashMap::new() }
+    }
+
+    pub(crate) fn options(&self) -> FragmentationOptions {
+        self.options
+    }
+
+    /// Returns the payloads of the packets `payload` is sent in, of `max_size` bytes at most: the
+    /// payload prefixed with its header if it fits, its fragments otherwise.
+    pub(crate) fn split(&mut self, payload: &[u8], max_size: usize) -> io::Result<Vec<Bytes>> {
+        if payload.len() < max_size {
+            let mut whole = BytesMut::with_capacity(payload.len() + 1);
+            whole.extend_from_slice(&[WHOLE]);
+            whole.extend_from_slice(payload);
+            return Ok(vec![whole.freeze()]);
+        }
+        let chunk_size = max_size.saturating_sub(FRAGMENT_HEADER_SIZE).max(1);
+        let count = payload.len().div_ceil(chunk_size);
+        if count > MAX_FRAGMENTS || max_size <= FRAGMENT_HEADER_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "unreliable payload of {} bytes exceeds the maximum of {} bytes it can be fragmented into",
+                    payload.len(),
+                    MAX_FRAGMENTS * chunk_size,
+                ),
+            ));
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let [id_high, id_low] = id.to_be_bytes();
+        Ok(payload
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut fragment = BytesMut::with_capacity(FRAGMENT_HEADER_SIZE + chunk.len());
+                fragment.extend_from_slice(&[FRAGMENT, id_high, id_low, index as u8, count as u8]);
+                fragment.extend_from_slice(chunk);
+                fragment.freeze()
+            })
+            .collect())
+    }
+
+    /// Strips the header of an unreliable payload received from `from`. Returns the payload once it
+    /// is whole, `None` while fragments of it are missing.
+    pub(crate) fn receive(&mut self, from: SocketAddr, payload: Bytes, now: Instant) -> io::Result<Option<Bytes>> {
+        let malformed = |reason| io::Error::new(io::ErrorKind::InvalidData, reason);
+        match payload.first() {
+            Some(&WHOLE) => return Ok(Some(payload.slice(1..))),
+            Some(&FRAGMENT) if payload.len() >= FRAGMENT_HEADER_SIZE => {}
+            Some(&FRAGMENT) => return Err(malformed("truncated fragment header")),
+            Some(_) => return Err(malformed("unknown fragmentation header")),
+            None => return Err(malformed("payload has no fragmentation header")),
+        }
+        let id = u16::from_be_bytes([payload[1], payload[2]]);
+        let index = usize::from(payload[3]);
+        let count = usize::from(payload[4]);
+        if index >= count {
+            return Err(malformed("fragment index is out of range"));
+        }
+        self.expire(now);
+
+        let max_payloads = self.options.max_payloads_per_peer.max(1);
+        let reassemblies = self.reassemblies.entry(from).or_default();
+        let at = match reassemblies.iter().position(|reassembly| reassembly.id == id) {
+            Some(at) if reassemblies[at].fragments.len() == count => at,
+            found => {
+                // a payload of the same id but another count is a stale one, its id reused
+                if let Some(at) = found {
+                    reassemblies.remove(at);
+                }
+                if reassemblies.len() >= max_payloads {
+                    reassemblies.remove(0);
+                }
+                reassemblies.push(Reassembly { id, started: now, fragments: vec![None; count], missing: count });
+                reassemblies.len() - 1
+            }
+        };
+        let reassembly = &mut reassemblies[at];
+        if reassembly.fragments[index].is_none() {
+            reassembly.fragments[index] = Some(payload.slice(FRAGMENT_HEADER_SIZE..));
+            reassembly.missing -= 1;
+        }
+        if reassembly.missing > 0 {
+            return Ok(None);
+        }
+
+        let reassembly = reassemblies.remove(at);
+        if reassemblies.is_empty() {
+            self.reassemblies.remove(&from);
+        }
+        let fragments: Vec<_> = reassembly.fragments.into_iter().flatten().collect();
+        let mut joined = BytesMut::with_capacity(fragments.iter().map(Bytes::len).sum());
+        for fragment in fragments {
+            joined.extend_from_slice(&fragment);
+        }
+        Ok(Some(joined.freeze()))
+    }
+
+    /// Handles the payload of a `Message` event like `receive`, `None` while fragments of it are
+    /// missing. A malformed payload is dropped and reported as a `RecvError` instead.
+    pub(crate) fn receive_event(&mut self, event: TransportEvent, now: Instant) -> Option<TransportEvent> {
+        match event {
+            TransportEvent::Message(addr, payload) => match self.receive(addr, payload, now) {
+                Ok(payload) => payload.map(|payload| TransportEvent::Message(addr, payload)),
+                Err(e) => Some(TransportEvent::RecvError(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("malformed payload from {}: {}", addr, e),
+                ))),
+            },
+            event => Some(event),
+        }
+    }
+
+    /// Discards the payloads still incomplete once the timeout elapsed.
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.options.timeout;
+        self.reassemblies.retain(|_, reassemblies| {
+            reassemblies.retain(|reassembly| now.saturating_duration_since(reassembly.started) < timeout);
+            !reassemblies.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fragments_are_reassembled_in_any_order() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let snapshot: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let mut fragmenter = Fragmenter::new(FragmentationOptions::default());
+        let mut fragments = fragmenter.split(&snapshot, 1000).unwrap();
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|fragment| fragment.len() <= 1000));
+
+        let now = Instant::now();
+        fragments.reverse();
+        let duplicate = fragments[0].clone();
+        let last = fragments.pop().unwrap();
+        for fragment in fragments.into_iter().chain([duplicate]) {
+            assert_eq!(fragmenter.receive(addr, fragment, now).unwrap(), None);
+        }
+        assert_eq!(fragmenter.receive(addr, last, now).unwrap().unwrap(), snapshot);
+        assert!(fragmenter.reassemblies.is_empty());
+
+        let whole = fragmenter.split(b"input", 1000).unwrap();
+        assert_eq!(&whole[0][..], b"\0input");
+        assert_eq!(fragmenter.receive(addr, whole[0].clone(), now).unwrap().unwrap(), &b"input"[..]);
+    }
+
+    #[test]
+    fn test_incomplete_payloads_are_discarded() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let options = FragmentationOptions { timeout: Duration::from_millis(100), max_payloads_per_peer: 2 };
+        let mut fragmenter = Fragmenter::new(options);
+        let now = Instant::now();
+        let mut payloads: Vec<_> = (0..3).map(|_| fragmenter.split(&[7; 30], 20).unwrap()).collect();
+        for fragments in &payloads {
+            assert_eq!(fragmenter.receive(addr, fragments[0].clone(), now).unwrap(), None);
+        }
+        // the first payload made room for the third one
+        assert_eq!(fragmenter.receive(addr, payloads[0].pop().unwrap(), now).unwrap(), None);
+        assert_eq!(fragmenter.reassemblies[&addr].len(), 2);
+
+        let later = now + Duration::from_millis(100);
+        assert_eq!(fragmenter.receive(addr, payloads[2].pop().unwrap(), later).unwrap(), None);
+        assert_eq!(fragmenter.reassemblies[&addr].len(), 1);
+
+        assert!(fragmenter.split(&[0; 20 * 256], 20).is_err());
+        for payload in [&b""[..], b"\x01\0", b"\x01\0\0\x02\x02", b"\x07"] {
+            assert!(fragmenter.receive(addr, Bytes::copy_from_slice(payload), now).is_err(), "{:?}", payload);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Round-trip time and packet loss estimation from the acknowledgment headers laminar puts on
+//! reliable packets.
+//!
+//! Laminar measures RTT in its congestion handler but keeps it private, so the same information is
+//! recovered here by looking at the raw datagrams: the sequence number of every outgoing reliable
+//! packet is remembered together with the time it was sent, and acked sequence numbers of incoming
+//! reliable packets yield the round-trip samples.
+//!
+//! A packet which leaves the reach of the ack bitfield without being acked is one laminar resends,
+//! it counts as lost. The loss is the share of the lost packets among the last `LOSS_WINDOW`
+//! packets whose fate is known. Only the reliable packets are measured, and the acks only come back
+//! on the reliable packets of the peer, so both ends must send some for the estimates to move.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+const STANDARD_HEADER_SIZE: usize = 5;
+const FRAGMENT_HEADER_SIZE: usize = 4;
+const ACKED_PACKET_HEADER_SIZE: usize = 8;
+const PACKET_TYPE_PACKET: u8 = 0;
+const PACKET_TYPE_FRAGMENT: u8 = 1;
+const DELIVERY_RELIABLE: u8 = 1;
+/// Number of packets acknowledged by the ack bitfield, in addition to the acked sequence itself.
+const REDUNDANT_ACKS: u16 = 32;
+/// Weight of a new sample in the smoothed estimate, the same as TCP's SRTT.
+const SMOOTHING_FACTOR: f64 = 0.125;
+/// Number of the latest acked or lost packets the loss is computed over.
+const LOSS_WINDOW: usize = 100;
+
+/// Acknowledgment header of a reliable laminar packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct AckHeader {
+    pub(super) sequence:  u16,
+    pub(super) ack_seq:   u16,
+    pub(super) ack_field: u32,
+}
+
+impl AckHeader {
+    /// Reads the acknowledgment header from a raw laminar datagram. Returns `None` for unreliable
+    /// packets, heartbeats and fragments other than the first one, which don't carry one.
+    pub(super) fn read(datagram: &[u8]) -> Option<Self> {
+        let header = datagram.get(..STANDARD_HEADER_SIZE)?;
+        if header[3] != DELIVERY_RELIABLE {
+            return None;
+        }
+        let offset = match header[2] {
+            PACKET_TYPE_PACKET => STANDARD_HEADER_SIZE,
+            PACKET_TYPE_FRAGMENT => {
+                let fragment = datagram.get(STANDARD_HEADER_SIZE..STANDARD_HEADER_SIZE + FRAGMENT_HEADER_SIZE)?;
+                if fragment[2] != 0 {
+                    return None;
+                }
+                STANDARD_HEADER_SIZE + FRAGMENT_HEADER_SIZE
+            }
+            _ => return None,
+        };
+        let ack = datagram.get(offset..offset + ACKED_PACKET_HEADER_SIZE)?;
+        Some(AckHeader {
+            sequence:  u16::from_be_bytes([ack[0], ack[1]]),
+            ack_seq:   u16::from_be_bytes([ack[2], ack[3]]),
+            ack_field: u32::from_be_bytes([ack[4], ack[5], ack[6], ack[7]]),
+        })
+    }
+
+    /// Sequence numbers acknowledged by this header.
+    pub(super) fn acked(&self) -> impl Iterator<Item = u16> + '_ {
+        std::iter::once(self.ack_seq).chain(
+            (1..=REDUNDANT_ACKS)
+                .filter(|i| self.ack_field & (1 << (i - 1)) != 0)
+                .map(|i| self.ack_seq.wrapping_sub(i)),
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct PeerLatency {
+    in_flight: HashMap<u16, Instant>,
+    rtt:       Option<Duration>,
+    // true for a lost packet, false for an acked one
+    outcomes:  VecDeque<bool>,
+}
+
+impl PeerLatency {
+    fn record(&mut self, lost: bool) {
+        if self.outcomes.len() == LOSS_WINDOW {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(lost);
+    }
+}
+
+/// Keeps a smoothed RTT estimate and a rolling packet loss estimate per peer.
+#[derive(Debug, Default)]
+pub(crate) struct LatencyTracker {
+    peers:   HashMap<SocketAddr, PeerLatency>,
+    updated: Vec<SocketAddr>,
+}
+
+impl LatencyTracker {
+    /// Records a datagram sent to `addr` at `time`.
+    pub(crate) fn on_send(&mut self, addr: SocketAddr, datagram: &[u8], time: Instant) {
+        if let Some(header) = AckHeader::read(datagram) {
+            self.peers
+                .entry(addr)
+                .or_default()
+                .in_flight
+                .entry(header.sequence)
+                .or_insert(time);
+        }
+    }
+
+    /// Processes a datagram received from `addr` at `time`, updating the estimate of that peer
+    /// if it acknowledges a packet still in flight.
+    pub(crate) fn on_recv(&mut self, addr: SocketAddr, datagram: &[u8], time: Instant) {
+        let (header, peer) = match (AckHeader::read(datagram), self.peers.get_mut(&addr)) {
+            (Some(header), Some(peer)) => (header, peer),
+            _ => return,
+        };
+
+        let samples: Vec<_> = header
+            .acked()
+            .filter_map(|sequence| peer.in_flight.remove(&sequence))
+            .map(|sent| time.saturating_duration_since(sent))
+            .collect();
+        for _ in &samples {
+            peer.record(false);
+        }
+        let sample = samples.into_iter().min();
+        // Whatever is older than the bitfield reaches won't be acked anymore, laminar resends it
+        // under a new sequence number.
+        let in_flight = peer.in_flight.len();
+        peer.in_flight.retain(|&sequence, _| {
+            let age = header.ack_seq.wrapping_sub(sequence);
+            age > u16::MAX / 2 || age <= REDUNDANT_ACKS
+        });
+        for _ in peer.in_flight.len()..in_flight {
+            peer.record(true);
+        }
+
+        if let Some(sample) = sample {
+            peer.rtt = Some(match peer.rtt {
+                Some(rtt) => rtt.mul_f64(1.0 - SMOOTHING_FACTOR) + sample.mul_f64(SMOOTHING_FACTOR),
+                None => sample,
+            });
+            if !self.updated.contains(&addr) {
+                self.updated.push(addr);
+            }
+        }
+    }
+
+    /// Returns the current estimate for `addr`, `None` until a packet has done a round trip.
+    pub(crate) fn rtt(&self, addr: &SocketAddr) -> Option<Duration> {
+        self.peers.get(addr).and_then(|peer| peer.rtt)
+    }
+
+    /// Returns the share, between 0 and 1, of the packets to `addr` lost among the latest ones,
+    /// `None` until a packet has been acked or lost.
+    pub(crate) fn packet_loss(&self, addr: &SocketAddr) -> Option<f32> {
+        let outcomes = &self.peers.get(addr)?.outcomes;
+        if outcomes.is_empty() {
+            return None;
+        }
+        let lost = outcomes.iter().filter(|lost| **lost).count();
+        Some(lost as f32 / outcomes.len() as f32)
+    }
+
+    /// Returns the peers estimates are kept for.
+    pub(crate) fn peers(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.peers.keys()
+    }
+
+    /// Returns the estimates which changed since the previous call.
+    pub(crate) fn drain_updates(&mut self) -> Vec<(SocketAddr, Duration)> {
+        let peers = &self.peers;
+        self.updated
+            .drain(..)
+            .filter_map(|addr| peers.get(&addr).and_then(|peer| peer.rtt).map(|rtt| (addr, rtt)))
+            .collect()
+    }
+
+    /// Forgets everything about `addr`.
+    pub(crate) fn remove(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+        self.updated.retain(|updated| updated != addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_smoothed_rtt_from_acks() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let start = Instant::now();
+        let mut tracker = LatencyTracker::default();
+
+        tracker.on_send(addr, &reliable(0, 0, 0), start);
+        tracker.on_send(addr, &reliable(1, 0, 0), start);
+        assert_eq!(tracker.rtt(&addr), None);
+
+        // acks 1, and 0 through the bitfield
+        tracker.on_recv(addr, &reliable(0, 1, 0b1), start + Duration::from_millis(80));
+        assert_eq!(tracker.rtt(&addr), Some(Duration::from_millis(80)));
+        assert_eq!(tracker.drain_updates(), vec![(addr, Duration::from_millis(80))]);
+
+        tracker.on_send(addr, &reliable(2, 0, 0), start + Duration::from_millis(100));
+        tracker.on_recv(addr, &reliable(1, 2, 0b11), start + Duration::from_millis(260));
+        assert_eq!(tracker.rtt(&addr), Some(Duration::from_millis(90)));
+
+        // nothing new acked, no update
+        tracker.drain_updates();
+        tracker.on_recv(addr, &reliable(2, 2, 0b11), start + Duration::from_millis(300));
+        assert!(tracker.drain_updates().is_empty());
+    }
+
+    #[test]
+    fn test_packets_out_of_the_ack_window_are_lost() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let start = Instant::now();
+        let mut tracker = LatencyTracker::default();
+        for sequence in 0..=40 {
+            tracker.on_send(addr, &reliable(sequence, 0, 0), start);
+        }
+        // acks 1 through 3, 0 is still within the reach of the bitfield
+        tracker.on_recv(addr, &reliable(0, 3, 0b11), start);
+        assert_eq!(tracker.packet_loss(&addr), Some(0.0));
+
+        // acks 8 through 40, leaving 0 and 4 through 7 behind
+        tracker.on_recv(addr, &reliable(1, 40, u32::MAX), start);
+        assert_eq!(tracker.packet_loss(&addr), Some(5.0 / 41.0));
+    }
+
+    #[test]
+    fn test_unreliable_packets_are_ignored() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let start = Instant::now();
+        let mut tracker = LatencyTracker::default();
+        let mut unreliable = reliable(0, 0, 0);
+        unreliable[3] = 0;
+
+        tracker.on_send(addr, &unreliable, start);
+        tracker.on_recv(addr, &reliable(0, 0, 0), start + Duration::from_millis(10));
+        assert_eq!(tracker.rtt(&addr), None);
+        assert_eq!(tracker.packet_loss(&addr), None);
+    }
+
+    fn reliable(sequence: u16, ack_seq: u16, ack_field: u32) -> Vec<u8> {
+        let mut datagram = vec![0, 0, PACKET_TYPE_PACKET, DELIVERY_RELIABLE, 0];
+        datagram.extend_from_slice(&sequence.to_be_bytes());
+        datagram.extend_from_slice(&ack_seq.to_be_bytes());
+        datagram.extend_from_slice(&ack_field.to_be_bytes());
+        datagram.extend_from_slice(b"payload");
+        datagram
+    }
+}
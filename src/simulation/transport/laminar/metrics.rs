@@ -0,0 +1,57 @@
+//! Per peer estimates of the connection quality, e.g. to adapt the rate or the detail of the
+//! snapshots. See the `latency` module on how they are computed.
+
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+#[cfg(feature = "bevy")]
+use bevy::prelude::Resource;
+
+/// Estimates of the connection to a single peer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PeerMetrics {
+    /// Smoothed round-trip time, `None` until a reliable packet has been acknowledged.
+    pub rtt:         Option<Duration>,
+    /// Share, between 0 and 1, of the last 100 reliable packets sent to the peer which were acked
+    /// or lost that got lost, `None` until one was either.
+    pub packet_loss: Option<f32>,
+}
+
+/// Resource holding the estimates of every peer laminar sent a reliable packet to, refreshed by
+/// `laminar_network_recv_system`. A peer is forgotten once it disconnects.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "bevy", derive(Resource))]
+pub struct ConnectionMetrics {
+    peers: HashMap<SocketAddr, PeerMetrics>,
+}
+
+impl ConnectionMetrics {
+    /// Returns the estimates of `addr`, if any.
+    #[must_use]
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PeerMetrics> {
+        self.peers.get(addr)
+    }
+
+    /// Returns the estimated packet loss to `addr`, see `PeerMetrics::packet_loss`.
+    #[must_use]
+    pub fn packet_loss(&self, addr: &SocketAddr) -> Option<f32> {
+        self.peers.get(addr).and_then(|metrics| metrics.packet_loss)
+    }
+
+    /// Returns the estimated round-trip time to `addr`, see `PeerMetrics::rtt`.
+    #[must_use]
+    pub fn rtt(&self, addr: &SocketAddr) -> Option<Duration> {
+        self.peers.get(addr).and_then(|metrics| metrics.rtt)
+    }
+
+    /// Returns the estimates of every peer.
+    pub fn iter(&self) -> impl Iterator<Item = (&SocketAddr, &PeerMetrics)> {
+        self.peers.iter()
+    }
+
+    /// Replaces the estimates with the ones given.
+    #[cfg(feature = "bevy")]
+    pub(crate) fn refresh(&mut self, peers: impl IntoIterator<Item = (SocketAddr, PeerMetrics)>) {
+        self.peers.clear();
+        self.peers.extend(peers);
+    }
+}
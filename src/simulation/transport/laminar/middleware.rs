@@ -0,0 +1,79 @@
+//! Middleware seeing, and possibly transforming, every laminar payload: right before it is
+//! handed to laminar when sending, and right after laminar delivers it when receiving.
+//!
+//! The middleware run in the order they were added when sending, and in the reverse order when
+//! receiving, after the compression and before the decompression respectively. They see the
+//! unreliable payloads whole, before the fragmentation and after the reassembly. Packets laminar
+//! sends on its own, e.g. heartbeats, don't go through them.
+
+use std::{io, net::SocketAddr};
+
+use bytes::{Bytes, BytesMut};
+
+use crate::simulation::transport::generic::TransportEvent;
+
+/// Error of a `PacketMiddleware`, the packet it was given is dropped.
+#[derive(Debug)]
+pub enum MiddlewareError {
+    // The packet is dropped silently, e.g. filtered out
+    Vetoed,
+    // The packet is dropped, and reported as a `SendError` or a `RecvError`
+    Failed(io::Error),
+}
+
+impl From<io::Error> for MiddlewareError {
+    fn from(e: io::Error) -> Self {
+        MiddlewareError::Failed(e)
+    }
+}
+
+/// Hook on the payloads of the laminar transport, see `LaminarPlugin::with_middleware`.
+pub trait PacketMiddleware: Send + Sync {
+    /// Called with the payload about to be sent to `destination`.
+    fn on_send(&mut self, _destination: SocketAddr, _payload: &mut BytesMut) -> Result<(), MiddlewareError> {
+        Ok(())
+    }
+
+    /// Called with the payload received from `source`.
+    fn on_recv(&mut self, _source: SocketAddr, _payload: &mut BytesMut) -> Result<(), MiddlewareError> {
+        Ok(())
+    }
+}
+
+/// Runs the payload through the middleware in order, returning `None` if one vetoed it.
+pub(crate) fn on_send(
+    middleware: &mut [Box<dyn PacketMiddleware>],
+    destination: SocketAddr,
+    payload: Bytes,
+) -> io::Result<Option<Bytes>> {
+    if middleware.is_empty() {
+        return Ok(Some(payload));
+    }
+    let mut payload = BytesMut::from(&payload[..]);
+    for middleware in middleware {
+        match middleware.on_send(destination, &mut payload) {
+            Ok(()) => {}
+            Err(MiddlewareError::Vetoed) => return Ok(None),
+            Err(MiddlewareError::Failed(e)) => return Err(e),
+        }
+    }
+    Ok(Some(payload.freeze()))
+}
+
+/// Runs the payload of a `Message` event through the middleware in reverse order. A vetoed payload
+/// is dropped, a failed one is reported as a `RecvError` instead.
+pub(crate) fn on_recv(middleware: &mut [Box<dyn PacketMiddleware>], event: TransportEvent) -> Option<TransportEvent> {
+    let (source, payload) = match event {
+        TransportEvent::Message(source, payload) if !middleware.is_empty() => (source, payload),
+        event => return Some(event),
+    };
+    let mut payload = BytesMut::from(&payload[..]);
+    for middleware in middleware.iter_mut().rev() {
+        match middleware.on_recv(source, &mut payload) {
+            Ok(()) => {}
+            Err(MiddlewareError::Vetoed) => return None,
+            Err(MiddlewareError::Failed(e)) => return Some(TransportEvent::RecvError(e)),
+        }
+    }
+    Some(TransportEvent::Message(source, payload.freeze()))
+}
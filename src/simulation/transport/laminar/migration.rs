@@ -0,0 +1,400 @@
+//! Host migration for peer hosted sessions: the host shares an ordered list of candidates with its
+//! peers, and when it goes away the first candidate left takes over as the host, the other peers
+//! connecting to it.
+//!
+//! The list goes through the laminar socket as reliable packets, which are set aside before laminar
+//! would emit them as `Message` events. It holds the addresses the host sees the candidates from,
+//! so those must be reachable from every peer, e.g. on a LAN or through a relay.
+//!
+//! The host is lost once laminar disconnects it, or once nothing came from it for the host timeout.
+//! Laminar only times a connection out after its `idle_connection_timeout`, 5 seconds by default,
+//! so the host timeout is what starts the migration promptly. Laminar's `heartbeat_interval` must
+//! be well below it, or a quiet host is taken for a lost one.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bevy::app::App;
+use bevy::prelude::{EventReader, EventWriter, Plugin, ResMut, Resource, SystemLabel, SystemSet};
+
+use crate::simulation::{
+    events::NetworkSimulationEvent,
+    transport::{
+        laminar::{LaminarSocketResource, Packet},
+        TransportResource,
+    },
+};
+use super::{
+    relay::{read_addr, write_addr},
+    socket::MIGRATION_PREFIX,
+};
+
+/// The candidates, sent by the host to each of them.
+const LIST: u8 = 1;
+/// Sent by a peer to its new host, so that laminar connects them.
+const HELLO: u8 = 2;
+const DEFAULT_HOST_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct HostMigrationLabel;
+
+/// Events reporting the changes of host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MigrationEvent {
+    // The given peer took over as the host, it is the local one if `HostMigration::is_host`
+    HostMigrated(SocketAddr),
+    // The given host was lost, and no candidate was left to take over
+    HostLost(SocketAddr),
+}
+
+/// What happens to the messages queued for the old host when it is replaced.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueuedMessages {
+    // They are sent to the new host instead, or dropped if the local peer is the new host
+    #[default]
+    Readdress,
+    // They are dropped
+    Drop,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Alone,
+    Host,
+    Client(SocketAddr),
+}
+
+/// Resource through which the local peer hosts or joins a session, and which tracks its host.
+#[derive(Debug, Resource)]
+pub struct HostMigration {
+    role:            Role,
+    candidates:      Vec<SocketAddr>,
+    own_addr:        Option<SocketAddr>,
+    last_heard:      Instant,
+    list_changed:    bool,
+    hello_pending:   bool,
+    host_timeout:    Duration,
+    queued_messages: QueuedMessages,
+}
+
+impl Default for HostMigration {
+    fn default() -> Self {
+        Self {
+            role:            Role::Alone,
+            candidates:      Vec::new(),
+            own_addr:        None,
+            last_heard:      Instant::now(),
+            list_changed:    false,
+            hello_pending:   false,
+            host_timeout:    DEFAULT_HOST_TIMEOUT,
+            queued_messages: QueuedMessages::default(),
+        }
+    }
+}
+
+impl HostMigration {
+    /// Makes the local peer the host. The peers connecting to it become candidates, in the order
+    /// they connect.
+    pub fn become_host(&mut self) {
+        self.role = Role::Host;
+        self.list_changed = true;
+    }
+
+    /// Joins the session hosted at `host`.
+    pub fn join(&mut self, host: SocketAddr) {
+        self.role = Role::Client(host);
+        self.last_heard = Instant::now();
+        self.hello_pending = true;
+    }
+
+    /// On the host, replaces the candidates with the given ones, in the order they take over.
+    pub fn set_candidates(&mut self, candidates: Vec<SocketAddr>) {
+        self.candidates = candidates;
+        self.list_changed = true;
+    }
+
+    /// Returns the candidates, in the order they take over. On a peer which isn't the host, they
+    /// are the ones the host shared, and may include the local peer.
+    #[must_use]
+    pub fn candidates(&self) -> &[SocketAddr] {
+        &self.candidates
+    }
+
+    /// Returns the address of the host, if the local peer joined one. The host itself only knows
+    /// its address if it took over from another one.
+    #[must_use]
+    pub fn host_addr(&self) -> Option<SocketAddr> {
+        match self.role {
+            Role::Alone => None,
+            Role::Host => self.own_addr,
+            Role::Client(host) => Some(host),
+        }
+    }
+
+    /// Returns true if the local peer is the host.
+    #[must_use]
+    pub fn is_host(&self) -> bool {
+        self.role == Role::Host
+    }
+
+    /// Returns how long the host may stay silent before it is considered lost.
+    #[must_use]
+    pub fn host_timeout(&self) -> Duration {
+        self.host_timeout
+    }
+
+    /// Sets how long the host may stay silent before it is considered lost, 1 second by default.
+    pub fn set_host_timeout(&mut self, timeout: Duration) {
+        self.host_timeout = timeout;
+    }
+
+    /// Sets what happens to the messages queued for the old host when it is replaced.
+    pub fn set_queued_messages(&mut self, policy: QueuedMessages) {
+        self.queued_messages = policy;
+    }
+
+    fn add_candidate(&mut self, addr: SocketAddr) {
+        if !self.candidates.contains(&addr) {
+            self.candidates.push(addr);
+            self.list_changed = true;
+        }
+    }
+
+    /// Replaces the lost host by the first candidate left.
+    fn elect(&mut self,
+             lost:      SocketAddr,
+             transport: &mut TransportResource,
+             events:    &mut EventWriter<MigrationEvent>) {
+        self.candidates.retain(|candidate| *candidate != lost);
+        let Some(new_host) = self.candidates.first().copied() else {
+            self.role = Role::Alone;
+            transport.drain_messages(|message| message.destination == lost);
+            events.send(MigrationEvent::HostLost(lost));
+            return;
+        };
+        if Some(new_host) == self.own_addr {
+            self.candidates.remove(0);
+            self.become_host();
+        } else {
+            self.join(new_host);
+        }
+        self.requeue(lost, new_host, transport);
+        events.send(MigrationEvent::HostMigrated(new_host));
+    }
+
+    fn requeue(&self, old_host: SocketAddr, new_host: SocketAddr, transport: &mut TransportResource) {
+        if self.queued_messages == QueuedMessages::Readdress && !self.is_host() {
+            transport.readdress(old_host, new_host);
+        } else {
+            transport.drain_messages(|message| message.destination == old_host);
+        }
+    }
+}
+
+/// Use this plugin next to the `LaminarPlugin` to let the peers of a session carry on when its host
+/// goes away. See `HostMigration`.
+pub struct HostMigrationPlugin;
+
+impl Plugin for HostMigrationPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_event::<MigrationEvent>()
+            .add_event::<NetworkSimulationEvent>()
+            .init_resource::<HostMigration>()
+            .init_resource::<TransportResource>()
+            .add_system_set(SystemSet::new()
+                .label(HostMigrationLabel)
+                .with_system(host_migration_system)
+            );
+    }
+}
+
+/// Creates a new host migration system, sharing the candidates from the host and electing a new
+/// host when it is lost.
+pub fn host_migration_system(mut migration:        ResMut<HostMigration>,
+                             mut socket:           ResMut<LaminarSocketResource>,
+                             mut transport:        ResMut<TransportResource>,
+                             mut network_events:   EventReader<NetworkSimulationEvent>,
+                             mut migration_events: EventWriter<MigrationEvent>) {
+    let now = Instant::now();
+    let migration = &mut *migration;
+    let mut lost = None;
+
+    for event in network_events.iter() {
+        match (event, migration.role) {
+            (NetworkSimulationEvent::Connect(addr), Role::Host) => migration.add_candidate(*addr),
+            (NetworkSimulationEvent::Disconnect(addr, _), Role::Host) if migration.candidates.contains(addr) => {
+                migration.candidates.retain(|candidate| candidate != addr);
+                migration.list_changed = true;
+            }
+            (NetworkSimulationEvent::Disconnect(addr, _), Role::Client(host)) if *addr == host => lost = Some(host),
+            (NetworkSimulationEvent::Connect(addr)
+                | NetworkSimulationEvent::Message(addr, _)
+                | NetworkSimulationEvent::Heartbeat(addr), Role::Client(host)) if *addr == host => {
+                migration.last_heard = now;
+            }
+            _ => {}
+        }
+    }
+
+    let messages: Vec<_> = socket
+        .sockets_mut()
+        .iter_mut()
+        .flat_map(|socket| socket.drain_migration_messages())
+        .collect();
+    for (from, message) in messages {
+        let Role::Client(host) = migration.role else {
+            if migration.role == Role::Host && message.first() == Some(&HELLO) {
+                migration.add_candidate(from);
+            }
+            continue;
+        };
+        if let Some((own_addr, candidates)) = message.strip_prefix(&[LIST]).and_then(parse_list) {
+            // a peer which noticed the loss of the host first may already have taken over
+            if from != host {
+                migration.role = Role::Client(from);
+                migration.requeue(host, from, &mut transport);
+                migration_events.send(MigrationEvent::HostMigrated(from));
+            }
+            migration.own_addr = Some(own_addr);
+            migration.candidates = candidates;
+        }
+        if migration.role == Role::Client(from) {
+            migration.last_heard = now;
+        }
+    }
+
+    if let Role::Client(host) = migration.role {
+        if lost.is_none() && now.duration_since(migration.last_heard) >= migration.host_timeout {
+            lost = Some(host);
+        }
+    }
+    if let Some(host) = lost {
+        migration.elect(host, &mut transport, &mut migration_events);
+    }
+
+    match migration.role {
+        Role::Client(host) if migration.hello_pending => {
+            migration.hello_pending = false;
+            send(&mut socket, host, vec![HELLO]);
+        }
+        Role::Host if migration.list_changed => {
+            migration.list_changed = false;
+            for candidate in &migration.candidates {
+                send(&mut socket, *candidate, list(*candidate, &migration.candidates));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Sends a migration message reliably, behind the back of the `TransportResource`.
+fn send(socket: &mut LaminarSocketResource, destination: SocketAddr, message: Vec<u8>) {
+    if let Some(socket) = socket.get_for_destination_mut(&destination) {
+        let mut payload = MIGRATION_PREFIX.to_vec();
+        payload.extend(message);
+        // a failed send ends up as a lost host, or a candidate which never connects
+        let _ = socket.send(Packet::reliable_ordered(destination, payload, None));
+    }
+}
+
+/// Writes the list of candidates sent to `recipient`, which starts with its own address.
+fn list(recipient: SocketAddr, candidates: &[SocketAddr]) -> Vec<u8> {
+    let mut message = vec![LIST];
+    write_addr(Some(recipient), &mut message);
+    message.extend_from_slice(&(candidates.len() as u16).to_be_bytes());
+    for candidate in candidates {
+        write_addr(Some(*candidate), &mut message);
+    }
+    message
+}
+
+/// Reads a list written by `list`, without its kind.
+fn parse_list(mut bytes: &[u8]) -> Option<(SocketAddr, Vec<SocketAddr>)> {
+    let read = |bytes: &mut &[u8]| {
+        let (addr, size) = read_addr(bytes)?;
+        *bytes = &bytes[size..];
+        addr
+    };
+    let own_addr = read(&mut bytes)?;
+    let count = u16::from_be_bytes(bytes.get(..2)?.try_into().ok()?);
+    bytes = &bytes[2..];
+    let candidates = (0..count).map(|_| read(&mut bytes)).collect::<Option<_>>()?;
+    Some((own_addr, candidates))
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::event::Events;
+
+    use super::*;
+    use crate::simulation::transport::laminar::{LaminarConfig, LaminarPlugin};
+
+    #[test]
+    fn test_clients_elect_the_first_candidate() {
+        let mut apps = [create_test_app(), create_test_app(), create_test_app()];
+        let [host, a, b] = apps.each_ref().map(|app| {
+            app.world.resource::<LaminarSocketResource>().get().unwrap().local_addr().unwrap()
+        });
+        apps[0].world.resource_mut::<HostMigration>().become_host();
+        for app in &mut apps[1..] {
+            let mut migration = app.world.resource_mut::<HostMigration>();
+            migration.join(host);
+            migration.set_host_timeout(Duration::from_millis(300));
+        }
+
+        update_until(&mut apps, |apps| apps[0].world.resource::<HostMigration>().candidates().len() == 2);
+        apps[0].world.resource_mut::<HostMigration>().set_candidates(vec![a, b]);
+        update_until(&mut apps, |apps| {
+            apps[1..].iter().all(|app| app.world.resource::<HostMigration>().candidates() == [a, b])
+        });
+
+        let [_, mut a_app, mut b_app] = apps;
+        let mut migrated = [false, false];
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while migrated.contains(&false) && Instant::now() < deadline {
+            for (app, migrated) in [&mut a_app, &mut b_app].into_iter().zip(migrated.iter_mut()) {
+                app.update();
+                let events = app.world.resource::<Events<MigrationEvent>>();
+                let event = events.get_reader().iter(events).next().cloned();
+                assert!(event.is_none() || event == Some(MigrationEvent::HostMigrated(a)), "{:?}", event);
+                *migrated |= event.is_some();
+            }
+        }
+
+        assert_eq!(migrated, [true, true]);
+        assert!(a_app.world.resource::<HostMigration>().is_host());
+        assert_eq!(a_app.world.resource::<HostMigration>().candidates(), [b]);
+        assert_eq!(b_app.world.resource::<HostMigration>().host_addr(), Some(a));
+    }
+
+    #[test]
+    fn test_list_round_trip() {
+        let recipient = "127.0.0.1:3000".parse().unwrap();
+        let candidates = ["127.0.0.1:3000".parse().unwrap(), "[::1]:3001".parse().unwrap()];
+        let message = list(recipient, &candidates);
+        assert_eq!(parse_list(&message[1..]), Some((recipient, candidates.to_vec())));
+        assert_eq!(parse_list(&message[1..message.len() - 1]), None);
+    }
+
+    fn update_until(apps: &mut [App], condition: impl Fn(&[App]) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !condition(apps) && Instant::now() < deadline {
+            for app in apps.iter_mut() {
+                app.update();
+            }
+        }
+        assert!(condition(apps), "timed out");
+    }
+
+    fn create_test_app() -> App {
+        let config = LaminarConfig { heartbeat_interval: Some(Duration::from_millis(50)), ..LaminarConfig::default() };
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), config))
+            .add_plugin(HostMigrationPlugin);
+        app
+    }
+}
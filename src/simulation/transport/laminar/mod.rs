@@ -0,0 +1,3372 @@
+//! Network systems implementation backed by the Laminar network protocol.
+
+mod acks;
+mod broadcast;
+mod coalesce;
+#[cfg(feature = "compression")]
+mod compression;
+mod fragment;
+mod latency;
+mod metrics;
+mod middleware;
+#[cfg(feature = "bevy")]
+mod migration;
+#[cfg(feature = "bevy")]
+mod nat;
+mod options;
+#[cfg(feature = "bevy")]
+mod reconnect;
+mod relay;
+mod socket;
+
+use std::{io, time::{Duration, Instant}};
+#[cfg(feature = "bevy")]
+use std::{collections::HashMap, sync::Mutex};
+
+use bytes::Bytes;
+pub use laminar::{Config as LaminarConfig, ErrorKind, Packet, SocketEvent};
+use laminar::{DeliveryGuarantee, OrderingGuarantee};
+pub use coalesce::CoalescingOptions;
+pub use fragment::FragmentationOptions;
+use fragment::Fragmenter;
+pub use metrics::{ConnectionMetrics, PeerMetrics};
+pub use middleware::{MiddlewareError, PacketMiddleware};
+#[cfg(feature = "bevy")]
+pub use migration::{HostMigration, HostMigrationLabel, HostMigrationPlugin, MigrationEvent, QueuedMessages};
+#[cfg(feature = "bevy")]
+pub use nat::{NatEvent, NatTraversal, NatTraversalLabel, NatTraversalPlugin, PublicAddress};
+pub use options::SocketOptions;
+#[cfg(feature = "bevy")]
+pub use reconnect::{Reconnect, ReconnectConfig, ReconnectEvent, ReconnectLabel, ReconnectPlugin};
+pub use relay::{RelayConfig, RelayServer};
+pub use socket::{LaminarSocket, SocketError};
+#[cfg(feature = "bevy")]
+use bevy::log::{info, error};
+
+use crate::simulation::{
+    events::{DisconnectReason, NetworkSimulationEvent},
+    message::Message,
+    requirements::DeliveryRequirement,
+    transport::{
+        generic::{Transport, TransportEvent},
+        routing::{expired_message_event, rejected_message_event, TransportId},
+        TransportResource,
+    },
+};
+#[cfg(feature = "bevy")]
+use crate::simulation::{
+    events::TaggedNetworkEvent,
+    peers::{ConnectedPeers, connected_peers_system},
+    timing::{NetworkSimulationTime, network_simulation_time_system},
+    transport::{
+        routing::{NetworkEventWriter, unroutable_messages_system},
+        Disposition, NetworkRole,
+    },
+};
+#[cfg(feature = "diagnostics")]
+use crate::simulation::diagnostics::NetworkStats;
+#[cfg(feature = "bevy")]
+use bevy::prelude::{Plugin, Resource, Res, ResMut, EventReader, EventWriter, SystemSet, SystemLabel, Time};
+#[cfg(feature = "bevy")]
+use bevy::app::{App, AppExit, CoreStage};
+#[cfg(feature = "bevy")]
+use bevy::ecs::schedule::{RunCriteriaDescriptor, State, StateData};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+
+/// Stream laminar sequences and orders the packets sent without a stream id on.
+const DEFAULT_STREAM: u8 = 255;
+const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[cfg(feature = "bevy")]
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct LaminarLabel;
+
+#[cfg(feature = "bevy")]
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct LaminarEndpointsLabel;
+
+/// Use this plugin to add the laminar transport layer to your game.
+#[cfg(feature = "bevy")]
+pub struct LaminarPlugin {
+    binding:   Binding,
+    config:    LaminarConfig,
+    broadcast: Broadcast,
+    relay:     Option<RelayConfig>,
+    name:      Option<&'static str>,
+    poll_rate: u16,
+    flush_on_exit: bool,
+    cancel_on_disconnect: bool,
+    socket_options: SocketOptions,
+    role:      Option<NetworkRole>,
+    coalesce:  Option<CoalescingOptions>,
+    fragmentation: Option<FragmentationOptions>,
+    ack_timeout:   Option<Duration>,
+    #[cfg(feature = "compression")]
+    compression: Option<usize>,
+    state:     Option<StateGate>,
+    drop_on_state_exit: bool,
+    report_deliveries: bool,
+    // `build` only borrows the plugin, the resource takes them from there
+    middleware: Mutex<Vec<Box<dyn PacketMiddleware>>>,
+}
+
+/// Run criteria of the app state the systems run in, see `LaminarPlugin::in_state`.
+#[cfg(feature = "bevy")]
+struct StateGate {
+    on_update: Box<dyn Fn() -> RunCriteriaDescriptor + Send + Sync>,
+    on_exit:   Box<dyn Fn() -> RunCriteriaDescriptor + Send + Sync>,
+}
+
+/// What the plugin does with messages to broadcast addresses.
+#[derive(Clone, Debug, Default)]
+struct Broadcast {
+    allowed: bool,
+    subnets: Vec<Ipv4Addr>,
+}
+
+/// Where the sockets of the plugin come from.
+#[cfg(feature = "bevy")]
+enum Binding {
+    Addresses(Vec<SocketAddr>),
+    Socket(UdpSocket),
+    // taken out by `build`, which only borrows the plugin
+    Laminar(Mutex<Option<Box<LaminarSocket>>>),
+}
+
+#[cfg(feature = "bevy")]
+impl LaminarPlugin {
+    pub fn new(address: SocketAddr, config: LaminarConfig) -> Self {
+        Self::with_addresses([address], config)
+    }
+
+    /// Creates a plugin binding a socket to each of the addresses, e.g. an IPv4 and an IPv6 one to
+    /// serve clients of both families. Messages are sent from the socket of the same family as
+    /// their destination. Note that on systems where IPv6 sockets accept IPv4 traffic by default,
+    /// `[::]` and `0.0.0.0` can't be bound to the same port.
+    pub fn with_addresses(addresses: impl IntoIterator<Item = SocketAddr>, config: LaminarConfig) -> Self {
+        LaminarPlugin {
+            binding:   Binding::Addresses(addresses.into_iter().collect()),
+            config,
+            broadcast: Broadcast::default(),
+            relay:     None,
+            name:      None,
+            poll_rate: 0,
+            flush_on_exit: false,
+            cancel_on_disconnect: false,
+            socket_options: SocketOptions::default(),
+            role:      None,
+            coalesce:  None,
+            fragmentation: None,
+            ack_timeout:   None,
+            #[cfg(feature = "compression")]
+            compression: None,
+            state:     None,
+            drop_on_state_exit: false,
+            report_deliveries: false,
+            middleware: Mutex::default(),
+        }
+    }
+
+    /// Creates a plugin for an additional endpoint, e.g. a separate port for voice or for status
+    /// queries next to the gameplay one. Its sockets are kept in `LaminarEndpoints` under `name`
+    /// rather than in the `LaminarSocketResource`, and driven by their own systems. Only the
+    /// messages sent with `TransportResource::send_via` and `TransportId(name)` go out of them,
+    /// their incoming events are emitted along with the others and tagged with that id.
+    pub fn named(name: &'static str, address: SocketAddr, config: LaminarConfig) -> Self {
+        LaminarPlugin { name: Some(name), ..Self::new(address, config) }
+    }
+
+    /// Creates a client plugin, binding to a port of every interface chosen by the OS. The port is
+    /// reported with a `Listening` event and by `LaminarSocketResource::local_addr`.
+    #[must_use]
+    pub fn client() -> Self {
+        Self::builder().build()
+    }
+
+    /// Returns a builder to tweak the bind address and a few settings of the default config.
+    #[must_use]
+    pub fn builder() -> LaminarPluginBuilder {
+        LaminarPluginBuilder::default()
+    }
+
+    /// Creates a plugin using a socket bound beforehand, keeping e.g. the NAT mapping it has.
+    pub fn from_socket(socket: UdpSocket, config: LaminarConfig) -> Self {
+        LaminarPlugin { binding: Binding::Socket(socket), ..Self::with_addresses([], config) }
+    }
+
+    /// Creates a plugin using a laminar socket made beforehand, e.g. from a descriptor handed over
+    /// by a supervisor, or kept from a previous app. The socket keeps the configuration and relay it
+    /// was made with, `allow_broadcast`, `with_relay` and `with_socket_options` don't apply to it.
+    /// It is moved into the resource by the first build of the plugin, the following ones report a
+    /// `ConnectionError`.
+    #[must_use]
+    pub fn from_laminar_socket(socket: LaminarSocket) -> Self {
+        LaminarPlugin {
+            binding: Binding::Laminar(Mutex::new(Some(Box::new(socket)))),
+            ..Self::with_addresses([], LaminarConfig::default())
+        }
+    }
+
+    /// Sets `SO_BROADCAST` on the IPv4 sockets so that messages to `255.255.255.255`, and to the
+    /// subnet broadcast addresses given to `broadcast_address`, go out. They bypass laminar's
+    /// connection handling: only `Unreliable` and `UnreliableSequenced` messages can be sent to
+    /// them, without any sequencing, the other requirements are reported as a `SendError`.
+    #[must_use]
+    pub fn allow_broadcast(mut self, allow: bool) -> Self {
+        self.broadcast.allowed = allow;
+        self
+    }
+
+    /// Declares the broadcast address of a subnet, e.g. `192.168.1.255`, to be handled like the
+    /// limited broadcast address.
+    #[must_use]
+    pub fn broadcast_address(mut self, address: Ipv4Addr) -> Self {
+        self.broadcast.subnets.push(address);
+        self
+    }
+
+    /// Sends all the traffic through a relay, for peers which can't be reached directly. Peers are
+    /// still addressed by their own address, as the relay sees it.
+    #[must_use]
+    pub fn with_relay(mut self, relay: RelayConfig) -> Self {
+        self.relay = Some(relay);
+        self
+    }
+
+    /// Polls the sockets `hz` times per second rather than at every frame, see
+    /// `LaminarSocketResource::set_poll_rate`.
+    #[must_use]
+    pub fn with_poll_rate(mut self, hz: u16) -> Self {
+        self.poll_rate = hz;
+        self
+    }
+
+    /// Sends the messages left in the queue and drops the socket when the app exits, see
+    /// `flush_laminar` for why this is best effort.
+    #[must_use]
+    pub fn flush_on_exit(mut self, flush: bool) -> Self {
+        self.flush_on_exit = flush;
+        self
+    }
+
+    /// Drops the messages queued for a peer once it disconnects or times out, see
+    /// `TransportResource::set_cancel_on_disconnect`.
+    #[must_use]
+    pub fn cancel_on_disconnect(mut self, cancel: bool) -> Self {
+        self.cancel_on_disconnect = cancel;
+        self
+    }
+
+    /// Only runs the laminar systems while the app is in `state`, e.g. `InGame`, the state having
+    /// been added with `App::add_state`. Meanwhile the messages stay queued, nothing is received,
+    /// and the peers time out if it lasts.
+    #[must_use]
+    pub fn in_state<S: StateData>(mut self, state: S) -> Self {
+        let exit = state.clone();
+        self.state = Some(StateGate {
+            on_update: Box::new(move || State::on_update(state.clone())),
+            on_exit:   Box::new(move || State::on_exit(exit.clone())),
+        });
+        self
+    }
+
+    /// Flushes the queued messages and drops the sockets when the app leaves the state given to
+    /// `in_state`, see `flush_laminar`. They aren't bound again when it comes back.
+    #[must_use]
+    pub fn drop_socket_on_state_exit(mut self, drop: bool) -> Self {
+        self.drop_on_state_exit = drop;
+        self
+    }
+
+    /// Creates the sockets bound to addresses with the options, e.g. `SO_REUSEPORT` to share the
+    /// port across processes. They decide whether the sockets block, overriding the laminar
+    /// configuration. An option which isn't supported is reported as a `ConnectionError`.
+    #[must_use]
+    pub fn with_socket_options(mut self, options: SocketOptions) -> Self {
+        self.config.blocking_mode = !options.nonblocking;
+        self.socket_options = options;
+        self
+    }
+
+    /// Sets the size of the OS send buffer of the sockets, see `SocketOptions::send_buffer_bytes`.
+    #[must_use]
+    pub fn with_send_buffer_bytes(mut self, bytes: usize) -> Self {
+        self.socket_options.send_buffer_bytes = Some(bytes);
+        self
+    }
+
+    /// Sets the size of the OS receive buffer of the sockets, see
+    /// `SocketOptions::recv_buffer_bytes`.
+    #[must_use]
+    pub fn with_recv_buffer_bytes(mut self, bytes: usize) -> Self {
+        self.socket_options.recv_buffer_bytes = Some(bytes);
+        self
+    }
+
+    /// Sets the role of the local peer in the `TransportResource`, e.g. so that a client can
+    /// `send_to_server` without naming it. See `NetworkRole`.
+    #[must_use]
+    pub fn with_role(mut self, role: NetworkRole) -> Self {
+        self.role = Some(role);
+        self
+    }
+
+    /// Packs the messages of the same destination and delivery requirement sent in a frame into as
+    /// few packets as possible, the peers must enable it too. See
+    /// `LaminarSocketResource::set_coalescing`.
+    #[must_use]
+    pub fn with_coalescing(mut self, coalesce: bool) -> Self {
+        self.coalesce = coalesce.then(CoalescingOptions::default);
+        self
+    }
+
+    /// Same as `with_coalescing`, with the given options rather than the default ones.
+    #[must_use]
+    pub fn with_coalescing_options(mut self, options: CoalescingOptions) -> Self {
+        self.coalesce = Some(options);
+        self
+    }
+
+    /// Splits the unreliable payloads too large for a single packet into fragments, reassembled by
+    /// the peers, which must enable it too. See `LaminarSocketResource::set_fragmentation`.
+    #[must_use]
+    pub fn with_fragmentation(mut self, options: FragmentationOptions) -> Self {
+        self.fragmentation = Some(options);
+        self
+    }
+
+    /// Sets how long a message sent with `TransportResource::send_with_ack` waits to be
+    /// acknowledged before a `MessageAckTimeout` event is emitted, 5 seconds by default. A tagged
+    /// message not acknowledged by then gets no `Acked` event.
+    #[must_use]
+    pub fn with_ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    /// Emits a `Delivered` event after every received `Message`, see
+    /// `LaminarSocketResource::set_delivery_reports`.
+    #[must_use]
+    pub fn report_deliveries(mut self, report: bool) -> Self {
+        self.report_deliveries = report;
+        self
+    }
+
+    /// Compresses the payloads of at least `threshold` bytes, the peers must enable it too. See
+    /// `LaminarSocketResource::set_compression`.
+    #[cfg(feature = "compression")]
+    #[must_use]
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compression = Some(threshold);
+        self
+    }
+
+    /// Adds a middleware seeing every payload sent and received, after the ones added before it
+    /// when sending and before them when receiving. See `PacketMiddleware`.
+    #[must_use]
+    pub fn with_middleware(self, middleware: Box<dyn PacketMiddleware>) -> Self {
+        self.middleware.lock().unwrap().push(middleware);
+        self
+    }
+
+    /// Binds every socket, along with the address each one was meant for.
+    fn bind(&self) -> Vec<(Option<SocketAddr>, Result<LaminarSocket, ErrorKind>)> {
+        match &self.binding {
+            Binding::Addresses(addresses) => addresses
+                .iter()
+                .map(|address| {
+                    let socket = options::bind(*address, &self.socket_options)
+                        .and_then(|socket| self.set_broadcast(socket))
+                        .map_err(ErrorKind::from)
+                        .and_then(|socket| self.create_socket(socket));
+                    (Some(*address), socket)
+                })
+                .collect(),
+            // `build` only borrows the plugin, the clone refers to the very same OS socket
+            Binding::Socket(socket) => {
+                let socket = socket
+                    .try_clone()
+                    .and_then(|clone| self.set_broadcast(clone))
+                    .map_err(ErrorKind::from)
+                    .and_then(|clone| self.create_socket(clone));
+                vec![(self.socket_address(), socket)]
+            }
+            Binding::Laminar(socket) => {
+                let socket = socket.lock().unwrap_or_else(std::sync::PoisonError::into_inner).take();
+                let address = socket.as_ref().and_then(|socket| socket.local_addr().ok());
+                let socket = socket.map(|socket| *socket).ok_or_else(|| {
+                    ErrorKind::IOError(io::Error::other("the socket was moved out by a previous build of the plugin"))
+                });
+                vec![(address, socket)]
+            }
+        }
+    }
+
+    /// Binds the sockets into a resource, reporting the ones which couldn't be set up.
+    fn socket_resource(&self, app: &mut App) -> LaminarSocketResource {
+        let mut resource = LaminarSocketResource {
+            broadcast: self.broadcast.clone(),
+            coalesce:  self.coalesce,
+            fragmentation: self.fragmentation.map(Fragmenter::new),
+            ack_timeout:   self.ack_timeout,
+            report_deliveries: self.report_deliveries,
+            #[cfg(feature = "compression")]
+            compression: self.compression,
+            middleware: std::mem::take(&mut *self.middleware.lock().unwrap()),
+            ..LaminarSocketResource::default()
+        };
+        resource.set_poll_rate(self.poll_rate);
+        for (address, socket) in self.bind() {
+            match socket {
+                Ok(socket) => {
+                    if let Ok(addr) = socket.local_addr() {
+                        app.world.send_event(NetworkSimulationEvent::Listening(addr));
+                    }
+                    resource.add_socket(socket);
+                }
+                Err(e) => {
+                    app.world.send_event(NetworkSimulationEvent::ConnectionError(
+                        into_io_error(e),
+                        address,
+                    ));
+                }
+            }
+        }
+        resource
+    }
+
+    fn create_socket(&self, socket: UdpSocket) -> Result<LaminarSocket, ErrorKind> {
+        match self.relay {
+            Some(relay) => LaminarSocket::relayed(socket, self.config.clone(), relay),
+            None => LaminarSocket::from_std_socket(socket, self.config.clone()),
+        }
+    }
+
+    /// Sets `SO_BROADCAST` on an IPv4 socket if broadcasts are allowed, leaving it alone otherwise.
+    fn set_broadcast(&self, socket: UdpSocket) -> io::Result<UdpSocket> {
+        if self.broadcast.allowed && socket.local_addr()?.is_ipv4() {
+            socket.set_broadcast(true)?;
+        }
+        Ok(socket)
+    }
+
+    fn socket_address(&self) -> Option<SocketAddr> {
+        match &self.binding {
+            Binding::Addresses(_) | Binding::Laminar(_) => None,
+            Binding::Socket(socket) => socket.local_addr().ok(),
+        }
+    }
+}
+
+/// Builder of a `LaminarPlugin`, binding to any port of every interface with the default laminar
+/// configuration unless told otherwise.
+#[cfg(feature = "bevy")]
+#[derive(Default)]
+pub struct LaminarPluginBuilder {
+    addresses: Vec<SocketAddr>,
+    config:    LaminarConfig,
+    broadcast: Broadcast,
+    relay:     Option<RelayConfig>,
+    poll_rate: u16,
+    flush_on_exit: bool,
+    socket_options: Option<SocketOptions>,
+    coalesce:  Option<CoalescingOptions>,
+    fragmentation: Option<FragmentationOptions>,
+    ack_timeout:   Option<Duration>,
+    #[cfg(feature = "compression")]
+    compression: Option<usize>,
+    middleware: Vec<Box<dyn PacketMiddleware>>,
+}
+
+#[cfg(feature = "bevy")]
+impl LaminarPluginBuilder {
+    /// Adds an address to bind a socket to, call it once per address family to listen on both.
+    #[must_use]
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.addresses.push(address);
+        self
+    }
+
+    /// Replaces the whole laminar configuration, the setters called afterwards still apply.
+    #[must_use]
+    pub fn config(mut self, config: LaminarConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets how long a peer can stay silent before it is considered disconnected.
+    #[must_use]
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_connection_timeout = timeout;
+        self
+    }
+
+    /// Sets the interval heartbeats are sent at when nothing else was sent, none are by default.
+    #[must_use]
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.config.heartbeat_interval = Some(interval);
+        self
+    }
+
+    /// Sets the maximum size of a packet in bytes, fragments included.
+    #[must_use]
+    pub fn max_packet_size(mut self, size: usize) -> Self {
+        self.config.max_packet_size = size;
+        self
+    }
+
+    /// See `LaminarPlugin::allow_broadcast`.
+    #[must_use]
+    pub fn allow_broadcast(mut self, allow: bool) -> Self {
+        self.broadcast.allowed = allow;
+        self
+    }
+
+    /// See `LaminarPlugin::broadcast_address`.
+    #[must_use]
+    pub fn broadcast_address(mut self, address: Ipv4Addr) -> Self {
+        self.broadcast.subnets.push(address);
+        self
+    }
+
+    /// See `LaminarPlugin::with_relay`.
+    #[must_use]
+    pub fn relay(mut self, relay: RelayConfig) -> Self {
+        self.relay = Some(relay);
+        self
+    }
+
+    /// See `LaminarPlugin::with_poll_rate`.
+    #[must_use]
+    pub fn poll_rate(mut self, hz: u16) -> Self {
+        self.poll_rate = hz;
+        self
+    }
+
+    /// See `LaminarPlugin::flush_on_exit`.
+    #[must_use]
+    pub fn flush_on_exit(mut self, flush: bool) -> Self {
+        self.flush_on_exit = flush;
+        self
+    }
+
+    /// See `LaminarPlugin::with_socket_options`.
+    #[must_use]
+    pub fn socket_options(mut self, options: SocketOptions) -> Self {
+        self.socket_options = Some(options);
+        self
+    }
+
+    /// See `LaminarPlugin::with_send_buffer_bytes`.
+    #[must_use]
+    pub fn send_buffer_bytes(mut self, bytes: usize) -> Self {
+        self.socket_options.get_or_insert_with(SocketOptions::default).send_buffer_bytes = Some(bytes);
+        self
+    }
+
+    /// See `LaminarPlugin::with_recv_buffer_bytes`.
+    #[must_use]
+    pub fn recv_buffer_bytes(mut self, bytes: usize) -> Self {
+        self.socket_options.get_or_insert_with(SocketOptions::default).recv_buffer_bytes = Some(bytes);
+        self
+    }
+
+    /// See `LaminarPlugin::with_coalescing`.
+    #[must_use]
+    pub fn coalescing(mut self, coalesce: bool) -> Self {
+        self.coalesce = coalesce.then(CoalescingOptions::default);
+        self
+    }
+
+    /// See `LaminarPlugin::with_coalescing_options`.
+    #[must_use]
+    pub fn coalescing_options(mut self, options: CoalescingOptions) -> Self {
+        self.coalesce = Some(options);
+        self
+    }
+
+    /// See `LaminarPlugin::with_fragmentation`.
+    #[must_use]
+    pub fn fragmentation(mut self, options: FragmentationOptions) -> Self {
+        self.fragmentation = Some(options);
+        self
+    }
+
+    /// See `LaminarPlugin::with_ack_timeout`.
+    #[must_use]
+    pub fn ack_timeout(mut self, timeout: Duration) -> Self {
+        self.ack_timeout = Some(timeout);
+        self
+    }
+
+    /// See `LaminarPlugin::with_compression`.
+    #[cfg(feature = "compression")]
+    #[must_use]
+    pub fn compression(mut self, threshold: usize) -> Self {
+        self.compression = Some(threshold);
+        self
+    }
+
+    /// See `LaminarPlugin::with_middleware`.
+    #[must_use]
+    pub fn middleware(mut self, middleware: Box<dyn PacketMiddleware>) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+
+    /// Creates the plugin.
+    #[must_use]
+    pub fn build(self) -> LaminarPlugin {
+        let addresses = if self.addresses.is_empty() {
+            vec![SocketAddr::from(([0, 0, 0, 0], 0))]
+        } else {
+            self.addresses
+        };
+        let mut plugin = LaminarPlugin::with_addresses(addresses, self.config);
+        if let Some(options) = self.socket_options {
+            plugin = plugin.with_socket_options(options);
+        }
+        LaminarPlugin {
+            broadcast: self.broadcast,
+            relay:     self.relay,
+            poll_rate: self.poll_rate,
+            flush_on_exit: self.flush_on_exit,
+            coalesce:  self.coalesce,
+            fragmentation: self.fragmentation,
+            ack_timeout:   self.ack_timeout,
+            #[cfg(feature = "compression")]
+            compression: self.compression,
+            middleware: Mutex::new(self.middleware),
+            ..plugin
+        }
+    }
+}
+
+#[cfg(feature = "bevy")]
+impl Plugin for LaminarPlugin {
+    /// Failing to set up a socket doesn't panic, it is reported as a `ConnectionError` on the
+    /// first frame and the resource is left without that socket.
+    fn build(&self, app: &mut App) {
+        // the plugin and the endpoints share these systems, which must only run once per frame
+        let first = !app.world.contains_resource::<LaminarSocketResource>()
+            && !app.world.contains_resource::<LaminarEndpoints>();
+        let shared_systems = |set: SystemSet| {
+            let set = match &self.state {
+                Some(state) => set.with_run_criteria((state.on_update)()),
+                None => set,
+            };
+            if first {
+                set.with_system(network_simulation_time_system)
+                    .with_system(unroutable_messages_system)
+                    .with_system(connected_peers_system)
+            } else {
+                set
+            }
+        };
+        app
+            .add_event::<NetworkSimulationEvent>()
+            .add_event::<TaggedNetworkEvent>()
+            .init_resource::<NetworkSimulationTime>()
+            .init_resource::<TransportResource>()
+            .init_resource::<ConnectedPeers>()
+            .init_resource::<ConnectionMetrics>();
+        let resource = self.socket_resource(app);
+        let mut transport = app.world.resource_mut::<TransportResource>();
+        if let Some(role) = self.role {
+            transport.set_role(role);
+        }
+        if self.cancel_on_disconnect {
+            transport.set_cancel_on_disconnect(true);
+        }
+
+        match self.name {
+            None => {
+                app
+                    .add_startup_system(log_startup)
+                    .add_system_set(shared_systems(SystemSet::new())
+                        .label(LaminarLabel)
+                        .with_system(laminar_network_send_system)
+                        .with_system(laminar_network_poll_system)
+                        .with_system(laminar_network_recv_system)
+                    );
+                if self.flush_on_exit {
+                    app
+                        .add_event::<AppExit>()
+                        .add_system_to_stage(CoreStage::Last, laminar_shutdown_system);
+                }
+                if let Some(state) = self.state.as_ref().filter(|_| self.drop_on_state_exit) {
+                    app.add_system_set(SystemSet::new()
+                        .with_run_criteria((state.on_exit)())
+                        .with_system(laminar_state_exit_system)
+                    );
+                }
+                app.world.resource_mut::<TransportResource>().register_transport(TransportId::LAMINAR);
+                app.insert_resource(resource);
+            }
+            Some(name) => {
+                // the systems drive every endpoint, they are only added along with the first one
+                if !app.world.contains_resource::<LaminarEndpoints>() {
+                    app
+                        .init_resource::<LaminarEndpoints>()
+                        .add_system_set(shared_systems(SystemSet::new())
+                            .label(LaminarEndpointsLabel)
+                            .with_system(laminar_endpoints_send_system)
+                            .with_system(laminar_endpoints_poll_system)
+                            .with_system(laminar_endpoints_recv_system)
+                        );
+                }
+                for addr in resource.local_addrs() {
+                    info!("Endpoint {} listening on {}", name, addr);
+                }
+                app.world.resource_mut::<TransportResource>().register_transport(TransportId(name));
+                app.world.resource_mut::<LaminarEndpoints>().endpoints.insert(name, resource);
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.name.unwrap_or("laminar")
+    }
+}
+
+fn into_io_error(e: ErrorKind) -> io::Error {
+    match e {
+        ErrorKind::IOError(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+/// Returns the event reporting that `message` couldn't be sent.
+fn send_error_event(e: ErrorKind, message: Message) -> NetworkSimulationEvent {
+    match e {
+        ErrorKind::IOError(e) => NetworkSimulationEvent::SendError(e, message),
+        e => NetworkSimulationEvent::ProtocolError(e, message),
+    }
+}
+
+/// Same as `send_error_event`, unless `transport` queued the message again to retry it, see
+/// `TransportResource::set_retry_policy`.
+fn retry_or_report(
+    transport: &mut TransportResource,
+    e: ErrorKind,
+    message: Message,
+) -> Option<NetworkSimulationEvent> {
+    match e {
+        ErrorKind::IOError(e) => transport
+            .retry_failed(e, message)
+            .map(|(e, message)| NetworkSimulationEvent::SendError(e, message)),
+        e => Some(send_error_event(e, message)),
+    }
+}
+
+#[cfg(feature = "bevy")]
+fn log_startup(socket: Res<LaminarSocketResource>) {
+    if socket.sockets().is_empty() {
+        error!("Laminar socket is not bound");
+    }
+    for addr in socket.local_addrs() {
+        info!("Start listening on {}", addr);
+    }
+}
+
+/// Creates a new laminar network send system. Each message goes out of the socket of the same
+/// address family as its destination, or is reported as a `SendError` if there is none. Without
+/// any socket, e.g. after a failed bind, the messages stay queued and a `SocketUnavailable` event
+/// is emitted once. Messages
+/// to broadcast addresses are written straight to the socket, see `LaminarPlugin::allow_broadcast`.
+/// The `Immediate` messages are sent on every run, the `OnTick` ones when the
+/// `NetworkSimulationTime` says so. The handshakes of `TransportResource::connect` go out first,
+/// each followed by a `Connecting` event. Unless they are coalesced, the messages are sent straight
+/// from the queue, see `TransportResource::for_each_message_to_send`.
+#[cfg(feature = "bevy")]
+pub fn laminar_network_send_system(mut transport: ResMut<TransportResource>,
+                               mut socket:        ResMut<LaminarSocketResource>,
+                               mut event_channel: EventWriter<NetworkSimulationEvent>,
+                                   sim_time:      Res<NetworkSimulationTime>,
+                               #[cfg(feature = "diagnostics")]
+                               mut stats:         Option<ResMut<NetworkStats>>) {
+
+    if socket.became_unavailable() {
+        event_channel.send(NetworkSimulationEvent::SocketUnavailable);
+    }
+    if socket.sockets().is_empty() {
+        return;
+    }
+    for addr in transport.drain_connect_requests() {
+        let handshake = Packet::reliable_unordered(addr, socket::RECONNECT_PAYLOAD.to_vec());
+        let sent = match socket.get_for_destination_mut(&addr) {
+            Some(socket) => socket.send(handshake).map_err(into_io_error),
+            None => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no laminar socket of the address family of {}", addr),
+            )),
+        };
+        match sent {
+            Ok(()) => event_channel.send(NetworkSimulationEvent::Connecting(addr)),
+            Err(e) => {
+                transport.cancel_connect(&addr);
+                event_channel.send(NetworkSimulationEvent::ConnectionError(e, Some(addr)));
+            }
+        }
+    }
+    #[cfg(feature = "diagnostics")]
+    let record_sent = |len| {
+        if let Some(stats) = stats.as_mut() {
+            stats.record_sent(len);
+        }
+    };
+    #[cfg(not(feature = "diagnostics"))]
+    let record_sent = |_| {};
+    let should_send = sim_time.should_send_message_now();
+    let (route, events) = (TransportId::LAMINAR, &mut event_channel);
+    send_routed_messages(&mut transport, &mut socket, route, false, should_send, events, record_sent);
+}
+
+/// Sends the messages `route` is given from `socket`, calling `record_sent` with the size of each
+/// payload sent. Unless `exclusive`, the messages routed to no transport are sent too. Unless they
+/// are coalesced, the messages go out one at a time, straight from the queue.
+#[cfg(feature = "bevy")]
+fn send_routed_messages(transport:       &mut TransportResource,
+                        socket:          &mut LaminarSocketResource,
+                        route:           TransportId,
+                        exclusive:       bool,
+                        should_send:     bool,
+                        event_channel:   &mut EventWriter<NetworkSimulationEvent>,
+                        mut record_sent: impl FnMut(usize)) {
+    let for_each = |transport: &mut TransportResource, send: &mut dyn FnMut(Message) -> Disposition| {
+        if exclusive {
+            transport.for_each_message_routed_to(route, |_| should_send, send);
+        } else {
+            transport.for_each_message_to_send(route, |_| should_send, send);
+        }
+    };
+    if !socket.coalesces() {
+        for_each(transport, &mut |message| {
+            let len = message.payload.len();
+            match socket.send_message(message) {
+                Ok(()) => {
+                    record_sent(len);
+                    Disposition::Done
+                }
+                Err((ErrorKind::IOError(e), message)) => Disposition::Failed(e, message),
+                Err((e, message)) => {
+                    event_channel.send(send_error_event(e, message));
+                    Disposition::Done
+                }
+            }
+        });
+        event_channel.send_batch(transport
+            .drain_failed_messages()
+            .into_iter()
+            .map(|(e, message)| NetworkSimulationEvent::SendError(e, message)));
+    } else {
+        // the messages of the frame are packed together, so they are collected first
+        let mut messages = Vec::new();
+        for_each(transport, &mut |message| {
+            messages.push(message);
+            Disposition::Done
+        });
+        let lens: Vec<_> = messages.iter().map(|message| message.payload.len()).collect();
+        for (result, len) in socket.send_messages(messages).into_iter().zip(lens) {
+            match result {
+                Ok(()) => record_sent(len),
+                Err((e, message)) => {
+                    if let Some(event) = retry_or_report(transport, e, message) {
+                        event_channel.send(event);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sends a message to a broadcast address, which only makes sense for unreliable messages.
+fn send_broadcast(
+    socket: &mut LaminarSocket,
+    allowed: bool,
+    destination: SocketAddr,
+    payload: &[u8],
+    delivery: DeliveryRequirement,
+) -> io::Result<()> {
+    if !allowed {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("broadcasts aren't allowed, can't send to {}", destination),
+        ));
+    }
+    match delivery {
+        DeliveryRequirement::Unreliable | DeliveryRequirement::UnreliableSequenced(_) => {
+            check_payload_size(socket, payload, delivery)?;
+            socket.send_broadcast(destination, payload)
+        }
+        delivery => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} delivery can't be used for the broadcast address {}", delivery, destination),
+        )),
+    }
+}
+
+/// Fails with a descriptive error, rather than laminar's opaque one later on, for an unreliable
+/// payload too large to be sent, as those aren't fragmented.
+fn check_payload_size(
+    socket: &LaminarSocket,
+    payload: &[u8],
+    delivery: DeliveryRequirement,
+) -> io::Result<()> {
+    let max_size = socket.max_unreliable_payload_size();
+    match delivery {
+        DeliveryRequirement::Unreliable | DeliveryRequirement::UnreliableSequenced(_)
+            if payload.len() > max_size =>
+        {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "unreliable payload of {} bytes exceeds the maximum of {} bytes, use a reliable delivery \
+                     to have it fragmented",
+                    payload.len(),
+                    max_size,
+                ),
+            ))
+        }
+        // laminar would only log the error once it polls
+        _ if payload.len() > socket.max_reliable_payload_size() => {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "payload of {} bytes exceeds the maximum of {} bytes laminar can fragment",
+                    payload.len(),
+                    socket.max_reliable_payload_size(),
+                ),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns true for the requirements laminar sends reliably, and which get acknowledged.
+fn is_reliable(delivery: DeliveryRequirement) -> bool {
+    !matches!(delivery, DeliveryRequirement::Unreliable | DeliveryRequirement::UnreliableSequenced(_))
+}
+
+/// Creates the laminar packet honoring the delivery requirement. laminar owns the payload of its
+/// packets: a payload nothing else holds is moved into the packet, a shared one, e.g. by the
+/// messages of a broadcast, is copied exactly once here.
+fn create_packet(destination: SocketAddr, payload: Bytes, delivery: DeliveryRequirement) -> Packet {
+    let payload = Vec::from(payload);
+    match delivery {
+        DeliveryRequirement::Unreliable => {
+            Packet::unreliable(destination, payload)
+        }
+        DeliveryRequirement::UnreliableSequenced(stream_id) => {
+            Packet::unreliable_sequenced(destination, payload, stream_id)
+        }
+        // `Reliable` carries no ordering guarantee either, both map to the same laminar packet
+        DeliveryRequirement::Reliable | DeliveryRequirement::ReliableUnordered => {
+            Packet::reliable_unordered(destination, payload)
+        }
+        DeliveryRequirement::ReliableSequenced(stream_id) => {
+            Packet::reliable_sequenced(destination, payload, stream_id)
+        }
+        DeliveryRequirement::ReliableOrdered(stream_id) => {
+            Packet::reliable_ordered(destination, payload, stream_id)
+        }
+        // laminar's default is reliable and ordered on the default stream, unless
+        // `TransportResource::set_default_delivery` resolved it when queued
+        DeliveryRequirement::Default => {
+            Packet::reliable_ordered(destination, payload, None)
+        }
+    }
+}
+
+impl Transport for LaminarSocket {
+    fn send(&mut self, destination: SocketAddr, payload: Bytes, delivery: DeliveryRequirement) -> io::Result<()> {
+        check_payload_size(self, &payload, delivery)?;
+        LaminarSocket::send(self, create_packet(destination, payload, delivery)).map_err(into_io_error)
+    }
+
+    fn poll(&mut self) -> Vec<TransportEvent> {
+        let now = Instant::now();
+        let mut events = poll_errors(self, now);
+        events.extend(received_events(self, now).into_iter().map(|(event, _)| event));
+        events
+    }
+}
+
+/// Polls the socket and returns the IO errors laminar ran into.
+fn poll_errors(socket: &mut LaminarSocket, time: Instant) -> Vec<TransportEvent> {
+    socket.manual_poll(time);
+    socket
+        .drain_errors()
+        .into_iter()
+        .map(|error| match error {
+            SocketError::Recv(e) => TransportEvent::RecvError(e),
+            SocketError::Send(e, addr) => TransportEvent::ConnectionError(e, Some(addr)),
+        })
+        .collect()
+}
+
+/// Returns the latency updates, the acks, the heartbeats and the events received by the previous
+/// polls, leaving out the punch packets of `NatTraversal`. The messages come with the requirement
+/// they were delivered with.
+fn received_events(
+    socket: &mut LaminarSocket,
+    now: Instant,
+) -> Vec<(TransportEvent, Option<DeliveryRequirement>)> {
+    let mut events: Vec<_> = socket
+        .drain_latency_updates()
+        .into_iter()
+        .map(|(addr, rtt)| (TransportEvent::Latency(addr, rtt), None))
+        .collect();
+    events.extend(socket.drain_acks().into_iter().map(|(addr, tag)| (TransportEvent::Acked(addr, tag), None)));
+    let (acked, timed_out) = socket.drain_message_acks(now);
+    events.extend(acked.into_iter().map(|id| (TransportEvent::MessageAcked(id), None)));
+    events.extend(timed_out.into_iter().map(|id| (TransportEvent::MessageAckTimeout(id), None)));
+    events.extend(socket.drain_heartbeats().into_iter().map(|addr| (TransportEvent::Heartbeat(addr), None)));
+
+    while let Some(event) = socket.recv() {
+        let delivery = match &event {
+            SocketEvent::Packet(packet) => Some(received_delivery(packet)),
+            _ => None,
+        };
+        events.extend(transport_event(event).map(|event| (event, delivery)));
+    }
+    events
+}
+
+/// Returns the requirement a received packet was delivered with. laminar reports the default
+/// streams by their id, they are given back as `None`.
+fn received_delivery(packet: &Packet) -> DeliveryRequirement {
+    let stream = |stream: Option<u8>| stream.filter(|stream| *stream != DEFAULT_STREAM);
+    match (packet.delivery_guarantee(), packet.order_guarantee()) {
+        (DeliveryGuarantee::Unreliable, OrderingGuarantee::Sequenced(id)) => {
+            DeliveryRequirement::UnreliableSequenced(stream(id))
+        }
+        (DeliveryGuarantee::Unreliable, _) => DeliveryRequirement::Unreliable,
+        (DeliveryGuarantee::Reliable, OrderingGuarantee::Sequenced(id)) => {
+            DeliveryRequirement::ReliableSequenced(stream(id))
+        }
+        (DeliveryGuarantee::Reliable, OrderingGuarantee::Ordered(id)) => {
+            DeliveryRequirement::ReliableOrdered(stream(id))
+        }
+        (DeliveryGuarantee::Reliable, OrderingGuarantee::None) => DeliveryRequirement::ReliableUnordered,
+    }
+}
+
+/// Maps a laminar event to the transport event, if it is one to emit.
+fn transport_event(event: SocketEvent) -> Option<TransportEvent> {
+    Some(match event {
+        SocketEvent::Packet(packet) if packet.payload() == socket::PUNCH_PAYLOAD => return None,
+        SocketEvent::Packet(packet) => {
+            TransportEvent::Message(
+                packet.addr(),
+                Bytes::copy_from_slice(packet.payload()),
+            )
+        }
+        SocketEvent::Disconnect(addr) => TransportEvent::Disconnect(addr, DisconnectReason::Closed),
+        SocketEvent::Timeout(addr) => TransportEvent::Disconnect(addr, DisconnectReason::Timeout),
+        SocketEvent::Connect(addr) => TransportEvent::Connect(addr),
+    })
+}
+
+/// Sends every message queued for laminar, then polls the sockets and pushes what they received,
+/// like the laminar systems do. This drives the sockets without bevy, at whatever rate it is
+/// called. Messages routed to another transport are left in the queue.
+pub fn pump_laminar(socket:    &mut LaminarSocketResource,
+                    transport: &mut TransportResource,
+                    events:    &mut Vec<NetworkSimulationEvent>) {
+    send_queued_messages(socket, transport, events, true);
+    poll_laminar(socket, events);
+    receive_laminar(socket, events);
+}
+
+/// Sends every message queued for laminar and polls once more so that they go out, then drops
+/// the sockets. This is best effort: nothing is resent if the datagrams get lost, and laminar has
+/// no disconnect packet to tell the peers, they time out.
+pub fn flush_laminar(socket:    &mut LaminarSocketResource,
+                     transport: &mut TransportResource,
+                     events:    &mut Vec<NetworkSimulationEvent>) {
+    // nothing would be left to retry the messages
+    send_queued_messages(socket, transport, events, false);
+    poll_laminar(socket, events);
+    socket.drop_socket();
+}
+
+fn send_queued_messages(socket:    &mut LaminarSocketResource,
+                        transport: &mut TransportResource,
+                        events:    &mut Vec<NetworkSimulationEvent>,
+                        retry:     bool) {
+    events.extend(transport.drain_rejected_messages().into_iter().map(rejected_message_event));
+    if !socket.sockets().is_empty() {
+        let messages = transport.drain_messages_to_send_fairly(TransportId::LAMINAR, |_| true);
+        events.extend(transport.drain_expired_messages().into_iter().map(expired_message_event));
+        for result in socket.send_messages(messages) {
+            if let Err((e, message)) = result {
+                if retry {
+                    events.extend(retry_or_report(transport, e, message));
+                } else {
+                    events.push(send_error_event(e, message));
+                }
+            }
+        }
+    }
+}
+
+/// Creates a new system flushing the laminar sockets with `flush_laminar` once the app exits, see
+/// `LaminarPlugin::flush_on_exit`.
+#[cfg(feature = "bevy")]
+pub fn laminar_shutdown_system(mut exit:          EventReader<AppExit>,
+                               mut socket:        ResMut<LaminarSocketResource>,
+                               mut transport:     ResMut<TransportResource>,
+                               mut event_channel: EventWriter<NetworkSimulationEvent>) {
+    if exit.iter().count() == 0 || socket.sockets().is_empty() {
+        return;
+    }
+    let mut events = Vec::new();
+    flush_laminar(&mut socket, &mut transport, &mut events);
+    event_channel.send_batch(events);
+}
+
+/// Creates a new system flushing the laminar sockets with `flush_laminar`, run when the app leaves
+/// its state, see `LaminarPlugin::drop_socket_on_state_exit`.
+#[cfg(feature = "bevy")]
+pub fn laminar_state_exit_system(mut socket:        ResMut<LaminarSocketResource>,
+                                 mut transport:     ResMut<TransportResource>,
+                                 mut event_channel: EventWriter<NetworkSimulationEvent>) {
+    let mut events = Vec::new();
+    flush_laminar(&mut socket, &mut transport, &mut events);
+    event_channel.send_batch(events);
+}
+
+/// Polls the sockets and pushes the IO errors laminar ran into, see `laminar_network_poll_system`.
+pub fn poll_laminar(socket: &mut LaminarSocketResource, events: &mut Vec<NetworkSimulationEvent>) {
+    socket.report_unavailable(events);
+    let now = socket.now();
+    for socket in socket.sockets_mut() {
+        events.extend(poll_errors(socket, now).into_iter().map(NetworkSimulationEvent::from));
+    }
+}
+
+/// Pushes the events received by the previous polls, see `laminar_network_recv_system`.
+pub fn receive_laminar(socket: &mut LaminarSocketResource, events: &mut Vec<NetworkSimulationEvent>) {
+    socket.report_unavailable(events);
+    #[cfg(feature = "compression")]
+    let compressed = socket.compression.is_some();
+    let now = socket.now();
+    let LaminarSocketResource { sockets, middleware, coalesce, fragmentation, report_deliveries, .. } = socket;
+    for socket in sockets {
+        for (event, delivery) in received_events(socket, now) {
+            let event = match fragmentation {
+                Some(fragmenter) if delivery.is_some_and(|delivery| !is_reliable(delivery)) => {
+                    match fragmenter.receive_event(event, now) {
+                        Some(event) => event,
+                        None => continue,
+                    }
+                }
+                _ => event,
+            };
+            let Some(event) = middleware::on_recv(middleware, event) else {
+                continue;
+            };
+            #[cfg(feature = "compression")]
+            let event = if compressed { compression::decode_event(event) } else { event };
+            let received = if coalesce.is_some() { coalesce::deframe_event(event) } else { vec![event] };
+            for event in received {
+                let delivered = match (&event, delivery) {
+                    (TransportEvent::Message(addr, payload), Some(delivery)) if *report_deliveries => {
+                        Some(NetworkSimulationEvent::Delivered(*addr, payload.clone(), delivery))
+                    }
+                    _ => None,
+                };
+                events.push(event.into());
+                events.extend(delivered);
+            }
+        }
+    }
+}
+
+/// Creates a new laminar network poll system. IO errors laminar runs into while polling are
+/// emitted as `RecvError` when receiving and as `ConnectionError` when sending to a peer.
+#[cfg(feature = "bevy")]
+pub fn laminar_network_poll_system(mut socket:        ResMut<LaminarSocketResource>,
+                                   mut event_channel: EventWriter<NetworkSimulationEvent>,
+                                       time:          Res<Time>) {
+    if !socket.should_poll(time.delta()) {
+        return;
+    }
+    let mut events = Vec::new();
+    poll_laminar(&mut socket, &mut events);
+    event_channel.send_batch(events);
+}
+
+/// Creates a new laminar receive system. Besides the received packets, it emits a `Latency` event
+/// for every peer whose RTT estimate changed during the last poll, and refreshes the
+/// `ConnectionMetrics`.
+#[cfg(feature = "bevy")]
+pub fn laminar_network_recv_system(mut socket:        ResMut<LaminarSocketResource>,
+                                   mut event_channel: NetworkEventWriter,
+                                   mut metrics:       Option<ResMut<ConnectionMetrics>>,
+                                   #[cfg(feature = "diagnostics")]
+                                   mut stats:         Option<ResMut<NetworkStats>>) {
+    let mut events = Vec::new();
+    receive_laminar(&mut socket, &mut events);
+    if let Some(metrics) = metrics.as_mut() {
+        metrics.refresh(socket.sockets().iter().flat_map(LaminarSocket::connection_metrics));
+    }
+    #[cfg(feature = "diagnostics")]
+    if let Some(stats) = stats.as_mut() {
+        for event in &events {
+            if let NetworkSimulationEvent::Message(_, payload) = event {
+                stats.record_received(payload.len());
+            }
+        }
+    }
+    event_channel.send_batch(TransportId::LAMINAR, events);
+}
+
+/// Creates a new system sending the messages routed to each endpoint of `LaminarEndpoints` from its
+/// sockets, like `laminar_network_send_system` does.
+#[cfg(feature = "bevy")]
+pub fn laminar_endpoints_send_system(mut transport: ResMut<TransportResource>,
+                                 mut endpoints:     ResMut<LaminarEndpoints>,
+                                 mut event_channel: EventWriter<NetworkSimulationEvent>,
+                                     sim_time:      Res<NetworkSimulationTime>,
+                                 #[cfg(feature = "diagnostics")]
+                                 mut stats:         Option<ResMut<NetworkStats>>) {
+    #[cfg(feature = "diagnostics")]
+    let mut record_sent = |len| {
+        if let Some(stats) = stats.as_mut() {
+            stats.record_sent(len);
+        }
+    };
+    #[cfg(not(feature = "diagnostics"))]
+    let mut record_sent = |_| {};
+    let should_send = sim_time.should_send_message_now();
+    for (name, socket) in &mut endpoints.endpoints {
+        let route = TransportId(name);
+        let events = &mut event_channel;
+        send_routed_messages(&mut transport, socket, route, true, should_send, events, &mut record_sent);
+    }
+}
+
+/// Creates a new system polling the sockets of each endpoint of `LaminarEndpoints`.
+#[cfg(feature = "bevy")]
+pub fn laminar_endpoints_poll_system(mut endpoints:     ResMut<LaminarEndpoints>,
+                                     mut event_channel: EventWriter<NetworkSimulationEvent>,
+                                         time:          Res<Time>) {
+    let mut events = Vec::new();
+    for socket in endpoints.endpoints.values_mut() {
+        if socket.should_poll(time.delta()) {
+            poll_laminar(socket, &mut events);
+        }
+    }
+    event_channel.send_batch(events);
+}
+
+/// Creates a new system receiving on each endpoint of `LaminarEndpoints`, the events are tagged
+/// with the name of their endpoint.
+#[cfg(feature = "bevy")]
+pub fn laminar_endpoints_recv_system(mut endpoints:     ResMut<LaminarEndpoints>,
+                                     mut event_channel: NetworkEventWriter,
+                                     #[cfg(feature = "diagnostics")]
+                                     mut stats:         Option<ResMut<NetworkStats>>) {
+    for (name, socket) in &mut endpoints.endpoints {
+        let mut events = Vec::new();
+        receive_laminar(socket, &mut events);
+        #[cfg(feature = "diagnostics")]
+        if let Some(stats) = stats.as_mut() {
+            for event in &events {
+                if let NetworkSimulationEvent::Message(_, payload) = event {
+                    stats.record_received(payload.len());
+                }
+            }
+        }
+        event_channel.send_batch(TransportId(name), events);
+    }
+}
+
+/// Resource that owns the Laminar sockets, usually a single one, or one per address family when
+/// listening on both IPv4 and IPv6.
+#[derive(Default)]
+#[cfg_attr(feature = "bevy", derive(Resource))]
+pub struct LaminarSocketResource {
+    sockets:   Vec<LaminarSocket>,
+    broadcast: Broadcast,
+    coalesce:  Option<CoalescingOptions>,
+    fragmentation: Option<Fragmenter>,
+    ack_timeout:   Option<Duration>,
+    report_deliveries: bool,
+    #[cfg(feature = "compression")]
+    compression: Option<usize>,
+    middleware: Vec<Box<dyn PacketMiddleware>>,
+    poll_interval: Option<Duration>,
+    poll_elapsed:  Duration,
+    manual_time:   Option<Instant>,
+    unavailable:   bool,
+}
+
+impl LaminarSocketResource {
+    /// Creates a new instance of the `LaminarSocketResource`.
+    #[must_use]
+    pub fn new(socket: Option<LaminarSocket>) -> Self {
+        Self { sockets: socket.into_iter().collect(), ..Self::default() }
+    }
+
+    /// Returns a reference to the first socket if there is one configured.
+    #[must_use]
+    pub fn get(&self) -> Option<&LaminarSocket> {
+        self.sockets.first()
+    }
+
+    /// Returns a mutable reference to the first socket if there is one configured.
+    pub fn get_mut(&mut self) -> Option<&mut LaminarSocket> {
+        self.sockets.first_mut()
+    }
+
+    /// Returns true if messages to broadcast addresses are sent.
+    #[must_use]
+    pub fn allows_broadcast(&self) -> bool {
+        self.broadcast.allowed
+    }
+
+    /// Packs the messages of the same destination and delivery requirement given to
+    /// `send_messages` into as few packets as possible, as long as each fits in a single one, and
+    /// splits the received payloads again. Only compatible deliveries are packed together. Every
+    /// payload is framed with its length meanwhile, so the peers must enable it too, and a received
+    /// payload which isn't framed is dropped and reported as a `RecvError`.
+    pub fn set_coalescing(&mut self, coalesce: bool) {
+        self.coalesce = coalesce.then(CoalescingOptions::default);
+    }
+
+    /// Same as `set_coalescing`, with the given options rather than the default ones.
+    pub fn set_coalescing_options(&mut self, options: CoalescingOptions) {
+        self.coalesce = Some(options);
+    }
+
+    /// Returns the options of the coalescing, if the messages are coalesced.
+    #[must_use]
+    pub fn coalescing_options(&self) -> Option<CoalescingOptions> {
+        self.coalesce
+    }
+
+    /// Returns true if the messages are coalesced, see `set_coalescing`.
+    #[must_use]
+    pub fn coalesces(&self) -> bool {
+        self.coalesce.is_some()
+    }
+
+    /// Splits the unreliable payloads too large for a single packet into fragments, and reassembles
+    /// the received ones, or stops doing so with `None`. Every unreliable payload then carries a
+    /// header telling whether it is a fragment, so the peers must enable it too, and a received one
+    /// without it is dropped and reported as a `RecvError`. Changing the options discards the
+    /// payloads being reassembled.
+    pub fn set_fragmentation(&mut self, options: Option<FragmentationOptions>) {
+        self.fragmentation = options.map(Fragmenter::new);
+    }
+
+    /// Returns the options of the fragmentation, if the unreliable payloads are fragmented.
+    #[must_use]
+    pub fn fragmentation(&self) -> Option<FragmentationOptions> {
+        self.fragmentation.as_ref().map(Fragmenter::options)
+    }
+
+    /// Sets how long a message sent with `TransportResource::send_with_ack` waits to be
+    /// acknowledged, from the time it is sent, before a `MessageAckTimeout` event is emitted. A
+    /// tagged message not acknowledged by then gets no `Acked` event.
+    pub fn set_ack_timeout(&mut self, timeout: Duration) {
+        self.ack_timeout = Some(timeout);
+    }
+
+    /// Returns how long a message sent with an ack waits to be acknowledged, 5 seconds by default.
+    #[must_use]
+    pub fn ack_timeout(&self) -> Duration {
+        self.ack_timeout.unwrap_or(DEFAULT_ACK_TIMEOUT)
+    }
+
+    /// Emits a `Delivered` event, with the requirement the message was delivered with, after every
+    /// `Message` received, or stops doing so. Off by default.
+    pub fn set_delivery_reports(&mut self, report: bool) {
+        self.report_deliveries = report;
+    }
+
+    /// Returns true if the deliveries are reported, see `set_delivery_reports`.
+    #[must_use]
+    pub fn reports_deliveries(&self) -> bool {
+        self.report_deliveries
+    }
+
+    /// Compresses the sent payloads of at least `threshold` bytes, and decompresses the received
+    /// ones, or stops doing so with `None`. A received payload which can't be decompressed is
+    /// dropped and reported as a `RecvError`.
+    #[cfg(feature = "compression")]
+    pub fn set_compression(&mut self, threshold: Option<usize>) {
+        self.compression = threshold;
+    }
+
+    /// Returns the number of times per second the sockets are polled, if set.
+    #[must_use]
+    pub fn poll_rate(&self) -> Option<u16> {
+        self.poll_interval
+            .map(|interval| (Duration::from_secs(1).as_nanos() / interval.as_nanos()) as u16)
+    }
+
+    /// Polls the sockets `hz` times per second, independently of the frame rate, rather than at
+    /// every frame. Setting it to 0 goes back to that.
+    ///
+    /// Laminar only sends, resends and acknowledges packets when polled, and sends the heartbeats
+    /// at the first poll after their interval. Keep the rate at 10 Hz at least, and above the
+    /// heartbeat frequency if one is set, or the latency grows and quiet peers time out.
+    pub fn set_poll_rate(&mut self, hz: u16) {
+        self.poll_interval = (hz != 0).then(|| Duration::from_secs(1) / u32::from(hz));
+        self.poll_elapsed = Duration::from_secs(0);
+    }
+
+    /// Accumulates the time of a frame, returning true if the sockets are due for a poll. Time
+    /// left over after a poll carries over so polls don't drift, but the polls missed by a long
+    /// frame are merged into one.
+    pub fn should_poll(&mut self, delta: Duration) -> bool {
+        let Some(poll_interval) = self.poll_interval else {
+            return true;
+        };
+        self.poll_elapsed += delta;
+        if self.poll_elapsed < poll_interval {
+            return false;
+        }
+        self.poll_elapsed = Duration::from_nanos((self.poll_elapsed.as_nanos() % poll_interval.as_nanos()) as u64);
+        true
+    }
+
+    /// Returns the time the sockets are polled at, the manual time if one is set.
+    #[must_use]
+    pub fn now(&self) -> Instant {
+        self.manual_time.unwrap_or_else(Instant::now)
+    }
+
+    /// Polls the sockets at `time` rather than at the real time, or goes back to it with `None`.
+    /// Laminar's timeouts, resends and heartbeats then only follow the time set, e.g. advanced by a
+    /// fixed step per frame for a lockstep simulation, or by a test to time a peer out right away.
+    pub fn set_manual_time(&mut self, time: Option<Instant>) {
+        self.manual_time = time;
+    }
+
+    /// Advances the manual time, starting from the real time if none is set.
+    pub fn advance_manual_time(&mut self, duration: Duration) {
+        self.manual_time = Some(self.now() + duration);
+    }
+
+    /// Adds a middleware, see `LaminarPlugin::with_middleware`.
+    pub fn add_middleware(&mut self, middleware: Box<dyn PacketMiddleware>) {
+        self.middleware.push(middleware);
+    }
+
+    /// Returns the address the first socket is bound to, with the port the OS chose if any.
+    #[must_use]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.get().and_then(|socket| socket.local_addr().ok())
+    }
+
+    /// Returns the addresses all the sockets are bound to.
+    #[must_use]
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.sockets.iter().filter_map(|socket| socket.local_addr().ok()).collect()
+    }
+
+    /// Drains the events the sockets received since their last poll, as laminar emits them, for
+    /// systems handling them without `NetworkSimulationEvent`s. The middleware, the compression and
+    /// the coalescing don't apply to them. Each event is only received once: the ones drained here
+    /// aren't emitted by `laminar_network_recv_system`, nor pushed by `receive_laminar`.
+    pub fn drain_events(&mut self) -> Vec<SocketEvent> {
+        self.sockets.iter_mut().flat_map(|socket| std::iter::from_fn(|| socket.recv())).collect()
+    }
+
+    /// Returns all the configured sockets.
+    #[must_use]
+    pub fn sockets(&self) -> &[LaminarSocket] {
+        &self.sockets
+    }
+
+    /// Returns mutable references to all the configured sockets.
+    pub fn sockets_mut(&mut self) -> &mut [LaminarSocket] {
+        &mut self.sockets
+    }
+
+    /// Returns the socket messages to `destination` are sent from, the first one of the same
+    /// address family.
+    pub fn get_for_destination_mut(&mut self, destination: &SocketAddr) -> Option<&mut LaminarSocket> {
+        let index = self.socket_index_for(destination)?;
+        Some(&mut self.sockets[index])
+    }
+
+    fn socket_index_for(&self, destination: &SocketAddr) -> Option<usize> {
+        self.sockets.iter().position(|socket| {
+            socket.local_addr().is_ok_and(|addr| addr.is_ipv4() == destination.is_ipv4())
+        })
+    }
+
+    /// Sends the message from the socket of the same address family as its destination, handing it
+    /// back along with the error on failure. Messages to broadcast addresses are written straight
+    /// to the socket. A message vetoed by a middleware is dropped as if it was sent. The systems
+    /// report an IO error as a `SendError`, any other as a `ProtocolError`.
+    // laminar's `ErrorKind` is large, but errors are rare and the message is handed back anyway
+    #[allow(clippy::result_large_err)]
+    pub fn send_message(&mut self, mut message: Message) -> Result<(), (ErrorKind, Message)> {
+        let payload = if self.coalesces() {
+            coalesce::frame([&message.payload[..]])
+        } else {
+            message.payload.clone()
+        };
+        self.send_payload(&mut message, payload).map_err(|e| (e, message))
+    }
+
+    /// Sends the messages like `send_message`, packing them into fewer packets if coalescing is
+    /// enabled, see `set_coalescing`. Returns the outcome of each message, in the order given. Every
+    /// message of a packet which couldn't be sent gets the error.
+    // see `send_message`
+    #[allow(clippy::result_large_err)]
+    pub fn send_messages(&mut self, mut messages: Vec<Message>) -> Vec<Result<(), (ErrorKind, Message)>> {
+        let Some(options) = self.coalesce else {
+            return messages.into_iter().map(|message| self.send_message(message)).collect();
+        };
+        let batches = coalesce::batches(&messages, options.mix_streams, |message| {
+            let Some(index) = self.socket_index_for(&message.destination) else {
+                return 0;
+            };
+            let socket = &self.sockets[index];
+            match options.max_size {
+                Some(max_size) if is_reliable(message.delivery) => max_size.min(socket.max_reliable_payload_size()),
+                Some(max_size) => max_size.min(socket.max_unreliable_payload_size()),
+                None => socket.max_unreliable_payload_size(),
+            }
+        });
+        let mut results: Vec<_> = (0..messages.len()).map(|_| Ok(())).collect();
+        for batch in batches {
+            let payload = coalesce::frame(batch.iter().map(|index| &messages[*index].payload[..]));
+            if let Err(e) = self.send_payload(&mut messages[batch[0]], payload) {
+                let e = into_io_error(e);
+                for index in batch {
+                    results[index] = Err(ErrorKind::IOError(io::Error::new(e.kind(), e.to_string())));
+                }
+            }
+        }
+        messages
+            .into_iter()
+            .zip(results)
+            .map(|(message, result)| result.map_err(|e| (e, message)))
+            .collect()
+    }
+
+    /// Sends `payload` to the destination of `message`, with its delivery requirement, tag and ack. The
+    /// message lets go of its own payload once it is sent as is.
+    fn send_payload(&mut self, message: &mut Message, payload: Bytes) -> Result<(), ErrorKind> {
+        let is_broadcast = broadcast::is_broadcast(&message.destination, &self.broadcast.subnets);
+        let allowed = self.broadcast.allowed;
+        let Some(index) = self.socket_index_for(&message.destination) else {
+            let e = io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no laminar socket of the address family of {}", message.destination),
+            );
+            return Err(e.into());
+        };
+        #[cfg(feature = "compression")]
+        let payload = match self.compression {
+            Some(_) if message.compress => compression::encode(&payload, 0),
+            Some(threshold) => compression::encode(&payload, threshold),
+            None => payload,
+        };
+        let payload = match middleware::on_send(&mut self.middleware, message.destination, payload) {
+            Ok(Some(payload)) => payload,
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let deadline = self.now() + self.ack_timeout();
+        let socket = &mut self.sockets[index];
+        if let Some(id) = message.ack.filter(|_| is_broadcast || !is_reliable(message.delivery)) {
+            socket.time_out_message_ack(id);
+        }
+        let payload = match &mut self.fragmentation {
+            Some(fragmenter) if !is_reliable(message.delivery) => {
+                // a broadcast isn't fragmented, it only gets the header
+                let max_size = if is_broadcast { usize::MAX } else { socket.max_unreliable_payload_size() };
+                let mut payloads = fragmenter.split(&payload, max_size)?;
+                if payloads.len() > 1 {
+                    for payload in payloads {
+                        LaminarSocket::send(socket, create_packet(message.destination, payload, message.delivery))?;
+                    }
+                    return Ok(());
+                }
+                payloads.remove(0)
+            }
+            _ => payload,
+        };
+        if is_broadcast {
+            return send_broadcast(socket, allowed, message.destination, &payload, message.delivery)
+                .map_err(ErrorKind::from);
+        }
+        check_payload_size(socket, &payload, message.delivery)?;
+        if let Some(tag) = message.tag.filter(|_| is_reliable(message.delivery)) {
+            socket.expect_ack(message.destination, tag, &payload, deadline);
+        }
+        if let Some(id) = message.ack.filter(|_| is_reliable(message.delivery)) {
+            socket.expect_message_ack(message.destination, id, &payload, deadline);
+        }
+        // nothing fails from here on, the payload moves into the packet unless something else, e.g.
+        // the other messages of a broadcast, still holds it
+        if message.payload.as_ptr() == payload.as_ptr() {
+            message.payload = Bytes::new();
+        }
+        LaminarSocket::send(socket, create_packet(message.destination, payload, message.delivery))
+    }
+
+    /// Adds a socket next to the configured ones.
+    pub fn add_socket(&mut self, socket: LaminarSocket) {
+        self.sockets.push(socket);
+    }
+
+    /// Sets the socket, dropping the previous ones if any.
+    pub fn set_socket(&mut self, socket: LaminarSocket) {
+        self.sockets = vec![socket];
+    }
+
+    /// Returns true if there is a socket to send and receive with. The systems emit a
+    /// `SocketUnavailable` event when this becomes false.
+    #[must_use]
+    pub fn is_available(&self) -> bool {
+        !self.sockets.is_empty()
+    }
+
+    /// Returns true the first time it is called without any socket configured, and again after a
+    /// socket was configured then dropped.
+    fn became_unavailable(&mut self) -> bool {
+        if self.is_available() {
+            self.unavailable = false;
+            return false;
+        }
+        !std::mem::replace(&mut self.unavailable, true)
+    }
+
+    /// Pushes a `SocketUnavailable` event if there just became no socket to use.
+    fn report_unavailable(&mut self, events: &mut Vec<NetworkSimulationEvent>) {
+        if self.became_unavailable() {
+            events.push(NetworkSimulationEvent::SocketUnavailable);
+        }
+    }
+
+    /// Drops the sockets, if there are any configured.
+    pub fn drop_socket(&mut self) {
+        self.sockets.clear();
+    }
+
+    /// Binds a new socket to `addr` and swaps it in place of the current ones. On failure the
+    /// current sockets are left untouched.
+    pub fn rebind(&mut self, addr: SocketAddr, config: LaminarConfig) -> Result<(), ErrorKind> {
+        self.set_socket(LaminarSocket::bind_with_config(addr, config)?);
+        Ok(())
+    }
+
+    /// Swaps in a socket using the already bound `socket`. On failure the current sockets are left
+    /// untouched.
+    pub fn set_from_std_socket(&mut self, socket: UdpSocket, config: LaminarConfig) -> Result<(), ErrorKind> {
+        self.set_socket(LaminarSocket::from_std_socket(socket, config)?);
+        Ok(())
+    }
+}
+
+/// Resource that owns the sockets of the endpoints added with `LaminarPlugin::named`, by name.
+#[cfg(feature = "bevy")]
+#[derive(Default, Resource)]
+pub struct LaminarEndpoints {
+    endpoints: HashMap<&'static str, LaminarSocketResource>,
+}
+
+#[cfg(feature = "bevy")]
+impl LaminarEndpoints {
+    /// Returns the sockets of the endpoint, if there is one of that name.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&LaminarSocketResource> {
+        self.endpoints.get(name)
+    }
+
+    /// Returns the sockets of the endpoint mutably, if there is one of that name.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut LaminarSocketResource> {
+        self.endpoints.get_mut(name)
+    }
+
+    /// Returns the names of the endpoints.
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.endpoints.keys().copied()
+    }
+}
+
+#[cfg(all(test, feature = "bevy"))]
+mod tests {
+    use std::net::UdpSocket;
+
+    use bevy::ecs::event::Events;
+    use laminar::{DatagramSocket, DeliveryGuarantee, OrderingGuarantee};
+
+    use super::*;
+    use crate::simulation::requirements::UrgencyRequirement;
+
+    #[test]
+    fn test_packet_guarantees_for_each_delivery_requirement() {
+        use DeliveryRequirement::{
+            Default, Reliable, ReliableOrdered, ReliableSequenced, ReliableUnordered, Unreliable,
+            UnreliableSequenced,
+        };
+        let expectations = [
+            (Unreliable, DeliveryGuarantee::Unreliable, OrderingGuarantee::None),
+            (UnreliableSequenced(Some(1)), DeliveryGuarantee::Unreliable, OrderingGuarantee::Sequenced(Some(1))),
+            (Reliable, DeliveryGuarantee::Reliable, OrderingGuarantee::None),
+            (ReliableUnordered, DeliveryGuarantee::Reliable, OrderingGuarantee::None),
+            (ReliableSequenced(Some(2)), DeliveryGuarantee::Reliable, OrderingGuarantee::Sequenced(Some(2))),
+            (ReliableOrdered(Some(3)), DeliveryGuarantee::Reliable, OrderingGuarantee::Ordered(Some(3))),
+            (Default, DeliveryGuarantee::Reliable, OrderingGuarantee::Ordered(None)),
+        ];
+
+        for (requirement, delivery, ordering) in expectations {
+            let message = Message::new(
+                "127.0.0.1:3000".parse().unwrap(),
+                Bytes::from_static(b"test"),
+                requirement,
+                UrgencyRequirement::OnTick,
+            );
+            let packet = create_packet(message.destination, message.payload.clone(), message.delivery);
+            assert_eq!(packet.delivery_guarantee(), delivery, "{:?}", requirement);
+            assert_eq!(packet.order_guarantee(), ordering, "{:?}", requirement);
+            assert_eq!(packet.payload(), b"test");
+        }
+    }
+
+    #[test]
+    fn test_disconnect_reasons() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let reasons = [
+            (SocketEvent::Disconnect(addr), DisconnectReason::Closed),
+            (SocketEvent::Timeout(addr), DisconnectReason::Timeout),
+        ];
+        for (event, expected) in reasons {
+            let event = transport_event(event).map(NetworkSimulationEvent::from);
+            assert!(
+                matches!(event, Some(NetworkSimulationEvent::Disconnect(a, reason)) if a == addr && reason == expected),
+                "{:?}",
+                event,
+            );
+        }
+        assert!(transport_event(SocketEvent::Packet(Packet::unreliable(
+            addr,
+            socket::PUNCH_PAYLOAD.to_vec()
+        )))
+        .is_none());
+    }
+
+    #[test]
+    fn test_create_packet_only_copies_shared_payloads() {
+        const PEERS: usize = 100;
+        let destination = "127.0.0.1:3000".parse().unwrap();
+        let delivery = DeliveryRequirement::ReliableOrdered(None);
+        // a payload of a single message moves into its packet
+        let snapshot = Bytes::from(vec![0u8; 256]);
+        let allocations = counting_allocator::count(|| drop(create_packet(destination, snapshot, delivery)));
+        assert_eq!(allocations, 0);
+
+        // the payload of a broadcast to 100 peers, which laminar needs a buffer of its own for in
+        // every packet: at most one copy per peer but the last one, which gets the buffer itself
+        let payloads: Vec<_> = std::iter::repeat_n(Bytes::from(vec![0u8; 256]), PEERS).collect();
+        let allocations = counting_allocator::count(|| {
+            for payload in payloads {
+                drop(create_packet(destination, payload, delivery));
+            }
+        });
+        assert!(allocations < PEERS, "{} allocations", allocations);
+    }
+
+    #[test]
+    fn test_sending_in_place_does_not_allocate() {
+        const MESSAGES: usize = 1000;
+        let destinations: Vec<SocketAddr> = (0..10)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], 3000 + port)))
+            .collect();
+        let payload = Bytes::from_static(b"position");
+        let mut transport = TransportResource::new();
+        let queue = |transport: &mut TransportResource| {
+            for i in 0..MESSAGES {
+                transport.send_bytes(
+                    destinations[i % destinations.len()],
+                    payload.clone(),
+                    DeliveryRequirement::Unreliable,
+                    UrgencyRequirement::OnTick,
+                );
+            }
+        };
+        // the queues and the scratch space grow on the first frame only
+        queue(&mut transport);
+        transport.for_each_message_to_send(TransportId::LAMINAR, |_| true, |_| Disposition::Done);
+
+        queue(&mut transport);
+        let drained = counting_allocator::count(|| {
+            drop(transport.drain_messages_to_send_fairly(TransportId::LAMINAR, |_| true));
+        });
+        queue(&mut transport);
+        let listed = counting_allocator::count(|| assert_eq!(transport.get_messages().count(), MESSAGES));
+        assert_eq!(listed, 0);
+        let mut sent = 0;
+        let in_place = counting_allocator::count(|| {
+            transport.for_each_message_to_send(TransportId::LAMINAR, |_| true, |_| {
+                sent += 1;
+                Disposition::Done
+            });
+        });
+        assert_eq!(sent, MESSAGES);
+        assert!(drained > 0);
+        assert_eq!(in_place, 0, "{} allocations were {} with the drain", in_place, drained);
+    }
+
+    #[test]
+    fn test_poll_errors_are_emitted_as_events() {
+        let mut app = App::new();
+        app.add_event::<NetworkSimulationEvent>()
+            .insert_resource(LaminarSocketResource::new(Some(LaminarSocket::with_datagram_socket(
+                FaultySocket,
+                LaminarConfig::default(),
+            ))))
+            .init_resource::<bevy::time::Time>()
+            .add_system(laminar_network_poll_system);
+
+        app.update();
+
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        let errors: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .map(|event| match event {
+                NetworkSimulationEvent::RecvError(e) => e.kind(),
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect();
+        assert_eq!(errors, vec![io::ErrorKind::ConnectionReset]);
+    }
+
+    #[test]
+    fn test_poll_rate_is_independent_of_frame_rate() {
+        let mut socket = LaminarSocketResource::new(Some(LaminarSocket::with_datagram_socket(
+            FaultySocket,
+            LaminarConfig::default(),
+        )));
+        socket.set_poll_rate(50);
+        assert_eq!(socket.poll_rate(), Some(50));
+        let mut app = App::new();
+        app.add_event::<NetworkSimulationEvent>()
+            .insert_resource(socket)
+            .init_resource::<bevy::time::Time>()
+            .add_system(laminar_network_poll_system);
+        app.world.resource_mut::<bevy::time::Time>().update();
+
+        // the faulty socket errors once per poll, a second of frames at 250 FPS
+        let mut reader = app.world.resource::<Events<NetworkSimulationEvent>>().get_reader();
+        let mut polls = 0;
+        for _ in 0..250 {
+            let mut time = app.world.resource_mut::<bevy::time::Time>();
+            let last_update = time.last_update().unwrap();
+            time.update_with_instant(last_update + Duration::from_millis(4));
+            app.update();
+            polls += reader.iter(app.world.resource::<Events<NetworkSimulationEvent>>()).count();
+        }
+        assert_eq!(polls, 50);
+
+        let plugin = LaminarPlugin::builder().poll_rate(30).build();
+        assert_eq!(plugin.socket_resource(&mut App::new()).poll_rate(), Some(30));
+    }
+
+    #[test]
+    fn test_builder() {
+        let address = "127.0.0.1:3000".parse().unwrap();
+        let plugin = LaminarPlugin::builder()
+            .address(address)
+            .idle_timeout(Duration::from_secs(10))
+            .heartbeat_interval(Duration::from_millis(500))
+            .max_packet_size(4096)
+            .build();
+
+        assert!(matches!(&plugin.binding, Binding::Addresses(addresses) if addresses == &[address]));
+        assert_eq!(plugin.config.idle_connection_timeout, Duration::from_secs(10));
+        assert_eq!(plugin.config.heartbeat_interval, Some(Duration::from_millis(500)));
+        assert_eq!(plugin.config.max_packet_size, 4096);
+        assert_eq!(plugin.config.fragment_size, LaminarConfig::default().fragment_size);
+    }
+
+    #[test]
+    fn test_client_reports_chosen_port() {
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::client());
+        let addr = app.world.resource::<LaminarSocketResource>().local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        app.update();
+
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        let listening: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .filter_map(|event| match event {
+                NetworkSimulationEvent::Listening(addr) => Some(*addr),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(listening, vec![addr]);
+    }
+
+    #[test]
+    fn test_named_endpoints_send_their_messages() {
+        let mut sender = App::new();
+        sender.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::named("game", "127.0.0.1:0".parse().unwrap(), LaminarConfig::default()))
+            .add_plugin(LaminarPlugin::named("voice", "127.0.0.1:0".parse().unwrap(), LaminarConfig::default()));
+        let mut receiver = App::new();
+        receiver.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default()));
+        let receiver_addr = receiver.world.resource::<LaminarSocketResource>().local_addr().unwrap();
+        let endpoints = sender.world.resource::<LaminarEndpoints>();
+        let game_addr = endpoints.get("game").unwrap().local_addr().unwrap();
+        let voice_addr = endpoints.get("voice").unwrap().local_addr().unwrap();
+        assert_ne!(game_addr, voice_addr);
+
+        let mut transport = sender.world.resource_mut::<TransportResource>();
+        for (name, payload) in [("game", b"game"), ("voice", b"talk")] {
+            transport.send_via(
+                TransportId(name),
+                receiver_addr,
+                payload,
+                DeliveryRequirement::Reliable,
+                UrgencyRequirement::Immediate,
+            );
+        }
+        transport.send(receiver_addr, b"unrouted");
+
+        let mut received = Vec::new();
+        let mut reader = receiver.world.resource::<Events<NetworkSimulationEvent>>().get_reader();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while received.len() < 2 && Instant::now() < deadline {
+            sender.update();
+            receiver.update();
+            let events = receiver.world.resource::<Events<NetworkSimulationEvent>>();
+            received.extend(reader.iter(events).filter_map(|event| match event {
+                NetworkSimulationEvent::Message(addr, payload) => Some((*addr, payload.clone())),
+                _ => None,
+            }));
+        }
+
+        received.sort();
+        let mut expected = vec![(game_addr, Bytes::from_static(b"game")), (voice_addr, Bytes::from_static(b"talk"))];
+        expected.sort();
+        assert_eq!(received, expected);
+        assert_eq!(sender.world.resource::<TransportResource>().get_messages().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_socket_options_let_servers_share_a_port() {
+        let options = SocketOptions { reuse_address: true, reuse_port: true, ..SocketOptions::default() };
+        let mut first = App::new();
+        first.add_plugin(LaminarPlugin::builder()
+            .address("127.0.0.1:0".parse().unwrap())
+            .socket_options(options)
+            .build());
+        let addr = local_addr(&first);
+
+        let mut second = App::new();
+        second.add_plugin(LaminarPlugin::new(addr, LaminarConfig::default()).with_socket_options(options));
+        assert_eq!(second.world.resource::<LaminarSocketResource>().local_addr(), Some(addr));
+        let events = second.world.resource::<Events<NetworkSimulationEvent>>();
+        assert!(!events
+            .get_reader()
+            .iter(events)
+            .any(|event| matches!(event, NetworkSimulationEvent::ConnectionError(..))));
+    }
+
+    #[test]
+    fn test_endpoints_next_to_the_main_socket() {
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut server = App::new();
+        server.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default()))
+            .add_plugin(LaminarPlugin::named("query", "127.0.0.1:0".parse().unwrap(), LaminarConfig::default()))
+            .add_plugin(LaminarPlugin::named("admin", taken.local_addr().unwrap(), LaminarConfig::default()));
+        let mut client = create_test_app();
+        let client_addr = local_addr(&client);
+        let query_addr = server.world.resource::<LaminarEndpoints>().get("query").unwrap().local_addr().unwrap();
+        assert!(server.world.resource::<LaminarEndpoints>().get("admin").unwrap().sockets().is_empty());
+
+        let mut transport = client.world.resource_mut::<TransportResource>();
+        transport.send_with_requirements(
+            local_addr(&server),
+            b"input",
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::Immediate,
+        );
+        transport.send_with_requirements(
+            query_addr,
+            b"status?",
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::Immediate,
+        );
+
+        let mut received = Vec::new();
+        let mut reader = server.world.resource::<Events<TaggedNetworkEvent>>().get_reader();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while received.len() < 2 && Instant::now() < deadline {
+            client.update();
+            server.update();
+            let events = server.world.resource::<Events<TaggedNetworkEvent>>();
+            received.extend(
+                reader
+                    .iter(events)
+                    .filter(|event| matches!(event, TaggedNetworkEvent::Message(..)))
+                    .cloned(),
+            );
+        }
+
+        received.sort_by_key(|event| event.transport().0);
+        assert_eq!(received, [
+            TaggedNetworkEvent::Message(TransportId::LAMINAR, client_addr, Bytes::from_static(b"input")),
+            TaggedNetworkEvent::Message(TransportId("query"), client_addr, Bytes::from_static(b"status?")),
+        ]);
+
+        // the simulation time advances once per frame, not once per plugin
+        let mut time = server.world.resource_mut::<bevy::time::Time>();
+        time.update();
+        let last_update = time.last_update().unwrap();
+        time.update_with_instant(last_update + Duration::from_millis(50));
+        let frame_number = server.world.resource::<NetworkSimulationTime>().frame_number();
+        server.update();
+        assert_eq!(server.world.resource::<NetworkSimulationTime>().frame_number(), frame_number + 1);
+    }
+
+    #[test]
+    fn test_flush_on_exit() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(
+                LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default())
+                    .flush_on_exit(true),
+            );
+        app.update();
+
+        let mut transport = app.world.resource_mut::<TransportResource>();
+        for _ in 0..3 {
+            transport.send_with_requirements(
+                receiver.local_addr().unwrap(),
+                b"goodbye",
+                DeliveryRequirement::ReliableOrdered(None),
+                UrgencyRequirement::OnTick,
+            );
+        }
+        // the messages are waiting for the next tick when the app exits
+        app.world.resource_mut::<NetworkSimulationTime>().set_sim_frame_rate(1);
+        app.world.send_event(AppExit);
+        app.update();
+
+        assert!(!app.world.resource::<TransportResource>().has_messages());
+        assert!(app.world.resource::<LaminarSocketResource>().sockets().is_empty());
+        let mut datagram = [0; 64];
+        for _ in 0..3 {
+            let (len, _) = receiver.recv_from(&mut datagram).unwrap();
+            assert!(datagram[..len].ends_with(b"goodbye"));
+        }
+    }
+
+    #[test]
+    fn test_pump_without_bevy() {
+        let mut sockets: Vec<_> = (0..2)
+            .map(|_| {
+                let socket = LaminarSocket::bind_with_config("127.0.0.1:0", LaminarConfig::default()).unwrap();
+                LaminarSocketResource::new(Some(socket))
+            })
+            .collect();
+        let receiver_addr = sockets[1].local_addr().unwrap();
+        let mut transports = [TransportResource::new(), TransportResource::new()];
+        transports[0].send(receiver_addr, b"test");
+        transports[0].send_via(
+            TransportId::TCP,
+            receiver_addr,
+            b"elsewhere",
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::Immediate,
+        );
+
+        let mut events = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !events
+            .iter()
+            .any(|event| matches!(event, NetworkSimulationEvent::Message(..)))
+            && Instant::now() < deadline
+        {
+            pump_laminar(&mut sockets[0], &mut transports[0], &mut Vec::new());
+            pump_laminar(&mut sockets[1], &mut transports[1], &mut events);
+        }
+
+        let sender_addr = sockets[0].local_addr().unwrap();
+        assert!(events.iter().any(|event| matches!(
+            event,
+            NetworkSimulationEvent::Message(addr, payload) if *addr == sender_addr && payload == &b"test"[..]
+        )));
+        assert!(transports[0].has_messages());
+    }
+
+    #[test]
+    fn test_heartbeats_are_reported() {
+        let mut apps: Vec<_> = (0..2)
+            .map(|_| {
+                let mut app = App::new();
+                app.init_resource::<bevy::time::Time>()
+                    .add_plugin(LaminarPlugin::builder()
+                        .address("127.0.0.1:0".parse().unwrap())
+                        .heartbeat_interval(Duration::from_millis(10))
+                        .build());
+                app
+            })
+            .collect();
+        let addrs: Vec<_> = apps.iter().map(local_addr).collect();
+        // heartbeats are only sent on established connections
+        for (app, destination) in apps.iter_mut().zip(addrs.iter().rev()) {
+            app.world.resource_mut::<TransportResource>().send_with_requirements(
+                *destination,
+                b"hello",
+                DeliveryRequirement::Unreliable,
+                UrgencyRequirement::Immediate,
+            );
+        }
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(1);
+        let mut heartbeat = None;
+        while heartbeat.is_none() && std::time::Instant::now() < deadline {
+            for app in &mut apps {
+                app.update();
+            }
+            let events = apps[0].world.resource::<Events<NetworkSimulationEvent>>();
+            heartbeat = events.get_reader().iter(events).find_map(|event| match event {
+                NetworkSimulationEvent::Heartbeat(addr) => Some(*addr),
+                _ => None,
+            });
+        }
+        assert_eq!(heartbeat, Some(addrs[1]));
+    }
+
+    #[test]
+    fn test_rebind_swaps_socket() {
+        let mut resource = LaminarSocketResource::new(Some(LaminarSocket::bind_any().unwrap()));
+        let old_addr = resource.get().unwrap().local_addr().unwrap();
+
+        resource.rebind("127.0.0.1:0".parse().unwrap(), LaminarConfig::default()).unwrap();
+
+        let new_addr = resource.get().unwrap().local_addr().unwrap();
+        assert_ne!(new_addr, old_addr);
+    }
+
+    #[test]
+    fn test_failed_rebind_keeps_socket() {
+        let mut resource = LaminarSocketResource::new(Some(LaminarSocket::bind_any().unwrap()));
+        let addr = resource.get().unwrap().local_addr().unwrap();
+        let taken = LaminarSocket::bind_any().unwrap().local_addr().unwrap();
+        let _taken = LaminarSocket::bind(taken).unwrap();
+
+        assert!(resource.rebind(taken, LaminarConfig::default()).is_err());
+        assert_eq!(resource.get().unwrap().local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn test_local_addr_without_socket_is_none() {
+        let resource = LaminarSocketResource::default();
+
+        assert_eq!(resource.local_addr(), None);
+        assert!(resource.local_addrs().is_empty());
+    }
+
+    #[test]
+    fn test_plugin_from_socket_keeps_address() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::from_socket(socket, LaminarConfig::default()));
+        app.update();
+
+        assert_eq!(local_addr(&app), addr);
+    }
+
+    #[test]
+    fn test_plugin_from_laminar_socket_moves_it_into_the_resource() {
+        let socket = LaminarSocket::bind_any().unwrap();
+        let addr = socket.local_addr().unwrap();
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::from_laminar_socket(socket));
+        app.update();
+
+        let resource = app.world.resource::<LaminarSocketResource>();
+        assert_eq!(resource.sockets().len(), 1);
+        assert_eq!(resource.local_addr(), Some(addr));
+    }
+
+    #[test]
+    fn test_bind_failure_is_emitted_as_event() {
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap();
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::new(addr, LaminarConfig::default()));
+        app.update();
+
+        assert!(app.world.resource::<LaminarSocketResource>().get().is_none());
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        let errors: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .filter_map(|event| match event {
+                NetworkSimulationEvent::ConnectionError(e, addr) => Some((e.kind(), *addr)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors, vec![(io::ErrorKind::AddrInUse, Some(addr))]);
+    }
+
+    #[test]
+    fn test_connect_reports_connecting_then_connect() {
+        let mut server = create_test_app();
+        let mut client = create_test_app();
+        let server_addr = server.world.resource::<LaminarSocketResource>().local_addr().unwrap();
+        let mut transport = client.world.resource_mut::<TransportResource>();
+        transport.connect(server_addr);
+        transport.connect(server_addr);
+        assert!(transport.is_connecting(&server_addr));
+
+        let mut reader = client.world.resource::<Events<NetworkSimulationEvent>>().get_reader();
+        let mut received = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !received.contains(&format!("Connect({})", server_addr)) && Instant::now() < deadline {
+            client.update();
+            server.update();
+            let events = client.world.resource::<Events<NetworkSimulationEvent>>();
+            received.extend(reader.iter(events).filter_map(|event| match event {
+                NetworkSimulationEvent::Connecting(addr) => Some(format!("Connecting({})", addr)),
+                NetworkSimulationEvent::Connect(addr) => Some(format!("Connect({})", addr)),
+                _ => None,
+            }));
+        }
+        client.update();
+
+        assert_eq!(received, [format!("Connecting({})", server_addr), format!("Connect({})", server_addr)]);
+        let transport = client.world.resource::<TransportResource>();
+        assert!(!transport.is_connecting(&server_addr));
+        assert!(client.world.resource::<ConnectedPeers>().is_connected(&server_addr));
+    }
+
+    #[test]
+    fn test_socket_unavailable_is_emitted_once() {
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::new(taken.local_addr().unwrap(), LaminarConfig::default()));
+        let mut reader = app.world.resource::<Events<NetworkSimulationEvent>>().get_reader();
+        let mut unavailable = 0;
+        for _ in 0..3 {
+            app.world.resource_mut::<TransportResource>().send("127.0.0.1:3000".parse().unwrap(), b"input");
+            app.update();
+            let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+            unavailable += reader
+                .iter(events)
+                .filter(|event| matches!(event, NetworkSimulationEvent::SocketUnavailable))
+                .count();
+        }
+        assert_eq!(unavailable, 1);
+        assert!(!app.world.resource::<LaminarSocketResource>().is_available());
+
+        let mut resource = LaminarSocketResource::new(Some(LaminarSocket::bind_any().unwrap()));
+        let mut events = Vec::new();
+        receive_laminar(&mut resource, &mut events);
+        resource.drop_socket();
+        receive_laminar(&mut resource, &mut events);
+        receive_laminar(&mut resource, &mut events);
+        assert!(matches!(events[..], [NetworkSimulationEvent::SocketUnavailable]));
+    }
+
+    #[test]
+    fn test_drain_events_manually() {
+        let mut sender = LaminarSocket::bind_any().unwrap();
+        let mut resource = LaminarSocketResource::new(Some(LaminarSocket::bind_any().unwrap()));
+        let sender_addr = sender.local_addr().unwrap();
+        let receiver_addr = resource.local_addr().unwrap();
+        sender.send(Packet::reliable_unordered(receiver_addr, b"raw".to_vec())).unwrap();
+        sender.manual_poll(Instant::now());
+
+        let mut events = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while events.is_empty() && Instant::now() < deadline {
+            resource.get_mut().unwrap().manual_poll(Instant::now());
+            events = resource.drain_events();
+        }
+
+        let payloads: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                SocketEvent::Packet(packet) => Some((packet.addr(), packet.payload().to_vec())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(payloads, [(sender_addr, b"raw".to_vec())]);
+        assert!(resource.drain_events().is_empty());
+    }
+
+    #[test]
+    fn test_socket_polls_on_its_own_thread() {
+        let mut sender = LaminarSocket::bind_any().unwrap();
+        let mut receiver = LaminarSocket::bind_any().unwrap();
+        let (sender_addr, receiver_addr) = (sender.local_addr().unwrap(), receiver.local_addr().unwrap());
+        let packets = sender.get_packet_sender();
+        let events = receiver.get_event_receiver();
+        std::thread::spawn(move || sender.start_polling());
+        std::thread::spawn(move || receiver.start_polling_with_duration(None));
+
+        packets.send(Packet::reliable_unordered(receiver_addr, b"threaded".to_vec())).unwrap();
+        let received = std::iter::from_fn(|| events.recv_timeout(Duration::from_secs(2)).ok())
+            .find_map(|event| match event {
+                SocketEvent::Packet(packet) => Some((packet.addr(), packet.payload().to_vec())),
+                _ => None,
+            });
+        assert_eq!(received, Some((sender_addr, b"threaded".to_vec())));
+    }
+
+    #[test]
+    fn test_manual_time_drives_the_timeouts() {
+        let mut sender = LaminarSocket::bind_any().unwrap();
+        let mut resource = LaminarSocketResource::new(Some(LaminarSocket::bind_any().unwrap()));
+        let start = Instant::now();
+        resource.set_manual_time(Some(start));
+        let sender_addr = sender.local_addr().unwrap();
+        sender.send(Packet::unreliable(resource.local_addr().unwrap(), b"test".to_vec())).unwrap();
+        sender.manual_poll(start);
+
+        let mut events = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while events.is_empty() && Instant::now() < deadline {
+            poll_laminar(&mut resource, &mut events);
+            receive_laminar(&mut resource, &mut events);
+        }
+        assert!(
+            matches!(events[..], [NetworkSimulationEvent::Message(addr, _)] if addr == sender_addr),
+            "{:?}",
+            events
+        );
+
+        let timeout = LaminarConfig::default().idle_connection_timeout;
+        resource.advance_manual_time(timeout - Duration::from_secs(1));
+        let mut events = Vec::new();
+        poll_laminar(&mut resource, &mut events);
+        receive_laminar(&mut resource, &mut events);
+        assert!(events.is_empty(), "{:?}", events);
+
+        resource.advance_manual_time(Duration::from_secs(1));
+        poll_laminar(&mut resource, &mut events);
+        receive_laminar(&mut resource, &mut events);
+        assert!(matches!(
+            events[..],
+            [NetworkSimulationEvent::Disconnect(addr, DisconnectReason::Timeout)] if addr == sender_addr
+        ), "{:?}", events);
+        assert_eq!(resource.now(), start + timeout);
+    }
+
+    #[test]
+    fn test_set_from_std_socket() {
+        let mut resource = LaminarSocketResource::default();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        resource.set_from_std_socket(socket, LaminarConfig::default()).unwrap();
+        assert_eq!(resource.get().unwrap().local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn test_dual_stack_sends_from_matching_family() {
+        let mut server = App::new();
+        server.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::with_addresses(
+                ["127.0.0.1:0".parse().unwrap(), "[::1]:0".parse().unwrap()],
+                LaminarConfig::default(),
+            ));
+        let mut v4_client = create_test_app();
+        let mut v6_client = App::new();
+        v6_client.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::new("[::1]:0".parse().unwrap(), LaminarConfig::default()));
+        assert_eq!(server.world.resource::<LaminarSocketResource>().sockets().len(), 2);
+
+        for client in [&v4_client, &v6_client] {
+            server.world.resource_mut::<TransportResource>().send_with_requirements(
+                local_addr(client),
+                b"hello",
+                DeliveryRequirement::Unreliable,
+                UrgencyRequirement::Immediate,
+            );
+        }
+
+        for client in [&mut v4_client, &mut v6_client] {
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(1);
+            let mut received = false;
+            while !received && std::time::Instant::now() < deadline {
+                // the packets are only flushed by the next poll of the server
+                server.update();
+                client.update();
+                let events = client.world.resource::<Events<NetworkSimulationEvent>>();
+                received = events.get_reader().iter(events).any(|event| {
+                    matches!(event, NetworkSimulationEvent::Message(_, payload) if payload == "hello")
+                });
+            }
+            assert!(received, "nothing received by {}", local_addr(client));
+        }
+    }
+
+    #[test]
+    fn test_send_without_matching_family_is_an_error() {
+        let mut app = create_test_app();
+        app.world.resource_mut::<TransportResource>().send_with_requirements(
+            "[::1]:3000".parse().unwrap(),
+            b"test",
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::Immediate,
+        );
+        app.update();
+
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        let errors: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .filter_map(|event| match event {
+                NetworkSimulationEvent::SendError(e, _) => Some(e.kind()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors, vec![io::ErrorKind::AddrNotAvailable]);
+    }
+
+    #[test]
+    fn test_send_error_keeps_the_message_tag() {
+        let mut app = create_test_app();
+        let mut transport = app.world.resource_mut::<TransportResource>();
+        transport.send_with_tag("[::1]:3000".parse().unwrap(), b"test", DeliveryRequirement::Unreliable, 42);
+        transport.send("[::1]:3000".parse().unwrap(), b"untagged");
+        app.update();
+
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        let tags: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .filter_map(|event| match event {
+                NetworkSimulationEvent::SendError(_, message) => Some(message.tag),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(tags, vec![Some(42), None]);
+    }
+
+    #[test]
+    fn test_oversized_reliable_payload_is_refused() {
+        let mut app = create_test_app();
+        let max_size = app.world.resource::<LaminarSocketResource>().get().unwrap().max_reliable_payload_size();
+        let destination = "127.0.0.1:3000".parse().unwrap();
+        let mut transport = app.world.resource_mut::<TransportResource>();
+        for size in [max_size + 1, max_size] {
+            let payload = vec![0; size];
+            transport.send_with_requirements(
+                destination,
+                &payload,
+                DeliveryRequirement::Reliable,
+                UrgencyRequirement::Immediate,
+            );
+        }
+        app.update();
+
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        let errors: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .filter_map(|event| match event {
+                NetworkSimulationEvent::SendError(e, message) => Some((e.kind(), message.payload.len())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors, vec![(io::ErrorKind::InvalidInput, max_size + 1)]);
+    }
+
+    #[test]
+    fn test_errors_other_than_io_are_protocol_errors() {
+        let destination = "127.0.0.1:3000".parse().unwrap();
+        let message = Message::new(
+            destination,
+            Bytes::from_static(b"test"),
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::Immediate,
+        );
+        let event = send_error_event(ErrorKind::ProtocolVersionMismatch, message.clone());
+        assert!(
+            matches!(
+                event,
+                NetworkSimulationEvent::ProtocolError(ErrorKind::ProtocolVersionMismatch, m) if m == message
+            )
+        );
+
+        let event = send_error_event(io::Error::from(io::ErrorKind::PermissionDenied).into(), message);
+        assert!(
+            matches!(
+                event,
+                NetworkSimulationEvent::SendError(e, _) if e.kind() == io::ErrorKind::PermissionDenied
+            )
+        );
+    }
+
+    #[test]
+    fn test_oversized_unreliable_payload_is_refused() {
+        let mut app = create_test_app();
+        let max_size = app.world.resource::<LaminarSocketResource>().get().unwrap().max_unreliable_payload_size();
+        let destination = "127.0.0.1:3000".parse().unwrap();
+        let mut transport = app.world.resource_mut::<TransportResource>();
+        for (size, delivery) in [
+            (max_size + 1, DeliveryRequirement::Unreliable),
+            (max_size, DeliveryRequirement::Unreliable),
+            (max_size + 1, DeliveryRequirement::ReliableOrdered(None)),
+        ] {
+            transport.send_with_requirements(destination, &vec![0; size], delivery, UrgencyRequirement::Immediate);
+        }
+        app.update();
+
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        let errors: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .filter_map(|event| match event {
+                NetworkSimulationEvent::SendError(e, message) => Some((e.kind(), message.payload.len())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors, vec![(io::ErrorKind::InvalidInput, max_size + 1)]);
+    }
+
+    #[test]
+    fn test_coalesced_messages_arrive_split_again() {
+        let plugin = || {
+            LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default())
+                .with_coalescing(true)
+        };
+        let (mut sender, sent) = create_recording_app(plugin());
+        let mut receiver = App::new();
+        receiver.init_resource::<bevy::time::Time>().add_plugin(plugin());
+        let receiver_addr = local_addr(&receiver);
+        let payloads = [&b"move"[..], b"jump", b"fire", b"reload", b"crouch"];
+        let mut transport = sender.world.resource_mut::<TransportResource>();
+        for payload in payloads {
+            transport.send_reliable(receiver_addr, payload);
+        }
+
+        let mut reader = receiver.world.resource::<Events<NetworkSimulationEvent>>().get_reader();
+        let mut received = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while received.len() < payloads.len() && Instant::now() < deadline {
+            sender.update();
+            receiver.update();
+            let events = receiver.world.resource::<Events<NetworkSimulationEvent>>();
+            received.extend(reader.iter(events).filter_map(|event| match event {
+                NetworkSimulationEvent::Message(_, payload) => Some(payload.clone()),
+                _ => None,
+            }));
+        }
+
+        assert_eq!(received, payloads);
+        assert_eq!(sent.lock().unwrap().len(), 1, "the messages should go out in a single packet");
+    }
+
+    #[test]
+    fn test_relayed_peers_exchange_messages() {
+        let mut relay = RelayServer::bind("127.0.0.1:0").unwrap();
+        let config = RelayConfig {
+            relay_addr: relay.local_addr().unwrap(),
+            session_token: 42,
+        };
+        let mut peers: Vec<_> = (0..2)
+            .map(|_| {
+                let mut app = App::new();
+                app.init_resource::<bevy::time::Time>()
+                    .add_plugin(LaminarPlugin::builder().address("127.0.0.1:0".parse().unwrap()).relay(config).build());
+                app
+            })
+            .collect();
+        let addrs: Vec<_> = peers.iter().map(local_addr).collect();
+
+        for (peer, destination) in peers.iter_mut().zip(addrs.iter().rev()) {
+            peer.world.resource_mut::<TransportResource>().send_with_requirements(
+                *destination,
+                b"relayed",
+                DeliveryRequirement::ReliableOrdered(None),
+                UrgencyRequirement::Immediate,
+            );
+        }
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(1);
+        let mut received = [false, false];
+        while received.contains(&false) && std::time::Instant::now() < deadline {
+            for (peer, (received, source)) in peers.iter_mut().zip(received.iter_mut().zip(addrs.iter().rev())) {
+                peer.update();
+                relay.poll().unwrap();
+                let events = peer.world.resource::<Events<NetworkSimulationEvent>>();
+                *received |= events.get_reader().iter(events).any(|event| {
+                    matches!(
+                        event,
+                        NetworkSimulationEvent::Message(addr, payload) if addr == source && payload == "relayed"
+                    )
+                });
+            }
+        }
+        assert_eq!(received, [true, true]);
+    }
+
+    #[test]
+    fn test_oversized_unreliable_payloads_are_fragmented() {
+        let mut apps: Vec<_> = (0..2)
+            .map(|_| {
+                let mut app = App::new();
+                app.init_resource::<bevy::time::Time>()
+                    .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default())
+                        .with_fragmentation(FragmentationOptions::default()));
+                app
+            })
+            .collect();
+        let addrs: Vec<_> = apps.iter().map(local_addr).collect();
+        let snapshot: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let mut transport = apps[0].world.resource_mut::<TransportResource>();
+        transport.send_with_requirements(
+            addrs[1],
+            &snapshot,
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::Immediate,
+        );
+        transport.send_with_requirements(
+            addrs[1],
+            b"input",
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::Immediate,
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut received = Vec::new();
+        while received.len() < 2 && Instant::now() < deadline {
+            for app in &mut apps {
+                app.update();
+            }
+            let mut events = apps[1].world.resource_mut::<Events<NetworkSimulationEvent>>();
+            received.extend(events.drain().filter_map(|event| match event {
+                NetworkSimulationEvent::Message(addr, payload) if addr == addrs[0] => Some(payload),
+                _ => None,
+            }));
+        }
+        received.sort_by_key(Bytes::len);
+        assert_eq!(received, [Bytes::from_static(b"input"), Bytes::from(snapshot)]);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compressed_payloads_are_restored() {
+        let mut apps: Vec<_> = (0..2)
+            .map(|_| {
+                let mut app = App::new();
+                app.init_resource::<bevy::time::Time>()
+                    .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default())
+                        .with_compression(64));
+                app
+            })
+            .collect();
+        let addrs: Vec<_> = apps.iter().map(local_addr).collect();
+        let snapshot: Vec<u8> = (0..4096).map(|i| (i / 64) as u8).collect();
+        apps[0].world.resource_mut::<TransportResource>().send_with_requirements(
+            addrs[1],
+            &snapshot,
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::Immediate,
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut received = None;
+        while received.is_none() && Instant::now() < deadline {
+            for app in &mut apps {
+                app.update();
+            }
+            let events = apps[1].world.resource::<Events<NetworkSimulationEvent>>();
+            received = events.get_reader().iter(events).find_map(|event| match event {
+                NetworkSimulationEvent::Message(addr, payload) if *addr == addrs[0] => Some(payload.clone()),
+                _ => None,
+            });
+        }
+        // sent unreliably, the raw snapshot wouldn't fit in a single packet
+        assert_eq!(received.unwrap(), snapshot);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_messages_sent_compressed_ignore_the_threshold() {
+        let mut apps: Vec<_> = (0..2)
+            .map(|_| {
+                let mut app = App::new();
+                app.init_resource::<bevy::time::Time>()
+                    .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default())
+                        .with_compression(usize::MAX));
+                app
+            })
+            .collect();
+        let addrs: Vec<_> = apps.iter().map(local_addr).collect();
+        let state: Vec<u8> = b"entity:0;position:0,0;".iter().copied().cycle().take(8192).collect();
+        apps[0].world.resource_mut::<TransportResource>().send_compressed(
+            addrs[1],
+            &state,
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::Immediate,
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut received = None;
+        while received.is_none() && Instant::now() < deadline {
+            for app in &mut apps {
+                app.update();
+            }
+            let events = apps[1].world.resource::<Events<NetworkSimulationEvent>>();
+            received = events.get_reader().iter(events).find_map(|event| match event {
+                NetworkSimulationEvent::Message(addr, payload) if *addr == addrs[0] => Some(payload.clone()),
+                _ => None,
+            });
+        }
+        // no payload reaches the threshold, only the one sent compressed fits in a single packet
+        assert_eq!(received.unwrap(), state);
+    }
+
+    #[test]
+    fn test_middleware_transform_and_veto_payloads() {
+        use bytes::BytesMut;
+
+        /// Drops the payloads starting with "drop" when sending, fails on "bad" when receiving.
+        struct Filter;
+        impl PacketMiddleware for Filter {
+            fn on_send(
+                &mut self,
+                _: SocketAddr,
+                payload: &mut BytesMut,
+            ) -> Result<(), MiddlewareError> {
+                if payload.starts_with(b"drop") {
+                    return Err(MiddlewareError::Vetoed);
+                }
+                Ok(())
+            }
+
+            fn on_recv(&mut self, _: SocketAddr, payload: &mut BytesMut) -> Result<(), MiddlewareError> {
+                if &payload[..] == b"bad" {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "bad payload").into());
+                }
+                Ok(())
+            }
+        }
+
+        struct Xor;
+        impl PacketMiddleware for Xor {
+            fn on_send(&mut self, _: SocketAddr, payload: &mut BytesMut) -> Result<(), MiddlewareError> {
+                payload.iter_mut().for_each(|byte| *byte ^= 0x5a);
+                Ok(())
+            }
+
+            fn on_recv(
+                &mut self,
+                source: SocketAddr,
+                payload: &mut BytesMut,
+            ) -> Result<(), MiddlewareError> {
+                self.on_send(source, payload)
+            }
+        }
+
+        /// Appends a tag when sending, which must be the last byte when receiving.
+        struct Tag;
+        impl PacketMiddleware for Tag {
+            fn on_send(
+                &mut self,
+                _: SocketAddr,
+                payload: &mut BytesMut,
+            ) -> Result<(), MiddlewareError> {
+                payload.extend_from_slice(b"!");
+                Ok(())
+            }
+
+            fn on_recv(&mut self, _: SocketAddr, payload: &mut BytesMut) -> Result<(), MiddlewareError> {
+                match payload.last() {
+                    Some(b'!') => {
+                        payload.truncate(payload.len() - 1);
+                        Ok(())
+                    }
+                    _ => Err(io::Error::new(io::ErrorKind::InvalidData, "missing tag").into()),
+                }
+            }
+        }
+
+        let mut apps: Vec<_> = (0..2)
+            .map(|_| {
+                let mut app = App::new();
+                app.init_resource::<bevy::time::Time>()
+                    .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default())
+                        .with_middleware(Box::new(Filter))
+                        .with_middleware(Box::new(Xor))
+                        .with_middleware(Box::new(Tag)));
+                app
+            })
+            .collect();
+        let addrs: Vec<_> = apps.iter().map(local_addr).collect();
+        let mut transport = apps[0].world.resource_mut::<TransportResource>();
+        for payload in [&b"dropped"[..], b"bad", b"hello"] {
+            transport.send_with_requirements(
+                addrs[1],
+                payload,
+                DeliveryRequirement::ReliableOrdered(None),
+                UrgencyRequirement::Immediate,
+            );
+        }
+
+        let (mut received, mut errors) = (Vec::new(), Vec::new());
+        let mut reader = apps[1].world.resource::<Events<NetworkSimulationEvent>>().get_reader();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while received.is_empty() && Instant::now() < deadline {
+            for app in &mut apps {
+                app.update();
+            }
+            let events = apps[1].world.resource::<Events<NetworkSimulationEvent>>();
+            for event in reader.iter(events) {
+                match event {
+                    NetworkSimulationEvent::Message(_, payload) => received.push(payload.clone()),
+                    NetworkSimulationEvent::RecvError(e) => errors.push(e.to_string()),
+                    _ => {}
+                }
+            }
+        }
+
+        assert_eq!(received, [Bytes::from_static(b"hello")]);
+        assert_eq!(errors, ["bad payload"]);
+        let events = apps[0].world.resource::<Events<NetworkSimulationEvent>>();
+        assert!(!events.get_reader().iter(events).any(|event| matches!(event, NetworkSimulationEvent::SendError(..))));
+    }
+
+    #[test]
+    fn test_send_system_hands_higher_priorities_first() {
+        let (mut app, sent) = create_recording_app(LaminarPlugin::new(
+            "127.0.0.1:0".parse().unwrap(),
+            LaminarConfig::default(),
+        ));
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut transport = app.world.resource_mut::<TransportResource>();
+        transport.send_with_priority(addr, b"telemetry", DeliveryRequirement::Unreliable, 0);
+        transport.send_with_priority(addr, b"chat", DeliveryRequirement::Unreliable, Message::DEFAULT_PRIORITY);
+        transport.send_with_priority(addr, b"input", DeliveryRequirement::Unreliable, u8::MAX);
+        transport.send_with_priority(addr, b"snapshot", DeliveryRequirement::Unreliable, u8::MAX);
+        app.update();
+
+        assert_eq!(*sent.lock().unwrap(), ["input", "snapshot", "chat", "telemetry"]);
+    }
+
+    #[test]
+    fn test_disconnect_cancels_the_messages_left_by_the_drain() {
+        let plugin = LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default())
+            .cancel_on_disconnect(true);
+        let (mut app, sent) = create_recording_app(plugin);
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut transport = app.world.resource_mut::<TransportResource>();
+        for payload in [b"1", b"2", b"3"] {
+            transport.send(addr, payload);
+        }
+        // the send system may drain the queue before or after the disconnect is handled
+        app.world.send_event(NetworkSimulationEvent::Disconnect(addr, DisconnectReason::Timeout));
+        app.update();
+
+        let sent_before = sent.lock().unwrap().len();
+        assert!(sent_before == 0 || sent_before == 3, "{} sent", sent_before);
+        assert_eq!(app.world.resource::<TransportResource>().queued_for(&addr), 0);
+
+        // only the messages queued before the disconnect are cancelled
+        app.world.resource_mut::<TransportResource>().send(addr, b"4");
+        app.update();
+        assert_eq!(sent.lock().unwrap().last().map(|payload| &payload[..]), Some(&b"4"[..]));
+    }
+
+    /// Creates an app recording the payloads its laminar plugin sends, in order.
+    fn create_recording_app(plugin: LaminarPlugin) -> (App, std::sync::Arc<Mutex<Vec<Bytes>>>) {
+        use bytes::BytesMut;
+
+        struct Record(std::sync::Arc<Mutex<Vec<Bytes>>>);
+        impl PacketMiddleware for Record {
+            fn on_send(&mut self, _: SocketAddr, payload: &mut BytesMut) -> Result<(), MiddlewareError> {
+                self.0.lock().unwrap().push(Bytes::copy_from_slice(payload));
+                Ok(())
+            }
+        }
+
+        let sent = std::sync::Arc::default();
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(plugin.with_middleware(Box::new(Record(std::sync::Arc::clone(&sent)))));
+        (app, sent)
+    }
+
+    #[test]
+    fn test_immediate_messages_dont_wait_for_the_tick() {
+        let (mut app, sent) = create_recording_app(LaminarPlugin::new(
+            "127.0.0.1:0".parse().unwrap(),
+            LaminarConfig::default(),
+        ));
+        app.insert_resource(NetworkSimulationTime::default().with_rate(1));
+        let destination = "127.0.0.1:3000".parse().unwrap();
+        let mut transport = app.world.resource_mut::<TransportResource>();
+        transport.send_with_requirements(
+            destination,
+            b"chat",
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::OnTick,
+        );
+        transport.send_with_requirements(
+            destination,
+            b"input",
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::Immediate,
+        );
+
+        app.update();
+        assert_eq!(*sent.lock().unwrap(), [Bytes::from_static(b"input")]);
+        assert_eq!(app.world.resource::<TransportResource>().pending_len(), 1);
+
+        // without a send frequency, the first frame is a tick
+        app.insert_resource(NetworkSimulationTime::default());
+        app.update();
+        assert_eq!(*sent.lock().unwrap(), [Bytes::from_static(b"input"), Bytes::from_static(b"chat")]);
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+    enum AppState {
+        Menu,
+        InGame,
+    }
+
+    #[test]
+    fn test_systems_only_run_in_their_state() {
+        let (mut app, sent) = create_recording_app(
+            LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default()).in_state(AppState::InGame),
+        );
+        app.add_state(AppState::Menu);
+        let destination = "127.0.0.1:3000".parse().unwrap();
+        app.world.resource_mut::<TransportResource>().send_immediate(destination, b"in menu");
+        app.update();
+        assert!(sent.lock().unwrap().is_empty());
+
+        app.world.resource_mut::<State<AppState>>().set(AppState::InGame).unwrap();
+        app.update();
+        assert_eq!(*sent.lock().unwrap(), [Bytes::from_static(b"in menu")]);
+        assert!(!app.world.resource::<LaminarSocketResource>().sockets().is_empty());
+    }
+
+    #[test]
+    fn test_socket_is_dropped_on_state_exit() {
+        let (mut app, sent) = create_recording_app(
+            LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default())
+                .in_state(AppState::InGame)
+                .drop_socket_on_state_exit(true),
+        );
+        app.add_state(AppState::InGame);
+        app.update();
+
+        let destination = "127.0.0.1:3000".parse().unwrap();
+        app.world.resource_mut::<TransportResource>().send(destination, b"bye");
+        app.world.resource_mut::<State<AppState>>().set(AppState::Menu).unwrap();
+        app.update();
+        assert_eq!(*sent.lock().unwrap(), [Bytes::from_static(b"bye")]);
+        assert!(app.world.resource::<LaminarSocketResource>().sockets().is_empty());
+    }
+
+    #[test]
+    fn test_broadcast_bypasses_connections() {
+        // loopback stands for the subnet broadcast address, the limited one may not be routable here
+        let mut sender = App::new();
+        sender.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::builder()
+                .address("127.0.0.1:0".parse().unwrap())
+                .allow_broadcast(true)
+                .broadcast_address(Ipv4Addr::LOCALHOST)
+                .build());
+        let mut receiver = create_test_app();
+        assert!(sender.world.resource::<LaminarSocketResource>().allows_broadcast());
+
+        let mut transport = sender.world.resource_mut::<TransportResource>();
+        for delivery in [DeliveryRequirement::Unreliable, DeliveryRequirement::ReliableOrdered(None)] {
+            transport.send_with_requirements(local_addr(&receiver), b"beacon", delivery, UrgencyRequirement::Immediate);
+        }
+        sender.update();
+
+        let events = sender.world.resource::<Events<NetworkSimulationEvent>>();
+        let errors: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .filter_map(|event| match event {
+                NetworkSimulationEvent::SendError(e, message) => Some((e.kind(), message.delivery)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors, vec![(io::ErrorKind::InvalidInput, DeliveryRequirement::ReliableOrdered(None))]);
+
+        // written right away, without waiting for the next poll of the sender
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(1);
+        let mut received = false;
+        while !received && std::time::Instant::now() < deadline {
+            receiver.update();
+            let events = receiver.world.resource::<Events<NetworkSimulationEvent>>();
+            received = events.get_reader().iter(events).any(|event| {
+                matches!(event, NetworkSimulationEvent::Message(_, payload) if payload == "beacon")
+            });
+        }
+        assert!(received);
+    }
+
+    #[test]
+    fn test_broadcast_must_be_allowed() {
+        let mut app = create_test_app();
+        app.world.resource_mut::<TransportResource>().send_with_requirements(
+            "255.255.255.255:3000".parse().unwrap(),
+            b"beacon",
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::Immediate,
+        );
+        app.update();
+
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        assert!(events.get_reader().iter(events).any(|event| {
+            matches!(
+                event,
+                NetworkSimulationEvent::SendError(e, _) if e.kind() == io::ErrorKind::PermissionDenied
+            )
+        }));
+    }
+
+    #[test]
+    fn test_latency_is_reported_after_reliable_exchange() {
+        let mut a = create_test_app();
+        let mut b = create_test_app();
+        let a_addr = local_addr(&a);
+        let b_addr = local_addr(&b);
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+        let mut latency = None;
+        while latency.is_none() && std::time::Instant::now() < deadline {
+            for (app, destination) in [(&mut a, b_addr), (&mut b, a_addr)] {
+                app.world.resource_mut::<TransportResource>().send_with_requirements(
+                    destination,
+                    b"ping",
+                    DeliveryRequirement::ReliableUnordered,
+                    UrgencyRequirement::Immediate,
+                );
+                app.update();
+            }
+
+            let events = a.world.resource::<Events<NetworkSimulationEvent>>();
+            latency = events.get_reader().iter(events).find_map(|event| match event {
+                NetworkSimulationEvent::Latency(addr, rtt) => Some((*addr, *rtt)),
+                _ => None,
+            });
+        }
+
+        let (addr, rtt) = latency.expect("no latency event");
+        assert_eq!(addr, b_addr);
+        assert!(rtt < std::time::Duration::from_secs(1));
+        assert!(a.world.resource::<LaminarSocketResource>().get().unwrap().rtt(&b_addr).is_some());
+    }
+
+    #[test]
+    fn test_packet_loss_rises_with_dropped_datagrams() {
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.set_nonblocking(true).unwrap();
+        let mut a = create_test_app();
+        a.insert_resource(LaminarSocketResource::new(Some(LaminarSocket::with_datagram_socket(
+            LossySocket { socket: udp, sent: 0 },
+            LaminarConfig::default(),
+        ))));
+        let mut b = create_test_app();
+        let a_addr = local_addr(&a);
+        let b_addr = local_addr(&b);
+
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        let mut loss = None;
+        while loss.is_none_or(|loss| loss == 0.0) && std::time::Instant::now() < deadline {
+            for (app, destination) in [(&mut a, b_addr), (&mut b, a_addr)] {
+                app.world.resource_mut::<TransportResource>().send_with_requirements(
+                    destination,
+                    b"ping",
+                    DeliveryRequirement::ReliableUnordered,
+                    UrgencyRequirement::Immediate,
+                );
+                app.update();
+            }
+            loss = a.world.resource::<ConnectionMetrics>().packet_loss(&b_addr);
+        }
+
+        let loss = loss.expect("no loss estimate");
+        assert!(loss > 0.0 && loss < 1.0, "{} lost", loss);
+        assert!(b.world.resource::<ConnectionMetrics>().packet_loss(&a_addr).is_some());
+    }
+
+    #[test]
+    fn test_deliveries_are_reported() {
+        let mut a = create_test_app();
+        let mut b = create_test_app();
+        b.world.resource_mut::<LaminarSocketResource>().set_delivery_reports(true);
+        let a_addr = local_addr(&a);
+        let b_addr = local_addr(&b);
+        let mut transport = a.world.resource_mut::<TransportResource>();
+        transport.send_reliable_ordered(b_addr, b"critical", Some(3));
+        transport.send_unreliable_sequenced(b_addr, b"position", None);
+
+        let mut reader = b.world.resource::<Events<NetworkSimulationEvent>>().get_reader();
+        let mut delivered = Vec::new();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+        while delivered.len() < 2 && std::time::Instant::now() < deadline {
+            a.update();
+            b.update();
+            let events = b.world.resource::<Events<NetworkSimulationEvent>>();
+            delivered.extend(reader.iter(events).filter_map(|event| match event {
+                NetworkSimulationEvent::Delivered(addr, payload, delivery) => Some((*addr, payload.clone(), *delivery)),
+                _ => None,
+            }));
+        }
+
+        delivered.sort_by_key(|(_, payload, _)| payload.clone());
+        assert_eq!(delivered, [
+            (a_addr, Bytes::from_static(b"critical"), DeliveryRequirement::ReliableOrdered(Some(3))),
+            (a_addr, Bytes::from_static(b"position"), DeliveryRequirement::UnreliableSequenced(None)),
+        ]);
+    }
+
+    #[test]
+    fn test_tagged_reliable_message_is_acked() {
+        let mut a = create_test_app();
+        let mut b = create_test_app();
+        let a_addr = local_addr(&a);
+        let b_addr = local_addr(&b);
+        let mut transport = a.world.resource_mut::<TransportResource>();
+        transport.send_with_tag(b_addr, b"important", DeliveryRequirement::ReliableOrdered(None), 7);
+        transport.send_with_tag(b_addr, b"fire and forget", DeliveryRequirement::Unreliable, 8);
+        a.update();
+
+        let mut reader = a.world.resource::<Events<NetworkSimulationEvent>>().get_reader();
+        let mut acked = Vec::new();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+        while acked.is_empty() && std::time::Instant::now() < deadline {
+            // laminar acks on the reliable packets going the other way
+            b.world.resource_mut::<TransportResource>().send_with_requirements(
+                a_addr,
+                b"pong",
+                DeliveryRequirement::ReliableUnordered,
+                UrgencyRequirement::Immediate,
+            );
+            b.update();
+            a.update();
+
+            let events = a.world.resource::<Events<NetworkSimulationEvent>>();
+            acked.extend(reader.iter(events).filter_map(|event| match event {
+                NetworkSimulationEvent::Acked(addr, tag) => Some((*addr, *tag)),
+                _ => None,
+            }));
+        }
+        assert_eq!(acked, vec![(b_addr, 7)]);
+    }
+
+    #[test]
+    fn test_messages_sent_with_an_ack_are_acked_or_time_out() {
+        let mut a = create_test_app();
+        let mut b = create_test_app();
+        let a_addr = local_addr(&a);
+        let b_addr = local_addr(&b);
+        a.world.resource_mut::<LaminarSocketResource>().set_ack_timeout(std::time::Duration::from_millis(500));
+        let mut transport = a.world.resource_mut::<TransportResource>();
+        let reliable = transport.send_with_ack(
+            b_addr,
+            b"match start",
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::OnTick,
+        );
+        let unreliable = transport.send_with_ack(
+            b_addr,
+            b"input",
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::OnTick,
+        );
+        // nothing listens there
+        let unanswered = transport.send_with_ack(
+            "127.0.0.1:1".parse().unwrap(),
+            b"match start",
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::OnTick,
+        );
+        assert_ne!(reliable, unreliable);
+
+        let mut reader = a.world.resource::<Events<NetworkSimulationEvent>>().get_reader();
+        let mut acked = Vec::new();
+        let mut timed_out = Vec::new();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(3);
+        while timed_out.len() < 2 && std::time::Instant::now() < deadline {
+            a.update();
+            b.world.resource_mut::<TransportResource>().send_with_requirements(
+                a_addr,
+                b"pong",
+                DeliveryRequirement::ReliableUnordered,
+                UrgencyRequirement::Immediate,
+            );
+            b.update();
+
+            let events = a.world.resource::<Events<NetworkSimulationEvent>>();
+            for event in reader.iter(events) {
+                match event {
+                    NetworkSimulationEvent::MessageAcked(id) => acked.push(*id),
+                    NetworkSimulationEvent::MessageAckTimeout(id) => timed_out.push(*id),
+                    _ => {}
+                }
+            }
+        }
+        assert_eq!(acked, vec![reliable]);
+        assert_eq!(timed_out, vec![unreliable, unanswered]);
+    }
+
+    #[test]
+    fn test_client_role_sends_to_the_server_it_was_given() {
+        let mut server = App::new();
+        server.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default())
+                .with_role(NetworkRole::Server));
+        let server_addr = local_addr(&server);
+        let mut client = App::new();
+        client.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default())
+                .with_role(NetworkRole::Client { server_addr }));
+        let client_addr = local_addr(&client);
+
+        let mut reader = server.world.resource::<Events<NetworkSimulationEvent>>().get_reader();
+        let mut received = Vec::new();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(2);
+        let accepted = |server: &App| server.world.resource::<TransportResource>().accepted_peers().count();
+        while (received.is_empty() || accepted(&server) == 0) && std::time::Instant::now() < deadline {
+            client.world.resource_mut::<TransportResource>().send_to_server(b"join").unwrap();
+            client.update();
+            server.update();
+            let events = server.world.resource::<Events<NetworkSimulationEvent>>();
+            received.extend(reader.iter(events).filter_map(|event| match event {
+                NetworkSimulationEvent::Message(addr, payload) => Some((*addr, payload.clone())),
+                _ => None,
+            }));
+            // laminar only connects once the server answered
+            if let Some((addr, _)) = received.first() {
+                server.world.resource_mut::<TransportResource>().send(*addr, b"welcome");
+            }
+        }
+
+        assert_eq!(received.first(), Some(&(client_addr, Bytes::from_static(b"join"))));
+        let accepted: Vec<_> = server.world.resource::<TransportResource>().accepted_peers().copied().collect();
+        assert_eq!(accepted, [client_addr]);
+    }
+
+    fn local_addr(app: &App) -> SocketAddr {
+        app.world.resource::<LaminarSocketResource>().get().unwrap().local_addr().unwrap()
+    }
+
+    fn create_test_app() -> App {
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default()));
+        app
+    }
+
+    /// Global allocator of the test binary, counting the allocations of the current thread.
+    mod counting_allocator {
+        use std::{
+            alloc::{GlobalAlloc, Layout, System},
+            cell::Cell,
+        };
+
+        struct CountingAllocator;
+
+        thread_local! {
+            static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+        }
+
+        unsafe impl GlobalAlloc for CountingAllocator {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                ALLOCATIONS.with(|count| count.set(count.get() + 1));
+                unsafe { System.alloc(layout) }
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                unsafe { System.dealloc(ptr, layout) }
+            }
+        }
+
+        #[global_allocator]
+        static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+        /// Returns the number of allocations `f` made.
+        pub fn count(f: impl FnOnce()) -> usize {
+            let before = ALLOCATIONS.with(Cell::get);
+            f();
+            ALLOCATIONS.with(Cell::get) - before
+        }
+    }
+
+    /// UDP socket dropping every third datagram it sends.
+    #[derive(Debug)]
+    struct LossySocket {
+        socket: UdpSocket,
+        sent:   usize,
+    }
+
+    impl DatagramSocket for LossySocket {
+        fn send_packet(&mut self, addr: &SocketAddr, payload: &[u8]) -> io::Result<usize> {
+            self.sent += 1;
+            if self.sent.is_multiple_of(3) {
+                return Ok(payload.len());
+            }
+            self.socket.send_to(payload, addr)
+        }
+
+        fn receive_packet<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<(&'a [u8], SocketAddr)> {
+            self.socket.recv_from(buffer).map(move |(len, addr)| (&buffer[..len], addr))
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.socket.local_addr()
+        }
+
+        fn is_blocking_mode(&self) -> bool {
+            false
+        }
+    }
+
+    /// Socket failing every operation.
+    #[derive(Debug)]
+    struct FaultySocket;
+
+    impl DatagramSocket for FaultySocket {
+        fn send_packet(&mut self, _addr: &SocketAddr, _payload: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::ConnectionReset.into())
+        }
+
+        fn receive_packet<'a>(&mut self, _buffer: &'a mut [u8]) -> io::Result<(&'a [u8], SocketAddr)> {
+            Err(io::ErrorKind::ConnectionReset.into())
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Ok("127.0.0.1:0".parse().unwrap())
+        }
+
+        fn is_blocking_mode(&self) -> bool {
+            false
+        }
+    }
+}
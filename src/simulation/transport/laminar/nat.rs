@@ -0,0 +1,357 @@
+//! NAT traversal helpers for connecting two clients directly: discovering the public address of
+//! the laminar socket with a STUN binding request, and punching a hole towards a peer.
+//!
+//! The STUN messages go through the laminar socket itself, so that the public address found is the
+//! one of the laminar traffic. They are written behind laminar's back, and the incoming ones are
+//! set aside before laminar sees them.
+//!
+//! Punching sends a small unreliable laminar packet to the peer at every interval, both peers
+//! doing so at the same time, until laminar reports the connection. Those packets are never
+//! emitted as `Message` events.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use bevy::app::App;
+use bevy::prelude::{EventReader, EventWriter, Plugin, Res, ResMut, Resource, SystemLabel, SystemSet};
+
+use crate::simulation::{
+    events::NetworkSimulationEvent,
+    peers::ConnectedPeers,
+    transport::laminar::{LaminarSocketResource, Packet},
+};
+use super::socket::{is_stun_message, PUNCH_PAYLOAD, STUN_HEADER_SIZE, STUN_MAGIC_COOKIE};
+
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_SUCCESS: u16 = 0x0101;
+const ATTRIBUTE_MAPPED_ADDRESS: u16 = 0x0001;
+const ATTRIBUTE_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const FAMILY_V4: u8 = 0x01;
+const FAMILY_V6: u8 = 0x02;
+/// How often an unanswered STUN request is sent again, and a punch packet is sent.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a STUN server has to answer.
+const STUN_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct NatTraversalLabel;
+
+/// Events reporting the outcome of the NAT traversal operations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NatEvent {
+    // The STUN server told the public address of the laminar socket
+    PublicAddress(SocketAddr),
+    // The STUN server didn't answer in time
+    StunTimeout(SocketAddr),
+    // Laminar connected to the peer a hole was punched towards
+    PunchSucceeded(SocketAddr),
+    // No connection to the peer before the timeout
+    PunchTimeout(SocketAddr),
+}
+
+/// Resource holding the public address of the laminar socket, once a STUN server told it.
+#[derive(Debug, Default, Resource)]
+pub struct PublicAddress {
+    addr: Option<SocketAddr>,
+}
+
+impl PublicAddress {
+    /// Returns the public address, if it was discovered.
+    #[must_use]
+    pub fn get(&self) -> Option<SocketAddr> {
+        self.addr
+    }
+}
+
+#[derive(Debug)]
+struct StunRequest {
+    server:         SocketAddr,
+    transaction_id: [u8; 12],
+    deadline:       Instant,
+    next_send:      Instant,
+}
+
+#[derive(Debug)]
+struct Punch {
+    peer:      SocketAddr,
+    deadline:  Instant,
+    next_send: Instant,
+}
+
+/// Resource through which the NAT traversal operations are started.
+#[derive(Debug, Default, Resource)]
+pub struct NatTraversal {
+    requests: Vec<StunRequest>,
+    punches:  Vec<Punch>,
+}
+
+impl NatTraversal {
+    /// Asks `stun_server` for the public address of the laminar socket, the answer is published in
+    /// `PublicAddress` and emitted as a `NatEvent`.
+    pub fn discover_public_address(&mut self, stun_server: SocketAddr) {
+        let now = Instant::now();
+        self.requests.push(StunRequest {
+            server:         stun_server,
+            transaction_id: transaction_id(),
+            deadline:       now + STUN_TIMEOUT,
+            next_send:      now,
+        });
+    }
+
+    /// Sends punch packets to the public address of `peer` until laminar connects to it, or for
+    /// `timeout` at most. The peer must punch towards our public address at the same time.
+    pub fn punch(&mut self, peer: SocketAddr, timeout: Duration) {
+        let now = Instant::now();
+        self.punches.retain(|punch| punch.peer != peer);
+        self.punches.push(Punch { peer, deadline: now + timeout, next_send: now });
+    }
+
+    /// Returns true while a STUN request or a punch is in progress.
+    #[must_use]
+    pub fn is_busy(&self) -> bool {
+        !self.requests.is_empty() || !self.punches.is_empty()
+    }
+}
+
+/// Use this plugin next to the `LaminarPlugin` to discover the public address of its socket and to
+/// punch holes towards peers.
+pub struct NatTraversalPlugin;
+
+impl Plugin for NatTraversalPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_event::<NatEvent>()
+            .add_event::<NetworkSimulationEvent>()
+            .init_resource::<NatTraversal>()
+            .init_resource::<PublicAddress>()
+            .init_resource::<ConnectedPeers>()
+            .add_system_set(SystemSet::new()
+                .label(NatTraversalLabel)
+                .with_system(nat_traversal_system)
+            );
+    }
+}
+
+/// Creates a new NAT traversal system, sending the STUN requests and punch packets and reporting
+/// their outcome.
+pub fn nat_traversal_system(mut nat:            ResMut<NatTraversal>,
+                            mut public_address: ResMut<PublicAddress>,
+                            mut socket:         ResMut<LaminarSocketResource>,
+                                peers:          Res<ConnectedPeers>,
+                            mut network_events: EventReader<NetworkSimulationEvent>,
+                            mut nat_events:     EventWriter<NatEvent>) {
+    let now = Instant::now();
+    let nat = &mut *nat;
+
+    for socket in socket.sockets_mut() {
+        for (from, message) in socket.drain_stun_messages() {
+            let answered = nat.requests.iter().position(|request| {
+                request.server == from && message.get(8..STUN_HEADER_SIZE) == Some(&request.transaction_id[..])
+            });
+            if let (Some(index), Some(addr)) = (answered, parse_binding_response(&message)) {
+                nat.requests.swap_remove(index);
+                public_address.addr = Some(addr);
+                nat_events.send(NatEvent::PublicAddress(addr));
+            }
+        }
+    }
+    nat.requests.retain_mut(|request| {
+        if now >= request.deadline {
+            nat_events.send(NatEvent::StunTimeout(request.server));
+            return false;
+        }
+        if now >= request.next_send {
+            request.next_send = now + RETRY_INTERVAL;
+            if let Some(socket) = socket.get_for_destination_mut(&request.server) {
+                // a failed send is retried like a lost one
+                let _ = socket.send_datagram(request.server, &binding_request(&request.transaction_id));
+            }
+        }
+        true
+    });
+
+    let connected: Vec<_> = network_events
+        .iter()
+        .filter_map(|event| match event {
+            NetworkSimulationEvent::Connect(addr) => Some(*addr),
+            _ => None,
+        })
+        .collect();
+    nat.punches.retain_mut(|punch| {
+        if connected.contains(&punch.peer) || peers.is_connected(&punch.peer) {
+            nat_events.send(NatEvent::PunchSucceeded(punch.peer));
+            return false;
+        }
+        if now >= punch.deadline {
+            nat_events.send(NatEvent::PunchTimeout(punch.peer));
+            return false;
+        }
+        if now >= punch.next_send {
+            punch.next_send = now + RETRY_INTERVAL;
+            if let Some(socket) = socket.get_for_destination_mut(&punch.peer) {
+                let _ = socket.send(Packet::unreliable(punch.peer, PUNCH_PAYLOAD.to_vec()));
+            }
+        }
+        true
+    });
+}
+
+fn transaction_id() -> [u8; 12] {
+    let random = || RandomState::new().build_hasher().finish().to_be_bytes();
+    let mut id = [0; 12];
+    id[..8].copy_from_slice(&random());
+    id[8..].copy_from_slice(&random()[..4]);
+    id
+}
+
+fn binding_request(transaction_id: &[u8; 12]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(STUN_HEADER_SIZE);
+    request.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(transaction_id);
+    request
+}
+
+/// Reads the mapped address of a binding success response, preferring the XOR-ed one.
+fn parse_binding_response(message: &[u8]) -> Option<SocketAddr> {
+    if !is_stun_message(message) || message[..2] != STUN_BINDING_SUCCESS.to_be_bytes() {
+        return None;
+    }
+    let mut mapped = None;
+    let mut attributes = &message[STUN_HEADER_SIZE..];
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+        let value = attributes.get(4..4 + len)?;
+        match kind {
+            ATTRIBUTE_XOR_MAPPED_ADDRESS => return read_address(value, Some(&message[4..STUN_HEADER_SIZE])),
+            ATTRIBUTE_MAPPED_ADDRESS => mapped = read_address(value, None),
+            _ => {}
+        }
+        // attributes are padded to 4 bytes
+        attributes = attributes.get(4 + len.div_ceil(4) * 4..).unwrap_or_default();
+    }
+    mapped
+}
+
+/// Reads an address attribute, undoing the XOR with the cookie and transaction id if given.
+fn read_address(value: &[u8], xor: Option<&[u8]>) -> Option<SocketAddr> {
+    let xor_byte = |i: usize| xor.map_or(0, |xor| xor[i]);
+    let port = u16::from_be_bytes([value.get(2)? ^ xor_byte(0), value.get(3)? ^ xor_byte(1)]);
+    let ip = match value[1] {
+        FAMILY_V4 => {
+            let mut octets: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            octets.iter_mut().enumerate().for_each(|(i, octet)| *octet ^= xor_byte(i));
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        FAMILY_V6 => {
+            let mut octets: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            octets.iter_mut().enumerate().for_each(|(i, octet)| *octet ^= xor_byte(i));
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use bevy::ecs::event::Events;
+
+    use super::*;
+    use crate::simulation::transport::laminar::{LaminarConfig, LaminarPlugin};
+
+    #[test]
+    fn test_public_address_from_stun_server() {
+        let stun_server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        stun_server.set_nonblocking(true).unwrap();
+        let mut app = create_test_app();
+        let local_addr = app.world.resource::<LaminarSocketResource>().get().unwrap().local_addr().unwrap();
+        app.world.resource_mut::<NatTraversal>().discover_public_address(stun_server.local_addr().unwrap());
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while app.world.resource::<PublicAddress>().get().is_none() && Instant::now() < deadline {
+            app.update();
+            let mut request = [0; 64];
+            if let Ok((len, from)) = stun_server.recv_from(&mut request) {
+                stun_server.send_to(&binding_response(&request[..len], from), from).unwrap();
+            }
+        }
+
+        assert_eq!(app.world.resource::<PublicAddress>().get(), Some(local_addr));
+        assert!(!app.world.resource::<NatTraversal>().is_busy());
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        assert_eq!(events.get_reader().iter(events).count(), 0, "STUN messages reached laminar");
+    }
+
+    #[test]
+    fn test_simultaneous_punch_connects() {
+        let mut apps = [create_test_app(), create_test_app()];
+        let addrs = apps.each_ref().map(|app| {
+            app.world.resource::<LaminarSocketResource>().get().unwrap().local_addr().unwrap()
+        });
+        for (app, peer) in apps.iter_mut().zip(addrs.iter().rev()) {
+            app.world.resource_mut::<NatTraversal>().punch(*peer, Duration::from_secs(1));
+        }
+
+        let mut outcomes = [None, None];
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while outcomes.contains(&None) && Instant::now() < deadline {
+            for (app, outcome) in apps.iter_mut().zip(outcomes.iter_mut()) {
+                app.update();
+                let events = app.world.resource::<Events<NatEvent>>();
+                *outcome = outcome.take().or_else(|| events.get_reader().iter(events).next().cloned());
+                let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+                assert!(!events
+                    .get_reader()
+                    .iter(events)
+                    .any(|event| matches!(event, NetworkSimulationEvent::Message(..))));
+            }
+        }
+        assert_eq!(outcomes, [Some(NatEvent::PunchSucceeded(addrs[1])), Some(NatEvent::PunchSucceeded(addrs[0]))]);
+    }
+
+    #[test]
+    fn test_binding_response_parsing() {
+        let addr = "[2001:db8::1]:3000".parse().unwrap();
+        let request = binding_request(&transaction_id());
+        assert_eq!(parse_binding_response(&binding_response(&request, addr)), Some(addr));
+        assert_eq!(parse_binding_response(&request), None);
+        assert!(!is_stun_message(b"not a stun message at all"));
+    }
+
+    fn create_test_app() -> App {
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default()))
+            .add_plugin(NatTraversalPlugin);
+        app
+    }
+
+    /// Answers a binding request with the XOR-MAPPED-ADDRESS of `addr`.
+    fn binding_response(request: &[u8], addr: SocketAddr) -> Vec<u8> {
+        let xor = &request[4..STUN_HEADER_SIZE];
+        let (family, ip) = match addr.ip() {
+            IpAddr::V4(ip) => (FAMILY_V4, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (FAMILY_V6, ip.octets().to_vec()),
+        };
+        let mut value = vec![0, family];
+        value.extend(addr.port().to_be_bytes().iter().zip(xor).map(|(byte, xor)| byte ^ xor));
+        value.extend(ip.iter().zip(xor).map(|(byte, xor)| byte ^ xor));
+
+        let mut response = STUN_BINDING_SUCCESS.to_be_bytes().to_vec();
+        response.extend_from_slice(&(4 + value.len() as u16).to_be_bytes());
+        response.extend_from_slice(xor);
+        response.extend_from_slice(&ATTRIBUTE_XOR_MAPPED_ADDRESS.to_be_bytes());
+        response.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        response.extend_from_slice(&value);
+        response
+    }
+}
@@ -0,0 +1,251 @@
+//! Options of the laminar sockets which must be set before they are bound, which `UdpSocket::bind`
+//! doesn't allow, and the ones of the OS laminar doesn't give access to once it owns the socket.
+
+use std::{io, net::{SocketAddr, UdpSocket}};
+
+/// Options the sockets are created with, see `LaminarPlugin::with_socket_options`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Sets `SO_REUSEADDR`, e.g. so that a restarted server can bind its port again right away.
+    /// Only supported on unix.
+    pub reuse_address: bool,
+    /// Sets `SO_REUSEPORT`, letting several processes bind the same port, the kernel spreading the
+    /// incoming traffic across them.
+    #[cfg(unix)]
+    pub reuse_port: bool,
+    /// Whether the socket is non-blocking, overriding `blocking_mode` of the laminar
+    /// configuration. The network systems expect non-blocking sockets.
+    pub nonblocking: bool,
+    /// Sets `SO_SNDBUF`, the size in bytes of the OS send buffer, e.g. to absorb the bursts of a
+    /// server under load. The OS may round it, Linux doubles it for its own bookkeeping and caps it
+    /// at `net.core.wmem_max`. Only supported on unix.
+    pub send_buffer_bytes: Option<usize>,
+    /// Sets `SO_RCVBUF`, the size in bytes of the OS receive buffer, see `send_buffer_bytes`.
+    /// Linux caps it at `net.core.rmem_max`.
+    pub recv_buffer_bytes: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            reuse_address: false,
+            #[cfg(unix)]
+            reuse_port: false,
+            nonblocking: true,
+            send_buffer_bytes: None,
+            recv_buffer_bytes: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Returns true if an option keeps the socket from being created by `UdpSocket::bind`.
+    #[cfg(unix)]
+    fn needs_raw_socket(&self) -> bool {
+        self.reuse_address || self.reuse_port
+    }
+
+    #[cfg(not(unix))]
+    fn needs_raw_socket(&self) -> bool {
+        self.reuse_address
+    }
+}
+
+/// Binds a UDP socket to `addr` with the options. An option the platform doesn't support is an
+/// `Unsupported` error.
+pub(crate) fn bind(addr: SocketAddr, options: &SocketOptions) -> io::Result<UdpSocket> {
+    let socket = if options.needs_raw_socket() {
+        bind_raw(addr, options)?
+    } else {
+        UdpSocket::bind(addr)?
+    };
+    socket.set_nonblocking(options.nonblocking)?;
+    set_buffer_sizes(&socket, options)?;
+    Ok(socket)
+}
+
+#[cfg(unix)]
+fn set_buffer_sizes(socket: &UdpSocket, options: &SocketOptions) -> io::Result<()> {
+    use std::os::fd::AsFd;
+
+    let sizes = [(libc::SO_SNDBUF, options.send_buffer_bytes), (libc::SO_RCVBUF, options.recv_buffer_bytes)];
+    for (option, bytes) in sizes {
+        if let Some(bytes) = bytes {
+            let bytes = libc::c_int::try_from(bytes).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("socket buffer of {} bytes is too large", bytes))
+            })?;
+            set_int_option(socket.as_fd(), option, bytes)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_buffer_sizes(_: &UdpSocket, options: &SocketOptions) -> io::Result<()> {
+    if options.send_buffer_bytes.is_some() || options.recv_buffer_bytes.is_some() {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "the socket buffers can't be sized on this platform"));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn bind_raw(addr: SocketAddr, options: &SocketOptions) -> io::Result<UdpSocket> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    let family = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    // SAFETY: the descriptor is a new one, owned right away so that it's closed on error
+    let fd = unsafe {
+        let fd = check(libc::socket(family, libc::SOCK_DGRAM, 0))?;
+        OwnedFd::from_raw_fd(fd)
+    };
+    // SAFETY: `fd` is a valid descriptor
+    check(unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) })?;
+    if options.reuse_address {
+        set_option(&fd, libc::SO_REUSEADDR)?;
+    }
+    if options.reuse_port {
+        set_reuse_port(&fd)?;
+    }
+    raw_bind(&fd, addr)?;
+    Ok(UdpSocket::from(fd))
+}
+
+#[cfg(not(unix))]
+fn bind_raw(_: SocketAddr, _: &SocketOptions) -> io::Result<UdpSocket> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "SO_REUSEADDR can't be set on this platform"))
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(fd: &std::os::fd::OwnedFd) -> io::Result<()> {
+    set_option(fd, libc::SO_REUSEPORT)
+}
+
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+fn set_reuse_port(_: &std::os::fd::OwnedFd) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "SO_REUSEPORT isn't supported on this platform"))
+}
+
+/// Enables a boolean `SOL_SOCKET` option.
+#[cfg(unix)]
+fn set_option(fd: &std::os::fd::OwnedFd, option: libc::c_int) -> io::Result<()> {
+    use std::os::fd::AsFd;
+
+    set_int_option(fd.as_fd(), option, 1)
+}
+
+/// Sets an integer `SOL_SOCKET` option.
+#[cfg(unix)]
+fn set_int_option(fd: std::os::fd::BorrowedFd<'_>, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the option value is a valid `c_int` of the given size
+    check(unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            (&value as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    })
+    .map(|_| ())
+}
+
+#[cfg(unix)]
+fn raw_bind(fd: &std::os::fd::OwnedFd, addr: SocketAddr) -> io::Result<()> {
+    use std::{mem, os::fd::AsRawFd};
+
+    // SAFETY: the addresses are plain C structs, valid when zeroed, passed with their own size
+    let result = unsafe {
+        match addr {
+            SocketAddr::V4(addr) => {
+                let mut raw: libc::sockaddr_in = mem::zeroed();
+                raw.sin_family = libc::AF_INET as libc::sa_family_t;
+                raw.sin_port = addr.port().to_be();
+                raw.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+                libc::bind(
+                    fd.as_raw_fd(),
+                    (&raw as *const libc::sockaddr_in).cast(),
+                    mem::size_of_val(&raw) as libc::socklen_t,
+                )
+            }
+            SocketAddr::V6(addr) => {
+                let mut raw: libc::sockaddr_in6 = mem::zeroed();
+                raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                raw.sin6_port = addr.port().to_be();
+                raw.sin6_addr.s6_addr = addr.ip().octets();
+                raw.sin6_flowinfo = addr.flowinfo();
+                raw.sin6_scope_id = addr.scope_id();
+                libc::bind(
+                    fd.as_raw_fd(),
+                    (&raw as *const libc::sockaddr_in6).cast(),
+                    mem::size_of_val(&raw) as libc::socklen_t,
+                )
+            }
+        }
+    };
+    check(result).map(|_| ())
+}
+
+/// Turns the -1 returned by a failed call into the error it set.
+#[cfg(unix)]
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse_port_lets_two_sockets_share_a_port() {
+        let options = SocketOptions { reuse_address: true, reuse_port: true, ..SocketOptions::default() };
+        let first = bind("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind(addr, &options).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+
+        // without the options the port is taken
+        let e = bind(addr, &SocketOptions::default()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_buffer_sizes_are_applied() {
+        use std::os::fd::AsRawFd;
+
+        let get = |socket: &UdpSocket, option| {
+            let mut value: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            // SAFETY: the option value is a valid `c_int` of the given size
+            check(unsafe {
+                libc::getsockopt(
+                    socket.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    option,
+                    (&mut value as *mut libc::c_int).cast(),
+                    &mut len,
+                )
+            })
+            .unwrap();
+            value
+        };
+        // well below the default caps of Linux, which reports twice the sizes set
+        let options = SocketOptions {
+            send_buffer_bytes: Some(20_000),
+            recv_buffer_bytes: Some(30_000),
+            ..SocketOptions::default()
+        };
+        let socket = bind("127.0.0.1:0".parse().unwrap(), &options).unwrap();
+        assert_eq!(get(&socket, libc::SO_SNDBUF), 40_000);
+        assert_eq!(get(&socket, libc::SO_RCVBUF), 60_000);
+
+        let too_large = SocketOptions { send_buffer_bytes: Some(usize::MAX), ..SocketOptions::default() };
+        assert_eq!(bind("127.0.0.1:0".parse().unwrap(), &too_large).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
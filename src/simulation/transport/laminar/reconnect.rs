@@ -0,0 +1,284 @@
+//! Reconnection of a client to its server once laminar disconnected it, e.g. after a timeout.
+//!
+//! Each attempt sends a small unreliable laminar packet to the server, which its laminar socket
+//! answers on its own rather than emitting it, so that laminar connects both ends. The attempts are
+//! spaced on an exponential backoff until laminar reports the connection with a `Connect` event
+//! again.
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use bevy::app::App;
+use bevy::prelude::{EventReader, EventWriter, Plugin, ResMut, Resource, SystemLabel, SystemSet};
+
+use crate::simulation::{
+    events::NetworkSimulationEvent,
+    transport::laminar::{LaminarSocketResource, Packet},
+};
+use super::socket::RECONNECT_PAYLOAD;
+
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct ReconnectLabel;
+
+/// Events reporting the progress of the reconnection to the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReconnectEvent {
+    // The given attempt, counting from 1, was sent to the server
+    Attempt(SocketAddr, u32),
+    // Laminar connected to the server again
+    Reconnected(SocketAddr),
+    // The server didn't answer any of the attempts
+    GaveUp(SocketAddr),
+}
+
+/// Backoff schedule of the reconnection attempts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReconnectConfig {
+    /// Time between the disconnection and the first attempt, then the first two attempts.
+    pub initial_delay: Duration,
+    /// Factor the time between two attempts grows by after each of them.
+    pub multiplier:    f32,
+    /// Longest time between two attempts.
+    pub max_delay:     Duration,
+    /// Number of attempts after which the reconnection is given up, `None` to never give up.
+    pub max_attempts:  Option<u32>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            multiplier:    2.0,
+            max_delay:     Duration::from_secs(30),
+            max_attempts:  None,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// Returns the time to wait before the attempt following the given number of attempts.
+    fn delay(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let factor = f64::from(self.multiplier).powi(exponent);
+        let delay = self.initial_delay.as_secs_f64() * factor;
+        Duration::from_secs_f64(delay.min(self.max_delay.as_secs_f64()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stage {
+    Connected,
+    Reconnecting { attempts: u32, next: Instant },
+    GaveUp,
+}
+
+/// Resource driving the reconnection to the server.
+#[derive(Debug, Resource)]
+pub struct Reconnect {
+    server: SocketAddr,
+    config: ReconnectConfig,
+    stage:  Stage,
+}
+
+impl Reconnect {
+    /// Creates a resource reconnecting to `server` on the given schedule.
+    #[must_use]
+    pub fn new(server: SocketAddr, config: ReconnectConfig) -> Self {
+        Self { server, config, stage: Stage::Connected }
+    }
+
+    /// Returns the address of the server reconnected to.
+    #[must_use]
+    pub fn server_addr(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// Returns the backoff schedule.
+    #[must_use]
+    pub fn config(&self) -> ReconnectConfig {
+        self.config
+    }
+
+    /// Sets the backoff schedule, which applies from the next attempt on.
+    pub fn set_config(&mut self, config: ReconnectConfig) {
+        self.config = config;
+    }
+
+    /// Returns true while the attempts are being made.
+    #[must_use]
+    pub fn is_reconnecting(&self) -> bool {
+        matches!(self.stage, Stage::Reconnecting { .. })
+    }
+
+    /// Returns the number of attempts made since the server was lost.
+    #[must_use]
+    pub fn attempts(&self) -> u32 {
+        match self.stage {
+            Stage::Reconnecting { attempts, .. } => attempts,
+            Stage::Connected | Stage::GaveUp => 0,
+        }
+    }
+
+    /// Stops the attempts, e.g. once the player gave up on the server. The next `Disconnect` of the
+    /// server starts them again.
+    pub fn stop(&mut self) {
+        self.stage = Stage::Connected;
+    }
+
+    /// Starts the attempts, the first coming after the initial delay.
+    fn lost(&mut self, now: Instant) {
+        if !self.is_reconnecting() {
+            self.stage = Stage::Reconnecting { attempts: 0, next: now + self.config.initial_delay };
+        }
+    }
+
+    /// Returns the number of the attempt due at `now`, if one is, counting from 1.
+    fn poll(&mut self, now: Instant) -> Option<u32> {
+        let Stage::Reconnecting { attempts, next } = self.stage else {
+            return None;
+        };
+        if now < next {
+            return None;
+        }
+        if self.config.max_attempts.is_some_and(|max| attempts >= max) {
+            self.stage = Stage::GaveUp;
+            return None;
+        }
+        let attempts = attempts + 1;
+        self.stage = Stage::Reconnecting { attempts, next: now + self.config.delay(attempts) };
+        Some(attempts)
+    }
+}
+
+/// Use this plugin next to the `LaminarPlugin` on a client to reconnect to its server. See
+/// `Reconnect`.
+pub struct ReconnectPlugin {
+    server: SocketAddr,
+    config: ReconnectConfig,
+}
+
+impl ReconnectPlugin {
+    #[must_use]
+    pub fn new(server: SocketAddr) -> Self {
+        Self { server, config: ReconnectConfig::default() }
+    }
+
+    /// Sets the backoff schedule of the attempts, see `ReconnectConfig`.
+    #[must_use]
+    pub fn with_config(mut self, config: ReconnectConfig) -> Self {
+        self.config = config;
+        self
+    }
+}
+
+impl Plugin for ReconnectPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_event::<ReconnectEvent>()
+            .add_event::<NetworkSimulationEvent>()
+            .insert_resource(Reconnect::new(self.server, self.config))
+            .add_system_set(SystemSet::new()
+                .label(ReconnectLabel)
+                .with_system(reconnect_system)
+            );
+    }
+}
+
+/// Creates a new reconnection system, making the attempts once the server disconnected, until it
+/// connects again.
+pub fn reconnect_system(mut reconnect:        ResMut<Reconnect>,
+                        mut socket:           ResMut<LaminarSocketResource>,
+                        mut network_events:   EventReader<NetworkSimulationEvent>,
+                        mut reconnect_events: EventWriter<ReconnectEvent>) {
+    let now = socket.now();
+    let server = reconnect.server;
+    for event in network_events.iter() {
+        match event {
+            NetworkSimulationEvent::Disconnect(addr, _) if *addr == server => reconnect.lost(now),
+            NetworkSimulationEvent::Connect(addr) if *addr == server => {
+                if reconnect.is_reconnecting() {
+                    reconnect_events.send(ReconnectEvent::Reconnected(server));
+                }
+                reconnect.stage = Stage::Connected;
+            }
+            _ => {}
+        }
+    }
+
+    let was_reconnecting = reconnect.is_reconnecting();
+    match reconnect.poll(now) {
+        Some(attempt) => {
+            if let Some(socket) = socket.get_for_destination_mut(&server) {
+                // a failed send is like a lost packet, the next attempt follows anyway
+                let _ = socket.send(Packet::unreliable(server, RECONNECT_PAYLOAD.to_vec()));
+            }
+            reconnect_events.send(ReconnectEvent::Attempt(server, attempt));
+        }
+        None if was_reconnecting && reconnect.stage == Stage::GaveUp => {
+            reconnect_events.send(ReconnectEvent::GaveUp(server));
+        }
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::event::Events;
+
+    use super::*;
+    use crate::simulation::{
+        events::DisconnectReason,
+        transport::laminar::{LaminarConfig, LaminarPlugin},
+    };
+
+    #[test]
+    fn test_attempts_follow_the_backoff() {
+        let config = ReconnectConfig {
+            initial_delay: Duration::from_millis(100),
+            multiplier:    2.0,
+            max_delay:     Duration::from_millis(400),
+            max_attempts:  Some(5),
+        };
+        let mut reconnect = Reconnect::new("127.0.0.1:3000".parse().unwrap(), config);
+        let start = Instant::now();
+        reconnect.lost(start);
+
+        let mut attempts = Vec::new();
+        for millis in (0..=3000).step_by(10) {
+            if let Some(attempt) = reconnect.poll(start + Duration::from_millis(millis)) {
+                attempts.push((attempt, millis));
+            }
+        }
+        assert_eq!(attempts, [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1200)]);
+        assert_eq!(reconnect.stage, Stage::GaveUp);
+    }
+
+    #[test]
+    fn test_reconnects_after_a_disconnect() {
+        let mut server = App::new();
+        server.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default()));
+        let server_addr = server.world.resource::<LaminarSocketResource>().local_addr().unwrap();
+        let reconnect = ReconnectConfig { initial_delay: Duration::from_millis(20), ..ReconnectConfig::default() };
+        let mut client = App::new();
+        client.init_resource::<bevy::time::Time>()
+            .add_plugin(LaminarPlugin::new("127.0.0.1:0".parse().unwrap(), LaminarConfig::default()))
+            .add_plugin(ReconnectPlugin::new(server_addr).with_config(reconnect));
+        client.world.send_event(NetworkSimulationEvent::Disconnect(server_addr, DisconnectReason::Timeout));
+
+        let mut reader = client.world.resource::<Events<ReconnectEvent>>().get_reader();
+        let mut progress = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !progress.contains(&ReconnectEvent::Reconnected(server_addr)) && Instant::now() < deadline {
+            client.update();
+            server.update();
+            progress.extend(reader.iter(client.world.resource::<Events<ReconnectEvent>>()).cloned());
+        }
+
+        assert_eq!(progress.first(), Some(&ReconnectEvent::Attempt(server_addr, 1)));
+        assert_eq!(progress.last(), Some(&ReconnectEvent::Reconnected(server_addr)));
+        assert!(!client.world.resource::<Reconnect>().is_reconnecting());
+    }
+}
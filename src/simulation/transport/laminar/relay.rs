@@ -0,0 +1,199 @@
+//! Relaying of the laminar traffic through a server, for peers which can't reach each other
+//! directly, e.g. behind symmetric NATs.
+//!
+//! Every datagram sent to the relay is prefixed with the session token and the address of the peer
+//! it is meant for. The relay forwards it to that peer, prefixed with the address of the sender
+//! instead, as long as both peers are in the same session. Peers are known by the address the relay
+//! sees them from, and the wrapping happens below laminar so the rest of the stack only ever sees
+//! those addresses.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+use laminar::DatagramSocket;
+
+const FAMILY_NONE: u8 = 0;
+const FAMILY_V4: u8 = 4;
+const FAMILY_V6: u8 = 6;
+/// Largest header, a token and an IPv6 address.
+const MAX_HEADER_SIZE: usize = 8 + 1 + 16 + 2;
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// Where to relay the laminar traffic through, and the session the peers talking together share.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RelayConfig {
+    pub relay_addr:    SocketAddr,
+    pub session_token: u64,
+}
+
+/// Prefixes `payload` with the relay header.
+fn wrap(token: u64, addr: Option<SocketAddr>, payload: &[u8], datagram: &mut Vec<u8>) {
+    datagram.clear();
+    datagram.extend_from_slice(&token.to_be_bytes());
+    write_addr(addr, datagram);
+    datagram.extend_from_slice(payload);
+}
+
+/// Reads the relay header, returning the token, the address and the size of the header.
+fn unwrap(datagram: &[u8]) -> Option<(u64, Option<SocketAddr>, usize)> {
+    let token = u64::from_be_bytes(datagram.get(..8)?.try_into().ok()?);
+    let (addr, size) = read_addr(&datagram[8..])?;
+    Some((token, addr, 8 + size))
+}
+
+/// Appends the family of the address, followed by its IP and port.
+pub(crate) fn write_addr(addr: Option<SocketAddr>, buffer: &mut Vec<u8>) {
+    match addr.map(|addr| (addr.ip(), addr.port())) {
+        Some((IpAddr::V4(ip), port)) => {
+            buffer.push(FAMILY_V4);
+            buffer.extend_from_slice(&ip.octets());
+            buffer.extend_from_slice(&port.to_be_bytes());
+        }
+        Some((IpAddr::V6(ip), port)) => {
+            buffer.push(FAMILY_V6);
+            buffer.extend_from_slice(&ip.octets());
+            buffer.extend_from_slice(&port.to_be_bytes());
+        }
+        None => buffer.push(FAMILY_NONE),
+    }
+}
+
+/// Reads an address written by `write_addr`, returning it along with the bytes it took.
+pub(crate) fn read_addr(bytes: &[u8]) -> Option<(Option<SocketAddr>, usize)> {
+    Some(match *bytes.first()? {
+        FAMILY_NONE => (None, 1),
+        FAMILY_V4 => {
+            let octets: [u8; 4] = bytes.get(1..5)?.try_into().ok()?;
+            let port = u16::from_be_bytes(bytes.get(5..7)?.try_into().ok()?);
+            (Some(SocketAddr::from((Ipv4Addr::from(octets), port))), 7)
+        }
+        FAMILY_V6 => {
+            let octets: [u8; 16] = bytes.get(1..17)?.try_into().ok()?;
+            let port = u16::from_be_bytes(bytes.get(17..19)?.try_into().ok()?);
+            (Some(SocketAddr::from((Ipv6Addr::from(octets), port))), 19)
+        }
+        _ => return None,
+    })
+}
+
+/// `DatagramSocket` wrapper sending everything through the relay.
+#[derive(Debug)]
+pub(crate) struct RelayedSocket<S> {
+    socket:   S,
+    config:   RelayConfig,
+    datagram: Vec<u8>,
+}
+
+impl<S: DatagramSocket> RelayedSocket<S> {
+    /// Wraps `socket` and joins the session, so that the relay forwards the peers' datagrams before
+    /// this one sends any.
+    pub(crate) fn join(mut socket: S, config: RelayConfig) -> io::Result<Self> {
+        let mut datagram = Vec::with_capacity(MAX_HEADER_SIZE);
+        wrap(config.session_token, None, &[], &mut datagram);
+        socket.send_packet(&config.relay_addr, &datagram)?;
+        Ok(Self { socket, config, datagram })
+    }
+}
+
+impl<S: DatagramSocket> DatagramSocket for RelayedSocket<S> {
+    fn send_packet(&mut self, addr: &SocketAddr, payload: &[u8]) -> io::Result<usize> {
+        wrap(self.config.session_token, Some(*addr), payload, &mut self.datagram);
+        self.socket.send_packet(&self.config.relay_addr, &self.datagram)?;
+        Ok(payload.len())
+    }
+
+    fn receive_packet<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<(&'a [u8], SocketAddr)> {
+        loop {
+            let (len, from) = self.socket.receive_packet(buffer).map(|(data, from)| (data.len(), from))?;
+            // anything not coming from the relay, or for another session, is dropped
+            if from != self.config.relay_addr {
+                continue;
+            }
+            if let Some((token, Some(source), header)) = unwrap(&buffer[..len]) {
+                if token == self.config.session_token {
+                    return Ok((&buffer[header..len], source));
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn is_blocking_mode(&self) -> bool {
+        self.socket.is_blocking_mode()
+    }
+}
+
+/// A minimal relay forwarding the datagrams between the peers of each session. A peer joins the
+/// session of the token of the first datagram it sends.
+#[derive(Debug)]
+pub struct RelayServer {
+    socket:   UdpSocket,
+    sessions: HashMap<u64, HashSet<SocketAddr>>,
+    buffer:   Vec<u8>,
+    datagram: Vec<u8>,
+}
+
+impl RelayServer {
+    /// Binds the relay to the given address, in non-blocking mode.
+    pub fn bind<A: ToSocketAddrs>(addresses: A) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addresses)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            sessions: HashMap::new(),
+            buffer: vec![0; MAX_DATAGRAM_SIZE],
+            datagram: Vec::new(),
+        })
+    }
+
+    /// Returns the local socket address.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Forwards every pending datagram, returning how many were forwarded. Datagrams for a peer
+    /// outside of the session of their sender are dropped.
+    pub fn poll(&mut self) -> io::Result<usize> {
+        let mut forwarded = 0;
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut self.buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(forwarded),
+                Err(e) => return Err(e),
+            };
+            let (token, target, header) = match unwrap(&self.buffer[..len]) {
+                Some(unwrapped) => unwrapped,
+                None => continue,
+            };
+            let session = self.sessions.entry(token).or_default();
+            session.insert(from);
+            if let Some(target) = target.filter(|target| session.contains(target)) {
+                wrap(token, Some(from), &self.buffer[header..len], &mut self.datagram);
+                self.socket.send_to(&self.datagram, target)?;
+                forwarded += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_round_trip() {
+        let mut datagram = Vec::new();
+        for addr in [None, Some("10.0.0.1:3000".parse().unwrap()), Some("[2001:db8::1]:3000".parse().unwrap())] {
+            wrap(42, addr, b"payload", &mut datagram);
+            let (token, unwrapped, header) = unwrap(&datagram).unwrap();
+            assert_eq!((token, unwrapped, &datagram[header..]), (42, addr, &b"payload"[..]));
+        }
+        assert_eq!(unwrap(&[0, 0, 0, 0, 0, 0, 0, 42, FAMILY_V4, 10]), None);
+    }
+}
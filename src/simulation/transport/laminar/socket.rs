@@ -0,0 +1,432 @@
+//! Laminar socket which keeps track of the IO errors laminar itself only logs.
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket},
+    thread::{sleep, yield_now},
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{Receiver, Sender};
+use laminar::{
+    Config, ConnectionManager, DatagramSocket, Packet, Result, SocketEvent, VirtualConnection,
+};
+
+use crate::simulation::message::MessageId;
+use super::{
+    acks::AckTracker,
+    broadcast,
+    latency::LatencyTracker,
+    metrics::PeerMetrics,
+    options::{self, SocketOptions},
+    relay::{RelayConfig, RelayedSocket},
+};
+
+/// Offset of the packet type in laminar's standard header.
+const PACKET_TYPE_OFFSET: usize = 2;
+const PACKET_TYPE_HEARTBEAT: u8 = 2;
+/// Payload of the punch packets of `NatTraversal`, dropped by the receiving laminar systems.
+pub(crate) const PUNCH_PAYLOAD: &[u8] = b"\0blaminar punch\0";
+/// Size of the largest header laminar puts on an unreliable packet, the standard one and the one
+/// of its sequencing.
+const UNRELIABLE_HEADER_SIZE: usize = 5 + 3;
+
+/// Payload of the attempts of `Reconnect` and of the handshakes of `TransportResource::connect`,
+/// answered with a punch packet so that laminar connects both ends, and never emitted.
+pub(crate) const RECONNECT_PAYLOAD: &[u8] = b"\0blaminar reconnect\0";
+/// Prefix of the payloads of `HostMigration`, set aside for it rather than emitted.
+pub(crate) const MIGRATION_PREFIX: &[u8] = b"\0blaminar migration\0";
+pub(crate) const STUN_HEADER_SIZE: usize = 20;
+pub(crate) const STUN_MAGIC_COOKIE: u32 = 0x2112_a442;
+
+/// An IO error which occurred on the underlying datagram socket while laminar was polling it.
+#[derive(Debug)]
+pub enum SocketError {
+    /// Receiving from the socket failed.
+    Recv(io::Error),
+    /// Sending a datagram to the given address failed.
+    Send(io::Error, SocketAddr),
+}
+
+/// Plain UDP implementation of the laminar `DatagramSocket`.
+#[derive(Debug)]
+struct UdpDatagramSocket {
+    socket:           UdpSocket,
+    is_blocking_mode: bool,
+}
+
+impl DatagramSocket for UdpDatagramSocket {
+    fn send_packet(&mut self, addr: &SocketAddr, payload: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(payload, addr)
+    }
+
+    fn receive_packet<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<(&'a [u8], SocketAddr)> {
+        self.socket
+            .recv_from(buffer)
+            .map(move |(recv_len, address)| (&buffer[..recv_len], address))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn is_blocking_mode(&self) -> bool {
+        self.is_blocking_mode
+    }
+}
+
+/// `DatagramSocket` wrapper recording every error before handing it back to laminar, which would
+/// otherwise only log it. It also looks at the datagrams going through to estimate the RTT and to
+/// ack the tagged messages, and keeps the STUN messages away from laminar.
+#[derive(Debug)]
+struct ReportingSocket {
+    socket:     Box<dyn DatagramSocket + Send + Sync>,
+    errors:     Vec<SocketError>,
+    latency:    LatencyTracker,
+    acks:       AckTracker,
+    stun:       Vec<(SocketAddr, Vec<u8>)>,
+    heartbeats: Vec<SocketAddr>,
+}
+
+impl DatagramSocket for ReportingSocket {
+    fn send_packet(&mut self, addr: &SocketAddr, payload: &[u8]) -> io::Result<usize> {
+        match self.socket.send_packet(addr, payload) {
+            Ok(sent) => {
+                self.latency.on_send(*addr, payload, Instant::now());
+                self.acks.on_send(*addr, payload);
+                Ok(sent)
+            }
+            Err(e) => {
+                self.errors.push(SocketError::Send(copy_io_error(&e), *addr));
+                Err(e)
+            }
+        }
+    }
+
+    fn receive_packet<'a>(&mut self, buffer: &'a mut [u8]) -> io::Result<(&'a [u8], SocketAddr)> {
+        loop {
+            // the payload may not start at the beginning of the buffer, e.g. with a relay header
+            let start = buffer.as_ptr() as usize;
+            let received = self.socket.receive_packet(buffer).map(|(payload, addr)| {
+                let offset = payload.as_ptr() as usize - start;
+                (offset..offset + payload.len(), addr)
+            });
+            match received {
+                Ok((range, addr)) if is_stun_message(&buffer[range.clone()]) => {
+                    self.stun.push((addr, buffer[range].to_vec()));
+                }
+                Ok((range, addr)) => {
+                    self.latency.on_recv(addr, &buffer[range.clone()], Instant::now());
+                    self.acks.on_recv(addr, &buffer[range.clone()]);
+                    if is_heartbeat(&buffer[range.clone()]) && !self.heartbeats.contains(&addr) {
+                        self.heartbeats.push(addr);
+                    }
+                    return Ok((&buffer[range], addr));
+                }
+                Err(e) => {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        self.errors.push(SocketError::Recv(copy_io_error(&e)));
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn is_blocking_mode(&self) -> bool {
+        self.socket.is_blocking_mode()
+    }
+}
+
+/// Returns true for a laminar heartbeat, which laminar handles without emitting any event.
+fn is_heartbeat(datagram: &[u8]) -> bool {
+    datagram.get(PACKET_TYPE_OFFSET) == Some(&PACKET_TYPE_HEARTBEAT)
+}
+
+/// Returns true for a datagram shaped like a STUN message, which laminar must not see.
+pub(crate) fn is_stun_message(datagram: &[u8]) -> bool {
+    datagram.len() >= STUN_HEADER_SIZE
+        && datagram[0] & 0xc0 == 0
+        && datagram[4..8] == STUN_MAGIC_COOKIE.to_be_bytes()
+        && u16::from_be_bytes([datagram[2], datagram[3]]) as usize == datagram.len() - STUN_HEADER_SIZE
+}
+
+/// `io::Error` isn't `Clone`, but laminar wants the original back so it can log it.
+fn copy_io_error(e: &io::Error) -> io::Error {
+    io::Error::new(e.kind(), e.to_string())
+}
+
+/// A reliable UDP socket built on laminar's connection management. It mirrors the API of
+/// `laminar::Socket`, but additionally keeps the IO errors encountered while polling so they can be
+/// surfaced as `NetworkSimulationEvent`s.
+#[derive(Debug)]
+pub struct LaminarSocket {
+    handler:                ConnectionManager<ReportingSocket, VirtualConnection>,
+    max_unreliable_payload: usize,
+    max_reliable_payload:   usize,
+    migration:              Vec<(SocketAddr, Vec<u8>)>,
+}
+
+impl LaminarSocket {
+    /// Binds to the given address with the default laminar configuration.
+    pub fn bind<A: ToSocketAddrs>(addresses: A) -> Result<Self> {
+        Self::bind_with_config(addresses, Config::default())
+    }
+
+    /// Binds to any local port on the system, if available.
+    pub fn bind_any() -> Result<Self> {
+        Self::bind_any_with_config(Config::default())
+    }
+
+    /// Binds to any local port on the system, if available, with a given config.
+    pub fn bind_any_with_config(config: Config) -> Result<Self> {
+        let loopback = Ipv4Addr::new(127, 0, 0, 1);
+        Self::bind_with_config(SocketAddrV4::new(loopback, 0), config)
+    }
+
+    /// Binds to the given address and configures laminar with the passed configuration.
+    pub fn bind_with_config<A: ToSocketAddrs>(addresses: A, config: Config) -> Result<Self> {
+        let socket = UdpSocket::bind(addresses)?;
+        Self::bind_internal(socket, config)
+    }
+
+    /// Binds to the given address with the socket options, e.g. to share the port with other
+    /// processes. The options decide whether the socket blocks, whatever the laminar configuration
+    /// says.
+    pub fn bind_with_options(address: SocketAddr, mut config: Config, options: &SocketOptions) -> Result<Self> {
+        let socket = options::bind(address, options)?;
+        config.blocking_mode = !options.nonblocking;
+        Self::bind_internal(socket, config)
+    }
+
+    /// Takes over an already bound std socket, e.g. one used for NAT probing beforehand, and puts it
+    /// in the blocking mode asked for by the configuration.
+    pub fn from_std_socket(socket: UdpSocket, config: Config) -> Result<Self> {
+        Self::bind_internal(socket, config)
+    }
+
+    /// Takes over an already bound std socket like `from_std_socket`, but sends everything through
+    /// the relay, which forwards it to the peers of the same session. See `RelayConfig`.
+    pub fn relayed(socket: UdpSocket, config: Config, relay: RelayConfig) -> Result<Self> {
+        socket.set_nonblocking(!config.blocking_mode)?;
+        let is_blocking_mode = config.blocking_mode;
+        let socket = RelayedSocket::join(UdpDatagramSocket { socket, is_blocking_mode }, relay)?;
+        Ok(Self::with_datagram_socket(socket, config))
+    }
+
+    fn bind_internal(socket: UdpSocket, config: Config) -> Result<Self> {
+        socket.set_nonblocking(!config.blocking_mode)?;
+        let is_blocking_mode = config.blocking_mode;
+        Ok(Self::with_datagram_socket(UdpDatagramSocket { socket, is_blocking_mode }, config))
+    }
+
+    /// Creates a laminar socket on top of any `DatagramSocket` implementation.
+    pub(crate) fn with_datagram_socket(
+        socket: impl DatagramSocket + Send + Sync + 'static,
+        config: Config,
+    ) -> Self {
+        let socket = ReportingSocket {
+            socket:     Box::new(socket),
+            errors:     Vec::new(),
+            latency:    LatencyTracker::default(),
+            acks:       AckTracker::default(),
+            stun:       Vec::new(),
+            heartbeats: Vec::new(),
+        };
+        // laminar refuses unreliable payloads larger than the receive buffer, but it is the datagram,
+        // headers included, which must fit in the buffer of the peer or it is truncated
+        let max_unreliable_payload = config
+            .max_packet_size
+            .min(config.receive_buffer_max_size.saturating_sub(UNRELIABLE_HEADER_SIZE));
+        // laminar counts the fragments of a payload from its length as a `u16`
+        let max_reliable_payload = (usize::from(config.max_fragments) * usize::from(config.fragment_size))
+            .min(usize::from(u16::MAX));
+        Self {
+            handler: ConnectionManager::new(socket, config),
+            max_unreliable_payload,
+            max_reliable_payload,
+            migration: Vec::new(),
+        }
+    }
+
+    /// Returns a handle to the packet sender, to queue packets from another thread while the socket
+    /// runs its polling loop in its own, see `start_polling`.
+    pub fn get_packet_sender(&self) -> Sender<Packet> {
+        self.handler.event_sender().clone()
+    }
+
+    /// Returns a handle to the event receiver, to receive the events from another thread while the
+    /// socket runs its polling loop in its own. Unlike `recv`, it hands out the `HostMigration` and
+    /// reconnect packets as they are.
+    pub fn get_event_receiver(&self) -> Receiver<SocketEvent> {
+        self.handler.event_receiver().clone()
+    }
+
+    /// Queues a single packet, it is actually sent on the next `manual_poll`.
+    pub fn send(&mut self, packet: Packet) -> Result<()> {
+        self.handler
+            .event_sender()
+            .send(packet)
+            .expect("Receiver must exist.");
+        Ok(())
+    }
+
+    /// Sends `payload` right away as an unreliable packet to a broadcast address, without going
+    /// through laminar's connection management. The socket must have `SO_BROADCAST` set.
+    pub fn send_broadcast(&mut self, destination: SocketAddr, payload: &[u8]) -> io::Result<()> {
+        self.send_datagram(destination, &broadcast::unreliable_datagram(payload))
+    }
+
+    /// Writes `datagram` as is to the socket, behind laminar's back.
+    pub(crate) fn send_datagram(&mut self, destination: SocketAddr, datagram: &[u8]) -> io::Result<()> {
+        self.handler.socket_mut().socket.send_packet(&destination, datagram).map(|_| ())
+    }
+
+    /// Returns and clears the peers a heartbeat came from during the previous polls.
+    pub fn drain_heartbeats(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.handler.socket_mut().heartbeats)
+    }
+
+    /// Returns and clears the STUN messages received by the previous polls, along with their
+    /// sender. `NatTraversalPlugin` handles them, without it they pile up until drained.
+    pub fn drain_stun_messages(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        std::mem::take(&mut self.handler.socket_mut().stun)
+    }
+
+    /// Returns and clears the `HostMigration` messages received by the previous `recv` calls,
+    /// without their prefix, along with their sender. `HostMigrationPlugin` handles them, without
+    /// it they pile up until drained.
+    pub fn drain_migration_messages(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        std::mem::take(&mut self.migration)
+    }
+
+    /// Receives a single event, if there is one.
+    pub fn recv(&mut self) -> Option<SocketEvent> {
+        loop {
+            let event = self.handler.event_receiver().try_recv().ok();
+            match &event {
+                Some(SocketEvent::Packet(packet)) if packet.payload().starts_with(MIGRATION_PREFIX) => {
+                    let message = packet.payload()[MIGRATION_PREFIX.len()..].to_vec();
+                    self.migration.push((packet.addr(), message));
+                    continue;
+                }
+                Some(SocketEvent::Packet(packet)) if packet.payload() == RECONNECT_PAYLOAD => {
+                    // a lost answer is like a lost attempt, the client makes another one
+                    let _ = self.send(Packet::unreliable(packet.addr(), PUNCH_PAYLOAD.to_vec()));
+                    continue;
+                }
+                Some(SocketEvent::Disconnect(addr) | SocketEvent::Timeout(addr)) => {
+                    self.handler.socket_mut().latency.remove(addr);
+                    self.handler.socket_mut().acks.remove(addr);
+                }
+                _ => {}
+            }
+            return event;
+        }
+    }
+
+    /// Processes any inbound/outbound packets and handles idle clients.
+    pub fn manual_poll(&mut self, time: Instant) {
+        self.handler.manual_poll(time);
+    }
+
+    /// Runs the polling loop, sleeping 1ms between the polls. It never returns, so it should run in
+    /// a spawned thread.
+    pub fn start_polling(&mut self) {
+        self.start_polling_with_duration(Some(Duration::from_millis(1)))
+    }
+
+    /// Runs the polling loop like `start_polling`, sleeping `sleep_duration` between the polls, or
+    /// only yielding with `None`.
+    pub fn start_polling_with_duration(&mut self, sleep_duration: Option<Duration>) {
+        loop {
+            self.manual_poll(Instant::now());
+            match sleep_duration {
+                None => yield_now(),
+                Some(duration) => sleep(duration),
+            }
+        }
+    }
+
+    /// Returns and clears the IO errors encountered by the previous polls.
+    pub fn drain_errors(&mut self) -> Vec<SocketError> {
+        std::mem::take(&mut self.handler.socket_mut().errors)
+    }
+
+    /// Returns the smoothed round-trip time to `addr`, or `None` as long as no reliable packet sent
+    /// to it has been acknowledged.
+    pub fn rtt(&self, addr: &SocketAddr) -> Option<Duration> {
+        self.handler.socket().latency.rtt(addr)
+    }
+
+    /// Returns the share, between 0 and 1, of the latest reliable packets sent to `addr` which got
+    /// lost, or `None` as long as none has been acknowledged or lost. See `ConnectionMetrics`.
+    pub fn packet_loss(&self, addr: &SocketAddr) -> Option<f32> {
+        self.handler.socket().latency.packet_loss(addr)
+    }
+
+    /// Returns the estimates of every peer a reliable packet was sent to.
+    pub fn connection_metrics(&self) -> impl Iterator<Item = (SocketAddr, PeerMetrics)> + '_ {
+        let latency = &self.handler.socket().latency;
+        latency.peers().map(|addr| {
+            (*addr, PeerMetrics { rtt: latency.rtt(addr), packet_loss: latency.packet_loss(addr) })
+        })
+    }
+
+    /// Returns and clears the RTT estimates updated by the previous polls.
+    pub fn drain_latency_updates(&mut self) -> Vec<(SocketAddr, Duration)> {
+        self.handler.socket_mut().latency.drain_updates()
+    }
+
+    /// Waits for `addr` to acknowledge the reliable packet carrying `payload`, queued with `send`,
+    /// until `deadline`, to report it with `tag` in `drain_acks`. Past the deadline, the payload is
+    /// forgotten by `drain_message_acks`.
+    pub fn expect_ack(&mut self, addr: SocketAddr, tag: u64, payload: &[u8], deadline: Instant) {
+        self.handler.socket_mut().acks.expect(addr, tag, payload, deadline);
+    }
+
+    /// Returns and clears the tags of the packets acknowledged during the previous polls, along
+    /// with the peer which acknowledged them.
+    pub fn drain_acks(&mut self) -> Vec<(SocketAddr, u64)> {
+        self.handler.socket_mut().acks.drain_acked()
+    }
+
+    /// Waits for `addr` to acknowledge the reliable packet carrying `payload`, queued with `send`,
+    /// until `deadline`, to report `id` in `drain_message_acks`.
+    pub fn expect_message_ack(&mut self, addr: SocketAddr, id: MessageId, payload: &[u8], deadline: Instant) {
+        self.handler.socket_mut().acks.expect_message(addr, id, payload, deadline);
+    }
+
+    /// Reports `id` as timed out in the next `drain_message_acks`, e.g. for a message which can't
+    /// be acknowledged.
+    pub fn time_out_message_ack(&mut self, id: MessageId) {
+        self.handler.socket_mut().acks.time_out(id);
+    }
+
+    /// Returns and clears the ids of the messages acknowledged during the previous polls, then the
+    /// ones which weren't before their deadline, as of `now`, or whose peer disconnected.
+    pub fn drain_message_acks(&mut self, now: Instant) -> (Vec<MessageId>, Vec<MessageId>) {
+        self.handler.socket_mut().acks.drain_message_acks(now)
+    }
+
+    /// Returns the size in bytes of the largest payload laminar accepts to send unreliably, as the
+    /// configuration allows it. Unreliable packets aren't fragmented.
+    pub fn max_unreliable_payload_size(&self) -> usize {
+        self.max_unreliable_payload
+    }
+
+    /// Returns the size in bytes of the largest payload laminar accepts to send reliably, in as
+    /// many fragments as the configuration allows.
+    pub fn max_reliable_payload_size(&self) -> usize {
+        self.max_reliable_payload
+    }
+
+    /// Returns the local socket address.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.handler.socket().local_addr()?)
+    }
+}
@@ -0,0 +1,529 @@
+//! Network systems implementation routing messages through in-process queues, so that several
+//! `App`s can talk to each other deterministically without binding any real socket. This is
+//! mostly useful to test game systems.
+//!
+//! By default every message is delivered exactly once and in the order it was sent, which satisfies
+//! all the `DeliveryRequirement`s. `LinkConditions` can delay messages and drop unreliable ones,
+//! the drops being drawn from a seeded generator so a test sees the same losses on every run.
+//! Reliable messages are only ever delayed, as if the transport resent them. As with laminar, a
+//! `Connect` event is emitted once messages went both ways between two endpoints, and a
+//! `Disconnect` event once the remote endpoint went away.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::simulation::{
+    events::{DisconnectReason, NetworkSimulationEvent, TaggedNetworkEvent},
+    peers::{ConnectedPeers, connected_peers_system},
+    requirements::DeliveryRequirement,
+    timing::{NetworkSimulationTime, network_simulation_time_system},
+    transport::{
+        routing::{NetworkEventWriter, TransportId, unroutable_messages_system},
+        TransportResource,
+    },
+};
+use bevy::prelude::{Plugin, Resource, Res, ResMut, SystemSet, SystemLabel};
+use bevy::app::App;
+
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct MemoryLabel;
+
+/// Seed of the drop generator when none is given.
+const DEFAULT_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+/// Conditions every message sent over a `MemoryNetwork` goes through.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkConditions {
+    /// Time between sending a message and it being available to the receiver.
+    pub delay:     Duration,
+    /// Probability, between 0 and 1, for an unreliable message to be lost.
+    pub drop_rate: f32,
+}
+
+/// A message waiting in the inbox of its destination.
+struct InFlight {
+    source:     SocketAddr,
+    payload:    Bytes,
+    deliver_at: Instant,
+}
+
+/// Queues of the messages waiting to be received, keyed by their destination.
+type Inboxes = HashMap<SocketAddr, VecDeque<InFlight>>;
+
+struct NetworkState {
+    inboxes:    Inboxes,
+    conditions: LinkConditions,
+    rng:        u64,
+}
+
+impl NetworkState {
+    /// Draws whether the next unreliable message gets lost, with a xorshift generator.
+    fn drop_next(&mut self) -> bool {
+        if self.conditions.drop_rate <= 0.0 {
+            return false;
+        }
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        ((self.rng >> 40) as f32 / (1u64 << 24) as f32) < self.conditions.drop_rate
+    }
+}
+
+/// Handle to an in-process network. Clone it to connect several endpoints, usually living in
+/// different `App`s, to the same network.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl Default for MemoryNetwork {
+    fn default() -> Self {
+        Self::with_conditions(LinkConditions::default(), DEFAULT_SEED)
+    }
+}
+
+impl MemoryNetwork {
+    /// Creates a new network without any endpoint, delivering every message right away.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new network applying `conditions` to the messages, the drops being drawn from a
+    /// generator seeded with `seed`.
+    #[must_use]
+    pub fn with_conditions(conditions: LinkConditions, seed: u64) -> Self {
+        let state = NetworkState {
+            inboxes: HashMap::new(),
+            conditions,
+            // xorshift gets stuck on zero
+            rng: seed.max(1),
+        };
+        Self { state: Arc::new(Mutex::new(state)) }
+    }
+
+    /// Returns the conditions applied to the messages sent from now on.
+    #[must_use]
+    pub fn conditions(&self) -> LinkConditions {
+        self.state().conditions
+    }
+
+    /// Changes the conditions applied to the messages sent from now on.
+    pub fn set_conditions(&self, conditions: LinkConditions) {
+        self.state().conditions = conditions;
+    }
+
+    /// Returns true if an endpoint is bound to `addr`.
+    #[must_use]
+    pub fn is_bound(&self, addr: &SocketAddr) -> bool {
+        self.state().inboxes.contains_key(addr)
+    }
+
+    /// Returns the number of messages waiting to be received by the endpoint bound to `addr`,
+    /// including the delayed ones.
+    #[must_use]
+    pub fn pending(&self, addr: &SocketAddr) -> usize {
+        self.state().inboxes.get(addr).map_or(0, VecDeque::len)
+    }
+
+    /// Binds an endpoint to `addr`.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<MemorySocket> {
+        let inboxes = &mut self.state().inboxes;
+        if inboxes.contains_key(&addr) {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is already bound", addr)));
+        }
+        inboxes.insert(addr, VecDeque::new());
+        Ok(MemorySocket { network: self.clone(), addr, peers: HashMap::new() })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, NetworkState> {
+        // a panicking test shouldn't take the other endpoints down with it
+        self.state.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// Whether messages went each way between an endpoint and one of its peers.
+#[derive(Clone, Copy, Debug, Default)]
+struct PeerState {
+    sent:     bool,
+    received: bool,
+}
+
+impl PeerState {
+    fn is_established(self) -> bool {
+        self.sent && self.received
+    }
+}
+
+/// Endpoint bound to an address of a `MemoryNetwork`. It unbinds itself when dropped.
+pub struct MemorySocket {
+    network: MemoryNetwork,
+    addr:    SocketAddr,
+    peers:   HashMap<SocketAddr, PeerState>,
+}
+
+impl MemorySocket {
+    /// Returns the address the endpoint is bound to.
+    #[must_use]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Queues the payload in the inbox of `destination`, unless the network drops it because it is
+    /// unreliable. Returns whether the connection with the destination got established by this send.
+    pub fn send_to(
+        &mut self,
+        payload: Bytes,
+        destination: SocketAddr,
+        delivery: DeliveryRequirement,
+    ) -> io::Result<bool> {
+        let mut state = self.network.state();
+        let dropped = matches!(
+            delivery,
+            DeliveryRequirement::Unreliable | DeliveryRequirement::UnreliableSequenced(_)
+        ) && state.drop_next();
+        let deliver_at = Instant::now() + state.conditions.delay;
+        match state.inboxes.get_mut(&destination) {
+            Some(_) if dropped => {}
+            Some(inbox) => inbox.push_back(InFlight { source: self.addr, payload, deliver_at }),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("no endpoint bound to {}", destination),
+                ));
+            }
+        }
+        drop(state);
+        Ok(self.record(destination, |peer| peer.sent = true))
+    }
+
+    /// Takes the next received message whose delay has elapsed. The returned flag tells whether the
+    /// connection with the sender got established by this message.
+    pub fn recv(&mut self) -> Option<(SocketAddr, Bytes, bool)> {
+        let message = {
+            let mut state = self.network.state();
+            let inbox = state.inboxes.get_mut(&self.addr)?;
+            if inbox.front()?.deliver_at > Instant::now() {
+                return None;
+            }
+            inbox.pop_front()?
+        };
+        let established = self.record(message.source, |peer| peer.received = true);
+        Some((message.source, message.payload, established))
+    }
+
+    /// Forgets the established peers which aren't bound to the network anymore and returns them.
+    pub fn drop_unbound_peers(&mut self) -> Vec<SocketAddr> {
+        let state = self.network.state();
+        let gone: Vec<_> = self
+            .peers
+            .keys()
+            .filter(|addr| !state.inboxes.contains_key(addr))
+            .copied()
+            .collect();
+        drop(state);
+        gone.into_iter()
+            .filter(|addr| self.peers.remove(addr).is_some_and(PeerState::is_established))
+            .collect()
+    }
+
+    fn record(&mut self, peer: SocketAddr, update: impl FnOnce(&mut PeerState)) -> bool {
+        let state = self.peers.entry(peer).or_default();
+        let was_established = state.is_established();
+        update(state);
+        !was_established && state.is_established()
+    }
+}
+
+impl Drop for MemorySocket {
+    fn drop(&mut self) {
+        self.network.state().inboxes.remove(&self.addr);
+    }
+}
+
+/// Use this plugin to add the in-memory transport layer to your game.
+pub struct MemoryTransportPlugin {
+    network: MemoryNetwork,
+    address: SocketAddr,
+}
+
+impl MemoryTransportPlugin {
+    /// Creates a plugin binding the given address of the network.
+    pub fn new(network: MemoryNetwork, address: SocketAddr) -> Self {
+        MemoryTransportPlugin { network, address }
+    }
+}
+
+impl Plugin for MemoryTransportPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_event::<NetworkSimulationEvent>()
+            .add_event::<TaggedNetworkEvent>()
+            .init_resource::<NetworkSimulationTime>()
+            .init_resource::<TransportResource>()
+            .init_resource::<ConnectedPeers>()
+            .insert_resource(MemorySocketResource::new(self.network.bind(self.address).ok()))
+            .add_system_set(SystemSet::new()
+                .label(MemoryLabel)
+                .with_system(network_simulation_time_system)
+                .with_system(unroutable_messages_system)
+                .with_system(connected_peers_system)
+                .with_system(memory_network_send_system)
+                .with_system(memory_network_recv_system)
+            );
+        app.world.resource_mut::<TransportResource>().register_transport(TransportId::MEMORY);
+    }
+
+    fn name(&self) -> &str {
+        "memory"
+    }
+}
+
+/// Creates a new memory network send system.
+pub fn memory_network_send_system(mut transport: ResMut<TransportResource>,
+                                  mut socket:        ResMut<MemorySocketResource>,
+                                  mut event_channel: NetworkEventWriter,
+                                      sim_time:      Res<NetworkSimulationTime>) {
+    if let Some(socket) = socket.get_mut() {
+        let messages = transport
+            .drain_messages_to_send_via(TransportId::MEMORY, |_| sim_time.should_send_message_now());
+
+        for message in messages {
+            match socket.send_to(message.payload.clone(), message.destination, message.delivery) {
+                Ok(true) => {
+                    event_channel.send(TransportId::MEMORY, NetworkSimulationEvent::Connect(message.destination))
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    if let Some((e, message)) = transport.retry_failed(e, message) {
+                        event_channel.send(TransportId::MEMORY, NetworkSimulationEvent::SendError(e, message));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Creates a new memory network receive system.
+pub fn memory_network_recv_system(mut socket:        ResMut<MemorySocketResource>,
+                                  mut event_channel: NetworkEventWriter) {
+    if let Some(socket) = socket.get_mut() {
+        while let Some((source, payload, established)) = socket.recv() {
+            if established {
+                event_channel.send(TransportId::MEMORY, NetworkSimulationEvent::Connect(source));
+            }
+            event_channel.send(TransportId::MEMORY, NetworkSimulationEvent::Message(source, payload));
+        }
+        for addr in socket.drop_unbound_peers() {
+            event_channel.send(TransportId::MEMORY, NetworkSimulationEvent::Disconnect(addr, DisconnectReason::Closed));
+        }
+    }
+}
+
+/// Resource that owns the in-memory endpoint.
+#[derive(Default, Resource)]
+pub struct MemorySocketResource {
+    socket: Option<MemorySocket>,
+}
+
+impl MemorySocketResource {
+    /// Creates a new instance of the `MemorySocketResource`.
+    #[must_use]
+    pub fn new(socket: Option<MemorySocket>) -> Self {
+        Self { socket }
+    }
+
+    /// Returns a reference to the endpoint if there is one configured.
+    #[must_use]
+    pub fn get(&self) -> Option<&MemorySocket> {
+        self.socket.as_ref()
+    }
+
+    /// Returns a mutable reference to the endpoint if there is one configured.
+    pub fn get_mut(&mut self) -> Option<&mut MemorySocket> {
+        self.socket.as_mut()
+    }
+
+    /// Drops the endpoint, unbinding its address from the network.
+    pub fn drop_socket(&mut self) {
+        self.socket = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::event::Events;
+
+    use super::*;
+    use crate::simulation::requirements::{DeliveryRequirement, UrgencyRequirement};
+
+    #[test]
+    fn test_messages_are_delivered_in_order() {
+        let network = MemoryNetwork::new();
+        let (mut a, a_addr) = create_test_app(&network, 1);
+        let (mut b, b_addr) = create_test_app(&network, 2);
+
+        for payload in [b"one", b"two"] {
+            a.world.resource_mut::<TransportResource>().send_with_requirements(
+                b_addr,
+                payload,
+                DeliveryRequirement::Unreliable,
+                UrgencyRequirement::OnTick,
+            );
+        }
+        step(&mut [&mut a, &mut b]);
+
+        assert_eq!(network.pending(&b_addr), 0);
+        assert_eq!(drain_events(&mut b), vec![
+            Received::Message(a_addr, Bytes::from_static(b"one")),
+            Received::Message(a_addr, Bytes::from_static(b"two")),
+        ]);
+    }
+
+    #[test]
+    fn test_connect_and_disconnect() {
+        let network = MemoryNetwork::new();
+        let (mut a, a_addr) = create_test_app(&network, 1);
+        let (mut b, b_addr) = create_test_app(&network, 2);
+
+        a.world.resource_mut::<TransportResource>().send(b_addr, b"ping");
+        step(&mut [&mut a, &mut b]);
+        b.world.resource_mut::<TransportResource>().send(a_addr, b"pong");
+        step(&mut [&mut b, &mut a]);
+
+        assert_eq!(drain_events(&mut b), vec![
+            Received::Message(a_addr, Bytes::from_static(b"ping")),
+            Received::Connect(a_addr),
+        ]);
+        assert_eq!(drain_events(&mut a), vec![
+            Received::Connect(b_addr),
+            Received::Message(b_addr, Bytes::from_static(b"pong")),
+        ]);
+
+        b.world.resource_mut::<MemorySocketResource>().drop_socket();
+        assert!(!network.is_bound(&b_addr));
+        step(&mut [&mut a]);
+        assert_eq!(drain_events(&mut a), vec![Received::Disconnect(b_addr)]);
+    }
+
+    #[test]
+    fn test_send_to_unbound_address_fails() {
+        let network = MemoryNetwork::new();
+        let (mut a, _) = create_test_app(&network, 1);
+
+        a.world.resource_mut::<TransportResource>().send("10.0.0.9:9".parse().unwrap(), b"test");
+        step(&mut [&mut a]);
+
+        assert_eq!(drain_events(&mut a), vec![Received::SendError(io::ErrorKind::ConnectionRefused)]);
+    }
+
+    #[test]
+    fn test_reliable_message_is_delayed_but_never_dropped() {
+        let delay = Duration::from_millis(50);
+        let network = MemoryNetwork::with_conditions(LinkConditions { delay, drop_rate: 1.0 }, 7);
+        let (mut a, a_addr) = create_test_app(&network, 1);
+        let (mut b, b_addr) = create_test_app(&network, 2);
+        let sent_at = Instant::now();
+
+        a.world.resource_mut::<TransportResource>().send_with_requirements(
+            b_addr,
+            b"reliable",
+            DeliveryRequirement::ReliableOrdered(None),
+            UrgencyRequirement::Immediate,
+        );
+        a.world.resource_mut::<TransportResource>().send_with_requirements(
+            b_addr,
+            b"unreliable",
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::Immediate,
+        );
+        step(&mut [&mut a, &mut b]);
+        assert_eq!(network.pending(&b_addr), 1);
+
+        let mut events = Vec::new();
+        while events.is_empty() {
+            step(&mut [&mut b]);
+            events = drain_events(&mut b);
+        }
+        assert!(sent_at.elapsed() >= delay);
+        assert_eq!(events, vec![Received::Message(a_addr, Bytes::from_static(b"reliable"))]);
+    }
+
+    #[test]
+    fn test_drops_are_reproducible() {
+        let losses = |seed| {
+            let network = MemoryNetwork::with_conditions(
+                LinkConditions { delay: Duration::ZERO, drop_rate: 0.5 },
+                seed,
+            );
+            let receiver = network.bind("10.0.0.2:3000".parse().unwrap()).unwrap();
+            let mut sender = network.bind("10.0.0.1:3000".parse().unwrap()).unwrap();
+            (0..100)
+                .map(|_| {
+                    let before = network.pending(&receiver.local_addr());
+                    sender
+                        .send_to(Bytes::new(), receiver.local_addr(), DeliveryRequirement::Unreliable)
+                        .unwrap();
+                    network.pending(&receiver.local_addr()) == before
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let dropped = losses(42);
+        assert_eq!(dropped, losses(42));
+        let count = dropped.iter().filter(|dropped| **dropped).count();
+        assert!((30..70).contains(&count), "{} dropped", count);
+    }
+
+    /// Runs one update of each app, in order.
+    fn step(apps: &mut [&mut App]) {
+        for app in apps {
+            app.update();
+        }
+    }
+
+    /// Comparable summary of a `NetworkSimulationEvent`.
+    #[derive(Debug, PartialEq)]
+    enum Received {
+        Message(SocketAddr, Bytes),
+        Connect(SocketAddr),
+        Disconnect(SocketAddr),
+        SendError(io::ErrorKind),
+    }
+
+    fn drain_events(app: &mut App) -> Vec<Received> {
+        app.world
+            .resource_mut::<Events<NetworkSimulationEvent>>()
+            .drain()
+            // the ids of the connections are reported along with the `Connect` and `Disconnect`
+            .filter(|event| {
+                !matches!(
+                    event,
+                    NetworkSimulationEvent::PeerConnected(..) | NetworkSimulationEvent::PeerDisconnected(..)
+                )
+            })
+            .map(|event| match event {
+                NetworkSimulationEvent::Message(addr, payload) => Received::Message(addr, payload),
+                NetworkSimulationEvent::Connect(addr) => Received::Connect(addr),
+                NetworkSimulationEvent::Disconnect(addr, _) => Received::Disconnect(addr),
+                NetworkSimulationEvent::SendError(e, _) => Received::SendError(e.kind()),
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect()
+    }
+
+    fn create_test_app(network: &MemoryNetwork, host: u8) -> (App, SocketAddr) {
+        let addr = SocketAddr::from(([10, 0, 0, host], 3000));
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(MemoryTransportPlugin::new(network.clone(), addr));
+        (app, addr)
+    }
+}
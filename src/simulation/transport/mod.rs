@@ -2,23 +2,429 @@
 //! protocols. One important thing to note if you're implementing your own, the underlying sockets
 //! MUST be non-blocking in order to play nicely with the ECS scheduler.
 
+#[cfg(feature = "conditioner")]
+pub mod conditioner;
+#[cfg(feature = "bevy")]
+pub mod fallback;
+pub mod generic;
 pub mod laminar;
+#[cfg(feature = "bevy")]
+pub mod memory;
+pub mod routing;
+#[cfg(feature = "steam")]
+pub mod steam;
+#[cfg(feature = "bevy")]
+pub mod tcp;
+#[cfg(feature = "bevy")]
+pub mod udp;
+#[cfg(all(unix, feature = "bevy"))]
+pub mod unix;
 
-use std::{collections::VecDeque, net::SocketAddr};
+use std::{
+    collections::{btree_map::Entry, BTreeMap, HashMap, HashSet, VecDeque},
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "bevy")]
 use bevy::prelude::Resource;
+use bytes::{Bytes, BytesMut};
 use crate::simulation::{
-    message::Message,
-    requirements::{DeliveryRequirement, UrgencyRequirement},
+    connections::{ConnectionId, ConnectionIds},
+    message::{Message, MessageId},
+    requirements::{DeliveryRequirement, StreamId, UrgencyRequirement},
+    transport::routing::TransportId,
 };
 
 /// Resource serving as the owner of the queue of messages to be sent. This resource also serves
 /// as the interface for other systems to send messages.
-#[derive(Resource)]
+#[cfg_attr(feature = "bevy", derive(Resource))]
 pub struct TransportResource {
-    messages: VecDeque<Message>,
+    messages: MessageQueue,
     frame_budget_bytes: i32,
     latency_nanos: i64,
     packet_loss: f32,
+    transports: HashSet<TransportId>,
+    capacity: Option<(usize, QueuePolicy)>,
+    destination_capacities: HashMap<SocketAddr, (usize, QueuePolicy)>,
+    overflowing: bool,
+    dropped: u64,
+    rejected: Vec<Message>,
+    expired: Vec<Message>,
+    expired_count: u64,
+    failed: Vec<(io::Error, Message)>,
+    send_order: Vec<SendSlot>,
+    default_ttl: Option<Duration>,
+    default_delivery: Option<DeliveryRequirement>,
+    default_urgency: UrgencyRequirement,
+    max_payload_size: Option<usize>,
+    retry_policy: Option<RetryPolicy>,
+    rate_limits: HashMap<SocketAddr, RateLimit>,
+    routes: HashMap<SocketAddr, (TransportId, SocketAddr)>,
+    peers: HashSet<SocketAddr>,
+    connecting: HashSet<SocketAddr>,
+    connect_requests: Vec<SocketAddr>,
+    connections: ConnectionIds,
+    retired_sends: Vec<(ConnectionId, Bytes)>,
+    role: Option<NetworkRole>,
+    cancel_on_disconnect: bool,
+    next_message_id: u64,
+    #[cfg(feature = "conditioner")]
+    conditioner: Option<conditioner::NetworkConditioner>,
+}
+
+/// What becomes of a message handed out by `TransportResource::for_each_message_to_send`.
+#[derive(Debug)]
+pub enum Disposition {
+    // The message was sent, or reported, and leaves the queue
+    Done,
+    // The message goes back to its place in the queue, for a later drain
+    Requeue(Message),
+    // Sending the message failed, it is retried if the retry policy allows it, and kept for
+    // `drain_failed_messages` otherwise
+    Failed(io::Error, Message),
+}
+
+/// A message `for_each_message_to_send` hands out, sorted by priority, then turn of its
+/// destination, then number.
+type SendSlot = (std::cmp::Reverse<u8>, usize, u64, SocketAddr);
+
+/// What happens to a message queued while the queue is full, see `TransportResource::set_capacity`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueuePolicy {
+    // The oldest queued message is dropped to make room for it
+    DropOldest,
+    // The oldest queued unreliable message is dropped to make room for it. If none is queued, the
+    // message is dropped if it's unreliable itself, refused otherwise
+    DropOldestUnreliable,
+    // The message is dropped
+    DropNewest,
+    // The message is refused, and reported as a `SendError`
+    Reject,
+}
+
+/// How the messages which failed to be sent with a transient IO error, e.g. `WouldBlock` or
+/// `NetworkUnreachable` after a Wi-Fi blip, are sent again, see `TransportResource::set_retry_policy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of times a reliable message, or one of the `Default` delivery, is sent again.
+    pub max_retries:            u32,
+    /// Same for the unreliable messages, none by default as a fresher one usually follows.
+    pub max_unreliable_retries: u32,
+    /// Time before the first retry, doubled after each of them.
+    pub backoff:                Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries:            3,
+            max_unreliable_retries: 0,
+            backoff:                Duration::from_millis(50),
+        }
+    }
+}
+
+/// Snapshot of the queue of the `TransportResource`, see `TransportResource::queue_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Number of queued messages.
+    pub messages:   usize,
+    /// Total size of their payloads, in bytes.
+    pub bytes:      usize,
+    /// How long the oldest of them has been waiting, `None` while the queue is empty.
+    pub oldest_age: Option<Duration>,
+}
+
+/// Role of the local peer in a star topology, see `TransportResource::set_role`. Without one, every
+/// peer is addressed alike.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NetworkRole {
+    // Accepts the connections of the clients, tracked as `accepted_peers`
+    Server,
+    // Talks to a single server, the one `send_to_server` sends to
+    Client { server_addr: SocketAddr },
+}
+
+/// The queued messages, one queue per destination. Each message is numbered, so that the drains
+/// still go through all of them in the order they were queued.
+#[derive(Default)]
+struct MessageQueue {
+    queues: HashMap<SocketAddr, VecDeque<(u64, Message)>>,
+    len:    usize,
+    // total size of the queued payloads
+    bytes:  usize,
+    times:  QueuedTimes,
+    next:   u64,
+}
+
+/// Number of queued messages by the time they were queued, so that the oldest one is known
+/// without going through the queues.
+#[derive(Default)]
+struct QueuedTimes(BTreeMap<Instant, usize>);
+
+impl QueuedTimes {
+    fn add(&mut self, message: &Message) {
+        *self.0.entry(message.queued_at).or_default() += 1;
+    }
+
+    fn remove(&mut self, message: &Message) {
+        if let Entry::Occupied(mut count) = self.0.entry(message.queued_at) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+    }
+
+    fn oldest(&self) -> Option<Instant> {
+        self.0.keys().next().copied()
+    }
+}
+
+impl MessageQueue {
+    fn push(&mut self, mut message: Message) {
+        self.next += 1;
+        message.number = self.next;
+        self.bytes += message.payload.len();
+        self.insert(self.next, message);
+        self.len += 1;
+    }
+
+    /// Puts a message back in the queue of its destination, at the place its number gives it.
+    fn insert(&mut self, number: u64, message: Message) {
+        self.times.add(&message);
+        let queue = self.queues.entry(message.destination).or_default();
+        let at = queue.partition_point(|(queued, _)| *queued < number);
+        queue.insert(at, (number, message));
+    }
+
+    /// Removes the oldest message for which `filter` is true, among the ones to `destination` if
+    /// given, among all of them otherwise.
+    fn pop_oldest(
+        &mut self,
+        destination: Option<SocketAddr>,
+        filter: impl Fn(&Message) -> bool,
+    ) -> Option<Message> {
+        let (destination, at, _) = self
+            .queues
+            .iter()
+            .filter(|(queued_for, _)| destination.is_none_or(|destination| **queued_for == destination))
+            .filter_map(|(queued_for, queue)| {
+                let at = queue.iter().position(|(_, message)| filter(message))?;
+                Some((*queued_for, at, queue[at].0))
+            })
+            .min_by_key(|(_, _, number)| *number)?;
+        let queue = self.queues.get_mut(&destination)?;
+        let (_, message) = queue.remove(at)?;
+        if queue.is_empty() {
+            self.queues.remove(&destination);
+        }
+        self.times.remove(&message);
+        self.len -= 1;
+        self.bytes -= message.payload.len();
+        Some(message)
+    }
+
+    /// Removes the message of the given number from the queue of `destination`, leaving the queue
+    /// there even once empty.
+    fn remove(&mut self, destination: &SocketAddr, number: u64) -> Option<Message> {
+        let queue = self.queues.get_mut(destination)?;
+        let at = queue.binary_search_by_key(&number, |(queued, _)| *queued).ok()?;
+        let (_, message) = queue.remove(at)?;
+        self.times.remove(&message);
+        self.len -= 1;
+        self.bytes -= message.payload.len();
+        Some(message)
+    }
+
+    /// Puts a message taken out of the queue back at its place, ahead of the ones queued after it.
+    fn put_back(&mut self, message: Message) {
+        self.bytes += message.payload.len();
+        self.insert(message.number, message);
+        self.len += 1;
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn len_for(&self, destination: &SocketAddr) -> usize {
+        self.queues.get(destination).map_or(0, VecDeque::len)
+    }
+
+    fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Returns when the oldest message was queued, which may not be the first one, as a retried
+    /// message queued for the first time keeps the time it was created.
+    fn oldest_queued_at(&self) -> Option<Instant> {
+        self.times.oldest()
+    }
+
+    /// Returns the messages in the order they were queued, merging the queues of the destinations
+    /// as it goes rather than collecting them.
+    fn ordered(&self) -> impl Iterator<Item = &Message> {
+        let mut last: Option<u64> = None;
+        std::iter::from_fn(move || {
+            let is_past = |number: &u64| last.is_some_and(|last| *number <= last);
+            let (number, message) = self
+                .queues
+                .values()
+                .filter_map(|queue| queue.get(queue.partition_point(|(number, _)| is_past(number))))
+                .min_by_key(|(number, _)| *number)?;
+            last = Some(*number);
+            Some(message)
+        })
+    }
+
+    /// Drains the messages of every destination for which `filter` is true, in the order they were
+    /// queued. The other messages stay in place, except the ones the filter gave another
+    /// destination, which move to its queue.
+    fn drain(&mut self, filter: impl FnMut(&mut Message) -> bool) -> Vec<Message> {
+        self.drain_from(None, filter)
+    }
+
+    /// Same as `drain`, only going through the messages to `destination`.
+    fn take(&mut self, destination: SocketAddr, filter: impl FnMut(&mut Message) -> bool) -> Vec<Message> {
+        self.drain_from(Some(destination), filter)
+    }
+
+    fn drain_from(
+        &mut self,
+        destination: Option<SocketAddr>,
+        mut filter: impl FnMut(&mut Message) -> bool,
+    ) -> Vec<Message> {
+        let mut drained = Vec::new();
+        let mut moved = Vec::new();
+        let Self { queues, len, bytes, times, .. } = self;
+        let queues = queues
+            .iter_mut()
+            .filter(|(queued_for, _)| destination.is_none_or(|destination| **queued_for == destination));
+        for (queued_for, queue) in queues {
+            let mut readdressed = false;
+            drained.extend(split_off_where(queue, |message| {
+                // counted again if it stays, in case the filter changed the payload or the time
+                *bytes -= message.payload.len();
+                times.remove(message);
+                if filter(message) {
+                    return true;
+                }
+                *bytes += message.payload.len();
+                times.add(message);
+                readdressed |= message.destination != *queued_for;
+                false
+            }));
+            if readdressed {
+                moved.extend(split_off_where(queue, |message| message.destination != *queued_for));
+            }
+        }
+        *len -= drained.len();
+        for (number, message) in moved {
+            self.times.remove(&message);
+            self.insert(number, message);
+        }
+        self.queues.retain(|_, queue| !queue.is_empty());
+        // only the drained messages are merged back in the order they were queued
+        drained.sort_unstable_by_key(|(number, _)| *number);
+        drained.into_iter().map(|(_, message)| message).collect()
+    }
+}
+
+/// Orders `messages`, which come by priority, so that the ones of the same priority take turns by
+/// destination, see `TransportResource::drain_messages_to_send_fairly`.
+fn take_turns(messages: Vec<Message>) -> Vec<Message> {
+    let mut turns: HashMap<(SocketAddr, u8), usize> = HashMap::new();
+    let mut messages: Vec<_> = messages
+        .into_iter()
+        .map(|message| {
+            let turn = turns.entry((message.destination, message.priority)).or_default();
+            *turn += 1;
+            (*turn, message)
+        })
+        .collect();
+    messages.sort_by_key(|(turn, message)| (std::cmp::Reverse(message.priority), *turn));
+    messages.into_iter().map(|(_, message)| message).collect()
+}
+
+/// Removes the messages of `queue` for which `remove` is true, like `VecDeque::retain_mut` but
+/// handing them out, in no particular order. The others stay in order.
+fn split_off_where(
+    queue: &mut VecDeque<(u64, Message)>,
+    mut remove: impl FnMut(&mut Message) -> bool,
+) -> impl Iterator<Item = (u64, Message)> + '_ {
+    let messages = queue.make_contiguous();
+    let mut kept = 0;
+    for at in 0..messages.len() {
+        if !remove(&mut messages[at].1) {
+            messages.swap(kept, at);
+            kept += 1;
+        }
+    }
+    queue.drain(kept..)
+}
+
+/// Returns true if `message` may be lost on the way anyway. `Default` isn't, as it may be reliable
+/// depending on the transport.
+fn is_unreliable(message: &Message) -> bool {
+    matches!(message.delivery, DeliveryRequirement::Unreliable | DeliveryRequirement::UnreliableSequenced(_))
+}
+
+/// Returns true if sending again after an error of this kind may succeed.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkDown
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Token bucket limiting what is drained for a destination, holding one second of tokens at most.
+#[derive(Clone, Copy, Debug)]
+struct RateLimit {
+    per_second: f64,
+    // whether a message costs one token rather than one per byte
+    per_packet: bool,
+    tokens:     f64,
+    refilled:   Instant,
+}
+
+impl RateLimit {
+    fn new(per_second: u32, per_packet: bool) -> Self {
+        let per_second = f64::from(per_second);
+        Self { per_second, per_packet, tokens: per_second, refilled: Instant::now() }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.per_second);
+        self.refilled = now;
+    }
+
+    /// Spends the cost of `message` if any token is left, the bucket going into debt for a
+    /// message costing more than what is left, so that no message is held back forever.
+    fn take(&mut self, message: &Message) -> bool {
+        if self.tokens <= 0.0 {
+            return false;
+        }
+        self.tokens -= self.cost(message);
+        true
+    }
+
+    /// Gives back what `take` spent on a message which wasn't sent after all.
+    fn refund(&mut self, message: &Message) {
+        self.tokens = (self.tokens + self.cost(message)).min(self.per_second);
+    }
+
+    fn cost(&self, message: &Message) -> f64 {
+        if self.per_packet { 1.0 } else { message.payload.len() as f64 }
+    }
 }
 
 impl TransportResource {
@@ -26,10 +432,37 @@ impl TransportResource {
     #[must_use]
     pub fn new() -> Self {
         Self {
-            messages: VecDeque::new(),
+            messages: MessageQueue::default(),
             frame_budget_bytes: 0,
             latency_nanos: 0,
             packet_loss: 0.0,
+            transports: HashSet::new(),
+            capacity: None,
+            destination_capacities: HashMap::new(),
+            overflowing: false,
+            dropped: 0,
+            rejected: Vec::new(),
+            expired: Vec::new(),
+            expired_count: 0,
+            failed: Vec::new(),
+            send_order: Vec::new(),
+            default_ttl: None,
+            default_delivery: None,
+            default_urgency: UrgencyRequirement::OnTick,
+            next_message_id: 0,
+            max_payload_size: None,
+            retry_policy: None,
+            rate_limits: HashMap::new(),
+            routes: HashMap::new(),
+            peers: HashSet::new(),
+            connecting: HashSet::new(),
+            connect_requests: Vec::new(),
+            connections: ConnectionIds::default(),
+            retired_sends: Vec::new(),
+            role: None,
+            cancel_on_disconnect: false,
+            #[cfg(feature = "conditioner")]
+            conditioner: None,
         }
     }
 
@@ -76,14 +509,292 @@ impl TransportResource {
         self.packet_loss = loss;
     }
 
+    /// Limits the queue to `capacity` messages, `policy` deciding what happens to the messages
+    /// queued beyond that, e.g. while the sends are stalled. The queue is unbounded by default.
+    pub fn set_capacity(&mut self, capacity: usize, policy: QueuePolicy) {
+        self.capacity = Some((capacity, policy));
+    }
+
+    /// Limits the messages queued for `destination` to `capacity`, e.g. so that an unreachable
+    /// peer can't fill the queue shared with the others. This applies on top of `set_capacity`.
+    pub fn set_destination_capacity(&mut self, destination: SocketAddr, capacity: usize, policy: QueuePolicy) {
+        self.destination_capacities.insert(destination, (capacity, policy));
+    }
+
+    /// Lifts the limit on the number of messages queued for `destination`.
+    pub fn clear_destination_capacity(&mut self, destination: &SocketAddr) {
+        self.destination_capacities.remove(destination);
+    }
+
+    /// Returns the maximum number of messages queued for `destination`, if there is one.
+    #[must_use]
+    pub fn destination_capacity(&self, destination: &SocketAddr) -> Option<usize> {
+        self.destination_capacities.get(destination).map(|(capacity, _)| capacity).copied()
+    }
+
+    /// Lifts the limit on the number of queued messages.
+    pub fn clear_capacity(&mut self) {
+        self.capacity = None;
+    }
+
+    /// Returns the maximum number of queued messages, if there is one.
+    #[must_use]
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity.map(|(capacity, _)| capacity)
+    }
+
+    /// Returns how many messages were dropped by the `DropOldest`, `DropOldestUnreliable` and
+    /// `DropNewest` policies.
+    #[must_use]
+    pub fn dropped_messages(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Drains the messages refused by the `Reject` policy.
+    pub fn drain_rejected_messages(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.rejected)
+    }
+
+    /// Drains the messages dropped because their time to live elapsed, see `send_with_ttl`.
+    pub fn drain_expired_messages(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.expired)
+    }
+
+    /// Drains the messages `for_each_message_to_send` was told failed to send, with their error,
+    /// unless they were queued again to be retried.
+    pub fn drain_failed_messages(&mut self) -> Vec<(io::Error, Message)> {
+        std::mem::take(&mut self.failed)
+    }
+
+    /// Returns how many messages were dropped because their time to live elapsed, drained or not.
+    #[must_use]
+    pub fn expired_messages(&self) -> u64 {
+        self.expired_count
+    }
+
+    /// Gives the unreliable messages queued from now on without a time to live of their own, e.g.
+    /// the position updates, `ttl` as one, `None` by default. The reliable messages only expire
+    /// when given a time to live with `send_with_ttl`.
+    pub fn set_default_ttl(&mut self, ttl: Option<Duration>) {
+        self.default_ttl = ttl;
+    }
+
+    /// Returns the time to live of the unreliable messages, see `set_default_ttl`.
+    #[must_use]
+    pub fn default_ttl(&self) -> Option<Duration> {
+        self.default_ttl
+    }
+
+    /// Gives the messages queued from now on with `DeliveryRequirement::Default`, e.g. by `send`,
+    /// `delivery` instead, say `UnreliableSequenced` when most of the traffic is position updates.
+    /// The messages already queued keep theirs. With `None`, the default, each transport decides
+    /// what `Default` means, reliable and ordered for laminar.
+    pub fn set_default_delivery(&mut self, delivery: Option<DeliveryRequirement>) {
+        self.default_delivery = delivery.filter(|delivery| *delivery != DeliveryRequirement::Default);
+    }
+
+    /// Returns the requirement `Default` stands for, see `set_default_delivery`.
+    #[must_use]
+    pub fn default_delivery(&self) -> Option<DeliveryRequirement> {
+        self.default_delivery
+    }
+
+    /// Sets the urgency of the messages queued from now on by `send` and `send_to_server`,
+    /// `OnTick` by default. The messages already queued keep theirs.
+    pub fn set_default_urgency(&mut self, urgency: UrgencyRequirement) {
+        self.default_urgency = urgency;
+    }
+
+    /// Returns the urgency of the messages queued by `send`, see `set_default_urgency`.
+    #[must_use]
+    pub fn default_urgency(&self) -> UrgencyRequirement {
+        self.default_urgency
+    }
+
+    /// Sets the largest payload `send_segments` queues, e.g. the `max_reliable_payload_size` of the
+    /// laminar socket, `None` by default. The other sends are still only checked by the transport.
+    pub fn set_max_payload_size(&mut self, max_size: Option<usize>) {
+        self.max_payload_size = max_size;
+    }
+
+    /// Returns the largest payload `send_segments` queues, see `set_max_payload_size`.
+    #[must_use]
+    pub fn max_payload_size(&self) -> Option<usize> {
+        self.max_payload_size
+    }
+
+    /// Sends the messages which failed with a transient IO error again, following `policy`, or
+    /// reports them right away as a `SendError` with `None`, the default. Only the final failure of
+    /// a message retried is reported, with the number of attempts it took in `Message::attempts`.
+    pub fn set_retry_policy(&mut self, policy: Option<RetryPolicy>) {
+        self.retry_policy = policy;
+    }
+
+    /// Returns how the failed messages are sent again, see `set_retry_policy`.
+    #[must_use]
+    pub fn retry_policy(&self) -> Option<RetryPolicy> {
+        self.retry_policy
+    }
+
+    /// Queues `message`, which a transport failed to send with `e`, again if the retry policy
+    /// allows it, held back until its backoff elapsed. Returns both otherwise, to be reported as a
+    /// `SendError`. The message takes its place in the queue again, ahead of the ones queued after
+    /// it, so that an ordered message isn't overtaken, and skips the capacity checks it already
+    /// went through.
+    pub fn retry_failed(&mut self, e: io::Error, message: Message) -> Option<(io::Error, Message)> {
+        self.retry_failed_at(e, message, Instant::now())
+    }
+
+    fn retry_failed_at(&mut self, e: io::Error, mut message: Message, now: Instant) -> Option<(io::Error, Message)> {
+        let Some(policy) = self.retry_policy else {
+            return Some((e, message));
+        };
+        let max_retries = if is_unreliable(&message) { policy.max_unreliable_retries } else { policy.max_retries };
+        if !is_transient(&e) || message.attempts >= max_retries {
+            return Some((e, message));
+        }
+        let backoff = policy.backoff.saturating_mul(2u32.saturating_pow(message.attempts));
+        message.attempts += 1;
+        message.retry_at = Some(now + backoff);
+        self.requeue(message);
+        None
+    }
+
+    /// Limits the messages drained for `destination` to `bytes_per_sec` of payload, with bursts of
+    /// one second's worth. The messages beyond that stay queued, in order, for the next drains.
+    /// The higher priorities are charged first, and a message requeued by the send gets its cost
+    /// back.
+    pub fn set_peer_rate_limit(&mut self, destination: SocketAddr, bytes_per_sec: u32) {
+        self.rate_limits.insert(destination, RateLimit::new(bytes_per_sec, false));
+    }
+
+    /// Same as `set_peer_rate_limit`, but counting messages rather than bytes.
+    pub fn set_peer_packet_rate_limit(&mut self, destination: SocketAddr, packets_per_sec: u32) {
+        self.rate_limits.insert(destination, RateLimit::new(packets_per_sec, true));
+    }
+
+    /// Lifts the rate limit of `destination`.
+    pub fn clear_peer_rate_limit(&mut self, destination: &SocketAddr) {
+        self.rate_limits.remove(destination);
+    }
+
+    /// Routes the messages to `destination` to `transport`, which sends them to `address` instead,
+    /// e.g. to the port of a fallback transport. This applies to the messages queued and to the ones
+    /// sent from now on, except those given a transport with `send_via`. The messages queued under
+    /// a previous route of `destination` are moved to the new one.
+    pub fn set_route(&mut self, destination: SocketAddr, transport: TransportId, address: SocketAddr) {
+        let previous = self.routes.insert(destination, (transport, address));
+        let mut reroute = |message: &mut Message| {
+            let routed_before = previous
+                .is_some_and(|(route, addr)| message.transport == Some(route) && message.destination == addr);
+            if routed_before || (message.transport.is_none() && message.destination == destination) {
+                message.transport = Some(transport);
+                message.destination = address;
+            }
+            false
+        };
+        // only the queues of `destination` and of its previous address hold messages to reroute
+        self.messages.take(destination, &mut reroute);
+        if let Some((_, addr)) = previous.filter(|(_, addr)| *addr != destination) {
+            self.messages.take(addr, &mut reroute);
+        }
+    }
+
+    /// Stops routing the messages sent to `destination`, the ones already queued keep their route.
+    pub fn clear_route(&mut self, destination: &SocketAddr) {
+        self.routes.remove(destination);
+    }
+
+    /// Returns the transport and the address the messages to `destination` are routed to, if set.
+    #[must_use]
+    pub fn route(&self, destination: &SocketAddr) -> Option<(TransportId, SocketAddr)> {
+        self.routes.get(destination).copied()
+    }
+
+    fn enqueue(&mut self, mut message: Message) {
+        if message.delivery == DeliveryRequirement::Default {
+            message.delivery = self.default_delivery.unwrap_or(DeliveryRequirement::Default);
+        }
+        if message.transport.is_none() {
+            if let Some((transport, address)) = self.routes.get(&message.destination) {
+                message.transport = Some(*transport);
+                message.destination = *address;
+            }
+        }
+        if message.expires_at.is_none() && is_unreliable(&message) {
+            message.expires_at = self.default_ttl.map(|ttl| message.queued_at + ttl);
+        }
+        let destination = message.destination;
+        let limits = [
+            self.destination_capacities.get(&destination).map(|limit| (*limit, Some(destination))),
+            self.capacity.map(|limit| (limit, None)),
+        ];
+        let mut overflowed = false;
+        for ((capacity, policy), scope) in limits.into_iter().flatten() {
+            let len = scope.map_or(self.messages.len(), |destination| self.messages.len_for(&destination));
+            if len < capacity {
+                continue;
+            }
+            overflowed = true;
+            match self.make_room(scope, policy, message) {
+                Some(kept) => message = kept,
+                None => return,
+            }
+        }
+        self.overflowing = overflowed;
+        self.messages.push(message);
+    }
+
+    /// Puts a message which was queued back at its place, queues it like a new one otherwise.
+    fn requeue(&mut self, message: Message) {
+        if message.number == 0 {
+            self.enqueue(message);
+        } else {
+            self.messages.put_back(message);
+        }
+    }
+
+    /// Applies `policy` to the queue, or to the queue of `scope`, which is full. Returns `message`
+    /// if there is room for it now.
+    fn make_room(&mut self, scope: Option<SocketAddr>, policy: QueuePolicy, message: Message) -> Option<Message> {
+        let dropped = match policy {
+            QueuePolicy::DropOldest => self.messages.pop_oldest(scope, |_| true),
+            QueuePolicy::DropOldestUnreliable => self.messages.pop_oldest(scope, is_unreliable),
+            QueuePolicy::DropNewest | QueuePolicy::Reject => None,
+        };
+        if dropped.is_some() {
+            self.count_dropped(scope);
+            return Some(message);
+        }
+        if policy == QueuePolicy::Reject || policy == QueuePolicy::DropOldestUnreliable && !is_unreliable(&message) {
+            self.rejected.push(message);
+        } else {
+            self.count_dropped(scope);
+        }
+        None
+    }
+
+    /// Counts a dropped message, only warning about the first one of each overflow.
+    fn count_dropped(&mut self, scope: Option<SocketAddr>) {
+        self.dropped += 1;
+        if !self.overflowing {
+            self.overflowing = true;
+            match scope {
+                Some(destination) => log::warn!("The queue of messages to {} is full, dropping messages", destination),
+                None => log::warn!("The queue of messages to send is full, dropping messages"),
+            }
+        }
+    }
+
     /// Creates a `Message` with the default guarantees provided by the `Socket` implementation and
-    /// pushes it onto the messages queue to be sent on next sim tick.
+    /// pushes it onto the messages queue to be sent on next sim tick, unless other defaults were
+    /// set with `set_default_delivery` and `set_default_urgency`.
     pub fn send(&mut self, destination: SocketAddr, payload: &[u8]) {
         self.send_with_requirements(
             destination,
             payload,
             DeliveryRequirement::Default,
-            UrgencyRequirement::OnTick,
+            self.default_urgency,
         );
     }
 
@@ -98,67 +809,962 @@ impl TransportResource {
         );
     }
 
-    /// Creates and queue a `Message` with the specified guarantee.
-    pub fn send_with_requirements(
-        &mut self,
-        destination: SocketAddr,
-        payload: &[u8],
-        delivery: DeliveryRequirement,
-        timing: UrgencyRequirement,
-    ) {
-        let message = Message::new(destination, payload, delivery, timing);
-        self.messages.push_back(message);
+    /// Same as `send_with_requirements`, but addressed to the peer of `connection` at its current
+    /// address, see `connections`. A message to a connection which was retired is dropped and
+    /// reported as a `RetiredConnection` event, rather than sent to whoever has the address now.
+    pub fn send_to(
+        &mut self,
+        connection: ConnectionId,
+        payload: &[u8],
+        delivery: DeliveryRequirement,
+        timing: UrgencyRequirement,
+    ) {
+        match self.connections.addr_of(connection) {
+            Some(destination) => self.send_with_requirements(destination, payload, delivery, timing),
+            None => self.retired_sends.push((connection, Bytes::copy_from_slice(payload))),
+        }
+    }
+
+    /// Drains the payloads `send_to` dropped as their connection was retired, with the connection.
+    pub fn drain_retired_connection_payloads(&mut self) -> Vec<(ConnectionId, Bytes)> {
+        std::mem::take(&mut self.retired_sends)
+    }
+
+    /// Returns the ids of the connected peers, see `send_to`.
+    #[must_use]
+    pub fn connections(&self) -> &ConnectionIds {
+        &self.connections
+    }
+
+    /// Creates and queue a `Message` with the specified guarantee.
+    pub fn send_with_requirements(
+        &mut self,
+        destination: SocketAddr,
+        payload: &[u8],
+        delivery: DeliveryRequirement,
+        timing: UrgencyRequirement,
+    ) {
+        let message = Message::new(destination, Bytes::copy_from_slice(payload), delivery, timing);
+        self.enqueue(message);
+    }
+
+    /// Same as `send_with_requirements`, but takes the payload as is rather than copying it, e.g.
+    /// the `Vec<u8>` a snapshot was serialized into. Laminar then moves it into its packet without
+    /// copying it either, as long as nothing else holds it.
+    pub fn send_bytes(
+        &mut self,
+        destination: SocketAddr,
+        payload: impl Into<Bytes>,
+        delivery: DeliveryRequirement,
+        timing: UrgencyRequirement,
+    ) {
+        self.enqueue(Message::new(destination, payload.into(), delivery, timing));
+    }
+
+    /// Same as `send_bytes`, but with the payload made of `segments` one after the other, e.g. a
+    /// header and a body serialized apart, so the peer gets them as a single payload. They are
+    /// joined into a buffer of the right size, the only copy made, as laminar then moves it into
+    /// its packet, and a single segment isn't copied at all. A payload larger than
+    /// `max_payload_size` is an `InvalidInput` error and isn't queued.
+    pub fn send_segments(
+        &mut self,
+        destination: SocketAddr,
+        segments: &[Bytes],
+        delivery: DeliveryRequirement,
+        timing: UrgencyRequirement,
+    ) -> io::Result<()> {
+        let len = segments.iter().map(Bytes::len).sum();
+        if let Some(max_size) = self.max_payload_size.filter(|max_size| len > *max_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("payload of {} bytes exceeds the maximum of {} bytes", len, max_size),
+            ));
+        }
+        let payload = match segments {
+            [segment] => segment.clone(),
+            segments => {
+                let mut joined = BytesMut::with_capacity(len);
+                for segment in segments {
+                    joined.extend_from_slice(segment);
+                }
+                joined.freeze()
+            }
+        };
+        self.enqueue(Message::new(destination, payload, delivery, timing));
+        Ok(())
+    }
+
+    /// Same as `send_with_requirements`, but a message refused by the full queue, see
+    /// `QueuePolicy::Reject`, is returned as a `WouldBlock` error rather than reported as a
+    /// `SendError`. A message dropped by the other policies is not an error.
+    pub fn try_send_with_requirements(
+        &mut self,
+        destination: SocketAddr,
+        payload: &[u8],
+        delivery: DeliveryRequirement,
+        timing: UrgencyRequirement,
+    ) -> io::Result<()> {
+        let rejected = self.rejected.len();
+        self.send_with_requirements(destination, payload, delivery, timing);
+        if self.rejected.len() > rejected {
+            self.rejected.pop();
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "the queue of messages to send is full"));
+        }
+        Ok(())
+    }
+
+    /// Queues a message with the `Unreliable` requirement, to be sent on next sim tick. The
+    /// laminar transport sends it as a `Packet::unreliable`.
+    pub fn send_unreliable(&mut self, destination: SocketAddr, payload: &[u8]) {
+        self.send_with_requirements(
+            destination,
+            payload,
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::OnTick,
+        );
+    }
+
+    /// Same as `send_unreliable`, but the message is sent immediately.
+    pub fn send_unreliable_now(&mut self, destination: SocketAddr, payload: &[u8]) {
+        self.send_with_requirements(
+            destination,
+            payload,
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::Immediate,
+        );
+    }
+
+    /// Queues a message with the `UnreliableSequenced` requirement on `stream`, to be sent on next sim
+    /// tick. The laminar transport sends it as a `Packet::unreliable_sequenced`.
+    pub fn send_unreliable_sequenced(
+        &mut self,
+        destination: SocketAddr,
+        payload: &[u8],
+        stream: Option<u8>,
+    ) {
+        self.send_with_requirements(
+            destination,
+            payload,
+            DeliveryRequirement::UnreliableSequenced(stream),
+            UrgencyRequirement::OnTick,
+        );
+    }
+
+    /// Same as `send_unreliable_sequenced`, but the message is sent immediately.
+    pub fn send_unreliable_sequenced_now(
+        &mut self,
+        destination: SocketAddr,
+        payload: &[u8],
+        stream: Option<u8>,
+    ) {
+        self.send_with_requirements(
+            destination,
+            payload,
+            DeliveryRequirement::UnreliableSequenced(stream),
+            UrgencyRequirement::Immediate,
+        );
+    }
+
+    /// Queues a message with the `Reliable` requirement, to be sent on next sim tick. The
+    /// laminar transport sends it as a `Packet::reliable_unordered`.
+    pub fn send_reliable(&mut self, destination: SocketAddr, payload: &[u8]) {
+        self.send_with_requirements(
+            destination,
+            payload,
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::OnTick,
+        );
+    }
+
+    /// Same as `send_reliable`, but the message is sent immediately.
+    pub fn send_reliable_now(&mut self, destination: SocketAddr, payload: &[u8]) {
+        self.send_with_requirements(
+            destination,
+            payload,
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::Immediate,
+        );
+    }
+
+    /// Queues a message with the `ReliableSequenced` requirement on `stream`, to be sent on next sim
+    /// tick. The laminar transport sends it as a `Packet::reliable_sequenced`.
+    pub fn send_reliable_sequenced(
+        &mut self,
+        destination: SocketAddr,
+        payload: &[u8],
+        stream: Option<u8>,
+    ) {
+        self.send_with_requirements(
+            destination,
+            payload,
+            DeliveryRequirement::ReliableSequenced(stream),
+            UrgencyRequirement::OnTick,
+        );
+    }
+
+    /// Same as `send_reliable_sequenced`, but the message is sent immediately.
+    pub fn send_reliable_sequenced_now(
+        &mut self,
+        destination: SocketAddr,
+        payload: &[u8],
+        stream: Option<u8>,
+    ) {
+        self.send_with_requirements(
+            destination,
+            payload,
+            DeliveryRequirement::ReliableSequenced(stream),
+            UrgencyRequirement::Immediate,
+        );
+    }
+
+    /// Queues a message with the `ReliableOrdered` requirement on `stream`, to be sent on next sim
+    /// tick. The laminar transport sends it as a `Packet::reliable_ordered`.
+    pub fn send_reliable_ordered(
+        &mut self,
+        destination: SocketAddr,
+        payload: &[u8],
+        stream: Option<u8>,
+    ) {
+        self.send_with_requirements(
+            destination,
+            payload,
+            DeliveryRequirement::ReliableOrdered(stream),
+            UrgencyRequirement::OnTick,
+        );
+    }
+
+    /// Same as `send_reliable_ordered`, but the message is sent immediately.
+    pub fn send_reliable_ordered_now(
+        &mut self,
+        destination: SocketAddr,
+        payload: &[u8],
+        stream: Option<u8>,
+    ) {
+        self.send_with_requirements(
+            destination,
+            payload,
+            DeliveryRequirement::ReliableOrdered(stream),
+            UrgencyRequirement::Immediate,
+        );
+    }
+
+    /// Creates and queues a `Message` with the specified guarantee and `tag`, to be sent on next sim
+    /// tick. The tag isn't sent, it stays with the message, e.g. when it's reported as a
+    /// `SendError`, and the laminar transport reports an `Acked` event with it once a reliable
+    /// message was acknowledged, within its ack timeout, see `LaminarPlugin::with_ack_timeout`.
+    pub fn send_with_tag(
+        &mut self,
+        destination: SocketAddr,
+        payload: &[u8],
+        delivery: DeliveryRequirement,
+        tag: u64,
+    ) {
+        let mut message = Message::new(
+            destination,
+            Bytes::copy_from_slice(payload),
+            delivery,
+            UrgencyRequirement::OnTick,
+        );
+        message.tag = Some(tag);
+        self.enqueue(message);
+    }
+
+    /// Creates and queues a `Message` with the specified requirements, and returns the id it is
+    /// reported with: the laminar transport emits a `MessageAcked` event once the destination
+    /// acknowledged it, or a `MessageAckTimeout` one if it didn't within the ack timeout. The acks
+    /// ride on the header of the reliable packets laminar sends anyway, so they cost no packet of
+    /// their own, but the destination must send reliable packets back within the timeout. An
+    /// unreliable message is never acknowledged, it times out right away once sent.
+    pub fn send_with_ack(
+        &mut self,
+        destination: SocketAddr,
+        payload: &[u8],
+        delivery: DeliveryRequirement,
+        urgency: UrgencyRequirement,
+    ) -> MessageId {
+        let id = MessageId(self.next_message_id);
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        let mut message = Message::new(destination, Bytes::copy_from_slice(payload), delivery, urgency);
+        message.ack = Some(id);
+        self.enqueue(message);
+        id
+    }
+
+    /// Creates and queues a `Message` with the specified guarantee, to be sent on next sim tick. If
+    /// it is still queued once `ttl` elapsed, e.g. a stale input, the next drain to send drops it
+    /// instead, to be reported as a `SendError` with `drain_expired_messages`.
+    pub fn send_with_ttl(
+        &mut self,
+        destination: SocketAddr,
+        payload: &[u8],
+        delivery: DeliveryRequirement,
+        ttl: Duration,
+    ) {
+        let mut message = Message::new(
+            destination,
+            Bytes::copy_from_slice(payload),
+            delivery,
+            UrgencyRequirement::OnTick,
+        );
+        message.expires_at = Some(message.queued_at + ttl);
+        self.enqueue(message);
+    }
+
+    /// Creates and queues a `Message` with the specified guarantee, compressed by laminar even if it
+    /// is below the threshold given to `LaminarSocketResource::set_compression`, e.g. a large state
+    /// sync. Like any payload, it is sent raw if compressing it doesn't make it any smaller, or if
+    /// the socket doesn't compress at all, as the peer then wouldn't decompress it.
+    #[cfg(feature = "compression")]
+    pub fn send_compressed(
+        &mut self,
+        destination: SocketAddr,
+        payload: &[u8],
+        delivery: DeliveryRequirement,
+        timing: UrgencyRequirement,
+    ) {
+        let mut message = Message::new(destination, Bytes::copy_from_slice(payload), delivery, timing);
+        message.compress = true;
+        self.enqueue(message);
+    }
+
+    /// Creates and queues a `Message` with the specified guarantee and priority, to be sent on next
+    /// sim tick. See `drain_messages_to_send` for how the priority is taken into account.
+    pub fn send_with_priority(
+        &mut self,
+        destination: SocketAddr,
+        payload: &[u8],
+        delivery: DeliveryRequirement,
+        priority: u8,
+    ) {
+        let mut message = Message::new(
+            destination,
+            Bytes::copy_from_slice(payload),
+            delivery,
+            UrgencyRequirement::OnTick,
+        );
+        message.priority = priority;
+        self.enqueue(message);
+    }
+
+    /// Returns a handle queuing messages on the given stream, so that the stream id isn't repeated
+    /// at every send.
+    pub fn stream(&mut self, id: StreamId) -> StreamSender<'_> {
+        StreamSender { transport: self, id }
+    }
+
+    /// Creates and queues a `Message` with the specified guarantee, which only the given transport
+    /// will send.
+    pub fn send_via(
+        &mut self,
+        transport: TransportId,
+        destination: SocketAddr,
+        payload: &[u8],
+        delivery: DeliveryRequirement,
+        timing: UrgencyRequirement,
+    ) {
+        let mut message = Message::new(destination, Bytes::copy_from_slice(payload), delivery, timing);
+        message.transport = Some(transport);
+        self.enqueue(message);
+    }
+
+    /// Registers a transport, so that the messages routed to it are kept for it. This should be
+    /// called by a transport implementation.
+    pub fn register_transport(&mut self, transport: TransportId) {
+        self.transports.insert(transport);
+    }
+
+    /// Returns true if the transport is registered.
+    #[must_use]
+    pub fn is_registered(&self, transport: TransportId) -> bool {
+        self.transports.contains(&transport)
+    }
+
+    /// Creates and queues one `Message` per destination with the specified guarantee, to be sent
+    /// on next sim tick. All messages share the same payload buffer. laminar owns the payload of
+    /// its packets though, so the laminar transport still copies it once per destination but the
+    /// last one.
+    pub fn broadcast(
+        &mut self,
+        destinations: &[SocketAddr],
+        payload: impl Into<Bytes>,
+        delivery: DeliveryRequirement,
+    ) {
+        let payload = payload.into();
+        for destination in destinations {
+            let message = Message::new(
+                *destination,
+                payload.clone(),
+                delivery,
+                UrgencyRequirement::OnTick,
+            );
+            self.enqueue(message);
+        }
+    }
+
+    /// Creates and queues one `Message` per connected peer with the specified guarantees, all of
+    /// them sharing the payload buffer. The copies still queued when their peer disconnects are
+    /// dropped. The peers are the ones of `ConnectedPeers`, as of its last update. As with
+    /// `broadcast`, the laminar transport copies the payload for every peer but the last one.
+    pub fn broadcast_to_peers(
+        &mut self,
+        payload: impl Into<Bytes>,
+        delivery: DeliveryRequirement,
+        urgency: UrgencyRequirement,
+    ) {
+        self.send_to_all_except_list(&[], payload, delivery, urgency);
+    }
+
+    /// Same as `broadcast_to_peers`, but the messages are sent immediately.
+    pub fn broadcast_to_peers_now(&mut self, payload: impl Into<Bytes>, delivery: DeliveryRequirement) {
+        self.broadcast_to_peers(payload, delivery, UrgencyRequirement::Immediate);
+    }
+
+    /// Same as `broadcast_to_peers`, but skips `exclude`, e.g. to relay a message to every client
+    /// but the one it came from. An `exclude` which isn't a connected peer is ignored.
+    pub fn send_to_all_except(
+        &mut self,
+        exclude: SocketAddr,
+        payload: impl Into<Bytes>,
+        delivery: DeliveryRequirement,
+        urgency: UrgencyRequirement,
+    ) {
+        self.send_to_all_except_list(&[exclude], payload, delivery, urgency);
+    }
+
+    /// Same as `send_to_all_except`, but skips every address of `excluded`.
+    pub fn send_to_all_except_list(
+        &mut self,
+        excluded: &[SocketAddr],
+        payload: impl Into<Bytes>,
+        delivery: DeliveryRequirement,
+        urgency: UrgencyRequirement,
+    ) {
+        let payload = payload.into();
+        let peers: Vec<_> = self.peers.iter().filter(|peer| !excluded.contains(peer)).copied().collect();
+        for peer in peers {
+            let mut message = Message::new(peer, payload.clone(), delivery, urgency);
+            message.to_peers = true;
+            self.enqueue(message);
+        }
+    }
+
+    /// Opens a connection to `addr`, e.g. a client dialing its server, rather than waiting for the
+    /// first message to do so. The transport sends the host a handshake and emits a `Connecting`
+    /// event right away, then the usual `Connect` once the host answered. Does nothing while `addr`
+    /// is connecting or connected already.
+    pub fn connect(&mut self, addr: SocketAddr) {
+        if !self.peers.contains(&addr) && self.connecting.insert(addr) {
+            self.connect_requests.push(addr);
+        }
+    }
+
+    /// Returns true from `connect` until the host is connected, see `peer_connected`.
+    #[must_use]
+    pub fn is_connecting(&self, addr: &SocketAddr) -> bool {
+        self.connecting.contains(addr)
+    }
+
+    /// Gives up on connecting to `addr`, e.g. once its handshake couldn't be sent. The host still
+    /// connects if it answers a handshake sent already.
+    pub fn cancel_connect(&mut self, addr: &SocketAddr) {
+        self.connecting.remove(addr);
+        self.connect_requests.retain(|requested| requested != addr);
+    }
+
+    /// Drains the addresses given to `connect` since the last drain, for the transport to send
+    /// them its handshake.
+    pub fn drain_connect_requests(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.connect_requests)
+    }
+
+    /// Adds a peer to the ones `broadcast_to_peers` sends to, and no longer connecting if it was.
+    /// Returns the id of its connection, allocated unless it was connected already. This is called
+    /// by `connected_peers_system`.
+    pub fn peer_connected(&mut self, addr: SocketAddr) -> ConnectionId {
+        self.connecting.remove(&addr);
+        self.peers.insert(addr);
+        self.connections.allocate(addr).0
+    }
+
+    /// Removes a peer from the ones `broadcast_to_peers` sends to, dropping the copies still queued
+    /// for it, or every message queued for it if `set_cancel_on_disconnect` is set. This is called
+    /// by `connected_peers_system`. Returns the id of its connection, now retired, if it had one.
+    pub fn peer_disconnected(&mut self, addr: &SocketAddr) -> Option<ConnectionId> {
+        self.peers.remove(addr);
+        self.connecting.remove(addr);
+        let cancel_all = self.cancel_on_disconnect;
+        self.messages.take(*addr, |message| cancel_all || message.to_peers);
+        self.connections.retire(addr)
+    }
+
+    /// Drops every message queued for a peer once it disconnects or times out, rather than handing
+    /// them to the transport, which would open the connection again. Off by default.
+    pub fn set_cancel_on_disconnect(&mut self, cancel: bool) {
+        self.cancel_on_disconnect = cancel;
+    }
+
+    /// Returns true if the messages queued for a peer are dropped once it disconnects.
+    #[must_use]
+    pub fn cancels_on_disconnect(&self) -> bool {
+        self.cancel_on_disconnect
+    }
+
+    /// Removes the messages queued for `destination`, returning how many there were.
+    pub fn cancel_messages_for(&mut self, destination: &SocketAddr) -> usize {
+        self.messages.take(*destination, |_| true).len()
+    }
+
+    /// Removes the queued messages for which `filter` is true, returning how many there were.
+    pub fn cancel_messages_where(&mut self, mut filter: impl FnMut(&Message) -> bool) -> usize {
+        self.messages.drain(|message| filter(message)).len()
+    }
+
+    /// Sets the role of the local peer, e.g. from `LaminarPlugin::with_role`.
+    pub fn set_role(&mut self, role: NetworkRole) {
+        self.role = Some(role);
+    }
+
+    /// Returns the role of the local peer, if one was set.
+    #[must_use]
+    pub fn role(&self) -> Option<NetworkRole> {
+        self.role
+    }
+
+    /// Returns the address of the server, if the local peer is a client.
+    #[must_use]
+    pub fn server_addr(&self) -> Option<SocketAddr> {
+        match self.role {
+            Some(NetworkRole::Client { server_addr }) => Some(server_addr),
+            _ => None,
+        }
+    }
+
+    /// Returns the connected clients if the local peer is a server, in no particular order, none
+    /// otherwise.
+    pub fn accepted_peers(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.peers.iter().filter(|_| self.role == Some(NetworkRole::Server))
+    }
+
+    /// Queues `payload` for the server with the default guarantees, see `send`. Only a client has
+    /// a server to send to, for the other roles this is an `InvalidInput` error.
+    pub fn send_to_server(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.send_to_server_with_requirements(payload, DeliveryRequirement::Default, self.default_urgency)
+    }
+
+    /// Same as `send_to_server`, with the specified guarantee.
+    pub fn send_to_server_with_requirements(
+        &mut self,
+        payload: &[u8],
+        delivery: DeliveryRequirement,
+        timing: UrgencyRequirement,
+    ) -> io::Result<()> {
+        let server = self
+            .server_addr()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "only a client has a server to send to"))?;
+        self.send_with_requirements(server, payload, delivery, timing);
+        Ok(())
+    }
+
+    /// Returns true if there are messages enqueued to be sent.
+    #[must_use]
+    pub fn has_messages(&self) -> bool {
+        self.messages.len() != 0
+    }
+
+    /// Returns the number of messages queued, the ones held back by rate limits or by a
+    /// conditioner excluded.
+    #[must_use]
+    pub fn pending_len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Returns how long the oldest queued message has been waiting, if there is one. One growing
+    /// steadily means the messages are queued faster than they are sent.
+    #[must_use]
+    pub fn oldest_pending_age(&self) -> Option<Duration> {
+        self.oldest_pending_age_at(Instant::now())
+    }
+
+    /// Returns the total size, in bytes, of the payloads of the queued messages.
+    #[must_use]
+    pub fn pending_bytes(&self) -> usize {
+        self.messages.bytes()
+    }
+
+    /// Returns the number of messages queued for `destination`.
+    #[must_use]
+    pub fn queued_for(&self, destination: &SocketAddr) -> usize {
+        self.messages.len_for(destination)
+    }
+
+    /// Returns the destinations messages are queued for, with the number of them.
+    pub fn queued_destinations(&self) -> impl Iterator<Item = (SocketAddr, usize)> + '_ {
+        self.messages.queues.iter().map(|(destination, queue)| (*destination, queue.len()))
+    }
+
+    /// Returns the number of queued messages, their size and the age of the oldest one at once,
+    /// kept up to date rather than going through the queue, e.g. for a debug overlay sampling them
+    /// every frame. Like the other getters, it only needs a `Res<TransportResource>`.
+    #[must_use]
+    pub fn queue_stats(&self) -> QueueStats {
+        self.queue_stats_at(Instant::now())
+    }
+
+    fn queue_stats_at(&self, now: Instant) -> QueueStats {
+        QueueStats {
+            messages:   self.messages.len(),
+            bytes:      self.messages.bytes(),
+            oldest_age: self.oldest_pending_age_at(now),
+        }
+    }
+
+    fn oldest_pending_age_at(&self, now: Instant) -> Option<Duration> {
+        self.messages.oldest_queued_at().map(|queued_at| now.saturating_duration_since(queued_at))
+    }
+
+    /// Returns references to the queued messages, in the order they were queued, without
+    /// allocating.
+    pub fn get_messages(&self) -> impl Iterator<Item = &Message> {
+        self.messages.ordered()
+    }
+
+    /// Returns the queued messages in the order `drain_messages_to_send` hands them out, higher
+    /// priorities first, without draining them, e.g. for a debug overlay. The messages a drain would
+    /// hold back, by a rate limit or a retry backoff, or drop as expired, are included. The laminar
+    /// send system takes turns by destination, see `iter_pending_fairly` for its order.
+    pub fn iter_pending(&self) -> impl Iterator<Item = &Message> {
+        let mut messages: Vec<_> = self.get_messages().collect();
+        messages.sort_by_key(|message| std::cmp::Reverse(message.priority));
+        messages.into_iter()
+    }
+
+    /// Same as `iter_pending`, but in the order `drain_messages_to_send_fairly` and
+    /// `for_each_message_to_send` hand the messages out to `transport`, the one of the laminar
+    /// send system with `TransportId::LAMINAR`. The messages routed to other transports are left
+    /// out.
+    pub fn iter_pending_fairly(&self, transport: TransportId) -> impl Iterator<Item = &Message> {
+        let mut turns: HashMap<(SocketAddr, u8), usize> = HashMap::new();
+        let mut messages: Vec<_> = self
+            .get_messages()
+            .filter(|message| is_routed(message, Some(transport), false))
+            .map(|message| {
+                let turn = turns.entry((message.destination, message.priority)).or_default();
+                *turn += 1;
+                (*turn, message)
+            })
+            .collect();
+        messages.sort_by_key(|(turn, message)| (std::cmp::Reverse(message.priority), *turn));
+        messages.into_iter().map(|(_, message)| message)
+    }
+
+    /// Returns the messages to send by returning the immediate messages or anything adhering to
+    /// the given filter. The filter sees every message, e.g. to flush the reliable ones right away
+    /// but send the others only when `NetworkSimulationTime::should_send_message_now` says so.
+    /// Higher priority messages come first, messages of the same priority stay in the order they
+    /// were queued.
+    pub fn drain_messages_to_send(
+        &mut self,
+        mut filter: impl FnMut(&Message) -> bool,
+    ) -> Vec<Message> {
+        self.drain_routed_messages(None, false, &mut filter)
+    }
+
+    /// Same as `drain_messages_to_send`, but leaves the messages routed to other transports in the
+    /// queue.
+    pub fn drain_messages_to_send_via(
+        &mut self,
+        transport: TransportId,
+        mut filter: impl FnMut(&Message) -> bool,
+    ) -> Vec<Message> {
+        self.drain_routed_messages(Some(transport), false, &mut filter)
+    }
+
+    /// Same as `drain_messages_to_send_via`, but the messages of the same priority take turns by
+    /// destination: the first message of every destination comes before the second one of any, so
+    /// that a peer with a long backlog doesn't hold the others back.
+    pub fn drain_messages_to_send_fairly(
+        &mut self,
+        transport: TransportId,
+        mut filter: impl FnMut(&Message) -> bool,
+    ) -> Vec<Message> {
+        let messages = self.drain_routed_messages(Some(transport), false, &mut filter);
+        take_turns(messages)
+    }
+
+    /// Same as `drain_messages_to_send_fairly`, but hands the messages to `send` one at a time, in
+    /// the same order, rather than collecting them, so that the send systems don't allocate on
+    /// every frame once the queues have grown. `send` tells what becomes of each message, see
+    /// `Disposition`. The filter sees the messages of each destination in the order they were
+    /// queued. The laminar sockets which coalesce the messages still collect them, as they pack
+    /// the messages of a frame together.
+    pub fn for_each_message_to_send(
+        &mut self,
+        transport: TransportId,
+        mut filter: impl FnMut(&Message) -> bool,
+        mut send: impl FnMut(Message) -> Disposition,
+    ) {
+        self.for_each_message_to_send_at(transport, false, &mut filter, &mut send, Instant::now());
+    }
+
+    /// Same as `for_each_message_to_send`, but also leaves the messages which aren't routed to any
+    /// transport in the queue, like `drain_messages_routed_to`.
+    pub fn for_each_message_routed_to(
+        &mut self,
+        transport: TransportId,
+        mut filter: impl FnMut(&Message) -> bool,
+        mut send: impl FnMut(Message) -> Disposition,
+    ) {
+        self.for_each_message_to_send_at(transport, true, &mut filter, &mut send, Instant::now());
+    }
+
+    fn for_each_message_to_send_at(
+        &mut self,
+        transport: TransportId,
+        exclusive: bool,
+        filter: &mut impl FnMut(&Message) -> bool,
+        send: &mut impl FnMut(Message) -> Disposition,
+        now: Instant,
+    ) {
+        // the conditioner holds the messages in a queue of its own
+        #[cfg(feature = "conditioner")]
+        if self.conditioner.is_some() {
+            let messages = self.drain_routed_messages(Some(transport), exclusive, &mut *filter);
+            for message in take_turns(messages) {
+                self.dispose(send(message), now);
+            }
+            return;
+        }
+        let mut order = std::mem::take(&mut self.send_order);
+        self.rate_limits.values_mut().for_each(|limit| limit.refill(now));
+        let MessageQueue { queues, len, bytes, times, .. } = &mut self.messages;
+        for (destination, queue) in queues.iter_mut() {
+            let mut turns = [0usize; 256];
+            let mut at = 0;
+            while let Some((number, message)) = queue.get(at) {
+                if message.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                    let (_, message) = queue.remove(at).expect("message is queued");
+                    times.remove(&message);
+                    *len -= 1;
+                    *bytes -= message.payload.len();
+                    self.expired_count += 1;
+                    self.expired.push(message);
+                    continue;
+                }
+                let eligible = is_routed(message, Some(transport), exclusive)
+                    && message.retry_at.is_none_or(|retry_at| retry_at <= now)
+                    && (message.urgency == UrgencyRequirement::Immediate || filter(message));
+                if eligible {
+                    let turn = &mut turns[usize::from(message.priority)];
+                    *turn += 1;
+                    order.push((std::cmp::Reverse(message.priority), *turn, *number, *destination));
+                }
+                at += 1;
+            }
+        }
+        // the rate limits are charged in the order the messages go out, so that the higher
+        // priorities get the budget first
+        order.sort_unstable();
+        for (_, _, number, destination) in order.drain(..) {
+            let Some(message) = self.messages.remove(&destination, number) else {
+                continue;
+            };
+            if self.rate_limits.get_mut(&destination).is_some_and(|limit| !limit.take(&message)) {
+                self.messages.put_back(message);
+                continue;
+            }
+            self.dispose(send(message), now);
+        }
+        self.messages.queues.retain(|_, queue| !queue.is_empty());
+        self.send_order = order;
+    }
+
+    /// Applies what `for_each_message_to_send` was told to do with a message.
+    fn dispose(&mut self, disposition: Disposition, now: Instant) {
+        match disposition {
+            Disposition::Done => {}
+            Disposition::Requeue(message) => {
+                if let Some(limit) = self.rate_limits.get_mut(&message.destination) {
+                    limit.refund(&message);
+                }
+                self.requeue(message);
+            }
+            Disposition::Failed(e, message) => {
+                if let Some(failed) = self.retry_failed_at(e, message, now) {
+                    self.failed.push(failed);
+                }
+            }
+        }
+    }
+
+    /// Same as `drain_messages_to_send_via`, but also leaves the messages which aren't routed to
+    /// any transport in the queue.
+    pub fn drain_messages_routed_to(
+        &mut self,
+        transport: TransportId,
+        mut filter: impl FnMut(&Message) -> bool,
+    ) -> Vec<Message> {
+        self.drain_routed_messages(Some(transport), true, &mut filter)
+    }
+
+    fn drain_routed_messages(
+        &mut self,
+        transport: Option<TransportId>,
+        exclusive: bool,
+        filter: &mut impl FnMut(&Message) -> bool,
+    ) -> Vec<Message> {
+        self.drain_routed_messages_at(transport, exclusive, filter, Instant::now())
+    }
+
+    fn drain_routed_messages_at(
+        &mut self,
+        transport: Option<TransportId>,
+        exclusive: bool,
+        filter: &mut impl FnMut(&Message) -> bool,
+        now: Instant,
+    ) -> Vec<Message> {
+        let expired = self.drain_messages(|message| message.expires_at.is_some_and(|expires_at| expires_at <= now));
+        self.expired_count += expired.len() as u64;
+        self.expired.extend(expired);
+        let mut eligible = self.drain_messages(|message| {
+            is_routed(message, transport, exclusive)
+                && message.retry_at.is_none_or(|retry_at| retry_at <= now)
+                && (message.urgency == UrgencyRequirement::Immediate || filter(message))
+        });
+        // charged in the order the messages go out, the ones over the limit are put back
+        eligible.sort_by_key(|message| std::cmp::Reverse(message.priority));
+        self.rate_limits.values_mut().for_each(|limit| limit.refill(now));
+        let rate_limits = &mut self.rate_limits;
+        let (mut messages, held): (Vec<_>, Vec<_>) = eligible.into_iter().partition(|message| {
+            rate_limits.get_mut(&message.destination).is_none_or(|limit| limit.take(message))
+        });
+        for message in held {
+            self.messages.put_back(message);
+        }
+        #[cfg(feature = "conditioner")]
+        if let Some(conditioner) = &mut self.conditioner {
+            conditioner.hold(messages, now);
+            messages = conditioner.release(now, |message| is_routed(message, transport, exclusive));
+        }
+        messages.sort_by_key(|message| std::cmp::Reverse(message.priority));
+        messages
+    }
+
+    /// Sends the queued messages meant for `from` to `to` instead, e.g. when the peer moved. Its
+    /// connection keeps its id, and the one `to` had is retired, see `send_to`.
+    pub fn readdress(&mut self, from: SocketAddr, to: SocketAddr) {
+        self.connections.readdress(&from, to);
+        self.messages.take(from, |message| {
+            message.destination = to;
+            false
+        });
+    }
+
+    /// Drains the messages routed to a transport which isn't registered.
+    pub fn drain_unroutable_messages(&mut self) -> Vec<Message> {
+        let transports = std::mem::take(&mut self.transports);
+        let messages = self.drain_messages(|message| {
+            message.transport.is_some_and(|route| !transports.contains(&route))
+        });
+        self.transports = transports;
+        messages
+    }
+
+    /// Drains the messages queue and returns the drained messages. The filter allows you to drain
+    /// only messages that adhere to your filter. This might be useful in a scenario like draining
+    /// messages with a particular urgency requirement.
+    pub fn drain_messages(&mut self, filter: impl FnMut(&mut Message) -> bool) -> Vec<Message> {
+        self.messages.drain(filter)
+    }
+
+    /// Same as `drain_messages`, but only goes through the messages to `destination`, leaving the
+    /// queues of the other destinations alone, e.g. to flush a single peer.
+    pub fn drain_messages_for(
+        &mut self,
+        destination: SocketAddr,
+        filter: impl FnMut(&mut Message) -> bool,
+    ) -> Vec<Message> {
+        self.messages.take(destination, filter)
+    }
+}
+
+/// Handle queuing messages on a single stream, to be sent on next sim tick. See
+/// `TransportResource::stream`.
+pub struct StreamSender<'a> {
+    transport: &'a mut TransportResource,
+    id:        StreamId,
+}
+
+impl StreamSender<'_> {
+    /// Returns the id of the stream.
+    #[must_use]
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    /// Queues a message with the `UnreliableSequenced` requirement on this stream.
+    pub fn send_unreliable_sequenced(&mut self, destination: SocketAddr, payload: &[u8]) {
+        self.send(destination, payload, self.id.unreliable_sequenced());
     }
 
-    /// Returns true if there are messages enqueued to be sent.
-    #[must_use]
-    pub fn has_messages(&self) -> bool {
-        !self.messages.is_empty()
+    /// Queues a message with the `ReliableSequenced` requirement on this stream.
+    pub fn send_reliable_sequenced(&mut self, destination: SocketAddr, payload: &[u8]) {
+        self.send(destination, payload, self.id.reliable_sequenced());
     }
 
-    /// Returns a reference to the owned messages.
-    #[must_use]
-    pub fn get_messages(&self) -> &VecDeque<Message> {
-        &self.messages
+    /// Queues a message with the `ReliableOrdered` requirement on this stream.
+    pub fn send_reliable_ordered(&mut self, destination: SocketAddr, payload: &[u8]) {
+        self.send(destination, payload, self.id.reliable_ordered());
     }
 
-    /// Returns the messages to send by returning the immediate messages or anything adhering to
-    /// the given filter.
-    pub fn drain_messages_to_send(
-        &mut self,
-        mut filter: impl FnMut(&mut Message) -> bool,
-    ) -> Vec<Message> {
-        self.drain_messages(|message| {
-            message.urgency == UrgencyRequirement::Immediate || filter(message)
-        })
+    fn send(&mut self, destination: SocketAddr, payload: &[u8], delivery: DeliveryRequirement) {
+        self.transport.send_with_requirements(destination, payload, delivery, UrgencyRequirement::OnTick);
     }
+}
 
-    /// Drains the messages queue and returns the drained messages. The filter allows you to drain
-    /// only messages that adhere to your filter. This might be useful in a scenario like draining
-    /// messages with a particular urgency requirement.
-    pub fn drain_messages(&mut self, mut filter: impl FnMut(&mut Message) -> bool) -> Vec<Message> {
-        let mut drained = Vec::with_capacity(self.messages.len());
-        let mut i = 0;
-        while i != self.messages.len() {
-            if filter(&mut self.messages[i]) {
-                if let Some(m) = self.messages.remove(i) {
-                    drained.push(m);
-                }
-            } else {
-                i += 1;
-            }
-        }
-        drained
+/// Returns true if `message` may be drained for `transport`, or for any transport if `None`. With
+/// `exclusive`, the messages which aren't routed to any transport may not.
+fn is_routed(message: &Message, transport: Option<TransportId>, exclusive: bool) -> bool {
+    match (transport, message.transport) {
+        (Some(transport), Some(route)) => route == transport,
+        (Some(_), None) => !exclusive,
+        (None, _) => true,
     }
 }
 
 impl Default for TransportResource {
     fn default() -> Self {
         Self {
-            messages: VecDeque::new(),
+            messages: MessageQueue::default(),
             frame_budget_bytes: 0,
             latency_nanos: 0,
             packet_loss: 0.0,
+            transports: HashSet::new(),
+            capacity: None,
+            destination_capacities: HashMap::new(),
+            overflowing: false,
+            dropped: 0,
+            rejected: Vec::new(),
+            expired: Vec::new(),
+            expired_count: 0,
+            failed: Vec::new(),
+            send_order: Vec::new(),
+            default_ttl: None,
+            default_delivery: None,
+            default_urgency: UrgencyRequirement::OnTick,
+            next_message_id: 0,
+            max_payload_size: None,
+            retry_policy: None,
+            rate_limits: HashMap::new(),
+            routes: HashMap::new(),
+            peers: HashSet::new(),
+            connecting: HashSet::new(),
+            connect_requests: Vec::new(),
+            connections: ConnectionIds::default(),
+            retired_sends: Vec::new(),
+            role: None,
+            cancel_on_disconnect: false,
+            #[cfg(feature = "conditioner")]
+            conditioner: None,
         }
     }
 }
@@ -173,20 +1779,44 @@ mod tests {
 
         resource.send("127.0.0.1:3000".parse().unwrap(), test_payload());
 
-        let packet = &resource.messages[0];
+        let packet = resource.get_messages().next().unwrap();
 
         assert_eq!(resource.messages.len(), 1);
         assert_eq!(packet.delivery, DeliveryRequirement::Default);
         assert_eq!(packet.urgency, UrgencyRequirement::OnTick);
     }
 
+    #[test]
+    fn test_changed_defaults_only_apply_to_later_messages() {
+        let mut resource = create_test_resource();
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        resource.send(addr, b"before");
+        resource.set_default_delivery(Some(DeliveryRequirement::UnreliableSequenced(Some(1))));
+        resource.set_default_urgency(UrgencyRequirement::Immediate);
+        resource.send(addr, b"after");
+        resource.send_with_requirements(addr, b"explicit", DeliveryRequirement::Reliable, UrgencyRequirement::OnTick);
+        resource.set_default_delivery(None);
+        resource.send_immediate(addr, b"reset");
+
+        let requirements: Vec<_> = resource
+            .get_messages()
+            .map(|message| (message.delivery, message.urgency))
+            .collect();
+        assert_eq!(requirements, [
+            (DeliveryRequirement::Default, UrgencyRequirement::OnTick),
+            (DeliveryRequirement::UnreliableSequenced(Some(1)), UrgencyRequirement::Immediate),
+            (DeliveryRequirement::Reliable, UrgencyRequirement::OnTick),
+            (DeliveryRequirement::Default, UrgencyRequirement::Immediate),
+        ]);
+    }
+
     #[test]
     fn test_send_immediate_message() {
         let mut resource = create_test_resource();
 
         resource.send_immediate("127.0.0.1:3000".parse().unwrap(), test_payload());
 
-        let packet = &resource.messages[0];
+        let packet = resource.get_messages().next().unwrap();
 
         assert_eq!(resource.messages.len(), 1);
         assert_eq!(packet.delivery, DeliveryRequirement::Default);
@@ -216,6 +1846,25 @@ mod tests {
         assert_eq!(resource.drain_messages_to_send(|_| false).len(), 0);
     }
 
+    #[test]
+    fn test_drain_to_send_filters_by_message() {
+        let mut resource = create_test_resource();
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        resource.send_unreliable(addr, b"position");
+        resource.send_reliable(addr, b"chat");
+        resource.send_reliable_ordered(addr, b"inventory", None);
+        resource.send_unreliable(addr, b"position");
+
+        let flushed = resource.drain_messages_to_send(|message: &Message| {
+            !matches!(message.delivery, DeliveryRequirement::Unreliable | DeliveryRequirement::UnreliableSequenced(_))
+        });
+        let flushed: Vec<_> = flushed.iter().map(|message| &message.payload[..]).collect();
+        assert_eq!(flushed, [&b"chat"[..], b"inventory"]);
+        let queued: Vec<_> = resource.get_messages().collect();
+        assert_eq!(queued.len(), 2);
+        assert!(queued.iter().all(|message| message.delivery == DeliveryRequirement::Unreliable));
+    }
+
     #[test]
     fn test_drain_only_messages_with_specific_requirements() {
         let mut resource = create_test_resource();
@@ -270,7 +1919,8 @@ mod tests {
     #[test]
     fn test_send_with_requirements() {
         use DeliveryRequirement::{
-            Default, Reliable, ReliableOrdered, ReliableSequenced, Unreliable, UnreliableSequenced,
+            Default, Reliable, ReliableOrdered, ReliableSequenced, ReliableUnordered, Unreliable,
+            UnreliableSequenced,
         };
         let mut resource = create_test_resource();
         let addr = "127.0.0.1:3000".parse().unwrap();
@@ -279,6 +1929,7 @@ mod tests {
             Unreliable,
             UnreliableSequenced(None),
             Reliable,
+            ReliableUnordered,
             ReliableSequenced(None),
             ReliableOrdered(None),
             Default,
@@ -291,8 +1942,760 @@ mod tests {
         assert_eq!(resource.messages.len(), requirements.len());
 
         for (i, req) in requirements.iter().enumerate() {
-            assert_eq!(resource.messages[i].delivery, *req);
+            assert_eq!(resource.get_messages().nth(i).unwrap().delivery, *req);
+        }
+    }
+
+    #[test]
+    fn test_send_by_requirement() {
+        let mut transport = create_test_resource();
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        transport.send_unreliable(addr, test_payload());
+        transport.send_unreliable_now(addr, test_payload());
+        transport.send_unreliable_sequenced(addr, test_payload(), Some(1));
+        transport.send_unreliable_sequenced_now(addr, test_payload(), None);
+        transport.send_reliable(addr, test_payload());
+        transport.send_reliable_now(addr, test_payload());
+        transport.send_reliable_sequenced(addr, test_payload(), Some(2));
+        transport.send_reliable_sequenced_now(addr, test_payload(), None);
+        transport.send_reliable_ordered(addr, test_payload(), Some(3));
+        transport.send_reliable_ordered_now(addr, test_payload(), None);
+
+        let requirements: Vec<_> = transport
+            .get_messages()
+            .map(|message| (message.delivery, message.urgency))
+            .collect();
+        assert_eq!(requirements, [
+            (DeliveryRequirement::Unreliable, UrgencyRequirement::OnTick),
+            (DeliveryRequirement::Unreliable, UrgencyRequirement::Immediate),
+            (DeliveryRequirement::UnreliableSequenced(Some(1)), UrgencyRequirement::OnTick),
+            (DeliveryRequirement::UnreliableSequenced(None), UrgencyRequirement::Immediate),
+            (DeliveryRequirement::Reliable, UrgencyRequirement::OnTick),
+            (DeliveryRequirement::Reliable, UrgencyRequirement::Immediate),
+            (DeliveryRequirement::ReliableSequenced(Some(2)), UrgencyRequirement::OnTick),
+            (DeliveryRequirement::ReliableSequenced(None), UrgencyRequirement::Immediate),
+            (DeliveryRequirement::ReliableOrdered(Some(3)), UrgencyRequirement::OnTick),
+            (DeliveryRequirement::ReliableOrdered(None), UrgencyRequirement::Immediate),
+        ]);
+        assert!(transport
+            .get_messages()
+            .all(|message| message.destination == addr && message.payload == test_payload()));
+    }
+
+    #[test]
+    fn test_stream_sender_uses_its_stream() {
+        let mut resource = create_test_resource();
+        let addr = "127.0.0.1:3000".parse().unwrap();
+
+        let mut stream = resource.stream(StreamId(4));
+        stream.send_unreliable_sequenced(addr, test_payload());
+        stream.send_reliable_sequenced(addr, test_payload());
+        stream.send_reliable_ordered(addr, test_payload());
+        resource.stream(StreamId(5)).send_reliable_ordered(addr, test_payload());
+
+        let requirements: Vec<_> = resource.get_messages().map(|message| message.delivery).collect();
+        assert_eq!(requirements, vec![
+            DeliveryRequirement::UnreliableSequenced(Some(4)),
+            DeliveryRequirement::ReliableSequenced(Some(4)),
+            DeliveryRequirement::ReliableOrdered(Some(4)),
+            DeliveryRequirement::ReliableOrdered(Some(5)),
+        ]);
+        assert!(resource.get_messages().all(|message| message.urgency == UrgencyRequirement::OnTick));
+    }
+
+    #[test]
+    fn test_broadcast_shares_payload() {
+        let mut resource = create_test_resource();
+        let addrs = [
+            "127.0.0.1:3000".parse().unwrap(),
+            "127.0.0.1:3001".parse().unwrap(),
+            "127.0.0.1:3002".parse().unwrap(),
+        ];
+
+        resource.broadcast(&addrs, Bytes::from_static(test_payload()), DeliveryRequirement::Reliable);
+
+        assert_eq!(resource.messages.len(), 3);
+        for (message, addr) in resource.get_messages().zip(addrs) {
+            assert_eq!(message.destination, addr);
+            assert_eq!(message.payload, test_payload());
+            assert_eq!(message.payload.as_ptr(), resource.get_messages().next().unwrap().payload.as_ptr());
+            assert_eq!(message.delivery, DeliveryRequirement::Reliable);
+            assert_eq!(message.urgency, UrgencyRequirement::OnTick);
+        }
+    }
+
+    #[test]
+    fn test_send_bytes_keeps_the_payload() {
+        let mut resource = create_test_resource();
+        let payload = Bytes::from(test_payload().to_vec());
+        let ptr = payload.as_ptr();
+
+        resource.send_bytes(
+            "127.0.0.1:3000".parse().unwrap(),
+            payload,
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::OnTick,
+        );
+
+        assert_eq!(resource.get_messages().next().unwrap().payload.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn test_send_segments_joins_them() {
+        let mut resource = create_test_resource();
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let header = Bytes::from_static(&[1, 0]);
+        let body = Bytes::from(vec![7; 6]);
+        let send = |resource: &mut TransportResource, segments: &[Bytes]| {
+            resource.send_segments(addr, segments, DeliveryRequirement::Reliable, UrgencyRequirement::OnTick)
+        };
+
+        send(&mut resource, &[header.clone(), body.clone()]).unwrap();
+        send(&mut resource, std::slice::from_ref(&body)).unwrap();
+        resource.set_max_payload_size(Some(7));
+        let e = send(&mut resource, &[header, body.clone()]).unwrap_err();
+
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let messages: Vec<_> = resource.get_messages().collect();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(&messages[0].payload[..], [1, 0, 7, 7, 7, 7, 7, 7]);
+        assert_eq!(messages[1].payload.as_ptr(), body.as_ptr());
+    }
+
+    #[test]
+    fn test_broadcast_to_peers_skips_disconnected_peers() {
+        let mut transport = create_test_resource();
+        let a = "127.0.0.1:3000".parse().unwrap();
+        let b = "127.0.0.1:3001".parse().unwrap();
+        transport.peer_connected(a);
+        transport.peer_connected(b);
+        transport.send(b, test_payload());
+        transport.broadcast_to_peers(
+            Bytes::from_static(b"state"),
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::OnTick,
+        );
+        transport.broadcast_to_peers_now(Bytes::from_static(b"now"), DeliveryRequirement::Reliable);
+
+        let copies: Vec<_> = transport.get_messages().filter(|message| message.payload == b"state"[..]).collect();
+        assert_eq!(copies.len(), 2);
+        assert_eq!(copies[0].payload.as_ptr(), copies[1].payload.as_ptr());
+
+        transport.peer_disconnected(&b);
+
+        let sent: Vec<_> = transport
+            .get_messages()
+            .map(|message| (message.destination, &message.payload[..], message.urgency))
+            .collect();
+        assert_eq!(sent, [
+            (b, test_payload(), UrgencyRequirement::OnTick),
+            (a, &b"state"[..], UrgencyRequirement::OnTick),
+            (a, &b"now"[..], UrgencyRequirement::Immediate),
+        ]);
+    }
+
+    #[test]
+    fn test_send_to_all_except() {
+        let mut transport = create_test_resource();
+        let [a, b, c, unknown] = [3000, 3001, 3002, 3003].map(|port| SocketAddr::from(([127, 0, 0, 1], port)));
+        let destinations = |transport: &mut TransportResource| {
+            let mut destinations: Vec<_> = transport
+                .drain_messages(|_| true)
+                .iter()
+                .map(|message| message.destination)
+                .collect();
+            destinations.sort();
+            destinations
+        };
+
+        transport.send_to_all_except(a, test_payload(), DeliveryRequirement::Reliable, UrgencyRequirement::OnTick);
+        assert!(!transport.has_messages());
+
+        for peer in [a, b, c] {
+            transport.peer_connected(peer);
+        }
+        transport.send_to_all_except(a, test_payload(), DeliveryRequirement::Reliable, UrgencyRequirement::OnTick);
+        assert_eq!(destinations(&mut transport), [b, c]);
+        transport.send_to_all_except(
+            unknown,
+            test_payload(),
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::OnTick,
+        );
+        assert_eq!(destinations(&mut transport), [a, b, c]);
+        transport.send_to_all_except_list(
+            &[c, unknown],
+            test_payload(),
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::OnTick,
+        );
+        assert_eq!(destinations(&mut transport), [a, b]);
+        transport.send_to_all_except_list(
+            &[a, b, c, unknown],
+            test_payload(),
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::OnTick,
+        );
+        assert!(!transport.has_messages());
+    }
+
+    #[test]
+    fn test_queues_per_destination() {
+        let mut transport = create_test_resource();
+        let chatty = "127.0.0.1:3000".parse().unwrap();
+        let quiet = "127.0.0.1:3001".parse().unwrap();
+        for i in 0..3u8 {
+            transport.send(chatty, &[i]);
+        }
+        transport.send(quiet, &[3]);
+        transport.send(chatty, &[4]);
+        assert_eq!(transport.queued_for(&chatty), 4);
+        assert_eq!(transport.queued_for(&quiet), 1);
+
+        let order: Vec<_> = transport.get_messages().map(|message| message.payload[0]).collect();
+        assert_eq!(order, [0, 1, 2, 3, 4]);
+
+        let flushed = transport.drain_messages_for(chatty, |message| message.payload[0] != 1);
+        assert_eq!(flushed.iter().map(|message| message.payload[0]).collect::<Vec<_>>(), [0, 2, 4]);
+        assert_eq!(transport.queued_for(&chatty), 1);
+        assert_eq!(transport.queued_for(&quiet), 1);
+
+        let drained: Vec<_> = transport.drain_messages(|_| true).iter().map(|message| message.payload[0]).collect();
+        assert_eq!(drained, [1, 3]);
+        assert!(!transport.has_messages());
+    }
+
+    #[test]
+    fn test_fair_drain_takes_turns_by_destination() {
+        let mut transport = create_test_resource();
+        let chatty = "127.0.0.1:3000".parse().unwrap();
+        let quiet = "127.0.0.1:3001".parse().unwrap();
+        for i in 0..3u8 {
+            transport.send(chatty, &[i]);
+        }
+        transport.send(quiet, &[3]);
+        transport.send_with_priority(quiet, &[4], DeliveryRequirement::Default, u8::MAX);
+
+        let drained: Vec<_> = transport
+            .drain_messages_to_send_fairly(TransportId::LAMINAR, |_| true)
+            .iter()
+            .map(|message| message.payload[0])
+            .collect();
+        assert_eq!(drained, [4, 0, 3, 1, 2]);
+    }
+
+    #[test]
+    fn test_send_to_follows_the_connection() {
+        let mut transport = create_test_resource();
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let moved = "127.0.0.1:3001".parse().unwrap();
+        let connection = transport.peer_connected(addr);
+        transport.readdress(addr, moved);
+        transport.send_to(connection, b"input", DeliveryRequirement::Reliable, UrgencyRequirement::OnTick);
+        assert_eq!(transport.get_messages().next().unwrap().destination, moved);
+
+        assert_eq!(transport.peer_disconnected(&moved), Some(connection));
+        let reconnected = transport.peer_connected(moved);
+        assert_ne!(reconnected, connection);
+        transport.send_to(connection, b"stale", DeliveryRequirement::Reliable, UrgencyRequirement::OnTick);
+        assert_eq!(transport.pending_len(), 1);
+        assert_eq!(transport.drain_retired_connection_payloads(), [(connection, Bytes::from_static(b"stale"))]);
+    }
+
+    #[test]
+    fn test_send_to_the_id_a_readdress_replaced_is_reported() {
+        let mut transport = create_test_resource();
+        let lost = "127.0.0.1:3000".parse().unwrap();
+        let elected = "127.0.0.1:3001".parse().unwrap();
+        let moved = transport.peer_connected(lost);
+        let replaced = transport.peer_connected(elected);
+        transport.readdress(lost, elected);
+
+        transport.send_to(replaced, b"stale", DeliveryRequirement::Reliable, UrgencyRequirement::OnTick);
+        assert_eq!(transport.pending_len(), 0);
+        assert_eq!(transport.drain_retired_connection_payloads(), [(replaced, Bytes::from_static(b"stale"))]);
+        assert_eq!(transport.peer_disconnected(&elected), Some(moved));
+        assert!(transport.connections().is_empty());
+    }
+
+    #[test]
+    fn test_for_each_message_to_send_disposes_in_the_fair_order() {
+        let mut transport = create_test_resource();
+        let chatty = "127.0.0.1:3000".parse().unwrap();
+        let quiet = "127.0.0.1:3001".parse().unwrap();
+        for i in 0..3u8 {
+            transport.send(chatty, &[i]);
+        }
+        transport.send(quiet, &[3]);
+        transport.send_with_priority(quiet, &[4], DeliveryRequirement::Default, u8::MAX);
+
+        let mut sent = Vec::new();
+        transport.for_each_message_to_send(TransportId::LAMINAR, |_| true, |message| {
+            sent.push(message.payload[0]);
+            match message.payload[0] {
+                1 => Disposition::Requeue(message),
+                2 => Disposition::Failed(io::Error::new(io::ErrorKind::PermissionDenied, "denied"), message),
+                _ => Disposition::Done,
+            }
+        });
+        assert_eq!(sent, [4, 0, 3, 1, 2]);
+        assert_eq!(transport.pending_len(), 1);
+        let failed = transport.drain_failed_messages();
+        assert_eq!(failed.len(), 1);
+        assert_eq!((failed[0].0.kind(), &failed[0].1.payload[..]), (io::ErrorKind::PermissionDenied, &[2][..]));
+
+        transport.send(chatty, &[5]);
+        let drained: Vec<_> = transport
+            .drain_messages_to_send_fairly(TransportId::LAMINAR, |_| true)
+            .iter()
+            .map(|message| message.payload[0])
+            .collect();
+        assert_eq!(drained, [1, 5]);
+    }
+
+    #[test]
+    fn test_pending_len_and_age() {
+        let mut transport = create_test_resource();
+        assert_eq!(transport.pending_len(), 0);
+        assert_eq!(transport.oldest_pending_age(), None);
+
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        transport.send(addr, test_payload());
+        let oldest = transport.get_messages().next().unwrap().queued_at;
+        transport.send_with_priority(addr, test_payload(), DeliveryRequirement::Default, u8::MAX);
+
+        assert_eq!(transport.pending_len(), 2);
+        let later = oldest + Duration::from_millis(250);
+        assert_eq!(transport.oldest_pending_age_at(later), Some(Duration::from_millis(250)));
+
+        transport.drain_messages(|message| message.priority == Message::DEFAULT_PRIORITY);
+        assert_eq!(transport.pending_len(), 1);
+        assert!(transport.oldest_pending_age_at(later).unwrap() <= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_queue_stats_follow_the_queue() {
+        let mut transport = create_test_resource();
+        assert_eq!(transport.queue_stats(), QueueStats::default());
+
+        let a = "127.0.0.1:3000".parse().unwrap();
+        let b = "127.0.0.1:3001".parse().unwrap();
+        transport.send(a, b"input");
+        let oldest = transport.get_messages().next().unwrap().queued_at;
+        transport.send(a, b"chat");
+        transport.send(b, b"state");
+        let later = oldest + Duration::from_millis(100);
+        assert_eq!(transport.queue_stats_at(later), QueueStats {
+            messages:   3,
+            bytes:      14,
+            oldest_age: Some(Duration::from_millis(100)),
+        });
+        let mut destinations: Vec<_> = transport.queued_destinations().collect();
+        destinations.sort_unstable();
+        assert_eq!(destinations, [(a, 2), (b, 1)]);
+
+        transport.drain_messages_for(a, |message| &message.payload[..] == b"input");
+        assert_eq!(transport.pending_bytes(), 9);
+        transport.readdress(a, b);
+        assert_eq!(transport.queued_for(&b), 2);
+        let oldest = transport.get_messages().map(|message| message.queued_at).min().unwrap();
+        assert_eq!(transport.queue_stats_at(later).oldest_age, Some(later - oldest));
+        transport.drain_messages(|_| true);
+        assert_eq!(transport.queue_stats_at(later), QueueStats::default());
+    }
+
+    #[test]
+    fn test_iter_pending_matches_the_drain() {
+        let mut transport = create_test_resource();
+        let a = "127.0.0.1:3000".parse().unwrap();
+        let b = "127.0.0.1:3001".parse().unwrap();
+        transport.send(a, b"state");
+        transport.send_with_priority(b, b"input", DeliveryRequirement::Reliable, u8::MAX);
+        transport.send_with_priority(a, b"telemetry", DeliveryRequirement::Reliable, 10);
+        transport.send(b, b"chat");
+
+        let pending: Vec<_> = transport.iter_pending().map(|message| message.payload.clone()).collect();
+        assert_eq!(transport.pending_len(), 4);
+        let drained: Vec<_> = transport
+            .drain_messages_to_send(|_| true)
+            .into_iter()
+            .map(|message| message.payload)
+            .collect();
+        assert_eq!(pending, drained);
+        assert_eq!(pending, [&b"input"[..], b"state", b"chat", b"telemetry"]);
+    }
+
+    #[test]
+    fn test_iter_pending_fairly_matches_the_send_order() {
+        let mut transport = create_test_resource();
+        let chatty = "127.0.0.1:3000".parse().unwrap();
+        let quiet = "127.0.0.1:3001".parse().unwrap();
+        let elsewhere = "127.0.0.1:3002".parse().unwrap();
+        for payload in [&b"state 1"[..], b"state 2", b"state 3"] {
+            transport.send(chatty, payload);
+        }
+        transport.send(quiet, b"chat");
+        transport.send_with_priority(quiet, b"input", DeliveryRequirement::Reliable, u8::MAX);
+        let (delivery, urgency) = (DeliveryRequirement::Reliable, UrgencyRequirement::OnTick);
+        transport.send_via(TransportId::MEMORY, elsewhere, b"lobby", delivery, urgency);
+
+        let pending: Vec<_> = transport
+            .iter_pending_fairly(TransportId::LAMINAR)
+            .map(|message| message.payload.clone())
+            .collect();
+        let mut sent = Vec::new();
+        transport.for_each_message_to_send(TransportId::LAMINAR, |_| true, |message| {
+            sent.push(message.payload);
+            Disposition::Done
+        });
+        assert_eq!(pending, sent);
+        assert_eq!(pending, [&b"input"[..], b"state 1", b"chat", b"state 2", b"state 3"]);
+    }
+
+    #[test]
+    fn test_drain_by_priority() {
+        let mut resource = create_test_resource();
+        let addr = "127.0.0.1:3000".parse().unwrap();
+
+        resource.send_with_priority(addr, b"chat", DeliveryRequirement::Reliable, 10);
+        resource.send(addr, b"state");
+        resource.send_with_priority(addr, b"input", DeliveryRequirement::Reliable, 255);
+        resource.send_with_priority(addr, b"telemetry", DeliveryRequirement::Reliable, 10);
+        resource.send_with_priority(addr, b"hit", DeliveryRequirement::Reliable, 255);
+
+        let payloads: Vec<_> = resource
+            .drain_messages_to_send(|_| true)
+            .into_iter()
+            .map(|message| message.payload)
+            .collect();
+        assert_eq!(payloads, ["input", "hit", "state", "chat", "telemetry"]);
+    }
+
+    #[test]
+    fn test_queue_capacity_policies() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let queued = |policy| {
+            let mut resource = create_test_resource();
+            resource.set_capacity(2, policy);
+            for payload in [b"1", b"2", b"3", b"4"] {
+                resource.send(addr, payload);
+            }
+            let payloads: Vec<_> = resource.get_messages().map(|message| message.payload.clone()).collect();
+            let rejected: Vec<_> = resource
+                .drain_rejected_messages()
+                .into_iter()
+                .map(|message| message.payload)
+                .collect();
+            (payloads, resource.dropped_messages(), rejected)
+        };
+
+        let bytes = |payloads: &[&'static str]| -> Vec<Bytes> {
+            payloads.iter().map(|payload| Bytes::from_static(payload.as_bytes())).collect()
+        };
+
+        assert_eq!(queued(QueuePolicy::DropOldest), (bytes(&["3", "4"]), 2, bytes(&[])));
+        assert_eq!(queued(QueuePolicy::DropNewest), (bytes(&["1", "2"]), 2, bytes(&[])));
+        assert_eq!(queued(QueuePolicy::Reject), (bytes(&["1", "2"]), 0, bytes(&["3", "4"])));
+    }
+
+    #[test]
+    fn test_expired_messages_are_dropped_on_drain() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut transport = create_test_resource();
+        transport.send_with_ttl(addr, b"stale input", DeliveryRequirement::Unreliable, Duration::from_millis(50));
+        transport.send_with_ttl(addr, b"fresh input", DeliveryRequirement::Unreliable, Duration::from_secs(60));
+        transport.send(addr, b"chat");
+
+        let later = Instant::now() + Duration::from_secs(1);
+        let sent = transport.drain_routed_messages_at(None, false, &mut |_| true, later);
+        let payloads: Vec<_> = sent.iter().map(|message| &message.payload[..]).collect();
+        assert_eq!(payloads, [&b"fresh input"[..], b"chat"]);
+        let expired: Vec<_> = transport.drain_expired_messages().into_iter().map(|message| message.payload).collect();
+        assert_eq!(expired, [&b"stale input"[..]]);
+        assert_eq!(transport.expired_messages(), 1);
+    }
+
+    #[test]
+    fn test_default_ttl_only_applies_to_unreliable_messages() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut transport = create_test_resource();
+        transport.set_default_ttl(Some(Duration::from_millis(50)));
+        transport.send_unreliable(addr, b"position");
+        transport.send_reliable(addr, b"chat");
+        transport.send_with_ttl(addr, b"input", DeliveryRequirement::Unreliable, Duration::from_secs(60));
+        transport.send_with_ttl(addr, b"offer", DeliveryRequirement::Reliable, Duration::from_millis(50));
+
+        let later = Instant::now() + Duration::from_secs(1);
+        let sent = transport.drain_routed_messages_at(None, false, &mut |_| true, later);
+        let payloads: Vec<_> = sent.iter().map(|message| &message.payload[..]).collect();
+        assert_eq!(payloads, [&b"chat"[..], b"input"]);
+        assert_eq!(transport.expired_messages(), 2);
+    }
+
+    #[test]
+    fn test_retry_policy_backs_off_until_the_last_attempt() {
+        let mut transport = create_test_resource();
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let would_block = || io::Error::from(io::ErrorKind::WouldBlock);
+        let message = Message::new(
+            addr,
+            Bytes::from_static(b"input"),
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::OnTick,
+        );
+        let start = Instant::now();
+        assert!(transport.retry_failed_at(would_block(), message.clone(), start).is_some());
+
+        transport.set_retry_policy(Some(RetryPolicy::default()));
+        assert!(transport.retry_failed_at(would_block(), message, start).is_some());
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        let mut message = Message::new(
+            addr,
+            Bytes::from_static(b"chat"),
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::OnTick,
+        );
+        assert!(transport.retry_failed_at(denied, message.clone(), start).is_some());
+
+        let mut now = start;
+        for backoff in [50, 100, 200] {
+            assert!(transport.retry_failed_at(would_block(), message, now).is_none());
+            let retry_at = now + Duration::from_millis(backoff);
+            let early = retry_at - Duration::from_millis(1);
+            assert!(transport.drain_routed_messages_at(None, false, &mut |_| true, early).is_empty());
+            now = retry_at;
+            message = transport.drain_routed_messages_at(None, false, &mut |_| true, now).remove(0);
+        }
+        assert_eq!(message.attempts, 3);
+        let (e, message) = transport.retry_failed_at(would_block(), message, now).unwrap();
+        assert_eq!((e.kind(), &message.payload[..]), (io::ErrorKind::WouldBlock, &b"chat"[..]));
+        assert!(!transport.has_messages());
+    }
+
+    #[test]
+    fn test_retried_message_keeps_its_place_in_the_queue() {
+        let mut transport = create_test_resource();
+        transport.set_retry_policy(Some(RetryPolicy::default()));
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        transport.send_reliable_ordered(addr, b"first", None);
+        let start = Instant::now();
+        let failed = transport.drain_routed_messages_at(None, false, &mut |_| true, start).remove(0);
+        transport.set_destination_capacity(addr, 1, QueuePolicy::Reject);
+        transport.send_reliable_ordered(addr, b"second", None);
+        assert!(transport.retry_failed_at(io::Error::from(io::ErrorKind::WouldBlock), failed, start).is_none());
+        assert_eq!((transport.dropped_messages(), transport.drain_rejected_messages().len()), (0, 0));
+
+        let retry_at = start + RetryPolicy::default().backoff;
+        let payloads: Vec<_> = transport
+            .drain_routed_messages_at(None, false, &mut |_| true, retry_at)
+            .into_iter()
+            .map(|message| message.payload)
+            .collect();
+        assert_eq!(payloads, [Bytes::from_static(b"first"), Bytes::from_static(b"second")]);
+    }
+
+    #[test]
+    fn test_retried_message_counts_as_the_oldest() {
+        let mut transport = create_test_resource();
+        transport.set_retry_policy(Some(RetryPolicy::default()));
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        transport.send(addr, b"newer");
+        let now = transport.get_messages().next().unwrap().queued_at;
+        let payload = Bytes::from_static(b"chat");
+        let mut failed = Message::new(addr, payload, DeliveryRequirement::Reliable, UrgencyRequirement::OnTick);
+        failed.queued_at = now - Duration::from_secs(1);
+        assert!(transport.retry_failed_at(io::Error::from(io::ErrorKind::WouldBlock), failed, now).is_none());
+
+        assert_eq!(transport.get_messages().nth(1).unwrap().payload, Bytes::from_static(b"chat"));
+        assert_eq!(transport.oldest_pending_age_at(now), Some(Duration::from_secs(1)));
+        assert_eq!(transport.queue_stats_at(now).oldest_age, Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_cancel_messages() {
+        let gone = "127.0.0.1:3000".parse().unwrap();
+        let other = "127.0.0.1:3001".parse().unwrap();
+        let mut transport = create_test_resource();
+        for destination in [gone, other, gone] {
+            transport.send(destination, b"state");
+        }
+        transport.send_unreliable(other, b"input");
+
+        assert_eq!(transport.cancel_messages_for(&gone), 2);
+        assert_eq!(transport.cancel_messages_for(&gone), 0);
+        assert_eq!(transport.cancel_messages_where(|message| message.delivery == DeliveryRequirement::Unreliable), 1);
+        let payloads: Vec<_> = transport
+            .get_messages()
+            .map(|message| (message.destination, &message.payload[..]))
+            .collect();
+        assert_eq!(payloads, [(other, &b"state"[..])]);
+    }
+
+    #[test]
+    fn test_disconnect_cancels_the_queued_messages_when_set() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut transport = create_test_resource();
+        transport.send(addr, b"state");
+        transport.peer_disconnected(&addr);
+        assert_eq!(transport.queued_for(&addr), 1);
+
+        transport.set_cancel_on_disconnect(true);
+        transport.peer_disconnected(&addr);
+        assert_eq!(transport.queued_for(&addr), 0);
+    }
+
+    #[test]
+    fn test_client_sends_to_the_server() {
+        let server = "127.0.0.1:3000".parse().unwrap();
+        let mut resource = create_test_resource();
+        assert_eq!(resource.send_to_server(b"join").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        resource.set_role(NetworkRole::Client { server_addr: server });
+        resource.send_to_server(b"join").unwrap();
+        let messages: Vec<_> = resource.get_messages().collect();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].destination, server);
+        assert_eq!(messages[0].payload, &b"join"[..]);
+
+        resource.peer_connected(server);
+        assert_eq!(resource.accepted_peers().count(), 0);
+    }
+
+    #[test]
+    fn test_drop_oldest_unreliable_keeps_the_reliable_messages() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let mut resource = create_test_resource();
+        resource.set_capacity(2, QueuePolicy::DropOldestUnreliable);
+        resource.send_reliable(addr, b"1");
+        resource.send_unreliable(addr, b"2");
+        resource.send_reliable(addr, b"3");
+        resource.send_unreliable(addr, b"4");
+        resource.send_reliable(addr, b"5");
+
+        let payloads: Vec<_> = resource.get_messages().map(|message| &message.payload[..]).collect();
+        assert_eq!(payloads, [&b"1"[..], b"3"]);
+        assert_eq!(resource.dropped_messages(), 2);
+        let rejected: Vec<_> = resource.drain_rejected_messages().into_iter().map(|message| message.payload).collect();
+        assert_eq!(rejected, [&b"5"[..]]);
+    }
+
+    #[test]
+    fn test_destination_capacity_leaves_the_other_destinations_alone() {
+        let full = "127.0.0.1:3000".parse().unwrap();
+        let other = "127.0.0.1:3001".parse().unwrap();
+        let mut resource = create_test_resource();
+        resource.set_destination_capacity(full, 1, QueuePolicy::DropOldest);
+        resource.set_capacity(3, QueuePolicy::Reject);
+        resource.send(other, b"a");
+        resource.send(full, b"1");
+        resource.send(full, b"2");
+        resource.send(other, b"b");
+
+        assert_eq!(resource.queued_for(&full), 1);
+        assert_eq!(resource.queued_for(&other), 2);
+        assert_eq!(resource.dropped_messages(), 1);
+        let e = resource
+            .try_send_with_requirements(other, b"c", DeliveryRequirement::Reliable, UrgencyRequirement::OnTick)
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert!(resource.drain_rejected_messages().is_empty());
+    }
+
+    #[test]
+    fn test_rate_limit_spreads_messages_across_drains() {
+        let mut transport = create_test_resource();
+        let limited = "127.0.0.1:3000".parse().unwrap();
+        let other = "127.0.0.1:3001".parse().unwrap();
+        transport.set_peer_rate_limit(limited, 1000);
+        for _ in 0..20 {
+            transport.send_immediate(limited, &[0; 100]);
+        }
+        transport.send_immediate(other, &[0; 100]);
+
+        let start = Instant::now();
+        let mut drained_at = |millis| {
+            let now = start + std::time::Duration::from_millis(millis);
+            let messages = transport.drain_routed_messages_at(None, false, &mut |_| true, now);
+            messages.iter().filter(|message| message.destination == limited).count()
+        };
+        assert_eq!(drained_at(0), 10);
+        assert_eq!(drained_at(0), 0);
+        assert_eq!(drained_at(500), 5);
+        assert_eq!(drained_at(1000), 5);
+        assert!(!transport.has_messages());
+    }
+
+    #[test]
+    fn test_rate_limit_goes_to_the_higher_priorities_first() {
+        let mut transport = create_test_resource();
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        transport.set_peer_packet_rate_limit(addr, 2);
+        for (payload, priority) in [(&b"bulk 1"[..], 0), (b"bulk 2", 0), (b"critical", 255)] {
+            transport.send_with_priority(addr, payload, DeliveryRequirement::Reliable, priority);
+        }
+        let payloads = |messages: Vec<Message>| -> Vec<_> {
+            messages.into_iter().map(|message| message.payload).collect()
+        };
+        let now = Instant::now();
+        let drained = transport.drain_routed_messages_at(None, false, &mut |_| true, now);
+        assert_eq!(payloads(drained), [Bytes::from_static(b"critical"), Bytes::from_static(b"bulk 1")]);
+
+        // the messages requeued by the send get their tokens back
+        transport.send_with_priority(addr, b"critical", DeliveryRequirement::Reliable, 255);
+        let later = now + Duration::from_secs(1);
+        let mut sent = Vec::new();
+        for requeue in [true, false] {
+            let mut send = |message: Message| {
+                if requeue {
+                    return Disposition::Requeue(message);
+                }
+                sent.push(message);
+                Disposition::Done
+            };
+            transport.for_each_message_to_send_at(TransportId::LAMINAR, false, &mut |_| true, &mut send, later);
         }
+        assert_eq!(payloads(sent), [Bytes::from_static(b"critical"), Bytes::from_static(b"bulk 2")]);
+    }
+
+    #[test]
+    fn test_readdress() {
+        let mut transport = create_test_resource();
+        let old_host = "127.0.0.1:3000".parse().unwrap();
+        let new_host = "127.0.0.1:3001".parse().unwrap();
+        let other = "127.0.0.1:3002".parse().unwrap();
+        transport.send(old_host, test_payload());
+        transport.send(other, test_payload());
+
+        transport.readdress(old_host, new_host);
+
+        let destinations: Vec<_> = transport.get_messages().map(|message| message.destination).collect();
+        assert_eq!(destinations, [new_host, other]);
+    }
+
+    #[test]
+    fn test_route_moves_messages_to_another_transport() {
+        let mut transport = create_test_resource();
+        let server = "127.0.0.1:3000".parse().unwrap();
+        let fallback = "127.0.0.1:3001".parse().unwrap();
+        transport.send(server, b"queued");
+        transport.set_route(server, TransportId::LAMINAR, server);
+        transport.send(server, b"laminar");
+        transport.send_via(TransportId::UDP, server, b"udp", DeliveryRequirement::Default, UrgencyRequirement::OnTick);
+
+        transport.set_route(server, TransportId::TCP, fallback);
+        transport.send(server, b"tcp");
+
+        let routes: Vec<_> = transport
+            .get_messages()
+            .map(|message| (message.transport, message.destination, &message.payload[..]))
+            .collect();
+        assert_eq!(routes, [
+            (Some(TransportId::TCP), fallback, &b"queued"[..]),
+            (Some(TransportId::TCP), fallback, &b"laminar"[..]),
+            (Some(TransportId::UDP), server, &b"udp"[..]),
+            (Some(TransportId::TCP), fallback, &b"tcp"[..]),
+        ]);
+        assert_eq!(transport.route(&server), Some((TransportId::TCP, fallback)));
     }
 
     fn test_payload() -> &'static [u8] {
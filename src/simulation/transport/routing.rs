@@ -0,0 +1,177 @@
+//! Routing of the queued messages between several transports used at once, e.g. laminar for the
+//! gameplay traffic and TCP for bulk transfers.
+//!
+//! A message queued with `TransportResource::send_via` is only sent by the transport it names,
+//! the other messages are sent by whichever transport drains the queue first. The incoming
+//! `Message`, `Connect` and `Disconnect` events are mirrored as `TaggedNetworkEvent`s naming the
+//! transport they come from.
+
+use std::{fmt, io};
+
+#[cfg(feature = "bevy")]
+use bevy::ecs::system::SystemParam;
+#[cfg(feature = "bevy")]
+use bevy::prelude::{EventWriter, ResMut};
+
+use crate::simulation::{events::NetworkSimulationEvent, message::Message};
+#[cfg(feature = "bevy")]
+use crate::simulation::{events::TaggedNetworkEvent, transport::TransportResource};
+
+/// Identifies a transport, each plugin registers its own in the `TransportResource`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TransportId(pub &'static str);
+
+impl TransportId {
+    pub const LAMINAR: TransportId = TransportId("laminar");
+    pub const MEMORY: TransportId = TransportId("memory");
+    #[cfg(feature = "steam")]
+    pub const STEAM: TransportId = TransportId("steam");
+    pub const TCP: TransportId = TransportId("tcp");
+    pub const UDP: TransportId = TransportId("udp");
+    pub const UNIX: TransportId = TransportId("unix");
+}
+
+impl fmt::Display for TransportId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+/// Writer of the events of a transport, tagging the incoming ones with their transport.
+#[cfg(feature = "bevy")]
+#[derive(SystemParam)]
+pub struct NetworkEventWriter<'w, 's> {
+    events: EventWriter<'w, 's, NetworkSimulationEvent>,
+    tagged: EventWriter<'w, 's, TaggedNetworkEvent>,
+}
+
+#[cfg(feature = "bevy")]
+impl NetworkEventWriter<'_, '_> {
+    /// Sends the event, along with its tagged copy if there is one.
+    pub fn send(&mut self, transport: TransportId, event: NetworkSimulationEvent) {
+        if let Some(tagged) = TaggedNetworkEvent::tag(transport, &event) {
+            self.tagged.send(tagged);
+        }
+        self.events.send(event);
+    }
+
+    /// Sends every event, along with their tagged copies.
+    pub fn send_batch(&mut self, transport: TransportId, events: impl IntoIterator<Item = NetworkSimulationEvent>) {
+        for event in events {
+            self.send(transport, event);
+        }
+    }
+}
+
+#[cfg(feature = "bevy")]
+/// Creates a new system reporting the messages routed to a transport which isn't registered as a
+/// `SendError`, rather than leaving them in the queue forever. The messages the full queue refused
+/// are reported too, see `QueuePolicy::Reject`, and so are the ones which expired, see
+/// `TransportResource::send_with_ttl`, and the payloads sent to a retired connection, see
+/// `TransportResource::send_to`.
+pub fn unroutable_messages_system(mut transport:     ResMut<TransportResource>,
+                                  mut event_channel: EventWriter<NetworkSimulationEvent>) {
+    for message in transport.drain_unroutable_messages() {
+        let e = io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no {} transport registered", message.transport.unwrap()),
+        );
+        event_channel.send(NetworkSimulationEvent::SendError(e, message));
+    }
+    event_channel.send_batch(transport.drain_rejected_messages().into_iter().map(rejected_message_event));
+    event_channel.send_batch(transport.drain_expired_messages().into_iter().map(expired_message_event));
+    event_channel.send_batch(transport
+        .drain_retired_connection_payloads()
+        .into_iter()
+        .map(|(connection, payload)| NetworkSimulationEvent::RetiredConnection(connection, payload)));
+}
+
+/// Creates the `SendError` of a message which expired in the queue.
+pub(crate) fn expired_message_event(message: Message) -> NetworkSimulationEvent {
+    let e = io::Error::new(io::ErrorKind::TimedOut, "the message expired before it was sent");
+    NetworkSimulationEvent::SendError(e, message)
+}
+
+/// Creates the `SendError` of a message refused by the full queue.
+pub(crate) fn rejected_message_event(message: Message) -> NetworkSimulationEvent {
+    let e = io::Error::new(io::ErrorKind::WouldBlock, "the queue of messages to send is full");
+    NetworkSimulationEvent::SendError(e, message)
+}
+
+#[cfg(all(test, feature = "bevy"))]
+mod tests {
+    use std::net::SocketAddr;
+
+    use bevy::{app::App, ecs::event::Events};
+    use bytes::Bytes;
+
+    use crate::simulation::{
+        requirements::{DeliveryRequirement, UrgencyRequirement},
+        transport::memory::{MemoryNetwork, MemoryTransportPlugin},
+        transport::tcp::{TcpPlugin, TcpStreamsResource},
+    };
+    use super::*;
+
+    #[test]
+    fn test_messages_go_through_their_transport() {
+        let network = MemoryNetwork::new();
+        let memory_addr: SocketAddr = "10.0.0.1:3000".parse().unwrap();
+        let peer = network.bind("10.0.0.2:3000".parse().unwrap()).unwrap();
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(MemoryTransportPlugin::new(network.clone(), memory_addr))
+            .add_plugin(TcpPlugin::new("127.0.0.1:0".parse().unwrap()));
+        let tcp_addr = app.world.resource::<TcpStreamsResource>().listener().unwrap().local_addr().unwrap();
+
+        let mut transport = app.world.resource_mut::<TransportResource>();
+        for _ in 0..10 {
+            transport.send_via(
+                TransportId::MEMORY,
+                peer.local_addr(),
+                b"memory",
+                DeliveryRequirement::Reliable,
+                UrgencyRequirement::Immediate,
+            );
+        }
+        transport.send_via(
+            TransportId::UDP,
+            tcp_addr,
+            b"nowhere",
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::Immediate,
+        );
+        app.update();
+
+        assert_eq!(network.pending(&peer.local_addr()), 10);
+        assert!(!app.world.resource::<TransportResource>().has_messages());
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        let errors: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .filter_map(|event| match event {
+                NetworkSimulationEvent::SendError(e, message) => Some((e.kind(), message.payload.clone())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors, vec![(io::ErrorKind::NotFound, Bytes::from_static(b"nowhere"))]);
+    }
+
+    #[test]
+    fn test_incoming_events_are_tagged() {
+        let network = MemoryNetwork::new();
+        let addr = "10.0.0.1:3000".parse().unwrap();
+        let mut peer = network.bind("10.0.0.2:3000".parse().unwrap()).unwrap();
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(MemoryTransportPlugin::new(network, addr));
+
+        peer.send_to(Bytes::from_static(b"test"), addr, DeliveryRequirement::Reliable).unwrap();
+        app.update();
+
+        let events = app.world.resource::<Events<TaggedNetworkEvent>>();
+        let tagged: Vec<_> = events.get_reader().iter(events).cloned().collect();
+        assert_eq!(tagged, vec![
+            TaggedNetworkEvent::Message(TransportId::MEMORY, peer.local_addr(), Bytes::from_static(b"test")),
+        ]);
+    }
+}
@@ -0,0 +1,402 @@
+//! Network systems implementation backed by Steam's networking sockets, for its relays, peer
+//! authentication and P2P connections.
+//!
+//! The crate doesn't link against the Steamworks SDK: the few calls it needs go through the
+//! `SteamSockets` trait, implemented on top of the binding the game already uses, so the feature
+//! builds without the SDK. Steam peers are identified by their SteamID, which maps to a synthetic
+//! IPv6 address in a unique local range, see `steam_addr`, so that they are addressed like any
+//! other peer. A connection is opened with `ConnectP2P` by the first message sent to a peer, and
+//! the incoming ones are accepted. Steam has no sequencing, the sequenced requirements get the
+//! plain unreliable and reliable send flags, and its reliable messages are always ordered.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6},
+};
+
+#[cfg(feature = "bevy")]
+use bevy::app::App;
+#[cfg(feature = "bevy")]
+use bevy::prelude::Plugin;
+use bytes::Bytes;
+
+use crate::simulation::{
+    events::DisconnectReason,
+    requirements::DeliveryRequirement,
+    transport::generic::{Transport, TransportEvent},
+};
+#[cfg(feature = "bevy")]
+use crate::simulation::transport::{generic::TransportPlugin, routing::TransportId};
+
+/// `k_nSteamNetworkingSend_Unreliable`
+pub const SEND_UNRELIABLE: i32 = 0;
+/// `k_nSteamNetworkingSend_Reliable`
+pub const SEND_RELIABLE: i32 = 8;
+
+/// First segments of the synthetic addresses of the Steam peers, `fd` followed by "steam".
+const STEAM_PREFIX: [u16; 3] = [0xfd73, 0x7465, 0x616d];
+
+/// `HSteamNetConnection`, handle of a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SteamConnection(pub u32);
+
+/// `EResult` of a failed call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SteamError(pub i32);
+
+impl fmt::Display for SteamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Steam call failed with EResult {}", self.0)
+    }
+}
+
+impl std::error::Error for SteamError {}
+
+impl From<SteamError> for io::Error {
+    fn from(e: SteamError) -> Self {
+        io::Error::other(e)
+    }
+}
+
+/// State of a connection, as reported by `SteamNetConnectionStatusChangedCallback_t`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SteamConnectionState {
+    // Being established, finding a route included
+    Connecting,
+    // Messages go both ways
+    Connected,
+    // The peer closed the connection
+    ClosedByPeer,
+    // The connection was lost, e.g. the peer timed out
+    ProblemDetectedLocally,
+}
+
+/// A `SteamNetConnectionStatusChangedCallback_t`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SteamStatusChange {
+    pub connection: SteamConnection,
+    /// SteamID of the peer.
+    pub identity:   u64,
+    pub state:      SteamConnectionState,
+    /// True if the peer opened the connection, on one of our listen sockets.
+    pub incoming:   bool,
+}
+
+/// The calls of `ISteamNetworkingSockets` the transport needs, to implement on top of a Steamworks
+/// binding.
+pub trait SteamSockets: Send + Sync + 'static {
+    /// `ConnectP2P` to the peer with this SteamID.
+    fn connect_p2p(&mut self, identity: u64) -> Result<SteamConnection, SteamError>;
+
+    /// `AcceptConnection`
+    fn accept_connection(&mut self, connection: SteamConnection) -> Result<(), SteamError>;
+
+    /// `CloseConnection`
+    fn close_connection(&mut self, connection: SteamConnection);
+
+    /// `SendMessageToConnection`, `flags` being one of `SEND_UNRELIABLE` and `SEND_RELIABLE`.
+    fn send_message_to_connection(
+        &mut self,
+        connection: SteamConnection,
+        data: &[u8],
+        flags: i32,
+    ) -> Result<(), SteamError>;
+
+    /// `ReceiveMessagesOnPollGroup`, or on each connection, returning every message received.
+    fn receive_messages(&mut self) -> Vec<(SteamConnection, Vec<u8>)>;
+
+    /// Returns the status changes reported by the callbacks since the previous call.
+    fn status_changes(&mut self) -> Vec<SteamStatusChange>;
+}
+
+/// Returns the synthetic address standing for the Steam peer with this SteamID.
+#[must_use]
+pub fn steam_addr(identity: u64) -> SocketAddr {
+    let [a, b, c] = STEAM_PREFIX;
+    let [d, e, f, g] = [48, 32, 16, 0].map(|shift| (identity >> shift) as u16);
+    SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(a, b, c, 0, d, e, f, g), 0, 0, 0))
+}
+
+/// Returns the SteamID `addr` stands for, if it is the address of a Steam peer.
+#[must_use]
+pub fn steam_identity(addr: &SocketAddr) -> Option<u64> {
+    let SocketAddr::V6(addr) = addr else {
+        return None;
+    };
+    let segments = addr.ip().segments();
+    (segments[..3] == STEAM_PREFIX && segments[3] == 0 && addr.port() == 0)
+        .then(|| segments[4..].iter().fold(0, |identity, segment| identity << 16 | u64::from(*segment)))
+}
+
+/// Returns the send flags of the delivery requirement.
+#[must_use]
+pub fn send_flags(delivery: DeliveryRequirement) -> i32 {
+    match delivery {
+        DeliveryRequirement::Unreliable | DeliveryRequirement::UnreliableSequenced(_) => SEND_UNRELIABLE,
+        _ => SEND_RELIABLE,
+    }
+}
+
+/// `Transport` over Steam's networking sockets, keeping the connection of each peer.
+pub struct SteamTransport<S: SteamSockets> {
+    sockets:     S,
+    connections: HashMap<u64, SteamConnection>,
+    identities:  HashMap<SteamConnection, u64>,
+}
+
+impl<S: SteamSockets> SteamTransport<S> {
+    #[must_use]
+    pub fn new(sockets: S) -> Self {
+        Self { sockets, connections: HashMap::new(), identities: HashMap::new() }
+    }
+
+    /// Returns the Steam sockets.
+    #[must_use]
+    pub fn sockets(&self) -> &S {
+        &self.sockets
+    }
+
+    /// Returns the Steam sockets mutably.
+    pub fn sockets_mut(&mut self) -> &mut S {
+        &mut self.sockets
+    }
+
+    /// Returns the connection to the peer with this SteamID, if there is one.
+    #[must_use]
+    pub fn connection(&self, identity: u64) -> Option<SteamConnection> {
+        self.connections.get(&identity).copied()
+    }
+
+    fn track(&mut self, identity: u64, connection: SteamConnection) {
+        self.connections.insert(identity, connection);
+        self.identities.insert(connection, identity);
+    }
+
+    fn forget(&mut self, connection: SteamConnection) {
+        if let Some(identity) = self.identities.remove(&connection) {
+            self.connections.remove(&identity);
+        }
+    }
+}
+
+impl<S: SteamSockets> Transport for SteamTransport<S> {
+    fn send(
+        &mut self,
+        destination: SocketAddr,
+        payload: Bytes,
+        delivery: DeliveryRequirement,
+    ) -> io::Result<()> {
+        let identity = steam_identity(&destination).ok_or_else(|| {
+            let message = format!("{} isn't the address of a Steam peer", destination);
+            io::Error::new(io::ErrorKind::AddrNotAvailable, message)
+        })?;
+        let connection = match self.connection(identity) {
+            Some(connection) => connection,
+            None => {
+                let connection = self.sockets.connect_p2p(identity)?;
+                self.track(identity, connection);
+                connection
+            }
+        };
+        Ok(self.sockets.send_message_to_connection(connection, &payload, send_flags(delivery))?)
+    }
+
+    fn poll(&mut self) -> Vec<TransportEvent> {
+        let mut events = Vec::new();
+        for change in self.sockets.status_changes() {
+            let addr = steam_addr(change.identity);
+            match change.state {
+                SteamConnectionState::Connecting if change.incoming => {
+                    match self.sockets.accept_connection(change.connection) {
+                        Ok(()) => self.track(change.identity, change.connection),
+                        Err(e) => events.push(TransportEvent::ConnectionError(e.into(), Some(addr))),
+                    }
+                }
+                SteamConnectionState::Connecting => {}
+                SteamConnectionState::Connected => {
+                    self.track(change.identity, change.connection);
+                    events.push(TransportEvent::Connect(addr));
+                }
+                SteamConnectionState::ClosedByPeer | SteamConnectionState::ProblemDetectedLocally => {
+                    self.sockets.close_connection(change.connection);
+                    self.forget(change.connection);
+                    let reason = if change.state == SteamConnectionState::ClosedByPeer {
+                        DisconnectReason::Closed
+                    } else {
+                        DisconnectReason::Timeout
+                    };
+                    events.push(TransportEvent::Disconnect(addr, reason));
+                }
+            }
+        }
+        for (connection, payload) in self.sockets.receive_messages() {
+            if let Some(identity) = self.identities.get(&connection) {
+                events.push(TransportEvent::Message(steam_addr(*identity), payload.into()));
+            }
+        }
+        events
+    }
+}
+
+/// Use this plugin to send the messages over Steam's networking sockets, routed with
+/// `TransportId::STEAM`.
+#[cfg(feature = "bevy")]
+pub struct SteamTransportPlugin<S: SteamSockets> {
+    plugin: TransportPlugin<SteamTransport<S>>,
+}
+
+#[cfg(feature = "bevy")]
+impl<S: SteamSockets> SteamTransportPlugin<S> {
+    #[must_use]
+    pub fn new(sockets: S) -> Self {
+        Self { plugin: TransportPlugin::new(SteamTransport::new(sockets)).with_id(TransportId::STEAM) }
+    }
+}
+
+#[cfg(feature = "bevy")]
+impl<S: SteamSockets> Plugin for SteamTransportPlugin<S> {
+    fn build(&self, app: &mut App) {
+        self.plugin.build(app);
+    }
+
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: u64 = 76_561_197_960_287_930;
+
+    #[test]
+    fn test_steam_ids_map_to_addresses() {
+        let addr = steam_addr(PEER);
+        assert_eq!(steam_identity(&addr), Some(PEER));
+        assert_eq!(steam_identity(&steam_addr(u64::MAX)), Some(u64::MAX));
+        assert_eq!(steam_identity(&"127.0.0.1:3000".parse().unwrap()), None);
+        assert_eq!(steam_identity(&"[::1]:0".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_steam_connections_as_transport() {
+        let mut transport = SteamTransport::new(FakeSteam::default());
+        let peer = steam_addr(PEER);
+        let incoming = SteamConnection(7);
+        transport.sockets_mut().changes = vec![
+            SteamStatusChange {
+                connection: incoming,
+                identity:   PEER,
+                state:      SteamConnectionState::Connecting,
+                incoming:   true,
+            },
+            SteamStatusChange {
+                connection: incoming,
+                identity:   PEER,
+                state:      SteamConnectionState::Connected,
+                incoming:   true,
+            },
+        ];
+        transport.sockets_mut().received = vec![(incoming, b"hello".to_vec())];
+
+        let events: Vec<_> = transport
+            .poll()
+            .into_iter()
+            .map(|event| format!("{:?}", event))
+            .collect();
+        assert_eq!(events, [format!("Connect({})", peer), format!("Message({}, b\"hello\")", peer)]);
+        assert_eq!(transport.sockets().accepted, [incoming]);
+
+        Transport::send(&mut transport, peer, Bytes::from_static(b"input"), DeliveryRequirement::Unreliable).unwrap();
+        Transport::send(
+            &mut transport,
+            peer,
+            Bytes::from_static(b"chat"),
+            DeliveryRequirement::ReliableOrdered(None),
+        )
+        .unwrap();
+        assert_eq!(transport.sockets().sent, [
+            (incoming, b"input".to_vec(), SEND_UNRELIABLE),
+            (incoming, b"chat".to_vec(), SEND_RELIABLE),
+        ]);
+
+        transport.sockets_mut().changes = vec![
+            SteamStatusChange {
+                connection: incoming,
+                identity:   PEER,
+                state:      SteamConnectionState::ClosedByPeer,
+                incoming:   true,
+            },
+        ];
+        let events: Vec<_> = transport.poll().into_iter().map(|event| format!("{:?}", event)).collect();
+        assert_eq!(events, [format!("Disconnect({}, Closed)", peer)]);
+        assert_eq!(transport.connection(PEER), None);
+    }
+
+    #[test]
+    fn test_first_message_connects_to_the_peer() {
+        let mut transport = SteamTransport::new(FakeSteam::default());
+        Transport::send(
+            &mut transport,
+            steam_addr(PEER),
+            Bytes::from_static(b"join"),
+            DeliveryRequirement::Reliable,
+        )
+        .unwrap();
+        assert_eq!(transport.connection(PEER), Some(SteamConnection(1)));
+        assert_eq!(transport.sockets().sent, [(SteamConnection(1), b"join".to_vec(), SEND_RELIABLE)]);
+
+        let e = Transport::send(
+            &mut transport,
+            "127.0.0.1:3000".parse().unwrap(),
+            Bytes::new(),
+            DeliveryRequirement::Reliable,
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    /// Records the calls, and hands out the status changes and messages set beforehand.
+    #[derive(Default)]
+    struct FakeSteam {
+        next:     u32,
+        accepted: Vec<SteamConnection>,
+        sent:     Vec<(SteamConnection, Vec<u8>, i32)>,
+        changes:  Vec<SteamStatusChange>,
+        received: Vec<(SteamConnection, Vec<u8>)>,
+    }
+
+    impl SteamSockets for FakeSteam {
+        fn connect_p2p(&mut self, _: u64) -> Result<SteamConnection, SteamError> {
+            self.next += 1;
+            Ok(SteamConnection(self.next))
+        }
+
+        fn accept_connection(&mut self, connection: SteamConnection) -> Result<(), SteamError> {
+            self.accepted.push(connection);
+            Ok(())
+        }
+
+        fn close_connection(&mut self, _: SteamConnection) {}
+
+        fn send_message_to_connection(
+            &mut self,
+            connection: SteamConnection,
+            data: &[u8],
+            flags: i32,
+        ) -> Result<(), SteamError> {
+            self.sent.push((connection, data.to_vec(), flags));
+            Ok(())
+        }
+
+        fn receive_messages(&mut self) -> Vec<(SteamConnection, Vec<u8>)> {
+            std::mem::take(&mut self.received)
+        }
+
+        fn status_changes(&mut self) -> Vec<SteamStatusChange> {
+            std::mem::take(&mut self.changes)
+        }
+    }
+}
@@ -0,0 +1,473 @@
+//! Network systems implementation backed by TCP.
+//!
+//! Every message is sent as a frame made of its payload length as a big-endian `u32` followed by
+//! the payload itself. Reads and writes never block: whatever can't be read or written this frame
+//! is buffered per connection and carried over to the next one.
+//!
+//! TCP is always reliable and ordered, so `DeliveryRequirement::Unreliable` and
+//! `DeliveryRequirement::UnreliableSequenced` messages are rejected with a
+//! `NetworkSimulationEvent::SendError`. Every other requirement is sent over the stream.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use bevy::log::{info, error};
+
+use crate::simulation::{
+    events::{DisconnectReason, NetworkSimulationEvent, TaggedNetworkEvent},
+    peers::{ConnectedPeers, connected_peers_system},
+    requirements::DeliveryRequirement,
+    timing::{NetworkSimulationTime, network_simulation_time_system},
+    transport::{
+        routing::{NetworkEventWriter, TransportId, unroutable_messages_system},
+        TransportResource,
+    },
+};
+use bevy::prelude::{Plugin, Resource, Res, ResMut, SystemSet, SystemLabel};
+use bevy::app::App;
+
+/// Size of the length prefix of every frame.
+const FRAME_HEADER_SIZE: usize = 4;
+/// Default maximum payload size of a single frame.
+const DEFAULT_MAX_FRAME_SIZE_BYTES: usize = 1 << 20;
+/// Size of the chunks read from a stream.
+const READ_CHUNK_SIZE: usize = 4096;
+
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct TcpLabel;
+
+/// Use this plugin to add the TCP transport layer to your game.
+pub struct TcpPlugin {
+    address:              SocketAddr,
+    max_frame_size_bytes: usize,
+}
+
+impl TcpPlugin {
+    /// Creates a plugin listening for connections on `address`.
+    pub fn new(address: SocketAddr) -> Self {
+        TcpPlugin { address, max_frame_size_bytes: DEFAULT_MAX_FRAME_SIZE_BYTES }
+    }
+
+    /// Sets the maximum payload size of a received frame. A peer announcing a bigger frame is
+    /// disconnected.
+    #[must_use]
+    pub fn with_max_frame_size(mut self, max_frame_size_bytes: usize) -> Self {
+        self.max_frame_size_bytes = max_frame_size_bytes;
+        self
+    }
+}
+
+impl Plugin for TcpPlugin {
+    /// Failing to bind the listener doesn't panic, it is reported as a `ConnectionError` on the
+    /// first frame and the resource is left without a listener.
+    fn build(&self, app: &mut App) {
+        let (listener, error) = match TcpListener::bind(self.address)
+            .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        {
+            Ok(listener) => (Some(listener), None),
+            Err(e) => (None, Some(e)),
+        };
+
+        app
+            .add_startup_system(log_startup)
+            .add_event::<NetworkSimulationEvent>()
+            .add_event::<TaggedNetworkEvent>()
+            .init_resource::<NetworkSimulationTime>()
+            .init_resource::<TransportResource>()
+            .init_resource::<ConnectedPeers>()
+            .insert_resource(TcpStreamsResource::new(listener, self.max_frame_size_bytes))
+            .add_system_set(SystemSet::new()
+                .label(TcpLabel)
+                .with_system(network_simulation_time_system)
+                .with_system(unroutable_messages_system)
+                .with_system(connected_peers_system)
+                .with_system(tcp_connection_listener_system)
+                .with_system(tcp_network_send_system)
+                .with_system(tcp_network_recv_system)
+            );
+        app.world.resource_mut::<TransportResource>().register_transport(TransportId::TCP);
+
+        if let Some(e) = error {
+            app.world.send_event(NetworkSimulationEvent::ConnectionError(e, Some(self.address)));
+        }
+    }
+
+    fn name(&self) -> &str {
+        "tcp"
+    }
+}
+
+fn log_startup(streams: Res<TcpStreamsResource>) {
+    match streams.listener().map(TcpListener::local_addr) {
+        Some(Ok(addr)) => info!("Start listening on {}", addr),
+        _ => error!("TCP listener is not bound"),
+    }
+}
+
+/// Creates a new tcp connection listener system, accepting all pending connections.
+pub fn tcp_connection_listener_system(mut streams:       ResMut<TcpStreamsResource>,
+                                      mut event_channel: NetworkEventWriter) {
+    let TcpStreamsResource { listener, connections, .. } = &mut *streams;
+    if let Some(listener) = listener {
+        loop {
+            match listener.accept() {
+                Ok((stream, addr)) => match TcpConnection::new(stream) {
+                    Ok(connection) => {
+                        connections.insert(addr, connection);
+                        event_channel.send(TransportId::TCP, NetworkSimulationEvent::Connect(addr));
+                    }
+                    Err(e) => {
+                        event_channel.send(TransportId::TCP, NetworkSimulationEvent::ConnectionError(e, Some(addr)));
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    event_channel.send(TransportId::TCP, NetworkSimulationEvent::ConnectionError(e, None));
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Creates a new tcp network send system. Sending to an address without an open stream connects
+/// to it first, which blocks until the connection is established or refused.
+pub fn tcp_network_send_system(mut transport:     ResMut<TransportResource>,
+                               mut streams:       ResMut<TcpStreamsResource>,
+                               mut event_channel: NetworkEventWriter,
+                                   sim_time:      Res<NetworkSimulationTime>) {
+    let messages = transport
+        .drain_messages_to_send_via(TransportId::TCP, |_| sim_time.should_send_message_now());
+
+    for message in messages {
+        match message.delivery {
+            DeliveryRequirement::Unreliable | DeliveryRequirement::UnreliableSequenced(_) => {
+                let e = io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("TCP transport does not support {:?} delivery", message.delivery),
+                );
+                event_channel.send(TransportId::TCP, NetworkSimulationEvent::SendError(e, message));
+            }
+            _ => match streams.get_or_connect(message.destination) {
+                Ok((connection, connected)) => {
+                    connection.queue_frame(&message.payload);
+                    if connected {
+                        event_channel.send(TransportId::TCP, NetworkSimulationEvent::Connect(message.destination));
+                    }
+                }
+                Err(e) => {
+                    if let Some((e, message)) = transport.retry_failed(e, message) {
+                        event_channel.send(TransportId::TCP, NetworkSimulationEvent::SendError(e, message));
+                    }
+                }
+            },
+        }
+    }
+
+    streams.connections.retain(|addr, connection| match connection.flush() {
+        Ok(()) => true,
+        Err(e) => {
+            event_channel.send(TransportId::TCP, NetworkSimulationEvent::ConnectionError(e, Some(*addr)));
+            let closed = NetworkSimulationEvent::Disconnect(*addr, DisconnectReason::Closed);
+            event_channel.send(TransportId::TCP, closed);
+            false
+        }
+    });
+}
+
+/// Creates a new tcp receive system. Streams which reached EOF or failed are closed.
+pub fn tcp_network_recv_system(mut streams:       ResMut<TcpStreamsResource>,
+                               mut event_channel: NetworkEventWriter) {
+    let max_frame_size_bytes = streams.max_frame_size_bytes;
+    streams.connections.retain(|addr, connection| {
+        let result = connection.fill_read_buffer();
+        loop {
+            match connection.next_frame(max_frame_size_bytes) {
+                Ok(Some(payload)) => {
+                    event_channel.send(TransportId::TCP, NetworkSimulationEvent::Message(*addr, payload));
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    event_channel.send(TransportId::TCP, NetworkSimulationEvent::RecvError(e));
+                    let closed = NetworkSimulationEvent::Disconnect(*addr, DisconnectReason::Closed);
+                    event_channel.send(TransportId::TCP, closed);
+                    return false;
+                }
+            }
+        }
+
+        match result {
+            Ok(true) => true,
+            Ok(false) => {
+                let closed = NetworkSimulationEvent::Disconnect(*addr, DisconnectReason::Closed);
+                event_channel.send(TransportId::TCP, closed);
+                false
+            }
+            Err(e) => {
+                if !is_disconnect(&e) {
+                    event_channel.send(TransportId::TCP, NetworkSimulationEvent::RecvError(e));
+                }
+                let closed = NetworkSimulationEvent::Disconnect(*addr, DisconnectReason::Closed);
+                event_channel.send(TransportId::TCP, closed);
+                false
+            }
+        }
+    });
+}
+
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// A single non-blocking stream along with the data which couldn't be read or written yet.
+struct TcpConnection {
+    stream:       TcpStream,
+    read_buffer:  Vec<u8>,
+    write_buffer: Vec<u8>,
+}
+
+impl TcpConnection {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Self { stream, read_buffer: Vec::new(), write_buffer: Vec::new() })
+    }
+
+    fn queue_frame(&mut self, payload: &[u8]) {
+        self.write_buffer.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        self.write_buffer.extend_from_slice(payload);
+    }
+
+    /// Writes as much of the pending data as the stream accepts without blocking.
+    fn flush(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        let result = loop {
+            if written == self.write_buffer.len() {
+                break Ok(());
+            }
+            match self.stream.write(&self.write_buffer[written..]) {
+                Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.write_buffer.drain(..written);
+        result
+    }
+
+    /// Reads everything available without blocking. Returns `false` once the peer closed the
+    /// stream.
+    fn fill_read_buffer(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(false),
+                Ok(n) => self.read_buffer.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Pops the next complete frame out of the read buffer.
+    fn next_frame(&mut self, max_frame_size_bytes: usize) -> io::Result<Option<Bytes>> {
+        if self.read_buffer.len() < FRAME_HEADER_SIZE {
+            return Ok(None);
+        }
+        let mut header = [0; FRAME_HEADER_SIZE];
+        header.copy_from_slice(&self.read_buffer[..FRAME_HEADER_SIZE]);
+        let len = u32::from_be_bytes(header) as usize;
+        if len > max_frame_size_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame of {} bytes exceeds the maximum of {}", len, max_frame_size_bytes),
+            ));
+        }
+        if self.read_buffer.len() < FRAME_HEADER_SIZE + len {
+            return Ok(None);
+        }
+        let payload = Bytes::copy_from_slice(&self.read_buffer[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len]);
+        self.read_buffer.drain(..FRAME_HEADER_SIZE + len);
+        Ok(Some(payload))
+    }
+}
+
+/// Resource that owns the TCP listener and the currently open streams.
+#[derive(Resource)]
+pub struct TcpStreamsResource {
+    listener:             Option<TcpListener>,
+    connections:          HashMap<SocketAddr, TcpConnection>,
+    max_frame_size_bytes: usize,
+}
+
+impl Default for TcpStreamsResource {
+    fn default() -> Self {
+        Self::new(None, DEFAULT_MAX_FRAME_SIZE_BYTES)
+    }
+}
+
+impl TcpStreamsResource {
+    /// Creates a new instance of the `TcpStreamsResource`. The listener must be in non-blocking
+    /// mode.
+    #[must_use]
+    pub fn new(listener: Option<TcpListener>, max_frame_size_bytes: usize) -> Self {
+        Self { listener, connections: HashMap::new(), max_frame_size_bytes }
+    }
+
+    /// Returns a reference to the listener if there is one configured.
+    #[must_use]
+    pub fn listener(&self) -> Option<&TcpListener> {
+        self.listener.as_ref()
+    }
+
+    /// Returns true if a stream to the given address is open.
+    #[must_use]
+    pub fn is_connected(&self, addr: &SocketAddr) -> bool {
+        self.connections.contains_key(addr)
+    }
+
+    /// Returns the addresses of all open streams.
+    pub fn connected_addrs(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.connections.keys()
+    }
+
+    /// Closes the stream to the given address, dropping any data not sent yet. Returns whether a
+    /// stream was open.
+    pub fn drop_stream(&mut self, addr: &SocketAddr) -> bool {
+        self.connections.remove(addr).is_some()
+    }
+
+    /// Connects to `addr` unless a stream to it is open already, giving up after `timeout`. Returns
+    /// whether a new stream was opened. This blocks, up to `timeout`.
+    pub fn connect(&mut self, addr: SocketAddr, timeout: Duration) -> io::Result<bool> {
+        if self.connections.contains_key(&addr) {
+            return Ok(false);
+        }
+        let connection = TcpConnection::new(TcpStream::connect_timeout(&addr, timeout)?)?;
+        self.connections.insert(addr, connection);
+        Ok(true)
+    }
+
+    /// Returns the connection to `addr`, connecting to it first if needed. The returned flag tells
+    /// whether a new connection was made.
+    fn get_or_connect(&mut self, addr: SocketAddr) -> io::Result<(&mut TcpConnection, bool)> {
+        let connected = !self.connections.contains_key(&addr);
+        if connected {
+            let connection = TcpConnection::new(TcpStream::connect(addr)?)?;
+            self.connections.insert(addr, connection);
+        }
+        Ok((self.connections.get_mut(&addr).unwrap(), connected))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use bevy::ecs::event::Events;
+
+    use super::*;
+    use crate::simulation::requirements::UrgencyRequirement;
+
+    #[test]
+    fn test_frames_are_received_whole_and_in_order() {
+        let mut server = create_test_app();
+        let mut client = create_test_app();
+        let server_addr = listen_addr(&server);
+        // big enough to need several writes and reads
+        let big_payload = vec![7; 1 << 19];
+
+        {
+            let mut transport = client.world.resource_mut::<TransportResource>();
+            transport.send(server_addr, b"first");
+            transport.send(server_addr, &big_payload);
+            transport.send(server_addr, b"last");
+        }
+
+        let mut connects = 0;
+        let mut payloads = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while payloads.len() < 3 && Instant::now() < deadline {
+            client.update();
+            server.update();
+            let events = server.world.resource::<Events<NetworkSimulationEvent>>();
+            for event in events.get_reader().iter(events) {
+                match event {
+                    NetworkSimulationEvent::Connect(_) => connects += 1,
+                    NetworkSimulationEvent::PeerConnected(..) => {}
+                    NetworkSimulationEvent::Message(_, payload) => payloads.push(payload.clone()),
+                    event => panic!("unexpected event {:?}", event),
+                }
+            }
+            server.world.resource_mut::<Events<NetworkSimulationEvent>>().clear();
+        }
+
+        assert_eq!(connects, 1);
+        assert_eq!(payloads, vec![
+            Bytes::from_static(b"first"),
+            Bytes::from(big_payload),
+            Bytes::from_static(b"last"),
+        ]);
+    }
+
+    #[test]
+    fn test_unreliable_message_is_rejected() {
+        let mut app = create_test_app();
+        let addr = listen_addr(&app);
+
+        app.world.resource_mut::<TransportResource>().send_with_requirements(
+            addr,
+            b"test",
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::Immediate,
+        );
+        app.update();
+
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        let errors: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .filter_map(|event| match event {
+                NetworkSimulationEvent::SendError(e, message) => Some((e.kind(), message.delivery)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors, vec![(io::ErrorKind::InvalidInput, DeliveryRequirement::Unreliable)]);
+        assert!(!app.world.resource::<TcpStreamsResource>().is_connected(&addr));
+    }
+
+    #[test]
+    fn test_oversized_frame_is_refused() {
+        let mut connection = TcpConnection {
+            stream: TcpStream::connect(listen_addr(&create_test_app())).unwrap(),
+            read_buffer: Vec::new(),
+            write_buffer: Vec::new(),
+        };
+        connection.read_buffer.extend_from_slice(&16_u32.to_be_bytes());
+
+        assert_eq!(connection.next_frame(8).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(connection.next_frame(16).unwrap().is_none());
+    }
+
+    fn listen_addr(app: &App) -> SocketAddr {
+        app.world.resource::<TcpStreamsResource>().listener().unwrap().local_addr().unwrap()
+    }
+
+    fn create_test_app() -> App {
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(TcpPlugin::new("127.0.0.1:0".parse().unwrap()));
+        app
+    }
+}
@@ -0,0 +1,500 @@
+//! Network systems implementation backed by a plain, non-blocking UDP socket.
+//!
+//! UDP offers no delivery or ordering guarantees, so only `DeliveryRequirement::Unreliable` and
+//! `DeliveryRequirement::Default` (which means unreliable for this transport) are sent. Messages
+//! asking for anything stronger are rejected with a `NetworkSimulationEvent::SendError`.
+//!
+//! The socket can also join multicast groups, e.g. to stream the game to LAN spectators. Messages
+//! to a group address go out like any other, with the same delivery rules. Each group is received
+//! on a socket of its own bound to the port of the group, which can't be shared with another
+//! process of the same host.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+};
+
+use bytes::Bytes;
+use bevy::log::{info, error};
+
+use crate::simulation::{
+    events::{NetworkSimulationEvent, TaggedNetworkEvent},
+    requirements::DeliveryRequirement,
+    timing::{NetworkSimulationTime, network_simulation_time_system},
+    transport::{
+        routing::{NetworkEventWriter, TransportId, unroutable_messages_system},
+        TransportResource,
+    },
+};
+use bevy::prelude::{Plugin, Resource, Res, ResMut, EventWriter, SystemSet, SystemLabel};
+use bevy::app::App;
+
+/// Default size of the receive buffer, large enough for any UDP datagram.
+const DEFAULT_RECV_BUFFER_SIZE_BYTES: usize = 65_507;
+
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct UdpLabel;
+
+/// Interface a multicast group is joined on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MulticastInterface {
+    // The one the OS picks
+    Any,
+    // The IPv4 interface with this address
+    V4(Ipv4Addr),
+    // The IPv6 interface with this index
+    V6(u32),
+}
+
+/// Event emitted along with the `Message` event of each datagram received from a multicast group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MulticastMessage {
+    pub group:   SocketAddr,
+    pub from:    SocketAddr,
+    pub payload: Bytes,
+}
+
+/// Use this plugin to add the plain UDP transport layer to your game.
+pub struct UdpPlugin {
+    address:                SocketAddr,
+    recv_buffer_size_bytes: usize,
+    multicast_groups:       Vec<(SocketAddr, MulticastInterface)>,
+    multicast_ttl:          Option<u32>,
+}
+
+impl UdpPlugin {
+    /// Creates a plugin binding to `address`, with a receive buffer big enough for any datagram.
+    pub fn new(address: SocketAddr) -> Self {
+        UdpPlugin {
+            address,
+            recv_buffer_size_bytes: DEFAULT_RECV_BUFFER_SIZE_BYTES,
+            multicast_groups:       Vec::new(),
+            multicast_ttl:          None,
+        }
+    }
+
+    /// Sets the size of the receive buffer. Datagrams larger than this are truncated by the OS.
+    #[must_use]
+    pub fn with_recv_buffer_size(mut self, recv_buffer_size_bytes: usize) -> Self {
+        self.recv_buffer_size_bytes = recv_buffer_size_bytes;
+        self
+    }
+
+    /// Joins the multicast group on the given interface, see `UdpSocketResource::join_multicast`.
+    /// Failing to join is reported as a `ConnectionError` on the first frame.
+    #[must_use]
+    pub fn with_multicast_group(mut self, group: SocketAddr, interface: MulticastInterface) -> Self {
+        self.multicast_groups.push((group, interface));
+        self
+    }
+
+    /// Sets how many hops the sent multicast datagrams live for, see
+    /// `UdpSocketResource::set_multicast_ttl`.
+    #[must_use]
+    pub fn with_multicast_ttl(mut self, ttl: u32) -> Self {
+        self.multicast_ttl = Some(ttl);
+        self
+    }
+}
+
+impl Plugin for UdpPlugin {
+    /// Failing to bind the socket doesn't panic, it is reported as a `ConnectionError` on the first
+    /// frame and the resource is left without a socket.
+    fn build(&self, app: &mut App) {
+        let (socket, error) = match UdpSocket::bind(self.address)
+            .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+        {
+            Ok(socket) => (Some(socket), None),
+            Err(e) => (None, Some(e)),
+        };
+
+        let mut resource = UdpSocketResource::new(socket, self.recv_buffer_size_bytes);
+        let mut errors: Vec<_> = error.map(|e| (e, self.address)).into_iter().collect();
+        if let (Some(ttl), true) = (self.multicast_ttl, resource.get().is_some()) {
+            errors.extend(resource.set_multicast_ttl(ttl).err().map(|e| (e, self.address)));
+        }
+        for (group, interface) in &self.multicast_groups {
+            errors.extend(resource.join_multicast(*group, *interface).err().map(|e| (e, *group)));
+        }
+
+        app
+            .add_startup_system(log_startup)
+            .add_event::<NetworkSimulationEvent>()
+            .add_event::<TaggedNetworkEvent>()
+            .add_event::<MulticastMessage>()
+            .init_resource::<NetworkSimulationTime>()
+            .init_resource::<TransportResource>()
+            .insert_resource(resource)
+            .add_system_set(SystemSet::new()
+                .label(UdpLabel)
+                .with_system(network_simulation_time_system)
+                .with_system(unroutable_messages_system)
+                .with_system(udp_network_send_system)
+                .with_system(udp_network_recv_system)
+            );
+        app.world.resource_mut::<TransportResource>().register_transport(TransportId::UDP);
+
+        for (e, addr) in errors {
+            app.world.send_event(NetworkSimulationEvent::ConnectionError(e, Some(addr)));
+        }
+    }
+
+    fn name(&self) -> &str {
+        "udp"
+    }
+}
+
+fn log_startup(socket: Res<UdpSocketResource>) {
+    match socket.get().map(UdpSocket::local_addr) {
+        Some(Ok(addr)) => info!("Start listening on {}", addr),
+        _ => error!("UDP socket is not bound"),
+    }
+}
+
+/// Creates a new udp network send system.
+pub fn udp_network_send_system(mut transport: ResMut<TransportResource>,
+                               mut socket:        ResMut<UdpSocketResource>,
+                               mut event_channel: EventWriter<NetworkSimulationEvent>,
+                                   sim_time:      Res<NetworkSimulationTime>) {
+
+    if let Some(socket) = socket.get_mut() {
+        let messages = transport
+            .drain_messages_to_send_via(TransportId::UDP, |_| sim_time.should_send_message_now());
+
+        for message in messages {
+            match message.delivery {
+                DeliveryRequirement::Unreliable | DeliveryRequirement::Default => {
+                    if let Err(e) = socket.send_to(&message.payload, message.destination) {
+                        if let Some((e, message)) = transport.retry_failed(e, message) {
+                            event_channel.send(NetworkSimulationEvent::SendError(e, message));
+                        }
+                    }
+                }
+                delivery => {
+                    let e = io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("UDP transport does not support {:?} delivery", delivery),
+                    );
+                    event_channel.send(NetworkSimulationEvent::SendError(e, message));
+                }
+            }
+        }
+    }
+}
+
+/// Creates a new udp receive system. The datagrams of the multicast groups are emitted as
+/// `Message` events too, along with a `MulticastMessage` telling their group.
+pub fn udp_network_recv_system(mut socket:           ResMut<UdpSocketResource>,
+                               mut event_channel:    NetworkEventWriter,
+                               mut multicast_events: EventWriter<MulticastMessage>) {
+    let UdpSocketResource { socket, recv_buffer, multicast } = &mut *socket;
+    if let Some(socket) = socket {
+        receive(socket, recv_buffer, &mut event_channel, |_, _| {});
+    }
+    for joined in multicast {
+        receive(&joined.socket, recv_buffer, &mut event_channel, |from, payload| {
+            multicast_events.send(MulticastMessage { group: joined.group, from, payload });
+        });
+    }
+}
+
+/// Emits what the socket received until it would block, calling `on_message` for each datagram.
+fn receive(socket:        &UdpSocket,
+           recv_buffer:   &mut [u8],
+           event_channel: &mut NetworkEventWriter,
+       mut on_message:    impl FnMut(SocketAddr, Bytes)) {
+    loop {
+        match socket.recv_from(recv_buffer) {
+            Ok((recv_len, address)) => {
+                let payload = Bytes::copy_from_slice(&recv_buffer[..recv_len]);
+                on_message(address, payload.clone());
+                event_channel.send(TransportId::UDP, NetworkSimulationEvent::Message(address, payload));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => {
+                event_channel.send(TransportId::UDP, NetworkSimulationEvent::RecvError(e));
+                break;
+            }
+        }
+    }
+}
+
+/// Socket receiving the datagrams of a multicast group.
+struct MulticastSocket {
+    group:  SocketAddr,
+    socket: UdpSocket,
+}
+
+impl MulticastSocket {
+    fn join(group: SocketAddr, interface: MulticastInterface) -> io::Result<Self> {
+        if !group.ip().is_multicast() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a multicast address", group)));
+        }
+        let socket = match (group.ip(), interface) {
+            (IpAddr::V4(ip), MulticastInterface::Any | MulticastInterface::V4(_)) => {
+                // on unix, binding to the group leaves out the datagrams of the other groups on the port
+                let bind_ip = if cfg!(unix) { ip } else { Ipv4Addr::UNSPECIFIED };
+                let socket = UdpSocket::bind((bind_ip, group.port()))?;
+                let interface = match interface {
+                    MulticastInterface::V4(interface) => interface,
+                    _ => Ipv4Addr::UNSPECIFIED,
+                };
+                socket.join_multicast_v4(&ip, &interface)?;
+                socket
+            }
+            (IpAddr::V6(ip), MulticastInterface::Any | MulticastInterface::V6(_)) => {
+                let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, group.port()))?;
+                let index = match interface {
+                    MulticastInterface::V6(index) => index,
+                    _ => 0,
+                };
+                socket.join_multicast_v6(&ip, index)?;
+                socket
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("interface {:?} is not of the address family of {}", interface, group),
+                ));
+            }
+        };
+        socket.set_nonblocking(true)?;
+        Ok(Self { group, socket })
+    }
+}
+
+/// Resource that owns the UDP socket and the buffer datagrams are received into, along with the
+/// sockets of the multicast groups joined.
+#[derive(Resource)]
+pub struct UdpSocketResource {
+    socket:      Option<UdpSocket>,
+    recv_buffer: Vec<u8>,
+    multicast:   Vec<MulticastSocket>,
+}
+
+impl Default for UdpSocketResource {
+    fn default() -> Self {
+        Self::new(None, DEFAULT_RECV_BUFFER_SIZE_BYTES)
+    }
+}
+
+impl UdpSocketResource {
+    /// Creates a new instance of the `UdpSocketResource`. The socket must be in non-blocking mode.
+    #[must_use]
+    pub fn new(socket: Option<UdpSocket>, recv_buffer_size_bytes: usize) -> Self {
+        Self { socket, recv_buffer: vec![0; recv_buffer_size_bytes], multicast: Vec::new() }
+    }
+
+    /// Returns a reference to the socket if there is one configured.
+    #[must_use]
+    pub fn get(&self) -> Option<&UdpSocket> {
+        self.socket.as_ref()
+    }
+
+    /// Returns a mutable reference to the socket if there is one configured.
+    pub fn get_mut(&mut self) -> Option<&mut UdpSocket> {
+        self.socket.as_mut()
+    }
+
+    /// Returns the size of the buffer datagrams are received into.
+    #[must_use]
+    pub fn recv_buffer_size_bytes(&self) -> usize {
+        self.recv_buffer.len()
+    }
+
+    /// Joins the multicast group on the given interface, binding a socket to the port of the
+    /// group to receive its datagrams. Joining a group twice does nothing.
+    pub fn join_multicast(&mut self, group: SocketAddr, interface: MulticastInterface) -> io::Result<()> {
+        if !self.multicast.iter().any(|joined| joined.group == group) {
+            self.multicast.push(MulticastSocket::join(group, interface)?);
+        }
+        Ok(())
+    }
+
+    /// Leaves the multicast group, dropping its socket.
+    pub fn leave_multicast(&mut self, group: SocketAddr) -> io::Result<()> {
+        match self.multicast.iter().position(|joined| joined.group == group) {
+            Some(index) => {
+                self.multicast.remove(index);
+                Ok(())
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("multicast group {} isn't joined", group))),
+        }
+    }
+
+    /// Returns the multicast groups joined.
+    pub fn multicast_groups(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.multicast.iter().map(|joined| joined.group)
+    }
+
+    /// Sets how many hops the multicast datagrams sent live for, 1 by default which keeps them in
+    /// the local network. Only IPv4 sockets support it, the standard library offers no way to set
+    /// the hop limit of IPv6.
+    pub fn set_multicast_ttl(&mut self, ttl: u32) -> io::Result<()> {
+        match &self.socket {
+            Some(socket) if socket.local_addr()?.is_ipv4() => socket.set_multicast_ttl_v4(ttl),
+            Some(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "the IPv6 multicast hop limit can't be set")),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "UDP socket is not bound")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use bevy::ecs::event::Events;
+
+    use super::*;
+    use crate::simulation::requirements::UrgencyRequirement;
+
+    #[test]
+    fn test_unreliable_message_is_received() {
+        let mut sender = create_test_app();
+        let mut receiver = create_test_app();
+        let destination = local_addr(&receiver);
+
+        sender.world.resource_mut::<TransportResource>().send_with_requirements(
+            destination,
+            b"test",
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::Immediate,
+        );
+        sender.update();
+
+        let payloads = receive_payloads(&mut receiver);
+        assert_eq!(payloads, vec![Bytes::from_static(b"test")]);
+    }
+
+    #[test]
+    fn test_reliable_message_is_rejected() {
+        let mut sender = create_test_app();
+        let destination = local_addr(&sender);
+
+        sender.world.resource_mut::<TransportResource>().send_with_requirements(
+            destination,
+            b"test",
+            DeliveryRequirement::Reliable,
+            UrgencyRequirement::Immediate,
+        );
+        sender.update();
+
+        let events = sender.world.resource::<Events<NetworkSimulationEvent>>();
+        let errors: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .filter_map(|event| match event {
+                NetworkSimulationEvent::SendError(e, message) => Some((e.kind(), message.delivery)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors, vec![(io::ErrorKind::InvalidInput, DeliveryRequirement::Reliable)]);
+        assert!(!sender.world.resource::<TransportResource>().has_messages());
+    }
+
+    #[test]
+    fn test_recv_buffer_size_is_configurable() {
+        let mut app = App::new();
+        app.add_plugin(UdpPlugin::new("127.0.0.1:0".parse().unwrap()).with_recv_buffer_size(8));
+        assert_eq!(app.world.resource::<UdpSocketResource>().recv_buffer_size_bytes(), 8);
+    }
+
+    #[test]
+    fn test_bind_failure_is_emitted_as_event() {
+        let taken = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap();
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(UdpPlugin::new(addr));
+        app.update();
+
+        assert!(app.world.resource::<UdpSocketResource>().get().is_none());
+        let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+        let errors: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .filter_map(|event| match event {
+                NetworkSimulationEvent::ConnectionError(e, addr) => Some((e.kind(), *addr)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors, vec![(io::ErrorKind::AddrInUse, Some(addr))]);
+    }
+
+    #[test]
+    fn test_multicast_datagrams_are_received_with_their_group() {
+        // a socket bound to loopback can't send multicast
+        let mut sender = App::new();
+        sender.init_resource::<bevy::time::Time>()
+            .add_plugin(UdpPlugin::new("0.0.0.0:0".parse().unwrap()));
+        let port = UdpSocket::bind("0.0.0.0:0").unwrap().local_addr().unwrap().port();
+        let group = SocketAddr::from(([239, 255, 42, 99], port));
+        let mut receiver = App::new();
+        receiver.init_resource::<bevy::time::Time>()
+            .add_plugin(UdpPlugin::new("127.0.0.1:0".parse().unwrap())
+                .with_multicast_group(group, MulticastInterface::Any)
+                .with_multicast_ttl(1));
+        assert_eq!(receiver.world.resource::<UdpSocketResource>().multicast_groups().collect::<Vec<_>>(), [group]);
+
+        sender.world.resource_mut::<TransportResource>().send_with_requirements(
+            group,
+            b"spectate",
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::Immediate,
+        );
+        sender.update();
+
+        assert_eq!(receive_payloads(&mut receiver), vec![Bytes::from_static(b"spectate")]);
+        let events = receiver.world.resource::<Events<MulticastMessage>>();
+        let messages: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .map(|message| (message.group, message.payload.clone()))
+            .collect();
+        assert_eq!(messages, [(group, Bytes::from_static(b"spectate"))]);
+
+        let mut socket = receiver.world.resource_mut::<UdpSocketResource>();
+        socket.leave_multicast(group).unwrap();
+        assert_eq!(socket.multicast_groups().count(), 0);
+        assert_eq!(socket.leave_multicast(group).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_invalid_multicast_groups_are_refused() {
+        let mut socket = UdpSocketResource::default();
+        let unicast = "127.0.0.1:3000".parse().unwrap();
+        let group = "[ff02::1234]:3000".parse().unwrap();
+        for (group, interface) in [
+            (unicast, MulticastInterface::Any),
+            (group, MulticastInterface::V4(Ipv4Addr::LOCALHOST)),
+        ] {
+            assert_eq!(socket.join_multicast(group, interface).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(socket.set_multicast_ttl(1).unwrap_err().kind(), io::ErrorKind::NotConnected);
+    }
+
+    fn receive_payloads(app: &mut App) -> Vec<Bytes> {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut payloads = Vec::new();
+        while payloads.is_empty() && Instant::now() < deadline {
+            app.update();
+            let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+            payloads.extend(events.get_reader().iter(events).filter_map(|event| match event {
+                NetworkSimulationEvent::Message(_, payload) => Some(payload.clone()),
+                _ => None,
+            }));
+        }
+        payloads
+    }
+
+    fn local_addr(app: &App) -> SocketAddr {
+        app.world.resource::<UdpSocketResource>().get().unwrap().local_addr().unwrap()
+    }
+
+    fn create_test_app() -> App {
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(UdpPlugin::new("127.0.0.1:0".parse().unwrap()));
+        app
+    }
+}
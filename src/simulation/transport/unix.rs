@@ -0,0 +1,344 @@
+//! Network systems implementation backed by a non-blocking unix domain datagram socket, for
+//! exchanging messages with other processes on the same machine.
+//!
+//! `TransportResource` addresses destinations by `SocketAddr`, so peers are given a synthetic
+//! address out of the reserved 240.0.0.0/4 block by `UnixSocketResource::register_peer`. Messages
+//! from a peer are reported with the same synthetic address. Unix datagrams are neither lost nor
+//! reordered, so every `DeliveryRequirement` is accepted; a peer which can't keep up makes sends
+//! fail with `WouldBlock`, reported as a `NetworkSimulationEvent::SendError`.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+};
+
+use bytes::Bytes;
+use bevy::log::{info, error};
+
+use crate::simulation::{
+    events::{NetworkSimulationEvent, TaggedNetworkEvent},
+    timing::{NetworkSimulationTime, network_simulation_time_system},
+    transport::{
+        routing::{NetworkEventWriter, TransportId, unroutable_messages_system},
+        TransportResource,
+    },
+};
+use bevy::prelude::{Plugin, Resource, Res, ResMut, EventWriter, SystemSet, SystemLabel};
+use bevy::app::App;
+
+/// Default size of the receive buffer.
+const DEFAULT_RECV_BUFFER_SIZE_BYTES: usize = 65_507;
+/// First address of the reserved block synthetic addresses are allocated from.
+const SYNTHETIC_ADDR_BASE: u32 = 0xF000_0000;
+
+#[derive(SystemLabel, Clone, Hash, Debug, PartialEq, Eq)]
+pub struct UnixLabel;
+
+/// Use this plugin to add the unix domain socket transport layer to your game.
+pub struct UnixSocketPlugin {
+    path:                   PathBuf,
+    recv_buffer_size_bytes: usize,
+}
+
+impl UnixSocketPlugin {
+    /// Creates a plugin binding a datagram socket to `path`, which must not exist yet.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        UnixSocketPlugin { path: path.into(), recv_buffer_size_bytes: DEFAULT_RECV_BUFFER_SIZE_BYTES }
+    }
+
+    /// Sets the size of the receive buffer. Datagrams larger than this are truncated.
+    #[must_use]
+    pub fn with_recv_buffer_size(mut self, recv_buffer_size_bytes: usize) -> Self {
+        self.recv_buffer_size_bytes = recv_buffer_size_bytes;
+        self
+    }
+}
+
+impl Plugin for UnixSocketPlugin {
+    /// Failing to bind the socket doesn't panic, it is reported as a `ConnectionError` on the first
+    /// frame and the resource is left without a socket.
+    fn build(&self, app: &mut App) {
+        let (socket, error) = match UnixDatagram::bind(&self.path)
+            .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+        {
+            Ok(socket) => (Some(socket), None),
+            Err(e) => (None, Some(e)),
+        };
+
+        app
+            .add_startup_system(log_startup)
+            .add_event::<NetworkSimulationEvent>()
+            .add_event::<TaggedNetworkEvent>()
+            .init_resource::<NetworkSimulationTime>()
+            .init_resource::<TransportResource>()
+            .insert_resource(UnixSocketResource::new(socket, self.recv_buffer_size_bytes))
+            .add_system_set(SystemSet::new()
+                .label(UnixLabel)
+                .with_system(network_simulation_time_system)
+                .with_system(unroutable_messages_system)
+                .with_system(unix_network_send_system)
+                .with_system(unix_network_recv_system)
+            );
+        app.world.resource_mut::<TransportResource>().register_transport(TransportId::UNIX);
+
+        if let Some(e) = error {
+            app.world.send_event(NetworkSimulationEvent::ConnectionError(e, None));
+        }
+    }
+
+    fn name(&self) -> &str {
+        "unix"
+    }
+}
+
+fn log_startup(socket: Res<UnixSocketResource>) {
+    match socket.local_path() {
+        Some(path) => info!("Start listening on {}", path.display()),
+        None => error!("Unix socket is not bound"),
+    }
+}
+
+/// Creates a new unix socket network send system.
+pub fn unix_network_send_system(mut transport: ResMut<TransportResource>,
+                                mut socket:        ResMut<UnixSocketResource>,
+                                mut event_channel: EventWriter<NetworkSimulationEvent>,
+                                    sim_time:      Res<NetworkSimulationTime>) {
+    let UnixSocketResource { socket, peers, .. } = &mut *socket;
+    if let Some(socket) = socket {
+        let messages = transport
+            .drain_messages_to_send_via(TransportId::UNIX, |_| sim_time.should_send_message_now());
+
+        for message in messages {
+            let result = match peers.path(&message.destination) {
+                Some(path) => socket.send_to(&message.payload, path).map(|_| ()),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} is not a registered unix socket peer", message.destination),
+                )),
+            };
+            if let Err(e) = result {
+                if let Some((e, message)) = transport.retry_failed(e, message) {
+                    event_channel.send(NetworkSimulationEvent::SendError(e, message));
+                }
+            }
+        }
+    }
+}
+
+/// Creates a new unix socket receive system. Datagrams from sockets which aren't bound to a path
+/// can't be answered and are reported as `RecvError`.
+pub fn unix_network_recv_system(mut socket:        ResMut<UnixSocketResource>,
+                                mut event_channel: NetworkEventWriter) {
+    let UnixSocketResource { socket, peers, recv_buffer, .. } = &mut *socket;
+    if let Some(socket) = socket {
+        loop {
+            match socket.recv_from(recv_buffer) {
+                Ok((recv_len, address)) => match address.as_pathname() {
+                    Some(path) => {
+                        event_channel.send(TransportId::UNIX, NetworkSimulationEvent::Message(
+                            peers.register(path),
+                            Bytes::copy_from_slice(&recv_buffer[..recv_len]),
+                        ));
+                    }
+                    None => {
+                        event_channel.send(TransportId::UNIX, NetworkSimulationEvent::RecvError(io::Error::new(
+                            io::ErrorKind::AddrNotAvailable,
+                            "received a datagram from an unnamed unix socket",
+                        )));
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    event_channel.send(TransportId::UNIX, NetworkSimulationEvent::RecvError(e));
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Two way mapping between socket paths and the synthetic addresses standing in for them.
+#[derive(Debug, Default)]
+struct PeerTable {
+    addrs: HashMap<PathBuf, SocketAddr>,
+    paths: HashMap<SocketAddr, PathBuf>,
+}
+
+impl PeerTable {
+    fn register(&mut self, path: &Path) -> SocketAddr {
+        if let Some(addr) = self.addrs.get(path) {
+            return *addr;
+        }
+        let ip = Ipv4Addr::from(SYNTHETIC_ADDR_BASE + self.addrs.len() as u32 + 1);
+        let addr = SocketAddr::V4(SocketAddrV4::new(ip, 0));
+        self.addrs.insert(path.to_path_buf(), addr);
+        self.paths.insert(addr, path.to_path_buf());
+        addr
+    }
+
+    fn path(&self, addr: &SocketAddr) -> Option<&Path> {
+        self.paths.get(addr).map(PathBuf::as_path)
+    }
+}
+
+/// Resource that owns the unix datagram socket and the addresses assigned to its peers. The socket
+/// file is removed again when the resource is dropped.
+#[derive(Resource)]
+pub struct UnixSocketResource {
+    socket:      Option<UnixDatagram>,
+    local_path:  Option<PathBuf>,
+    peers:       PeerTable,
+    recv_buffer: Vec<u8>,
+}
+
+impl Default for UnixSocketResource {
+    fn default() -> Self {
+        Self::new(None, DEFAULT_RECV_BUFFER_SIZE_BYTES)
+    }
+}
+
+impl UnixSocketResource {
+    /// Creates a new instance of the `UnixSocketResource`. The socket must be in non-blocking mode.
+    #[must_use]
+    pub fn new(socket: Option<UnixDatagram>, recv_buffer_size_bytes: usize) -> Self {
+        let local_path = socket
+            .as_ref()
+            .and_then(|socket| socket.local_addr().ok())
+            .and_then(|addr| addr.as_pathname().map(Path::to_path_buf));
+        Self { socket, local_path, peers: PeerTable::default(), recv_buffer: vec![0; recv_buffer_size_bytes] }
+    }
+
+    /// Returns a reference to the socket if there is one configured.
+    #[must_use]
+    pub fn get(&self) -> Option<&UnixDatagram> {
+        self.socket.as_ref()
+    }
+
+    /// Returns a mutable reference to the socket if there is one configured.
+    pub fn get_mut(&mut self) -> Option<&mut UnixDatagram> {
+        self.socket.as_mut()
+    }
+
+    /// Returns the path the socket is bound to.
+    #[must_use]
+    pub fn local_path(&self) -> Option<&Path> {
+        self.local_path.as_deref()
+    }
+
+    /// Returns the address to send messages to the socket bound at `path` with. Registering the
+    /// same path again returns the same address.
+    pub fn register_peer(&mut self, path: impl AsRef<Path>) -> SocketAddr {
+        self.peers.register(path.as_ref())
+    }
+
+    /// Returns the path of the peer `addr` was assigned to.
+    #[must_use]
+    pub fn peer_path(&self, addr: &SocketAddr) -> Option<&Path> {
+        self.peers.path(addr)
+    }
+}
+
+impl Drop for UnixSocketResource {
+    fn drop(&mut self) {
+        if let Some(path) = self.local_path.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{process, time::{Duration, Instant}};
+
+    use bevy::ecs::event::Events;
+
+    use super::*;
+    use crate::simulation::requirements::{DeliveryRequirement, UrgencyRequirement};
+
+    #[test]
+    fn test_message_is_received_from_registered_peer() {
+        let sender_path = socket_path("sender");
+        let mut sender = create_test_app(&sender_path);
+        let mut receiver = create_test_app(&socket_path("receiver"));
+        let receiver_path = receiver.world.resource::<UnixSocketResource>().local_path().unwrap().to_path_buf();
+        let destination = sender.world.resource_mut::<UnixSocketResource>().register_peer(&receiver_path);
+
+        sender.world.resource_mut::<TransportResource>().send_with_requirements(
+            destination,
+            b"test",
+            DeliveryRequirement::ReliableOrdered(None),
+            UrgencyRequirement::Immediate,
+        );
+        sender.update();
+
+        let (source, payload) = receive_message(&mut receiver).expect("no message received");
+        assert_eq!(payload, Bytes::from_static(b"test"));
+        assert_eq!(receiver.world.resource::<UnixSocketResource>().peer_path(&source), Some(sender_path.as_path()));
+    }
+
+    #[test]
+    fn test_unregistered_destination_is_rejected() {
+        let mut sender = create_test_app(&socket_path("unregistered"));
+
+        sender.world.resource_mut::<TransportResource>().send_with_requirements(
+            "240.0.0.1:0".parse().unwrap(),
+            b"test",
+            DeliveryRequirement::Unreliable,
+            UrgencyRequirement::Immediate,
+        );
+        sender.update();
+
+        let events = sender.world.resource::<Events<NetworkSimulationEvent>>();
+        let errors: Vec<_> = events
+            .get_reader()
+            .iter(events)
+            .filter_map(|event| match event {
+                NetworkSimulationEvent::SendError(e, _) => Some(e.kind()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(errors, vec![io::ErrorKind::NotFound]);
+    }
+
+    #[test]
+    fn test_socket_file_is_removed_on_drop() {
+        let path = socket_path("drop");
+        let app = create_test_app(&path);
+        assert!(path.exists());
+
+        drop(app);
+        assert!(!path.exists());
+    }
+
+    fn receive_message(app: &mut App) -> Option<(SocketAddr, Bytes)> {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while Instant::now() < deadline {
+            app.update();
+            let events = app.world.resource::<Events<NetworkSimulationEvent>>();
+            let message = events.get_reader().iter(events).find_map(|event| match event {
+                NetworkSimulationEvent::Message(addr, payload) => Some((*addr, payload.clone())),
+                _ => None,
+            });
+            if message.is_some() {
+                return message;
+            }
+        }
+        None
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("blaminar-{}-{}.sock", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn create_test_app(path: &Path) -> App {
+        let mut app = App::new();
+        app.init_resource::<bevy::time::Time>()
+            .add_plugin(UnixSocketPlugin::new(path));
+        app
+    }
+}
@@ -0,0 +1,92 @@
+//! Typed messages, serialized with serde so that game code doesn't deal with raw payloads.
+//!
+//! Messages are encoded as JSON, which any peer speaking serde can read back. Only available with
+//! the `serde` feature.
+
+use std::net::SocketAddr;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::simulation::{
+    events::NetworkSimulationEvent,
+    requirements::{DeliveryRequirement, UrgencyRequirement},
+    transport::TransportResource,
+};
+
+/// Error encoding or decoding a typed message.
+pub type CodecError = serde_json::Error;
+
+impl TransportResource {
+    /// Encodes `message` and queues it with the specified guarantee, to be sent on next sim tick.
+    /// Nothing is queued if the message can't be encoded.
+    pub fn send_typed<T: Serialize>(
+        &mut self,
+        destination: SocketAddr,
+        message: &T,
+        delivery: DeliveryRequirement,
+    ) -> Result<(), CodecError> {
+        let payload = serde_json::to_vec(message)?;
+        self.send_with_requirements(destination, &payload, delivery, UrgencyRequirement::OnTick);
+        Ok(())
+    }
+}
+
+/// Decodes the payload of a `Message` event along with its sender. Returns `None` for the other
+/// events, and the error for a payload which isn't a `T`.
+pub fn decode_message<T: DeserializeOwned>(
+    event: &NetworkSimulationEvent,
+) -> Option<Result<(SocketAddr, T), CodecError>> {
+    match event {
+        NetworkSimulationEvent::Message(addr, payload) => {
+            Some(serde_json::from_slice(payload).map(|message| (*addr, message)))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct PlayerState {
+        id:        u32,
+        name:      String,
+        position:  (f32, f32),
+        inventory: Vec<Item>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Item {
+        Key { door: u8 },
+        Coins(u64),
+    }
+
+    #[test]
+    fn test_typed_message_round_trip() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let state = PlayerState {
+            id:        7,
+            name:      "player".to_string(),
+            position:  (1.5, -2.0),
+            inventory: vec![Item::Key { door: 3 }, Item::Coins(120)],
+        };
+        let mut transport = TransportResource::new();
+        transport.send_typed(addr, &state, DeliveryRequirement::ReliableOrdered(None)).unwrap();
+
+        let message = transport.drain_messages_to_send(|_| true).remove(0);
+        assert_eq!(message.delivery, DeliveryRequirement::ReliableOrdered(None));
+        let event = NetworkSimulationEvent::Message(addr, message.payload);
+        assert_eq!(decode_message::<PlayerState>(&event).unwrap().unwrap(), (addr, state));
+    }
+
+    #[test]
+    fn test_decode_errors_are_returned() {
+        let addr = "127.0.0.1:3000".parse().unwrap();
+        let event = NetworkSimulationEvent::Message(addr, bytes::Bytes::from_static(b"not a player"));
+        assert!(decode_message::<PlayerState>(&event).unwrap().is_err());
+        assert!(decode_message::<PlayerState>(&NetworkSimulationEvent::Connect(addr)).is_none());
+    }
+}